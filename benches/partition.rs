@@ -0,0 +1,141 @@
+//! Partition workloads, to measure changes to segments, logs and indexes
+//!
+//! cargo bench --bench partition [filter]
+//!
+//! Every workload runs against temp partitions, once per record size.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use shoju::partition::Partition;
+use tempdir::TempDir;
+
+const RECORD_SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+const RECORDS: u64 = 2000;
+
+/// Deterministic records of a given size, keys cycling over a small set
+struct Workload {
+    record_size: usize,
+    state: u32,
+}
+
+impl Workload {
+    fn new(record_size: usize) -> Self {
+        Self {
+            record_size,
+            state: 7,
+        }
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        self.state = self.state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        self.state >> 16
+    }
+
+    fn key(&mut self) -> Vec<u8> {
+        format!("key-{}", self.next_u32() % 64).into_bytes()
+    }
+
+    fn value(&mut self) -> Vec<u8> {
+        (0..self.record_size)
+            .map(|_| self.next_u32() as u8)
+            .collect()
+    }
+
+    /// An offset in `[0, end)`
+    fn offset(&mut self, end: u64) -> u64 {
+        self.next_u32() as u64 % end
+    }
+
+    /// A partition in a temp dir holding `records` records
+    fn fill(&mut self, records: u64) -> (TempDir, Partition) {
+        let dir = TempDir::new("shoju_bench").unwrap();
+        let mut partition = Partition::open(dir.path()).unwrap();
+        for _ in 0..records {
+            let (key, value) = (self.key(), self.value());
+            partition.append_record(Some(key), &value).unwrap();
+        }
+        partition.flush().unwrap();
+        (dir, partition)
+    }
+}
+
+fn append_only(c: &mut Criterion) {
+    let mut group = c.benchmark_group("append_only");
+    for size in RECORD_SIZES {
+        group.throughput(Throughput::Bytes(size as u64 * RECORDS));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let mut workload = Workload::new(size);
+            b.iter_batched(
+                || TempDir::new("shoju_bench").unwrap(),
+                |dir| {
+                    let mut partition = Partition::open(dir.path()).unwrap();
+                    for _ in 0..RECORDS {
+                        let (key, value) = (workload.key(), workload.value());
+                        partition.append_record(Some(key), &value).unwrap();
+                    }
+                    partition.flush().unwrap();
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+fn mixed_read_write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mixed_read_write");
+    for size in RECORD_SIZES {
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let mut workload = Workload::new(size);
+            let (_dir, mut partition) = workload.fill(RECORDS);
+            // One append every four lookups
+            b.iter(|| {
+                for _ in 0..4 {
+                    let offset = workload.offset(partition.end_offset());
+                    partition.find_record(offset).unwrap();
+                }
+                let (key, value) = (workload.key(), workload.value());
+                partition.append_record(Some(key), &value).unwrap();
+            });
+        });
+    }
+    group.finish();
+}
+
+fn random_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("random_lookup");
+    for size in RECORD_SIZES {
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let mut workload = Workload::new(size);
+            let (_dir, mut partition) = workload.fill(RECORDS);
+            b.iter(|| partition.find_record(workload.offset(RECORDS)).unwrap());
+        });
+    }
+    group.finish();
+}
+
+fn catch_up_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("catch_up_read");
+    for size in RECORD_SIZES {
+        group.throughput(Throughput::Bytes(size as u64 * RECORDS));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let mut workload = Workload::new(size);
+            let (_dir, mut partition) = workload.fill(RECORDS);
+            // A consumer reading the whole partition from the start, in batches
+            b.iter(|| {
+                let mut reader = partition.reader().unwrap();
+                for from in (0..RECORDS).step_by(100) {
+                    reader.read_range(from, from + 100).unwrap();
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    append_only,
+    mixed_read_write,
+    random_lookup,
+    catch_up_read
+);
+criterion_main!(benches);
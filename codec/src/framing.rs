@@ -0,0 +1,104 @@
+//! Delimiting values in a byte stream
+//!
+//! The framings `shoju pipe` reads and writes values with, usable by clients on
+//! their own end of a stream. Decoding works on what was received so far, a
+//! value not yet complete is left in the buffer until more bytes arrive.
+use crate::CodecError;
+use alloc::vec::Vec;
+use core::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Framing {
+    /// Values terminated by a newline, the last one may lack it
+    #[default]
+    Newline,
+    /// Values terminated by a null byte, the last one may lack it
+    Null,
+    /// Values prefixed by their length, as a 32 bits big endian integer
+    LengthPrefixed,
+}
+
+impl Framing {
+    /// The terminator of the values, `None` when they're length prefixed
+    pub fn delimiter(&self) -> Option<u8> {
+        match self {
+            Framing::Newline => Some(b'\n'),
+            Framing::Null => Some(0),
+            Framing::LengthPrefixed => None,
+        }
+    }
+
+    /// Append the frame of `value` to `out`
+    pub fn encode(&self, value: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError> {
+        match self.delimiter() {
+            Some(delimiter) => {
+                out.extend_from_slice(value);
+                out.push(delimiter);
+            }
+            None => {
+                let len = u32::try_from(value.len()).map_err(|_| CodecError::ValueTooLarge)?;
+                out.extend_from_slice(&len.to_be_bytes());
+                out.extend_from_slice(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// The next complete value of `buf`, advancing it past its frame, `None` until
+    /// the frame is complete. At the end of a stream the bytes left are the last
+    /// value of a delimited framing.
+    pub fn decode<'a>(&self, buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+        let (value, rest) = match self.delimiter() {
+            Some(delimiter) => {
+                let end = buf.iter().position(|&b| b == delimiter)?;
+                (&buf[..end], &buf[end + 1..])
+            }
+            None => {
+                let len = u32::from_be_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+                let end = 4usize.checked_add(len)?;
+                (buf.get(4..end)?, &buf[end..])
+            }
+        };
+        *buf = rest;
+        Some(value)
+    }
+}
+
+impl FromStr for Framing {
+    type Err = CodecError;
+
+    fn from_str(s: &str) -> Result<Self, CodecError> {
+        match s {
+            "newline" => Ok(Framing::Newline),
+            "null" => Ok(Framing::Null),
+            "length" => Ok(Framing::LengthPrefixed),
+            _ => Err(CodecError::UnknownFraming),
+        }
+    }
+}
+
+#[cfg(test)]
+mod framing_tests {
+    use super::Framing;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_decode_partial() {
+        let mut encoded = Vec::new();
+        for value in [b"a".as_slice(), b"", b"with\nnewline"] {
+            Framing::LengthPrefixed.encode(value, &mut encoded).unwrap();
+        }
+        let mut buf = &encoded[..encoded.len() - 1];
+        assert_eq!(Framing::LengthPrefixed.decode(&mut buf), Some(&b"a"[..]));
+        assert_eq!(Framing::LengthPrefixed.decode(&mut buf), Some(&b""[..]));
+        // The last value isn't complete yet
+        assert_eq!(Framing::LengthPrefixed.decode(&mut buf), None);
+        assert_eq!(buf.len(), 15);
+
+        let mut buf = &b"a\0b"[..];
+        assert_eq!(Framing::Null.decode(&mut buf), Some(&b"a"[..]));
+        assert_eq!(Framing::Null.decode(&mut buf), None);
+        assert_eq!(buf, b"b");
+        assert!("length".parse::<Framing>().is_ok() && "csv".parse::<Framing>().is_err());
+    }
+}
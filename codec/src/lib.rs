@@ -0,0 +1,289 @@
+//! Binary format of shoju records, without std
+//!
+//! Only `core` and `alloc` are needed, so a device can frame records exactly like
+//! a partition writes them and ship them for appending, or decode the records a
+//! fetch returns. A `Frame` borrows its key, value and headers, decoding doesn't
+//! copy them out of the buffer.
+//!
+//! Frames are encoded in the current version of the format:
+//!
+//! ```text
+//! | magic (u8) | version (u8) | attributes (u8) | offset (u64) | timestamp (u64) |
+//! | key size (u32) | key | value size (u32) | value | headers, when flagged |
+//! ```
+//!
+//! Integers are big endian, a key size of 0 stands for no key. A batch is frames
+//! one after the other, as fetched in binary or written in a log.
+//!
+//! Without any I/O the crate builds for `wasm32-unknown-unknown` as well, for
+//! browser or edge clients encoding and decoding records themselves.
+#![no_std]
+
+extern crate alloc;
+
+pub mod framing;
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+
+pub const MAGIC_BYTE: u8 = 35;
+/// Version of the format frames are encoded with
+pub const RECORD_VERSION: u8 = 3;
+/// Attributes flag of a frame carrying headers
+pub const HEADERS_FLAG: u8 = 0x80;
+/// Size of a frame without key, value nor headers
+pub const FRAME_OVERHEAD: usize = 3 * size_of::<u8>() + 2 * size_of::<u64>() + 2 * size_of::<u32>();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CodecError {
+    MissingMagicByte,
+    UnsupportedVersion(u8),
+    /// A field extending past the end of the buffer
+    Truncated {
+        field: &'static str,
+    },
+    TooManyHeaders,
+    HeaderNameTooLong,
+    InvalidHeaderName,
+    /// A value too large for its length prefix
+    ValueTooLarge,
+    UnknownFraming,
+}
+
+impl core::error::Error for CodecError {}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::MissingMagicByte => write!(f, "Missing magic byte"),
+            CodecError::UnsupportedVersion(v) => write!(f, "Unsupported record version {}", v),
+            CodecError::Truncated { field } => write!(f, "Truncated record {}", field),
+            CodecError::TooManyHeaders => write!(f, "Too many headers"),
+            CodecError::HeaderNameTooLong => write!(f, "Header name too long"),
+            CodecError::InvalidHeaderName => write!(f, "Header name not valid UTF-8"),
+            CodecError::ValueTooLarge => write!(f, "Value too large for its frame"),
+            CodecError::UnknownFraming => write!(f, "Unknown framing"),
+        }
+    }
+}
+
+/// A record as encoded, borrowing its contents
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Frame<'a> {
+    /// Attributes byte, the headers flag is set on encoding when there are headers
+    pub attributes: u8,
+    pub offset: u64,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub key: Option<&'a [u8]>,
+    pub value: &'a [u8],
+    pub headers: Vec<(&'a str, &'a [u8])>,
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(offset: u64, timestamp: u64, key: Option<&'a [u8]>, value: &'a [u8]) -> Self {
+        Self {
+            offset,
+            timestamp,
+            key,
+            value,
+            ..Default::default()
+        }
+    }
+
+    pub fn encoded_size(&self) -> usize {
+        let headers = match self.headers.is_empty() {
+            true => 0,
+            false => {
+                size_of::<u16>()
+                    + self
+                        .headers
+                        .iter()
+                        .map(|(n, v)| size_of::<u16>() + n.len() + size_of::<u32>() + v.len())
+                        .sum::<usize>()
+            }
+        };
+        FRAME_OVERHEAD + self.key.map_or(0, <[u8]>::len) + self.value.len() + headers
+    }
+
+    /// Append the encoding of the frame to `out`, returning its size. Nothing is
+    /// appended when the frame can't be encoded.
+    pub fn encode(&self, out: &mut Vec<u8>) -> Result<usize, CodecError> {
+        let count = u16::try_from(self.headers.len()).map_err(|_| CodecError::TooManyHeaders)?;
+        if self
+            .headers
+            .iter()
+            .any(|(n, _)| n.len() > u16::MAX as usize)
+        {
+            return Err(CodecError::HeaderNameTooLong);
+        }
+        let key = self.key.unwrap_or_default();
+        let key_size = length(key.len())?;
+        let value_size = length(self.value.len())?;
+        let header_sizes = self
+            .headers
+            .iter()
+            .map(|(_, v)| length(v.len()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let size = self.encoded_size();
+        out.reserve(size);
+        let attributes = match self.headers.is_empty() {
+            true => self.attributes & !HEADERS_FLAG,
+            false => self.attributes | HEADERS_FLAG,
+        };
+        out.extend_from_slice(&[MAGIC_BYTE, RECORD_VERSION, attributes]);
+        out.extend_from_slice(&self.offset.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&key_size.to_be_bytes());
+        out.extend_from_slice(key);
+        out.extend_from_slice(&value_size.to_be_bytes());
+        out.extend_from_slice(self.value);
+        if count > 0 {
+            out.extend_from_slice(&count.to_be_bytes());
+            for ((name, value), value_size) in self.headers.iter().zip(header_sizes) {
+                out.extend_from_slice(&(name.len() as u16).to_be_bytes());
+                out.extend_from_slice(name.as_bytes());
+                out.extend_from_slice(&value_size.to_be_bytes());
+                out.extend_from_slice(value);
+            }
+        }
+        Ok(size)
+    }
+
+    /// Decode the frame at the start of `buf`, advancing it past the frame
+    pub fn decode(buf: &mut &'a [u8]) -> Result<Self, CodecError> {
+        if take(buf, 1, "magic byte")? != [MAGIC_BYTE] {
+            return Err(CodecError::MissingMagicByte);
+        }
+        let version = take(buf, 1, "version")?[0];
+        if version != RECORD_VERSION {
+            return Err(CodecError::UnsupportedVersion(version));
+        }
+        let attributes = take(buf, 1, "attributes")?[0];
+        let offset = u64::from_be_bytes(array(buf, "offset")?);
+        let timestamp = u64::from_be_bytes(array(buf, "timestamp")?);
+        let key_size = u32::from_be_bytes(array(buf, "key size")?);
+        let key = take(buf, key_size as usize, "key")?;
+        let value_size = u32::from_be_bytes(array(buf, "value size")?);
+        let value = take(buf, value_size as usize, "value")?;
+        let mut headers = Vec::new();
+        if attributes & HEADERS_FLAG != 0 {
+            let count = u16::from_be_bytes(array(buf, "header count")?);
+            for _ in 0..count {
+                let name_size = u16::from_be_bytes(array(buf, "header name size")?);
+                let name = take(buf, name_size as usize, "header name")?;
+                let name = core::str::from_utf8(name).map_err(|_| CodecError::InvalidHeaderName)?;
+                let value_size = u32::from_be_bytes(array(buf, "header value size")?);
+                headers.push((name, take(buf, value_size as usize, "header value")?));
+            }
+        }
+        Ok(Self {
+            attributes: attributes & !HEADERS_FLAG,
+            offset,
+            timestamp,
+            key: (key_size > 0).then_some(key),
+            value,
+            headers,
+        })
+    }
+}
+
+/// The frames of a batch, decoded one at a time
+pub struct Frames<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Frames<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Result<Frame<'a>, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let frame = Frame::decode(&mut self.buf);
+        // Nothing sensible follows a frame that doesn't decode
+        if frame.is_err() {
+            self.buf = &[];
+        }
+        Some(frame)
+    }
+}
+
+/// Encode `frames` one after the other in `out`, returning the bytes appended
+pub fn encode_batch(frames: &[Frame], out: &mut Vec<u8>) -> Result<usize, CodecError> {
+    frames.iter().map(|f| f.encode(out)).sum()
+}
+
+fn take<'a>(buf: &mut &'a [u8], size: usize, field: &'static str) -> Result<&'a [u8], CodecError> {
+    if buf.len() < size {
+        return Err(CodecError::Truncated { field });
+    }
+    let (taken, rest) = buf.split_at(size);
+    *buf = rest;
+    Ok(taken)
+}
+
+/// The u32 length prefix of a field of `len` bytes
+fn length(len: usize) -> Result<u32, CodecError> {
+    u32::try_from(len).map_err(|_| CodecError::ValueTooLarge)
+}
+
+fn array<const N: usize>(buf: &mut &[u8], field: &'static str) -> Result<[u8; N], CodecError> {
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(take(buf, N, field)?);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod codec_tests {
+    use super::{encode_batch, length, CodecError, Frame, Frames, HEADERS_FLAG};
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_frames() {
+        let mut traced = Frame::new(1, 1000, Some(b"key"), b"value");
+        traced.attributes = 0x20;
+        traced.headers = vec![("traceparent", b"00-01".as_slice())];
+        let frames = [Frame::new(0, 1000, None, b""), traced];
+        let mut encoded = Vec::new();
+        let size = encode_batch(&frames, &mut encoded).unwrap();
+        assert_eq!(size, encoded.len());
+        assert_eq!(frames[0].encoded_size(), 27);
+        assert_eq!(encoded[27 + 2], 0x20 | HEADERS_FLAG);
+
+        let decoded: Vec<Frame> = Frames::new(&encoded).map(Result::unwrap).collect();
+        assert_eq!(decoded, frames);
+        let mut truncated = &encoded[..encoded.len() - 1];
+        Frame::decode(&mut truncated).unwrap();
+        assert_eq!(
+            Frame::decode(&mut truncated),
+            Err(CodecError::Truncated {
+                field: "header value"
+            })
+        );
+        encoded[1] = 2;
+        assert_eq!(
+            Frame::decode(&mut &encoded[..]),
+            Err(CodecError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_value_too_large() {
+        assert_eq!(length(5), Ok(5));
+        assert_eq!(length(u32::MAX as usize), Ok(u32::MAX));
+        assert_eq!(
+            length(u32::MAX as usize + 1),
+            Err(CodecError::ValueTooLarge)
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! Append a batch of records to a partition, unless it has some already, and look
+//! a few offsets up
+//!
+//! cargo run --example smoke_test [dir]
+use shoju::partition::manifest::PartitionConfig;
+use shoju::partition::Partition;
+
+fn generate_partition(partition: &mut Partition, n: i32) -> std::io::Result<()> {
+    for _i in 0..n {
+        partition.append_record(Some("key".into()), &[0, 0, 1, 0])?;
+    }
+    partition.flush()
+}
+
+fn replay_log(partition: &mut Partition, offsets: &[u64]) {
+    for offset in offsets.iter() {
+        match partition.find_record(*offset) {
+            Ok(record) => println!("{}", record),
+            Err(e) => println!("Failed lookup {}: {}", offset, e),
+        }
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let dir = std::env::args().nth(1).unwrap_or_else(|| "logdir".into());
+    let mut partition = Partition::open_or_create(dir, &PartitionConfig::default())?;
+    if partition.end_offset() == 0 {
+        generate_partition(&mut partition, 1200)?;
+    }
+    replay_log(
+        &mut partition,
+        &[
+            0, 9, 10, 14, 53, 163, 208, 400, 499, 563, 957, 980, 1010, 1400,
+        ],
+    );
+    Ok(())
+}
@@ -0,0 +1,81 @@
+//! Connectors reading and writing newline delimited files
+//!
+//! `FileSource` turns every complete line of a file into a record, its position is
+//! the byte offset following the latest line read, so a line still being written
+//! is picked up once complete. `FileSink` appends the value of every record to a
+//! file, one per line.
+use crate::connect::{SinkConnector, SourceConnector, SourceRecord};
+use crate::consumer::ConsumerRecord;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub struct FileSource {
+    path: PathBuf,
+    position: u64,
+}
+
+impl FileSource {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            position: 0,
+        }
+    }
+}
+
+impl SourceConnector for FileSource {
+    fn seek(&mut self, position: u64) -> Result<()> {
+        self.position = position;
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<(Vec<SourceRecord>, u64)> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.position))?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let complete = content
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let records = content[..complete]
+            .split_inclusive(|&b| b == b'\n')
+            .map(|line| SourceRecord {
+                partition: 0,
+                key: None,
+                value: line[..line.len() - 1].to_vec(),
+            })
+            .collect();
+        self.position += complete as u64;
+        Ok((records, self.position))
+    }
+}
+
+pub struct FileSink {
+    writer: BufWriter<File>,
+}
+
+impl FileSink {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl SinkConnector for FileSink {
+    fn put(&mut self, records: &[ConsumerRecord]) -> Result<()> {
+        for record in records {
+            self.writer.write_all(&record.record.value)?;
+            self.writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
@@ -0,0 +1,281 @@
+//! Connectors moving records between topics and external systems
+//!
+//! A `SourceConnector` produces records read from an external system, a
+//! `SinkConnector` writes the records of topics to one. The `Connect` runtime
+//! drives both: every pass polls each source into its topic and pushes the records
+//! polled for each sink into it.
+//!
+//! Progress is tracked in the offsets store of the manager, under the name of each
+//! connector as the group. A source commits its own position, opaque to the
+//! runtime, once the records read up to it are appended, a sink commits the
+//! consumed offsets once its connector flushed the records. Both are at least once,
+//! the records after the latest commit are handled again after a crash.
+pub mod file;
+
+use crate::consumer::{Consumer, ConsumerRecord};
+use crate::group::assignor::TopicPartition;
+use crate::offsets::OffsetReset;
+use crate::producer::{Producer, ProducerRecord};
+use crate::topic::TopicManager;
+use std::io::Result;
+
+/// Bytes polled for a sink on each pass
+const SINK_POLL_BYTES: usize = 1024 * 1024;
+
+/// A record read from an external system
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceRecord {
+    pub partition: u32,
+    pub key: Option<Vec<u8>>,
+    pub value: Vec<u8>,
+}
+
+pub trait SourceConnector: Send {
+    /// Resume reading from `position`, the latest one committed
+    fn seek(&mut self, position: u64) -> Result<()>;
+
+    /// Read the records available, along with the position following them
+    fn poll(&mut self) -> Result<(Vec<SourceRecord>, u64)>;
+}
+
+pub trait SinkConnector: Send {
+    fn put(&mut self, records: &[ConsumerRecord]) -> Result<()>;
+
+    /// Make the records put so far durable, they're committed right after
+    fn flush(&mut self) -> Result<()>;
+}
+
+struct Source {
+    name: String,
+    topic: String,
+    connector: Box<dyn SourceConnector>,
+    started: bool,
+}
+
+struct Sink {
+    consumer: Consumer,
+    connector: Box<dyn SinkConnector>,
+}
+
+#[derive(Default)]
+pub struct Connect {
+    sources: Vec<Source>,
+    sinks: Vec<Sink>,
+    producer: Producer,
+}
+
+impl Connect {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append the records of `connector` to `topic`, tracking its position as
+    /// `name`
+    pub fn add_source(
+        &mut self,
+        name: &str,
+        topic: &str,
+        connector: impl SourceConnector + 'static,
+    ) {
+        self.sources.push(Source {
+            name: name.into(),
+            topic: topic.into(),
+            connector: Box::new(connector),
+            started: false,
+        });
+    }
+
+    /// Push the records of `partitions` to `connector`, from the offsets committed
+    /// as `name`
+    pub fn add_sink(
+        &mut self,
+        manager: &TopicManager,
+        name: &str,
+        partitions: &[TopicPartition],
+        connector: impl SinkConnector + 'static,
+    ) -> Result<()> {
+        let mut consumer = Consumer::new(name, OffsetReset::Earliest);
+        consumer.assign(manager, partitions)?;
+        self.sinks.push(Sink {
+            consumer,
+            connector: Box::new(connector),
+        });
+        Ok(())
+    }
+
+    /// Run a pass over every connector, returning the number of records moved.
+    ///
+    /// A connector failing is moved back to its latest committed position, the
+    /// records of the failed pass are handled again by the next one.
+    pub fn run_once(&mut self, manager: &mut TopicManager) -> Result<usize> {
+        let mut moved = 0;
+        for source in &mut self.sources {
+            match source.run_once(manager, &self.producer) {
+                Ok(n) => moved += n,
+                Err(e) => {
+                    // Seeks again to the committed position on the next pass
+                    source.started = false;
+                    return Err(e);
+                }
+            }
+        }
+        for sink in &mut self.sinks {
+            match sink.run_once(manager) {
+                Ok(n) => moved += n,
+                Err(e) => {
+                    sink.consumer.seek_to_committed(manager)?;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(moved)
+    }
+}
+
+impl Source {
+    fn run_once(&mut self, manager: &mut TopicManager, producer: &Producer) -> Result<usize> {
+        if !self.started {
+            let committed = manager.offsets().committed(&self.name, &self.topic, 0);
+            self.connector.seek(committed.unwrap_or(0))?;
+            self.started = true;
+        }
+        let (records, position) = self.connector.poll()?;
+        if records.is_empty() {
+            return Ok(0);
+        }
+        for record in &records {
+            let record = ProducerRecord::new(
+                &self.topic,
+                record.partition,
+                record.key.clone(),
+                &record.value,
+            );
+            producer.send(manager, record)?;
+        }
+        if let Some(topic) = manager.topic(&self.topic) {
+            topic.flush()?;
+        }
+        manager
+            .offsets()
+            .commit(&self.name, &self.topic, 0, position)?;
+        Ok(records.len())
+    }
+}
+
+impl Sink {
+    fn run_once(&mut self, manager: &mut TopicManager) -> Result<usize> {
+        let records = self.consumer.poll(manager, SINK_POLL_BYTES)?;
+        if records.is_empty() {
+            return Ok(0);
+        }
+        self.connector.put(&records)?;
+        self.connector.flush()?;
+        self.consumer.commit(manager)?;
+        Ok(records.len())
+    }
+}
+
+#[cfg(test)]
+mod connect_tests {
+    use super::file::{FileSink, FileSource};
+    use super::{Connect, SinkConnector};
+    use crate::consumer::ConsumerRecord;
+    use crate::group::assignor::TopicPartition;
+    use crate::producer::{Producer, ProducerRecord};
+    use crate::topic::{TopicConfig, TopicManager};
+    use std::fs::{self, OpenOptions};
+    use std::io::{Error, Result, Write};
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+
+    /// Sink failing its first put, keeping the values of the records put after
+    struct FlakySink {
+        failed: bool,
+        values: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl SinkConnector for FlakySink {
+        fn put(&mut self, records: &[ConsumerRecord]) -> Result<()> {
+            if !self.failed {
+                self.failed = true;
+                return Err(Error::other("Sink unavailable"));
+            }
+            let mut values = self.values.lock().unwrap();
+            values.extend(records.iter().map(|r| r.record.value.clone()));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_file_to_file() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let input = tmp_dir.path().join("input.txt");
+        let output = tmp_dir.path().join("output.txt");
+        fs::write(&input, "a\nb\npartial").unwrap();
+        let root = tmp_dir.path().join("root");
+        let mut manager = TopicManager::open(&root).unwrap();
+        manager.create_topic("lines", TopicConfig::new(1)).unwrap();
+        let partitions = [TopicPartition::new("lines", 0)];
+
+        let mut connect = Connect::new();
+        connect.add_source("in", "lines", FileSource::new(&input));
+        let sink = FileSink::open(&output).unwrap();
+        connect
+            .add_sink(&manager, "out", &partitions, sink)
+            .unwrap();
+        // The source pass appends, the sink pass of the same run copies
+        assert_eq!(connect.run_once(&mut manager).unwrap(), 4);
+        assert_eq!(connect.run_once(&mut manager).unwrap(), 0);
+        assert_eq!(fs::read_to_string(&output).unwrap(), "a\nb\n");
+
+        // Restarted connectors resume from their committed positions
+        drop(connect);
+        let mut file = OpenOptions::new().append(true).open(&input).unwrap();
+        file.write_all(b" line\nc\n").unwrap();
+        let mut connect = Connect::new();
+        connect.add_source("in", "lines", FileSource::new(&input));
+        let sink = FileSink::open(&output).unwrap();
+        connect
+            .add_sink(&manager, "out", &partitions, sink)
+            .unwrap();
+        assert_eq!(connect.run_once(&mut manager).unwrap(), 4);
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "a\nb\npartial line\nc\n"
+        );
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_sink_failure_redelivers() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager.create_topic("events", TopicConfig::new(1)).unwrap();
+        let producer = Producer::new();
+        for value in [b"a", b"b"] {
+            let record = ProducerRecord::new("events", 0, None, value);
+            producer.send(&mut manager, record).unwrap();
+        }
+        let values = Arc::new(Mutex::new(Vec::new()));
+        let sink = FlakySink {
+            failed: false,
+            values: Arc::clone(&values),
+        };
+        let mut connect = Connect::new();
+        let partitions = [TopicPartition::new("events", 0)];
+        connect
+            .add_sink(&manager, "out", &partitions, sink)
+            .unwrap();
+
+        assert!(connect.run_once(&mut manager).is_err());
+        // The records of the failed pass are put again
+        assert_eq!(connect.run_once(&mut manager).unwrap(), 2);
+        assert_eq!(*values.lock().unwrap(), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(connect.run_once(&mut manager).unwrap(), 0);
+        tmp_dir.close().unwrap();
+    }
+}
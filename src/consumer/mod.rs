@@ -0,0 +1,469 @@
+//! Embedded consumers
+//!
+//! A `Consumer` reads the partitions assigned to it out of a `TopicManager`,
+//! tracking its position on each of them. Positions start from the offsets
+//! committed by its group, reset by the `OffsetReset` policy when missing or out of
+//! range, and move forward as records are polled. Committing stores them back in
+//! the offsets store of the manager.
+//!
+//! Paused partitions stay assigned, keeping their position, but aren't polled
+//! until resumed, letting a consumer stop reading for a slow downstream.
+//!
+//! Polled records go through the `ConsumerInterceptor`s of the consumer before
+//! being returned, which may rewrite or drop them.
+//!
+//! A consumer transforming records into another partition commits its positions
+//! with `commit_transaction`, in the marker of the transaction appending its
+//! output, and resumes from them with `restore_transaction`: the records polled
+//! since the latest transaction are polled again while their output, never
+//! committed, is aborted. Read committed consumers downstream see every output
+//! once.
+use crate::group::assignor::TopicPartition;
+use crate::offsets::OffsetReset;
+use crate::partition::record::Record;
+use crate::partition::Partition;
+use crate::topic::TopicManager;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+
+/// A record polled from a topic partition
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsumerRecord {
+    pub topic: String,
+    pub partition: u32,
+    pub record: Record,
+}
+
+pub trait ConsumerInterceptor: Send {
+    /// Called with the records polled, the ones returned are handed to the caller
+    fn on_consume(&self, records: Vec<ConsumerRecord>) -> Vec<ConsumerRecord>;
+}
+
+/// The positions of a consumer committed by a transaction, its marker value
+#[derive(Serialize, Deserialize)]
+struct CommittedPositions {
+    group: String,
+    positions: Vec<(String, u32, u64)>,
+}
+
+pub struct Consumer {
+    group: String,
+    reset: OffsetReset,
+    positions: BTreeMap<TopicPartition, u64>,
+    paused: BTreeSet<TopicPartition>,
+    interceptors: Vec<Box<dyn ConsumerInterceptor>>,
+    read_committed: bool,
+}
+
+impl Consumer {
+    pub fn new(group: &str, reset: OffsetReset) -> Self {
+        Self {
+            group: group.into(),
+            reset,
+            positions: BTreeMap::new(),
+            paused: BTreeSet::new(),
+            interceptors: Vec::new(),
+            read_committed: false,
+        }
+    }
+
+    /// Poll only the transactional records committed, the `isolation.level` of the
+    /// consumer, reading uncommitted ones by default
+    pub fn set_read_committed(&mut self, read_committed: bool) {
+        self.read_committed = read_committed;
+    }
+
+    /// Intercept the records polled, after the interceptors added before
+    pub fn add_interceptor(&mut self, interceptor: impl ConsumerInterceptor + 'static) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    /// Start consuming `partitions`, from the offsets committed by the group
+    pub fn assign(&mut self, manager: &TopicManager, partitions: &[TopicPartition]) -> Result<()> {
+        for tp in partitions {
+            let position =
+                manager.position(&self.group, &tp.topic, tp.partition, self.reset, |_| {})?;
+            self.positions.insert(tp.clone(), position);
+        }
+        Ok(())
+    }
+
+    /// The offset of the next record polled from `tp`, if assigned
+    pub fn position(&self, tp: &TopicPartition) -> Option<u64> {
+        self.positions.get(tp).copied()
+    }
+
+    /// Move the assigned partitions back to the offsets committed by the group,
+    /// to poll again the records of a pass that failed before its commit
+    pub fn seek_to_committed(&mut self, manager: &TopicManager) -> Result<()> {
+        for (tp, position) in &mut self.positions {
+            *position =
+                manager.position(&self.group, &tp.topic, tp.partition, self.reset, |_| {})?;
+        }
+        Ok(())
+    }
+
+    pub fn seek(&mut self, tp: &TopicPartition, offset: u64) -> Result<()> {
+        *self.positions.get_mut(tp).ok_or_else(|| not_assigned(tp))? = offset;
+        Ok(())
+    }
+
+    /// Stop polling `partitions` until resumed
+    pub fn pause(&mut self, partitions: &[TopicPartition]) -> Result<()> {
+        if let Some(tp) = partitions
+            .iter()
+            .find(|tp| !self.positions.contains_key(tp))
+        {
+            return Err(not_assigned(tp));
+        }
+        self.paused.extend(partitions.iter().cloned());
+        Ok(())
+    }
+
+    pub fn resume(&mut self, partitions: &[TopicPartition]) {
+        for tp in partitions {
+            self.paused.remove(tp);
+        }
+    }
+
+    pub fn paused(&self) -> Vec<&TopicPartition> {
+        self.paused.iter().collect()
+    }
+
+    /// Move every assigned partition to its first record with a timestamp at or
+    /// after `timestamp`, to its end offset if every record is older
+    pub fn seek_to_timestamp(&mut self, manager: &TopicManager, timestamp: u64) -> Result<()> {
+        for (tp, position) in &mut self.positions {
+            let log = manager.partition(&tp.topic, tp.partition)?;
+            *position = log
+                .offset_for_timestamp(timestamp)?
+                .unwrap_or(log.end_offset());
+        }
+        Ok(())
+    }
+
+    /// Read the records following the positions of the assigned partitions, taking
+    /// at most `max_bytes`, see `Partition::fetch`
+    pub fn poll(
+        &mut self,
+        manager: &TopicManager,
+        max_bytes: usize,
+    ) -> Result<Vec<ConsumerRecord>> {
+        let mut polled = Vec::new();
+        let mut remaining = max_bytes;
+        for (tp, position) in &mut self.positions {
+            if remaining == 0 {
+                break;
+            }
+            if self.paused.contains(tp) {
+                continue;
+            }
+            let log = manager.partition(&tp.topic, tp.partition)?;
+            let (mut records, mut next) = if self.read_committed {
+                log.fetch_committed(*position, remaining)?
+            } else {
+                let records = log.fetch(*position, remaining)?;
+                let next = records.last().map_or(*position, |r| r.offset + 1);
+                (records, next)
+            };
+            let bytes: usize = records.iter().map(Record::binary_size).sum();
+            // Only the first records polled may exceed the budget
+            if !polled.is_empty() && bytes > remaining {
+                records.clear();
+                next = *position;
+            }
+            remaining = remaining.saturating_sub(bytes);
+            *position = next;
+            polled.extend(records.into_iter().map(|record| ConsumerRecord {
+                topic: tp.topic.clone(),
+                partition: tp.partition,
+                record,
+            }));
+        }
+        Ok(self
+            .interceptors
+            .iter()
+            .fold(polled, |polled, i| i.on_consume(polled)))
+    }
+
+    /// Commit the positions of the assigned partitions for the group
+    pub fn commit(&self, manager: &mut TopicManager) -> Result<()> {
+        for (tp, position) in &self.positions {
+            manager
+                .offsets()
+                .commit(&self.group, &tp.topic, tp.partition, *position)?;
+        }
+        Ok(())
+    }
+
+    /// Append `records` to `output` in a transaction committing the positions of
+    /// the consumer along with them. The positions are committed for the group too,
+    /// for its lag to show, but only the ones of the transactions are resumed from.
+    /// Returns the offsets of the records.
+    pub fn commit_transaction(
+        &self,
+        manager: &mut TopicManager,
+        output: &TopicPartition,
+        records: &[(Option<Vec<u8>>, Vec<u8>)],
+    ) -> Result<Range<u64>> {
+        let committed = CommittedPositions {
+            group: self.group.clone(),
+            positions: self
+                .positions
+                .iter()
+                .map(|(tp, position)| (tp.topic.clone(), tp.partition, *position))
+                .collect(),
+        };
+        let value = serde_json::to_vec(&committed).map_err(Error::other)?;
+        let log = partition_mut(manager, output)?;
+        let offsets = log.append_transaction(records, &value)?;
+        log.flush()?;
+        self.commit(manager)?;
+        Ok(offsets)
+    }
+
+    /// Abort the transaction left open on `output` and move the assigned partitions
+    /// to the positions committed by the latest transaction there, if it's one of
+    /// the group
+    pub fn restore_transaction(
+        &mut self,
+        manager: &mut TopicManager,
+        output: &TopicPartition,
+    ) -> Result<()> {
+        let log = partition_mut(manager, output)?;
+        log.abort_open_transaction()?;
+        let Some(value) = log.last_commit()? else {
+            return Ok(());
+        };
+        let committed: CommittedPositions =
+            serde_json::from_slice(&value).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if committed.group != self.group {
+            return Ok(());
+        }
+        for (topic, partition, offset) in committed.positions {
+            if let Some(position) = self
+                .positions
+                .get_mut(&TopicPartition::new(&topic, partition))
+            {
+                *position = offset;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn partition_mut<'a>(
+    manager: &'a mut TopicManager,
+    tp: &TopicPartition,
+) -> Result<&'a mut Partition> {
+    manager
+        .topic(&tp.topic)
+        .and_then(|topic| topic.partition(tp.partition))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Partition {} of topic {} not found", tp.partition, tp.topic),
+            )
+        })
+}
+
+fn not_assigned(tp: &TopicPartition) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "Partition {} of topic {} not assigned",
+            tp.partition, tp.topic
+        ),
+    )
+}
+
+#[cfg(test)]
+mod consumer_tests {
+    use super::{Consumer, ConsumerInterceptor, ConsumerRecord};
+    use crate::group::assignor::TopicPartition;
+    use crate::offsets::OffsetReset;
+    use crate::partition::record::Record;
+    use crate::topic::{TopicConfig, TopicManager};
+    use tempdir::TempDir;
+
+    fn produce(manager: &mut TopicManager, partitions: u32, records: u64) {
+        let topic = manager
+            .create_topic("events", TopicConfig::new(partitions))
+            .unwrap();
+        for n in 0..partitions {
+            let partition = topic.partition(n).unwrap();
+            for i in 0..records {
+                partition
+                    .append_record_at(i * 1000, None, &i.to_be_bytes())
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_poll_and_commit() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        produce(&mut manager, 2, 10);
+        let assigned = [
+            TopicPartition::new("events", 0),
+            TopicPartition::new("events", 1),
+        ];
+
+        let mut consumer = Consumer::new("group", OffsetReset::Earliest);
+        consumer.assign(&manager, &assigned).unwrap();
+        assert_eq!(consumer.poll(&manager, usize::MAX).unwrap().len(), 20);
+        assert!(consumer.poll(&manager, usize::MAX).unwrap().is_empty());
+        consumer.seek(&assigned[1], 5).unwrap();
+        consumer.commit(&mut manager).unwrap();
+
+        let mut consumer = Consumer::new("group", OffsetReset::Earliest);
+        consumer.assign(&manager, &assigned).unwrap();
+        let polled = consumer.poll(&manager, usize::MAX).unwrap();
+        assert_eq!(polled.len(), 5);
+        assert!(polled.iter().all(|r| r.partition == 1));
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_seek_to_timestamp() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        produce(&mut manager, 2, 10);
+        let assigned = [
+            TopicPartition::new("events", 0),
+            TopicPartition::new("events", 1),
+        ];
+        let mut consumer = Consumer::new("group", OffsetReset::Latest);
+        consumer.assign(&manager, &assigned).unwrap();
+        assert_eq!(consumer.position(&assigned[0]), Some(10));
+
+        consumer.seek_to_timestamp(&manager, 6500).unwrap();
+        assert_eq!(consumer.position(&assigned[0]), Some(7));
+        assert_eq!(consumer.position(&assigned[1]), Some(7));
+        assert_eq!(consumer.poll(&manager, usize::MAX).unwrap().len(), 6);
+        consumer.seek_to_timestamp(&manager, 1_000_000).unwrap();
+        assert_eq!(consumer.position(&assigned[0]), Some(10));
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        produce(&mut manager, 2, 10);
+        let assigned = [
+            TopicPartition::new("events", 0),
+            TopicPartition::new("events", 1),
+        ];
+        let mut consumer = Consumer::new("group", OffsetReset::Earliest);
+        consumer.assign(&manager, &assigned).unwrap();
+
+        consumer.pause(&assigned[..1]).unwrap();
+        assert!(consumer.pause(&[TopicPartition::new("events", 2)]).is_err());
+        assert_eq!(consumer.paused(), vec![&assigned[0]]);
+        let polled = consumer.poll(&manager, usize::MAX).unwrap();
+        assert!(polled.len() == 10 && polled.iter().all(|r| r.partition == 1));
+        assert_eq!(consumer.position(&assigned[0]), Some(0));
+
+        consumer.resume(&assigned[..1]);
+        let polled = consumer.poll(&manager, usize::MAX).unwrap();
+        assert!(polled.len() == 10 && polled.iter().all(|r| r.partition == 0));
+        tmp_dir.close().unwrap();
+    }
+    struct EvenOnly;
+
+    impl ConsumerInterceptor for EvenOnly {
+        fn on_consume(&self, records: Vec<ConsumerRecord>) -> Vec<ConsumerRecord> {
+            records
+                .into_iter()
+                .filter(|r| r.record.offset.is_multiple_of(2))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_interceptor() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        produce(&mut manager, 1, 10);
+        let assigned = [TopicPartition::new("events", 0)];
+        let mut consumer = Consumer::new("group", OffsetReset::Earliest);
+        consumer.add_interceptor(EvenOnly);
+        consumer.assign(&manager, &assigned).unwrap();
+
+        let polled = consumer.poll(&manager, usize::MAX).unwrap();
+        assert_eq!(polled.len(), 5);
+        // Dropped records are still consumed
+        assert_eq!(consumer.position(&assigned[0]), Some(10));
+        tmp_dir.close().unwrap();
+    }
+
+    /// The values of `records`, doubled
+    fn double(records: &[ConsumerRecord]) -> Vec<(Option<Vec<u8>>, Vec<u8>)> {
+        records
+            .iter()
+            .map(|r| (None, r.record.value.repeat(2)))
+            .collect()
+    }
+
+    #[test]
+    fn test_exactly_once() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        produce(&mut manager, 1, 10);
+        manager
+            .create_topic("doubled", TopicConfig::new(1))
+            .unwrap();
+        let input = [TopicPartition::new("events", 0)];
+        let output = TopicPartition::new("doubled", 0);
+        let mut consumer = Consumer::new("doubler", OffsetReset::Earliest);
+        consumer.assign(&manager, &input).unwrap();
+        let polled = consumer.poll(&manager, 100).unwrap();
+        let first = polled.len() as u64;
+        let offsets = consumer
+            .commit_transaction(&mut manager, &output, &double(&polled))
+            .unwrap();
+        assert_eq!(offsets, 0..first);
+
+        // Crashing after appending the output of the next records, uncommitted
+        let polled = consumer.poll(&manager, usize::MAX).unwrap();
+        let log = manager.topic("doubled").unwrap().partition(0).unwrap();
+        let mut record = Record::new(log.end_offset(), None, polled[0].record.value.repeat(2));
+        record.attributes = record.attributes.with_transactional(true);
+        log.append_replicated(&[record]).unwrap();
+
+        let mut consumer = Consumer::new("doubler", OffsetReset::Earliest);
+        consumer.assign(&manager, &input).unwrap();
+        consumer.restore_transaction(&mut manager, &output).unwrap();
+        assert_eq!(consumer.position(&input[0]), Some(first));
+        let polled = consumer.poll(&manager, usize::MAX).unwrap();
+        consumer
+            .commit_transaction(&mut manager, &output, &double(&polled))
+            .unwrap();
+        assert_eq!(
+            manager.offsets().committed("doubler", "events", 0),
+            Some(10)
+        );
+
+        let mut downstream = Consumer::new("downstream", OffsetReset::Earliest);
+        downstream
+            .assign(&manager, std::slice::from_ref(&output))
+            .unwrap();
+        assert_eq!(downstream.poll(&manager, usize::MAX).unwrap().len(), 11);
+        let mut downstream = Consumer::new("downstream", OffsetReset::Earliest);
+        downstream.set_read_committed(true);
+        downstream.assign(&manager, &[output]).unwrap();
+        let values: Vec<_> = downstream
+            .poll(&manager, usize::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.record.value)
+            .collect();
+        let expected: Vec<_> = (0..10u64).map(|i| i.to_be_bytes().repeat(2)).collect();
+        assert_eq!(values, expected);
+        tmp_dir.close().unwrap();
+    }
+}
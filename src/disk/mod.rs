@@ -0,0 +1,210 @@
+//! Free space monitoring of the data volume
+//!
+//! A `DiskMonitor` samples the space available to the volume holding the data
+//! directory every `interval` and acts as a circuit breaker for writes: once it
+//! drops below `min_free_bytes` the breaker trips and `check_write` fails with
+//! `ErrorKind::StorageFull`, until the free space recovers past the minimum plus a
+//! 10% margin, so it doesn't flap around the threshold.
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Bytes available to unprivileged users on the volume holding `path`
+pub fn free_space(path: impl AsRef<Path>) -> Result<u64> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Latest state of the monitor, for metrics and health checks
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiskStatus {
+    pub free_bytes: u64,
+    /// Whether writes are being rejected
+    pub tripped: bool,
+    pub checks: u64,
+    /// The latest sampling failure, if any
+    pub error: Option<String>,
+}
+
+type Probe = Box<dyn Fn() -> Result<u64> + Send>;
+
+struct Shared {
+    status: Mutex<DiskStatus>,
+    stopped: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+pub struct DiskMonitor {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl DiskMonitor {
+    pub fn spawn(dir: impl AsRef<Path>, min_free_bytes: u64, interval: Duration) -> Self {
+        let dir = dir.as_ref().to_path_buf();
+        Self::with_probe(Box::new(move || free_space(&dir)), min_free_bytes, interval)
+    }
+
+    fn with_probe(probe: Probe, min_free_bytes: u64, interval: Duration) -> Self {
+        let shared = Arc::new(Shared {
+            status: Mutex::new(DiskStatus::default()),
+            stopped: Mutex::new(false),
+            wakeup: Condvar::new(),
+        });
+        // The first sample is taken right away, writes aren't accepted blindly
+        sample(&shared, &probe, min_free_bytes);
+        let background = Arc::clone(&shared);
+        let handle = thread::spawn(move || run(&background, probe, min_free_bytes, interval));
+        Self {
+            shared,
+            handle: Some(handle),
+        }
+    }
+
+    /// Fail with `ErrorKind::StorageFull` while the free space is below the minimum
+    pub fn check_write(&self) -> Result<()> {
+        let status = self.status();
+        if status.tripped {
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                format!(
+                    "Writes rejected, {} bytes left on the data volume",
+                    status.free_bytes
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> DiskStatus {
+        self.shared
+            .status
+            .lock()
+            .map(|s| s.clone())
+            .unwrap_or_default()
+    }
+
+    /// Healthy when writes are accepted and the volume can be sampled
+    pub fn is_healthy(&self) -> bool {
+        let status = self.status();
+        !status.tripped && status.error.is_none()
+    }
+}
+
+impl Drop for DiskMonitor {
+    fn drop(&mut self) {
+        if let Ok(mut stopped) = self.shared.stopped.lock() {
+            *stopped = true;
+        }
+        self.shared.wakeup.notify_one();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn sample(shared: &Shared, probe: &Probe, min_free_bytes: u64) {
+    let sampled = probe();
+    let Ok(mut status) = shared.status.lock() else {
+        return;
+    };
+    status.checks += 1;
+    match sampled {
+        Ok(free_bytes) => {
+            status.free_bytes = free_bytes;
+            status.error = None;
+            if free_bytes < min_free_bytes {
+                status.tripped = true;
+            } else if free_bytes >= min_free_bytes.saturating_add(min_free_bytes / 10) {
+                status.tripped = false;
+            }
+        }
+        Err(e) => status.error = Some(e.to_string()),
+    }
+}
+
+fn run(shared: &Shared, probe: Probe, min_free_bytes: u64, interval: Duration) {
+    let Ok(mut stopped) = shared.stopped.lock() else {
+        return;
+    };
+    while !*stopped {
+        stopped = match shared.wakeup.wait_timeout(stopped, interval) {
+            Ok((stopped, _)) => stopped,
+            Err(_) => return,
+        };
+        if !*stopped {
+            sample(shared, &probe, min_free_bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod disk_tests {
+    use super::{free_space, DiskMonitor};
+    use std::io::ErrorKind;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    fn wait_for(monitor: &DiskMonitor, tripped: bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if monitor.status().tripped == tripped {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+        false
+    }
+
+    #[test]
+    fn test_free_space() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        assert!(free_space(tmp_dir.path()).unwrap() > 0);
+        assert!(free_space(tmp_dir.path().join("missing")).is_err());
+        let monitor = DiskMonitor::spawn(tmp_dir.path(), 0, Duration::from_secs(3600));
+        assert!(monitor.is_healthy());
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let free = Arc::new(AtomicU64::new(1000));
+        let probe = Arc::clone(&free);
+        let monitor = DiskMonitor::with_probe(
+            Box::new(move || Ok(probe.load(Ordering::SeqCst))),
+            500,
+            Duration::from_millis(1),
+        );
+        monitor.check_write().unwrap();
+
+        free.store(400, Ordering::SeqCst);
+        assert!(wait_for(&monitor, true));
+        let err = monitor.check_write().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert!(!monitor.is_healthy());
+
+        // Within the margin the breaker stays open
+        free.store(520, Ordering::SeqCst);
+        let checks = monitor.status().checks;
+        while monitor.status().checks < checks + 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(monitor.status().tripped);
+
+        free.store(600, Ordering::SeqCst);
+        assert!(wait_for(&monitor, false));
+        monitor.check_write().unwrap();
+    }
+}
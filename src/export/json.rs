@@ -0,0 +1,145 @@
+//! Newline delimited JSON import and export of records
+//!
+//! Each line holds a single record, keys, values and header values are base64
+//! encoded as they are arbitrary binary payloads. It's the simplest format to move data in and
+//! out of a partition.
+use crate::partition::record::Record;
+use crate::partition::Partition;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct JsonRecord {
+    offset: u64,
+    timestamp: u64,
+    key: Option<String>,
+    value: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+}
+
+impl From<&Record> for JsonRecord {
+    fn from(record: &Record) -> Self {
+        Self {
+            offset: record.offset,
+            timestamp: record.timestamp,
+            key: record.key.as_ref().map(|k| STANDARD.encode(k)),
+            value: STANDARD.encode(&record.value),
+            headers: record
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), STANDARD.encode(value)))
+                .collect(),
+        }
+    }
+}
+
+/// Write the records with an offset in the `[from, to)` range to `writer`, one
+/// JSON document per line, returning the number of records written
+pub fn export(partition: &Partition, from: u64, to: u64, writer: &mut impl Write) -> Result<usize> {
+    partition.advise_scan(from, to)?;
+    let records = partition.read_range(from, to)?;
+    for record in &records {
+        serde_json::to_writer(&mut *writer, &JsonRecord::from(record))?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(records.len())
+}
+
+/// Append every record read from `reader` to the partition, returning the
+/// number of records imported.
+///
+/// Keys, values, headers and timestamps are imported, offsets are assigned by the partition.
+pub fn import(partition: &mut Partition, reader: impl BufRead) -> Result<usize> {
+    let mut count = 0;
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |e: &dyn std::fmt::Display| {
+            Error::new(ErrorKind::InvalidData, format!("line {}: {}", n + 1, e))
+        };
+        let record: JsonRecord = serde_json::from_str(&line).map_err(|e| invalid(&e))?;
+        let key = match record.key {
+            Some(k) => Some(STANDARD.decode(k).map_err(|e| invalid(&e))?),
+            None => None,
+        };
+        let value = STANDARD.decode(record.value).map_err(|e| invalid(&e))?;
+        let headers = record
+            .headers
+            .into_iter()
+            .map(|(name, value)| Ok((name, STANDARD.decode(value).map_err(|e| invalid(&e))?)))
+            .collect::<Result<Vec<_>>>()?;
+        partition.append_record_with_headers(Some(record.timestamp), key, &value, headers)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod json_tests {
+    use super::{export, import};
+    use crate::partition::Partition;
+    use std::io::BufReader;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_export() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition
+            .append_record(Some("key".into()), b"value")
+            .unwrap();
+        partition.append_record(None, &[0, 159, 146, 150]).unwrap();
+
+        let mut buffer = vec![];
+        assert_eq!(export(&partition, 0, 10, &mut buffer).unwrap(), 2);
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(buffer)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["offset"], 0);
+        assert_eq!(lines[0]["key"], "a2V5");
+        assert_eq!(lines[0]["value"], "dmFsdWU=");
+        assert_eq!(lines[1]["offset"], 1);
+        assert!(lines[1]["key"].is_null());
+        assert_eq!(lines[1]["value"], "AJ+Slg==");
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_import() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        let input = concat!(
+            "{\"offset\":10,\"timestamp\":0,\"key\":\"a2V5\",\"value\":\"dmFsdWU=\"}\n",
+            "\n",
+            "{\"offset\":11,\"timestamp\":0,\"key\":null,\"value\":\"AJ+Slg==\"}\n",
+        );
+
+        assert_eq!(
+            import(&mut partition, BufReader::new(input.as_bytes())).unwrap(),
+            2
+        );
+
+        let records = partition.read_range(0, 10).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].offset, 0);
+        assert_eq!(records[0].key, Some("key".into()));
+        assert_eq!(records[0].value, b"value");
+        assert_eq!(records[1].key, None);
+        assert_eq!(records[1].value, &[0, 159, 146, 150]);
+        assert!(records.iter().all(|r| r.timestamp == 0));
+
+        let invalid = "{\"offset\":0,\"timestamp\":0,\"key\":null,\"value\":\"%%\"}\n";
+        assert!(import(&mut partition, BufReader::new(invalid.as_bytes())).is_err());
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,7 @@
+//! Conversion of partition records into formats readable by external tools
+//!
+//! Every exporter works on a range of offsets of a `Partition`, the ones
+//! depending on heavy crates are gated behind their own feature.
+pub mod json;
+#[cfg(feature = "parquet")]
+pub mod parquet;
@@ -0,0 +1,112 @@
+//! Parquet export of a range of records
+//!
+//! Records are written in a single row group with columns `offset`, `timestamp`,
+//! `key` and `value`, so the file can be loaded directly by DuckDB, Spark or any
+//! other Parquet aware tool.
+use crate::partition::Partition;
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::fs::File;
+use std::io::{Error, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+const SCHEMA: &str = "
+    message record {
+        REQUIRED INT64 offset (INTEGER(64, false));
+        REQUIRED INT64 timestamp (TIMESTAMP(MILLIS, true));
+        OPTIONAL BYTE_ARRAY key;
+        REQUIRED BYTE_ARRAY value;
+    }
+";
+
+fn to_io_error(err: ParquetError) -> Error {
+    Error::other(err)
+}
+
+/// Export the records with an offset in the `[from, to)` range to a Parquet file
+/// at `path`, returning the number of records written
+pub fn export(partition: &Partition, from: u64, to: u64, path: &Path) -> Result<usize> {
+    partition.advise_scan(from, to)?;
+    let records = partition.read_range(from, to)?;
+    let schema = Arc::new(parse_message_type(SCHEMA).map_err(to_io_error)?);
+    let props = Arc::new(WriterProperties::builder().build());
+    let mut writer =
+        SerializedFileWriter::new(File::create(path)?, schema, props).map_err(to_io_error)?;
+
+    let offsets: Vec<i64> = records.iter().map(|r| r.offset as i64).collect();
+    let timestamps: Vec<i64> = records.iter().map(|r| r.timestamp as i64).collect();
+    let keys: Vec<ByteArray> = records
+        .iter()
+        .filter_map(|r| r.key.clone().map(ByteArray::from))
+        .collect();
+    let key_levels: Vec<i16> = records.iter().map(|r| r.key.is_some() as i16).collect();
+    let values: Vec<ByteArray> = records
+        .iter()
+        .map(|r| ByteArray::from(r.value.clone()))
+        .collect();
+
+    let mut row_group = writer.next_row_group().map_err(to_io_error)?;
+    while let Some(mut column) = row_group.next_column().map_err(to_io_error)? {
+        match column.untyped() {
+            ColumnWriter::Int64ColumnWriter(w) if w.get_descriptor().name() == "offset" => {
+                w.write_batch(&offsets, None, None)
+            }
+            ColumnWriter::Int64ColumnWriter(w) => w.write_batch(&timestamps, None, None),
+            ColumnWriter::ByteArrayColumnWriter(w) if w.get_descriptor().name() == "key" => {
+                w.write_batch(&keys, Some(&key_levels), None)
+            }
+            ColumnWriter::ByteArrayColumnWriter(w) => w.write_batch(&values, None, None),
+            _ => unreachable!("Unexpected column type in the record schema"),
+        }
+        .map_err(to_io_error)?;
+        column.close().map_err(to_io_error)?;
+    }
+    row_group.close().map_err(to_io_error)?;
+    writer.close().map_err(to_io_error)?;
+    Ok(records.len())
+}
+
+#[cfg(test)]
+mod parquet_tests {
+    use super::export;
+    use crate::partition::Partition;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_export() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition_dir = tmp_dir.path().join("partition");
+        std::fs::create_dir(&partition_dir).unwrap();
+        let mut partition = Partition::open(&partition_dir).unwrap();
+        for i in 0..200u64 {
+            let key = if i % 2 == 0 { Some("key".into()) } else { None };
+            partition.append_record(key, &i.to_be_bytes()).unwrap();
+        }
+        let path = tmp_dir.path().join("records.parquet");
+
+        assert_eq!(export(&partition, 10, 150, &path).unwrap(), 140);
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 140);
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        let first: Vec<_> = rows[0].get_column_iter().map(|(_, f)| f.clone()).collect();
+        assert_eq!(first[0], Field::ULong(10));
+        assert_eq!(first[2], Field::Bytes("key".as_bytes().to_vec().into()));
+        assert_eq!(first[3], Field::Bytes(10u64.to_be_bytes().to_vec().into()));
+        let second: Vec<_> = rows[1].get_column_iter().map(|(_, f)| f.clone()).collect();
+        assert_eq!(second[2], Field::Null);
+        tmp_dir.close().unwrap();
+    }
+}
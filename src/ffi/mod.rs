@@ -0,0 +1,221 @@
+//! C bindings of the storage engine
+//!
+//! A partition is an opaque handle opened with `shoju_open` and released with
+//! `shoju_close`, every call returns `SHOJU_OK` or a negative error code. Build the
+//! shared library with:
+//!
+//! ```text
+//! cargo rustc --release --features ffi --lib --crate-type cdylib
+//! ```
+//!
+//! and include `shoju.h`, next to this module. Handles aren't thread safe, callers
+//! sharing one across threads serialize their calls.
+use crate::partition::Partition;
+use std::ffi::{c_char, c_int, CStr};
+use std::io::{Error, ErrorKind};
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+
+pub const SHOJU_OK: c_int = 0;
+pub const SHOJU_ERR_INVALID_ARGUMENT: c_int = -1;
+pub const SHOJU_ERR_NOT_FOUND: c_int = -2;
+/// The buffer can't hold the value, its length is set to the size needed
+pub const SHOJU_ERR_BUFFER_TOO_SMALL: c_int = -3;
+pub const SHOJU_ERR_STORAGE_FULL: c_int = -4;
+pub const SHOJU_ERR_IO: c_int = -5;
+pub const SHOJU_ERR_PANIC: c_int = -6;
+
+/// Opaque handle of a partition
+pub struct ShojuPartition {
+    partition: Partition,
+}
+
+fn code(e: &Error) -> c_int {
+    match e.kind() {
+        ErrorKind::InvalidInput => SHOJU_ERR_INVALID_ARGUMENT,
+        ErrorKind::NotFound => SHOJU_ERR_NOT_FOUND,
+        ErrorKind::StorageFull => SHOJU_ERR_STORAGE_FULL,
+        _ => SHOJU_ERR_IO,
+    }
+}
+
+/// Run `call`, turning a panic into an error code instead of unwinding into C
+fn guard(call: impl FnOnce() -> c_int) -> c_int {
+    panic::catch_unwind(AssertUnwindSafe(call)).unwrap_or(SHOJU_ERR_PANIC)
+}
+
+/// # Safety
+///
+/// The caller must pass a `len` bytes long buffer, or null with a `len` of 0
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (ptr.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, len) => Some(slice::from_raw_parts(ptr, len)),
+    }
+}
+
+/// Open the partition in the directory `path`, setting `out` to its handle
+///
+/// # Safety
+///
+/// `path` must be a null terminated string and `out` a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn shoju_open(path: *const c_char, out: *mut *mut ShojuPartition) -> c_int {
+    if path.is_null() || out.is_null() {
+        return SHOJU_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let Ok(path) = CStr::from_ptr(path).to_str() else {
+            return SHOJU_ERR_INVALID_ARGUMENT;
+        };
+        match Partition::open(path) {
+            Ok(partition) => {
+                *out = Box::into_raw(Box::new(ShojuPartition { partition }));
+                SHOJU_OK
+            }
+            Err(e) => code(&e),
+        }
+    })
+}
+
+/// Append a record, setting `offset` to its offset when not null. A null `key`
+/// appends a record without key.
+///
+/// # Safety
+///
+/// `partition` must be a handle from `shoju_open`, `key` and `value` buffers of
+/// their lengths
+#[no_mangle]
+pub unsafe extern "C" fn shoju_append(
+    partition: *mut ShojuPartition,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+    offset: *mut u64,
+) -> c_int {
+    let (Some(handle), Some(value)) = (partition.as_mut(), bytes(value, value_len)) else {
+        return SHOJU_ERR_INVALID_ARGUMENT;
+    };
+    let key = match key.is_null() {
+        true => None,
+        false => Some(slice::from_raw_parts(key, key_len).to_vec()),
+    };
+    guard(|| match handle.partition.append_record(key, value) {
+        Ok(appended) => {
+            if !offset.is_null() {
+                *offset = appended.offset;
+            }
+            SHOJU_OK
+        }
+        Err(e) => code(&e),
+    })
+}
+
+/// Copy the value of the record at `offset` in `buf`, setting `len` to its length
+///
+/// # Safety
+///
+/// `partition` must be a handle from `shoju_open`, `buf` a buffer of `capacity`
+/// bytes and `len` a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn shoju_read(
+    partition: *mut ShojuPartition,
+    offset: u64,
+    buf: *mut u8,
+    capacity: usize,
+    len: *mut usize,
+) -> c_int {
+    let Some(handle) = partition.as_mut() else {
+        return SHOJU_ERR_INVALID_ARGUMENT;
+    };
+    if len.is_null() || (buf.is_null() && capacity > 0) {
+        return SHOJU_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| match handle.partition.find_record(offset) {
+        Ok(record) => {
+            *len = record.value.len();
+            if record.value.len() > capacity {
+                return SHOJU_ERR_BUFFER_TOO_SMALL;
+            }
+            if !record.value.is_empty() {
+                slice::from_raw_parts_mut(buf, capacity)[..record.value.len()]
+                    .copy_from_slice(&record.value);
+            }
+            SHOJU_OK
+        }
+        Err(e) => code(&e),
+    })
+}
+
+/// Flush the records appended to disk
+///
+/// # Safety
+///
+/// `partition` must be a handle from `shoju_open`
+#[no_mangle]
+pub unsafe extern "C" fn shoju_flush(partition: *mut ShojuPartition) -> c_int {
+    let Some(handle) = partition.as_mut() else {
+        return SHOJU_ERR_INVALID_ARGUMENT;
+    };
+    guard(|| match handle.partition.flush() {
+        Ok(()) => SHOJU_OK,
+        Err(e) => code(&e),
+    })
+}
+
+/// Flush and close the partition, the handle is invalid afterwards
+///
+/// # Safety
+///
+/// `partition` must be a handle from `shoju_open` or null, closed only once
+#[no_mangle]
+pub unsafe extern "C" fn shoju_close(partition: *mut ShojuPartition) -> c_int {
+    if partition.is_null() {
+        return SHOJU_OK;
+    }
+    let mut handle = Box::from_raw(partition);
+    guard(move || match handle.partition.flush() {
+        Ok(()) => SHOJU_OK,
+        Err(e) => code(&e),
+    })
+}
+
+#[cfg(test)]
+mod ffi_tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_c_api() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let path = CString::new(tmp_dir.path().to_str().unwrap()).unwrap();
+        let mut handle = ptr::null_mut();
+        unsafe {
+            assert_eq!(shoju_open(path.as_ptr(), &mut handle), SHOJU_OK);
+            let mut offset = u64::MAX;
+            let value = b"value";
+            let status = shoju_append(handle, b"k".as_ptr(), 1, value.as_ptr(), 5, &mut offset);
+            assert_eq!((status, offset), (SHOJU_OK, 0));
+            assert_eq!(shoju_flush(handle), SHOJU_OK);
+
+            let (mut buf, mut len) = ([0u8; 8], 0);
+            assert_eq!(
+                shoju_read(handle, 0, buf.as_mut_ptr(), 8, &mut len),
+                SHOJU_OK
+            );
+            assert_eq!(&buf[..len], value);
+            let status = shoju_read(handle, 0, buf.as_mut_ptr(), 2, &mut len);
+            assert_eq!((status, len), (SHOJU_ERR_BUFFER_TOO_SMALL, 5));
+            let status = shoju_read(handle, 1, buf.as_mut_ptr(), 8, &mut len);
+            assert_eq!(status, SHOJU_ERR_NOT_FOUND);
+            let status = shoju_append(handle, ptr::null(), 0, ptr::null(), 3, &mut offset);
+            assert_eq!(status, SHOJU_ERR_INVALID_ARGUMENT);
+            assert_eq!(shoju_close(handle), SHOJU_OK);
+        }
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,37 @@
+/* C API of the shoju storage engine, see src/ffi/mod.rs */
+#ifndef SHOJU_H
+#define SHOJU_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define SHOJU_OK 0
+#define SHOJU_ERR_INVALID_ARGUMENT -1
+#define SHOJU_ERR_NOT_FOUND -2
+/* The buffer can't hold the value, its length is set to the size needed */
+#define SHOJU_ERR_BUFFER_TOO_SMALL -3
+#define SHOJU_ERR_STORAGE_FULL -4
+#define SHOJU_ERR_IO -5
+#define SHOJU_ERR_PANIC -6
+
+typedef struct ShojuPartition shoju_partition;
+
+int shoju_open(const char *path, shoju_partition **out);
+/* A null key appends a record without key, offset may be null */
+int shoju_append(shoju_partition *partition, const uint8_t *key, size_t key_len,
+                 const uint8_t *value, size_t value_len, uint64_t *offset);
+int shoju_read(shoju_partition *partition, uint64_t offset, uint8_t *buf,
+               size_t capacity, size_t *len);
+int shoju_flush(shoju_partition *partition);
+/* Flushes, the handle is invalid afterwards */
+int shoju_close(shoju_partition *partition);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,184 @@
+//! Strategies distributing the partitions of the subscribed topics among the
+//! members of a consumer group
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TopicPartition {
+    pub topic: String,
+    pub partition: u32,
+}
+
+impl TopicPartition {
+    pub fn new(topic: &str, partition: u32) -> Self {
+        Self {
+            topic: topic.into(),
+            partition,
+        }
+    }
+}
+
+/// Member id to the partitions assigned to it
+pub type Assignment = BTreeMap<String, Vec<TopicPartition>>;
+
+pub trait Assignor {
+    fn name(&self) -> &str;
+
+    /// Assign the partitions of every topic, `subscriptions` maps each member to
+    /// its subscribed topics and `partitions` each topic to its partition count
+    fn assign(
+        &self,
+        subscriptions: &BTreeMap<String, Vec<String>>,
+        partitions: &HashMap<String, u32>,
+    ) -> Assignment;
+}
+
+fn empty_assignment(subscriptions: &BTreeMap<String, Vec<String>>) -> Assignment {
+    subscriptions
+        .keys()
+        .map(|member| (member.clone(), Vec::new()))
+        .collect()
+}
+
+/// Assign each member a contiguous range of partitions of every topic it's
+/// subscribed to, the first members getting one partition more when they don't
+/// divide evenly
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RangeAssignor;
+
+impl Assignor for RangeAssignor {
+    fn name(&self) -> &str {
+        "range"
+    }
+
+    fn assign(
+        &self,
+        subscriptions: &BTreeMap<String, Vec<String>>,
+        partitions: &HashMap<String, u32>,
+    ) -> Assignment {
+        let mut assignment = empty_assignment(subscriptions);
+        let mut topics: Vec<(&String, &u32)> = partitions.iter().collect();
+        topics.sort();
+        for (topic, count) in topics {
+            let members: Vec<&String> = subscriptions
+                .iter()
+                .filter(|(_, topics)| topics.contains(topic))
+                .map(|(member, _)| member)
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            let per_member = *count as usize / members.len();
+            let extra = *count as usize % members.len();
+            let mut next = 0;
+            for (i, member) in members.into_iter().enumerate() {
+                let size = per_member + usize::from(i < extra);
+                let assigned = assignment.get_mut(member).unwrap();
+                assigned.extend((next..next + size).map(|p| TopicPartition::new(topic, p as u32)));
+                next += size;
+            }
+        }
+        assignment
+    }
+}
+
+/// Lay out all the partitions of all the topics and deal them one by one to the
+/// members subscribed to them
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RoundRobinAssignor;
+
+impl Assignor for RoundRobinAssignor {
+    fn name(&self) -> &str {
+        "roundrobin"
+    }
+
+    fn assign(
+        &self,
+        subscriptions: &BTreeMap<String, Vec<String>>,
+        partitions: &HashMap<String, u32>,
+    ) -> Assignment {
+        let mut assignment = empty_assignment(subscriptions);
+        let members: Vec<&String> = subscriptions.keys().collect();
+        if members.is_empty() {
+            return assignment;
+        }
+        let mut all: Vec<TopicPartition> = partitions
+            .iter()
+            .flat_map(|(topic, count)| (0..*count).map(|p| TopicPartition::new(topic, p)))
+            .collect();
+        all.sort();
+        let mut next = 0;
+        for tp in all {
+            // Skip the members not subscribed to the topic, giving up if none is
+            for _ in 0..members.len() {
+                let member = members[next % members.len()];
+                next += 1;
+                if subscriptions[member].contains(&tp.topic) {
+                    assignment.get_mut(member).unwrap().push(tp);
+                    break;
+                }
+            }
+        }
+        assignment
+    }
+}
+
+#[cfg(test)]
+mod assignor_tests {
+    use super::{Assignor, RangeAssignor, RoundRobinAssignor, TopicPartition};
+    use std::collections::{BTreeMap, HashMap};
+
+    fn subscriptions(members: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        members
+            .iter()
+            .map(|(m, topics)| {
+                (
+                    m.to_string(),
+                    topics.iter().map(|t| t.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn tps(list: &[(&str, u32)]) -> Vec<TopicPartition> {
+        list.iter()
+            .map(|(t, p)| TopicPartition::new(t, *p))
+            .collect()
+    }
+
+    #[test]
+    fn test_range_assignor() {
+        let subs = subscriptions(&[("a", &["events", "orders"]), ("b", &["events", "orders"])]);
+        let partitions = HashMap::from([("events".to_string(), 3), ("orders".to_string(), 2)]);
+
+        let assignment = RangeAssignor.assign(&subs, &partitions);
+
+        assert_eq!(
+            assignment["a"],
+            tps(&[("events", 0), ("events", 1), ("orders", 0)])
+        );
+        assert_eq!(assignment["b"], tps(&[("events", 2), ("orders", 1)]));
+    }
+
+    #[test]
+    fn test_round_robin_assignor() {
+        let subs = subscriptions(&[("a", &["events", "orders"]), ("b", &["events"])]);
+        let partitions = HashMap::from([("events".to_string(), 3), ("orders".to_string(), 2)]);
+
+        let assignment = RoundRobinAssignor.assign(&subs, &partitions);
+
+        assert_eq!(
+            assignment["a"],
+            tps(&[("events", 0), ("events", 2), ("orders", 0), ("orders", 1)])
+        );
+        assert_eq!(assignment["b"], tps(&[("events", 1)]));
+    }
+
+    #[test]
+    fn test_unsubscribed_topic() {
+        let subs = subscriptions(&[("a", &["events"])]);
+        let partitions = HashMap::from([("orders".to_string(), 2)]);
+
+        assert!(RangeAssignor.assign(&subs, &partitions)["a"].is_empty());
+        assert!(RoundRobinAssignor.assign(&subs, &partitions)["a"].is_empty());
+    }
+}
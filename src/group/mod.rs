@@ -0,0 +1,301 @@
+//! Consumer group membership and partition assignment
+//!
+//! The `GroupCoordinator` tracks the members of each group through the join, sync,
+//! heartbeat and leave operations. Every change of membership (or of the
+//! partitions of a subscribed topic) triggers a rebalance: a new assignment is
+//! computed with the configured `Assignor` and the group generation is bumped.
+//! Members still on an older generation are told to rejoin on their next heartbeat
+//! or sync, members not heartbeating within the session timeout are evicted.
+//!
+//! The coordinator is transport agnostic, it's meant to be driven by the request
+//! handling of a server.
+pub mod assignor;
+
+use assignor::{Assignment, Assignor, TopicPartition};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq)]
+pub enum GroupError {
+    UnknownGroup,
+    UnknownMember,
+    /// The member is on an outdated generation and must rejoin the group
+    RebalanceInProgress,
+}
+
+impl Error for GroupError {}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GroupError::UnknownGroup => write!(f, "Unknown group"),
+            GroupError::UnknownMember => write!(f, "Unknown member"),
+            GroupError::RebalanceInProgress => write!(f, "Rebalance in progress, rejoin"),
+        }
+    }
+}
+
+struct Member {
+    topics: Vec<String>,
+    last_heartbeat: Instant,
+}
+
+#[derive(Default)]
+struct Group {
+    generation: u32,
+    members: BTreeMap<String, Member>,
+    assignment: Assignment,
+}
+
+impl Group {
+    fn member(&mut self, member_id: &str, generation: u32) -> Result<&mut Member, GroupError> {
+        let current = self.generation;
+        let member = self
+            .members
+            .get_mut(member_id)
+            .ok_or(GroupError::UnknownMember)?;
+        if generation != current {
+            return Err(GroupError::RebalanceInProgress);
+        }
+        Ok(member)
+    }
+}
+
+pub struct GroupCoordinator<A> {
+    assignor: A,
+    session_timeout: Duration,
+    partitions: HashMap<String, u32>,
+    groups: HashMap<String, Group>,
+}
+
+impl<A: Assignor> GroupCoordinator<A> {
+    pub fn new(assignor: A, session_timeout: Duration) -> Self {
+        Self {
+            assignor,
+            session_timeout,
+            partitions: HashMap::new(),
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Register the partition count of a topic, rebalancing the groups subscribed
+    /// to it when it changes
+    pub fn update_topic(&mut self, topic: &str, partitions: u32) {
+        if self.partitions.insert(topic.into(), partitions) == Some(partitions) {
+            return;
+        }
+        let subscribed: Vec<String> = self
+            .groups
+            .iter()
+            .filter(|(_, g)| {
+                g.members
+                    .values()
+                    .any(|m| m.topics.iter().any(|t| t == topic))
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in subscribed {
+            self.rebalance(&name);
+        }
+    }
+
+    /// Join `group` subscribing to `topics`, returning the generation to sync with.
+    /// Rejoining with unchanged subscriptions doesn't trigger a rebalance.
+    pub fn join(&mut self, group: &str, member_id: &str, topics: &[&str], now: Instant) -> u32 {
+        let topics: Vec<String> = topics.iter().map(|t| t.to_string()).collect();
+        let state = self.groups.entry(group.into()).or_default();
+        let changed = match state.members.get_mut(member_id) {
+            Some(member) => {
+                member.last_heartbeat = now;
+                member.topics != topics
+            }
+            None => true,
+        };
+        if changed {
+            state.members.insert(
+                member_id.into(),
+                Member {
+                    topics,
+                    last_heartbeat: now,
+                },
+            );
+            self.rebalance(group);
+        }
+        self.groups[group].generation
+    }
+
+    /// Fetch the partitions assigned to the member in the given generation
+    pub fn sync(
+        &mut self,
+        group: &str,
+        member_id: &str,
+        generation: u32,
+        now: Instant,
+    ) -> Result<Vec<TopicPartition>, GroupError> {
+        let state = self.groups.get_mut(group).ok_or(GroupError::UnknownGroup)?;
+        state.member(member_id, generation)?.last_heartbeat = now;
+        Ok(state.assignment.get(member_id).cloned().unwrap_or_default())
+    }
+
+    pub fn heartbeat(
+        &mut self,
+        group: &str,
+        member_id: &str,
+        generation: u32,
+        now: Instant,
+    ) -> Result<(), GroupError> {
+        let state = self.groups.get_mut(group).ok_or(GroupError::UnknownGroup)?;
+        state.member(member_id, generation)?.last_heartbeat = now;
+        Ok(())
+    }
+
+    pub fn leave(&mut self, group: &str, member_id: &str) -> Result<(), GroupError> {
+        let state = self.groups.get_mut(group).ok_or(GroupError::UnknownGroup)?;
+        state
+            .members
+            .remove(member_id)
+            .ok_or(GroupError::UnknownMember)?;
+        self.rebalance(group);
+        Ok(())
+    }
+
+    /// Evict the members whose session expired, returning their ids
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let mut expired = Vec::new();
+        let mut rebalancing = Vec::new();
+        for (name, group) in self.groups.iter_mut() {
+            let before = group.members.len();
+            group.members.retain(|id, member| {
+                let alive = now.duration_since(member.last_heartbeat) < self.session_timeout;
+                if !alive {
+                    expired.push(id.clone());
+                }
+                alive
+            });
+            if group.members.len() != before {
+                rebalancing.push(name.clone());
+            }
+        }
+        for name in rebalancing {
+            self.rebalance(&name);
+        }
+        expired
+    }
+
+    pub fn generation(&self, group: &str) -> Option<u32> {
+        self.groups.get(group).map(|g| g.generation)
+    }
+
+    pub fn members(&self, group: &str) -> Vec<&str> {
+        self.groups
+            .get(group)
+            .map(|g| g.members.keys().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    fn rebalance(&mut self, group: &str) {
+        let state = self.groups.get_mut(group).unwrap();
+        let subscriptions: BTreeMap<String, Vec<String>> = state
+            .members
+            .iter()
+            .map(|(id, m)| (id.clone(), m.topics.clone()))
+            .collect();
+        state.assignment = self.assignor.assign(&subscriptions, &self.partitions);
+        state.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod group_tests {
+    use super::assignor::{RangeAssignor, TopicPartition};
+    use super::{GroupCoordinator, GroupError};
+    use std::time::{Duration, Instant};
+
+    fn coordinator() -> GroupCoordinator<RangeAssignor> {
+        let mut coordinator = GroupCoordinator::new(RangeAssignor, Duration::from_secs(10));
+        coordinator.update_topic("events", 4);
+        coordinator
+    }
+
+    #[test]
+    fn test_join_sync() {
+        let mut coordinator = coordinator();
+        let now = Instant::now();
+
+        let generation = coordinator.join("group", "a", &["events"], now);
+        assert_eq!(generation, 1);
+        assert_eq!(
+            coordinator
+                .sync("group", "a", generation, now)
+                .unwrap()
+                .len(),
+            4
+        );
+        assert_eq!(coordinator.join("group", "a", &["events"], now), 1);
+
+        let generation = coordinator.join("group", "b", &["events"], now);
+        assert_eq!(generation, 2);
+        assert_eq!(
+            coordinator.heartbeat("group", "a", 1, now),
+            Err(GroupError::RebalanceInProgress)
+        );
+        assert_eq!(coordinator.join("group", "a", &["events"], now), 2);
+        assert_eq!(
+            coordinator.sync("group", "a", 2, now).unwrap(),
+            vec![
+                TopicPartition::new("events", 0),
+                TopicPartition::new("events", 1)
+            ]
+        );
+        assert_eq!(
+            coordinator.sync("group", "b", 2, now).unwrap(),
+            vec![
+                TopicPartition::new("events", 2),
+                TopicPartition::new("events", 3)
+            ]
+        );
+        assert_eq!(
+            coordinator.sync("other", "b", 2, now),
+            Err(GroupError::UnknownGroup)
+        );
+    }
+
+    #[test]
+    fn test_leave_and_expire() {
+        let mut coordinator = coordinator();
+        let now = Instant::now();
+        coordinator.join("group", "a", &["events"], now);
+        coordinator.join("group", "b", &["events"], now);
+        coordinator.join("group", "c", &["events"], now);
+
+        coordinator.leave("group", "c").unwrap();
+        assert_eq!(coordinator.generation("group"), Some(4));
+        assert_eq!(
+            coordinator.leave("group", "c"),
+            Err(GroupError::UnknownMember)
+        );
+
+        let later = now + Duration::from_secs(5);
+        coordinator.heartbeat("group", "a", 4, later).unwrap();
+        let expired = coordinator.expire(now + Duration::from_secs(12));
+        assert_eq!(expired, vec!["b".to_string()]);
+        assert_eq!(coordinator.members("group"), vec!["a"]);
+        assert_eq!(coordinator.generation("group"), Some(5));
+        assert_eq!(coordinator.sync("group", "a", 5, later).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_topic_update_rebalance() {
+        let mut coordinator = coordinator();
+        let now = Instant::now();
+        coordinator.join("group", "a", &["events"], now);
+
+        coordinator.update_topic("events", 4);
+        assert_eq!(coordinator.generation("group"), Some(1));
+        coordinator.update_topic("events", 6);
+        assert_eq!(coordinator.generation("group"), Some(2));
+        assert_eq!(coordinator.sync("group", "a", 2, now).unwrap().len(), 6);
+    }
+}
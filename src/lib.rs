@@ -1 +1,35 @@
+//! Append-only commit log
+//!
+//! `partition` is the storage engine, `partition::Partition` and its
+//! `partition::record::Record` are the types to build on. The other modules layer
+//! topics, consumer groups and tooling on top of it.
+pub mod connect;
+pub mod consumer;
+pub mod disk;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod group;
+pub mod memory;
+pub mod metrics;
+pub mod mirror;
+#[cfg(feature = "rumqttc")]
+pub mod mqtt;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod offsets;
 pub mod partition;
+pub mod pipe;
+pub mod producer;
+pub mod resp;
+#[cfg(feature = "tiny_http")]
+pub mod rest;
+pub mod scheduler;
+pub mod signal;
+pub mod sim;
+pub mod state;
+pub mod streams;
+pub mod topic;
+pub mod trace;
+pub mod transform;
+pub mod typed;
@@ -1,34 +1,283 @@
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use shoju::export::json;
+use shoju::mirror::Mirror;
+use shoju::offsets::OffsetTarget;
+use shoju::partition::manifest::PartitionConfig;
 use shoju::partition::Partition;
+use shoju::pipe::{self, Framing};
+use shoju::resp::RespServer;
+use shoju::signal::Shutdown;
+use shoju::topic::TopicManager;
+use std::io::{self, BufRead, BufWriter, Error, ErrorKind, Write};
+use std::path::PathBuf;
 
-mod smoke_test {
-    use shoju::partition::Partition;
-    pub fn generate_partition(partition: &mut Partition, n: i32) -> std::io::Result<()> {
-        for _i in 0..n {
-            partition
-                .append_record(Some("key".into()), &[0, 0, 1, 0])
-                .expect("Error writing to disk");
-        }
-        partition.flush()
-    }
+#[derive(Parser)]
+#[command(name = "shoju", about = "Append-only commit log")]
+struct Cli {
+    /// Directory of the partition
+    #[arg(long, global = true, default_value = "logdir")]
+    dir: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
 
-    pub fn replay_log(partition: &mut Partition, offsets: &[u64]) {
-        for offset in offsets.iter() {
-            let r = partition
-                .find_record(*offset)
-                .expect(&format!("Failed lookup {}", offset));
-            println!("{}", r);
-        }
-    }
+#[derive(Clone, Copy, ValueEnum)]
+enum Protocol {
+    Resp,
+    Rest,
 }
 
-fn main() -> std::io::Result<()> {
-    let mut partition = Partition::init()?;
-    // smoke_test::generate_partition(&mut partition, 1200)?;
-    smoke_test::replay_log(
-        &mut partition,
-        &[
-            0, 9, 10, 14, 53, 163, 208, 400, 499, 563, 957, 980, 1010, 1400,
-        ],
-    );
-    Ok(())
+#[derive(Subcommand)]
+enum Command {
+    /// Append records, one per argument or one per line of stdin if none given
+    Produce {
+        #[arg(long)]
+        key: Option<String>,
+        values: Vec<String>,
+    },
+    /// Print the records in the [from, to) range of offsets
+    Consume {
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        #[arg(long, default_value_t = u64::MAX)]
+        to: u64,
+        /// Skip the records that fail to decode, reporting them on stderr
+        #[arg(long)]
+        skip_corrupt: bool,
+    },
+    /// Print the state of the partition and of each of its segments
+    Dump,
+    /// Decode every record of the partition, failing on the first invalid one
+    Verify,
+    /// Write the records in the [from, to) range to stdout, in the format picked
+    #[command(group(ArgGroup::new("format").required(true)))]
+    Export {
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        #[arg(long, default_value_t = u64::MAX)]
+        to: u64,
+        /// As JSON lines, binary keys and values base64 encoded
+        #[arg(long, group = "format")]
+        json: bool,
+    },
+    /// Append the records read from stdin, in the format picked
+    #[command(group(ArgGroup::new("format").required(true)))]
+    Import {
+        /// As JSON lines, see `export --json`
+        #[arg(long, group = "format")]
+        json: bool,
+    },
+    /// Copy topics between two roots of topics
+    Mirror {
+        source: PathBuf,
+        target: PathBuf,
+        topics: Vec<String>,
+    },
+    /// Rewrite the segments written with an older binary format
+    Upgrade,
+    /// Move the committed offsets of a consumer group, to `earliest`, `latest`,
+    /// `offset:<offset>` or `timestamp:<millis>`
+    ResetOffsets {
+        root: PathBuf,
+        group: String,
+        topic: String,
+        to: OffsetTarget,
+        /// Partitions to reset, all of them if none given
+        #[arg(long = "partition")]
+        partitions: Vec<u32>,
+    },
+    /// Append stdin to the partition while writing its records to stdout, values
+    /// framed by `newline`, `null` or `length`
+    Pipe {
+        #[arg(long, default_value = "newline")]
+        framing: Framing,
+        /// Offset of the first record written, the end of the partition if not
+        /// given, its start with `--no-input`
+        #[arg(long)]
+        from: Option<u64>,
+        /// Only append stdin, writing nothing
+        #[arg(long, conflicts_with = "no_input")]
+        no_output: bool,
+        /// Only write the records already appended, reading nothing
+        #[arg(long)]
+        no_input: bool,
+    },
+    /// Serve the topics of a root over the Redis Streams subset of `shoju::resp`
+    /// or over the REST API
+    Serve {
+        root: PathBuf,
+        #[arg(long, value_enum, default_value_t = Protocol::Resp)]
+        protocol: Protocol,
+        /// Address to listen on, 127.0.0.1 on the default port of the protocol if
+        /// not given
+        #[arg(long)]
+        addr: Option<String>,
+    },
+}
+
+fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Produce { key, values } => {
+            let mut partition = Partition::open_or_create(&cli.dir, &PartitionConfig::default())?;
+            let key = key.map(String::into_bytes);
+            let mut produce = |value: &str| -> io::Result<()> {
+                let appended = partition.append_record(key.clone(), value.as_bytes())?;
+                println!("{}", appended.offset);
+                Ok(())
+            };
+            if values.is_empty() {
+                for line in io::stdin().lock().lines() {
+                    produce(&line?)?;
+                }
+            } else {
+                values.iter().try_for_each(|v| produce(v))?;
+            }
+            partition.close()
+        }
+        Command::Consume {
+            from,
+            to,
+            skip_corrupt,
+        } => {
+            let partition = Partition::open_existing(&cli.dir)?;
+            let records = if skip_corrupt {
+                let (records, corrupt) = partition.read_range_skipping_corrupt(from, to)?;
+                for range in corrupt {
+                    eprintln!(
+                        "Skipped corrupt bytes {}..{} of segment {}",
+                        range.begin, range.end, range.base_offset
+                    );
+                }
+                records
+            } else {
+                partition.read_range(from, to)?
+            };
+            let mut stdout = BufWriter::new(io::stdout().lock());
+            for record in records {
+                writeln!(
+                    stdout,
+                    "{}: {}",
+                    record,
+                    String::from_utf8_lossy(&record.value)
+                )?;
+            }
+            stdout.flush()
+        }
+        Command::Dump => {
+            let partition = Partition::open_existing(&cli.dir)?;
+            println!("{:?}", partition.stats()?);
+            for segment in partition.segments() {
+                println!("{:?}", segment);
+            }
+            for path in partition.unknown_files() {
+                println!("Unknown file {}", path.display());
+            }
+            Ok(())
+        }
+        Command::Verify => {
+            let partition = Partition::open_existing(&cli.dir)?;
+            let records = partition.read_range_with_control(partition.start_offset(), u64::MAX)?;
+            if records.windows(2).any(|w| w[0].offset >= w[1].offset) {
+                return Err(Error::new(ErrorKind::InvalidData, "Offsets out of order"));
+            }
+            println!("Verified {} records", records.len());
+            Ok(())
+        }
+        Command::Export { from, to, json: _ } => {
+            let partition = Partition::open_existing(&cli.dir)?;
+            let mut stdout = BufWriter::new(io::stdout().lock());
+            json::export(&partition, from, to, &mut stdout)?;
+            stdout.flush()
+        }
+        Command::Import { json: _ } => {
+            let mut partition = Partition::open_or_create(&cli.dir, &PartitionConfig::default())?;
+            json::import(&mut partition, io::stdin().lock())?;
+            partition.close()
+        }
+        Command::Mirror {
+            source,
+            target,
+            topics,
+        } => {
+            let mut source = TopicManager::open(source)?;
+            let mut target = TopicManager::open(target)?;
+            let mirror = Mirror::new("__mirror");
+            for topic in &topics {
+                let copied = mirror.mirror_topic(&mut source, &mut target, topic)?;
+                println!("Mirrored {} records of {}", copied, topic);
+            }
+            Ok(())
+        }
+        Command::Upgrade => {
+            let mut partition = Partition::open_existing(&cli.dir)?;
+            let upgraded = partition.upgrade_format()?;
+            println!("Upgraded {} segments", upgraded);
+            Ok(())
+        }
+        Command::ResetOffsets {
+            root,
+            group,
+            topic,
+            to,
+            partitions,
+        } => {
+            let mut manager = TopicManager::open(root)?;
+            for (n, offset) in manager.reset_group_offsets(&group, &topic, &partitions, to)? {
+                println!("{}/{}: {}", topic, n, offset);
+            }
+            Ok(())
+        }
+        Command::Pipe {
+            framing,
+            from,
+            no_output,
+            no_input,
+        } => {
+            let mut partition = Partition::open_or_create(&cli.dir, &PartitionConfig::default())?;
+            let mut stdout = BufWriter::new(io::stdout().lock());
+            if no_input {
+                let from = from.unwrap_or(partition.start_offset());
+                pipe::drain(&partition, framing, from, &mut stdout)?;
+            } else if no_output {
+                pipe::ingest(&mut partition, framing, io::stdin().lock())?;
+            } else {
+                let from = from.unwrap_or(partition.end_offset());
+                let stdin = io::BufReader::new(io::stdin());
+                partition = pipe::pipe(partition, framing, from, stdin, &mut stdout)?;
+            }
+            partition.close()
+        }
+        Command::Serve {
+            root,
+            protocol,
+            addr,
+        } => {
+            // Before any other thread, so that none of them gets the signals
+            let shutdown = Shutdown::on_termination()?;
+            let manager = TopicManager::open(root)?;
+            match protocol {
+                Protocol::Resp => {
+                    let addr = addr.unwrap_or_else(|| "127.0.0.1:6379".into());
+                    let mut server = RespServer::new(manager);
+                    server.set_shutdown(shutdown);
+                    server.serve(&addr)?;
+                    server.close()
+                }
+                #[cfg(feature = "tiny_http")]
+                Protocol::Rest => {
+                    let addr = addr.unwrap_or_else(|| "127.0.0.1:8080".into());
+                    let mut api = shoju::rest::RestApi::new(manager);
+                    api.set_shutdown(shutdown);
+                    api.serve(&addr)?;
+                    api.close()
+                }
+                #[cfg(not(feature = "tiny_http"))]
+                Protocol::Rest => Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "The REST API needs the tiny_http feature",
+                )),
+            }
+        }
+    }
 }
@@ -0,0 +1,111 @@
+//! Memory budget of the mapped segments
+//!
+//! Every segment keeps its log and index mapped, a process running many partitions
+//! can pin more memory than available through the pages of the files read. A
+//! `MemoryManager` bounds the bytes pinned across partitions: the active segments
+//! always count, the sealed ones do once read. When over budget, the pages of the
+//! least recently read sealed segments are released, to be read back from the
+//! files on their next access.
+use crate::partition::segment::Segment;
+use crate::partition::Partition;
+use std::io::Result;
+use std::sync::Arc;
+
+pub struct MemoryManager {
+    budget: usize,
+}
+
+impl MemoryManager {
+    pub fn new(budget: usize) -> Self {
+        Self { budget }
+    }
+
+    /// The bytes pinned by `partitions`, as far as the manager knows
+    pub fn pinned<'a>(&self, partitions: impl IntoIterator<Item = &'a Partition>) -> usize {
+        partitions
+            .into_iter()
+            .map(|p| match p.mapped_segments().split_last() {
+                Some((active, sealed)) => {
+                    active.mapped_size() + sealed.iter().map(|s| pinned_size(s)).sum::<usize>()
+                }
+                None => 0,
+            })
+            .sum()
+    }
+
+    /// Release the pages of the least recently read sealed segments of `partitions`
+    /// until the bytes pinned fit the budget, returning the bytes released
+    pub fn enforce<'a>(
+        &self,
+        partitions: impl IntoIterator<Item = &'a Partition>,
+    ) -> Result<usize> {
+        let mut pinned = 0;
+        let mut cold: Vec<(u64, &Arc<Segment>)> = Vec::new();
+        for partition in partitions {
+            let segments = partition.mapped_segments();
+            if let Some((active, sealed)) = segments.split_last() {
+                pinned += active.mapped_size();
+                for segment in sealed {
+                    if let Some(last_read) = segment.last_read() {
+                        pinned += segment.mapped_size();
+                        cold.push((last_read, segment));
+                    }
+                }
+            }
+        }
+        cold.sort_by_key(|(last_read, _)| *last_read);
+        let mut released = 0;
+        for (_, segment) in cold {
+            if pinned <= self.budget {
+                break;
+            }
+            segment.release_pages()?;
+            pinned -= segment.mapped_size();
+            released += segment.mapped_size();
+        }
+        Ok(released)
+    }
+}
+
+fn pinned_size(segment: &Segment) -> usize {
+    match segment.last_read() {
+        Some(_) => segment.mapped_size(),
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod memory_tests {
+    use super::MemoryManager;
+    use crate::partition::Partition;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_enforce() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partitions: Vec<Partition> = (0..2)
+            .map(|n| {
+                let dir = tmp_dir.path().join(n.to_string());
+                std::fs::create_dir(&dir).unwrap();
+                let mut partition = Partition::open(dir).unwrap();
+                for i in 0..500u64 {
+                    partition.append_record(None, &i.to_be_bytes()).unwrap();
+                }
+                partition
+            })
+            .collect();
+        let manager = MemoryManager::new(usize::MAX);
+        let pinned = manager.pinned(&partitions);
+        assert_eq!(manager.enforce(&partitions).unwrap(), 0);
+
+        let manager = MemoryManager::new(pinned / 2);
+        let released = manager.enforce(&partitions).unwrap();
+        assert!(released >= pinned / 2);
+        assert!(manager.pinned(&partitions) <= pinned / 2);
+        // The most recently read segments are kept, the others are read back
+        partitions[1].read_range(0, 100).unwrap();
+        assert!(manager.pinned(&partitions) > pinned - released);
+        assert_eq!(partitions[0].read_range(0, 500).unwrap().len(), 500);
+        tmp_dir.close().unwrap();
+    }
+}
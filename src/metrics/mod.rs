@@ -0,0 +1,165 @@
+//! In-process metrics
+//!
+//! A `Metrics` registry collects counters, gauges and timings from anywhere in the
+//! process, keyed by dotted names. Exporters take snapshots of it: counters and
+//! timings restart from scratch after each snapshot, gauges keep their latest
+//! value.
+pub mod statsd;
+
+use crate::disk::DiskStatus;
+use crate::partition::latency::Operation;
+use crate::partition::stats::PartitionStats;
+use crate::partition::Partition;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Metrics gathered since the previous snapshot
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub counters: BTreeMap<String, u64>,
+    pub gauges: BTreeMap<String, f64>,
+    /// Every duration recorded, in milliseconds
+    pub timings: BTreeMap<String, Vec<f64>>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    state: Mutex<Snapshot>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn incr(&self, name: &str, by: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            *state.counters.entry(name.into()).or_default() += by;
+        }
+    }
+
+    pub fn gauge(&self, name: &str, value: f64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.gauges.insert(name.into(), value);
+        }
+    }
+
+    pub fn timing(&self, name: &str, duration: Duration) {
+        if let Ok(mut state) = self.state.lock() {
+            let millis = duration.as_secs_f64() * 1000.0;
+            state.timings.entry(name.into()).or_default().push(millis);
+        }
+    }
+
+    /// The metrics gathered since the previous snapshot
+    pub fn snapshot(&self) -> Snapshot {
+        let Ok(mut state) = self.state.lock() else {
+            return Snapshot::default();
+        };
+        Snapshot {
+            counters: std::mem::take(&mut state.counters),
+            gauges: state.gauges.clone(),
+            timings: std::mem::take(&mut state.timings),
+        }
+    }
+
+    /// Count the records and bytes appended to `partition` as `<name>.records` and
+    /// `<name>.bytes`
+    pub fn observe_appends(self: &Arc<Self>, partition: &mut Partition, name: &str) {
+        let metrics = Arc::clone(self);
+        let (records, bytes) = (format!("{}.records", name), format!("{}.bytes", name));
+        partition.on_append(move |appended| {
+            metrics.incr(&records, 1);
+            metrics.incr(&bytes, appended.size as u64);
+        });
+    }
+
+    /// Gauge the size, offsets and number of segments of a partition under `name`
+    pub fn record_stats(&self, name: &str, stats: &PartitionStats) {
+        self.gauge(&format!("{}.size", name), stats.size as f64);
+        self.gauge(&format!("{}.segments", name), stats.segments as f64);
+        self.gauge(&format!("{}.start_offset", name), stats.start_offset as f64);
+        self.gauge(&format!("{}.end_offset", name), stats.end_offset as f64);
+    }
+
+    /// Gauge the free bytes of the data volume, whether writes are rejected and
+    /// whether it failed to be sampled under `name`
+    pub fn record_disk(&self, name: &str, status: &DiskStatus) {
+        self.gauge(&format!("{}.free_bytes", name), status.free_bytes as f64);
+        self.gauge(
+            &format!("{}.tripped", name),
+            u8::from(status.tripped) as f64,
+        );
+        self.gauge(
+            &format!("{}.errored", name),
+            u8::from(status.error.is_some()) as f64,
+        );
+    }
+
+    /// Gauge the p50 and p99 latencies of the operations of a partition under
+    /// `<name>.<operation>`, in milliseconds
+    pub fn record_latencies(&self, name: &str, partition: &Partition) {
+        for operation in [
+            Operation::Append,
+            Operation::Fetch,
+            Operation::Flush,
+            Operation::IndexLookup,
+        ] {
+            let histogram = partition.latency(operation);
+            for (suffix, quantile) in [("p50", 0.5), ("p99", 0.99)] {
+                let millis = histogram.percentile(quantile).as_secs_f64() * 1000.0;
+                self.gauge(&format!("{}.{}.{}", name, operation.name(), suffix), millis);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::Metrics;
+    use crate::disk::DiskStatus;
+    use crate::partition::Partition;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_snapshot() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        let metrics = Arc::new(Metrics::new());
+        metrics.observe_appends(&mut partition, "events.0");
+        for i in 0..10u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        metrics.record_stats("events.0", &partition.stats().unwrap());
+        metrics.record_latencies("events.0", &partition);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.counters["events.0.records"], 10);
+        assert_eq!(snapshot.counters["events.0.bytes"], partition.size() as u64);
+        assert_eq!(snapshot.gauges["events.0.end_offset"], 10.0);
+        assert!(snapshot.gauges["events.0.append.p99"] > 0.0);
+        // Counters restart, gauges stay
+        let snapshot = metrics.snapshot();
+        assert!(snapshot.counters.is_empty());
+        assert_eq!(snapshot.gauges["events.0.end_offset"], 10.0);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_disk_status() {
+        let metrics = Metrics::new();
+        let status = DiskStatus {
+            free_bytes: 1024,
+            tripped: true,
+            checks: 3,
+            error: None,
+        };
+        metrics.record_disk("disk", &status);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.gauges["disk.free_bytes"], 1024.0);
+        assert_eq!(snapshot.gauges["disk.tripped"], 1.0);
+        assert_eq!(snapshot.gauges["disk.errored"], 0.0);
+    }
+}
@@ -0,0 +1,172 @@
+//! StatsD push of the metrics
+//!
+//! A `StatsdEmitter` sends a snapshot of a `Metrics` registry every
+//! `flush_interval` over UDP, each metric name behind a common prefix. Lines are
+//! packed into datagrams of at most 512 bytes, separated by newlines as StatsD
+//! servers expect. A first snapshot is sent right away and a last one when the
+//! emitter is dropped.
+use crate::metrics::{Metrics, Snapshot};
+use std::io::Result;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Bytes of lines packed into a datagram, fitting the MTU of most networks
+const MAX_DATAGRAM: usize = 512;
+
+struct Shared {
+    stopped: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+pub struct StatsdEmitter {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StatsdEmitter {
+    pub fn spawn(
+        metrics: Arc<Metrics>,
+        addr: impl ToSocketAddrs,
+        prefix: &str,
+        flush_interval: Duration,
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        let shared = Arc::new(Shared {
+            stopped: Mutex::new(false),
+            wakeup: Condvar::new(),
+        });
+        let background = Arc::clone(&shared);
+        let prefix = prefix.to_string();
+        let handle = thread::spawn(move || {
+            run(&background, &metrics, &socket, &prefix, flush_interval);
+        });
+        Ok(Self {
+            shared,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for StatsdEmitter {
+    fn drop(&mut self) {
+        if let Ok(mut stopped) = self.shared.stopped.lock() {
+            *stopped = true;
+        }
+        self.shared.wakeup.notify_one();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run(
+    shared: &Shared,
+    metrics: &Metrics,
+    socket: &UdpSocket,
+    prefix: &str,
+    flush_interval: Duration,
+) {
+    let Ok(mut stopped) = shared.stopped.lock() else {
+        return;
+    };
+    loop {
+        let done = *stopped;
+        // Sending is best effort, a metrics server down isn't worth stopping for
+        for datagram in datagrams(&lines(&metrics.snapshot(), prefix)) {
+            let _ = socket.send(datagram.as_bytes());
+        }
+        if done {
+            return;
+        }
+        stopped = match shared.wakeup.wait_timeout(stopped, flush_interval) {
+            Ok((stopped, _)) => stopped,
+            Err(_) => return,
+        };
+    }
+}
+
+/// The StatsD lines of a snapshot, every name behind `prefix`
+pub fn lines(snapshot: &Snapshot, prefix: &str) -> Vec<String> {
+    let name = |name: &str| match prefix {
+        "" => name.to_string(),
+        prefix => format!("{}.{}", prefix, name),
+    };
+    let mut lines = Vec::new();
+    for (key, value) in &snapshot.counters {
+        lines.push(format!("{}:{}|c", name(key), value));
+    }
+    for (key, value) in &snapshot.gauges {
+        lines.push(format!("{}:{}|g", name(key), value));
+    }
+    for (key, values) in &snapshot.timings {
+        for value in values {
+            lines.push(format!("{}:{}|ms", name(key), value));
+        }
+    }
+    lines
+}
+
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(last) if last.len() + 1 + line.len() <= MAX_DATAGRAM => {
+                last.push('\n');
+                last.push_str(line);
+            }
+            _ => datagrams.push(line.clone()),
+        }
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod statsd_tests {
+    use super::{datagrams, StatsdEmitter};
+    use crate::metrics::Metrics;
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_emit() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let metrics = Arc::new(Metrics::new());
+        metrics.incr("appends", 3);
+        metrics.gauge("size", 1024.0);
+        metrics.timing("flush", Duration::from_millis(2));
+
+        let emitter = StatsdEmitter::spawn(
+            Arc::clone(&metrics),
+            server.local_addr().unwrap(),
+            "shoju",
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+        let mut buf = [0; 512];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..len]).unwrap(),
+            "shoju.appends:3|c\nshoju.size:1024|g\nshoju.flush:2|ms"
+        );
+
+        // Dropping the emitter sends what's left
+        metrics.incr("appends", 1);
+        drop(emitter);
+        let len = server.recv(&mut buf).unwrap();
+        assert!(std::str::from_utf8(&buf[..len])
+            .unwrap()
+            .starts_with("shoju.appends:1|c\n"));
+
+        let long: Vec<String> = (0..100).map(|i| format!("metric.{}:1|c", i)).collect();
+        let packed = datagrams(&long);
+        assert!(packed.len() > 1 && packed.iter().all(|d| d.len() <= 512));
+        assert_eq!(packed.iter().map(|d| d.lines().count()).sum::<usize>(), 100);
+    }
+}
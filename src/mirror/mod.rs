@@ -0,0 +1,163 @@
+//! Topic mirroring between two shoju roots
+//!
+//! A `Mirror` copies the records of a source topic into the topic of the same name
+//! in a target root, creating it with the source configuration if missing. Keys,
+//! timestamps and headers are preserved, so are tombstones, offsets are assigned
+//! by the target partitions.
+//!
+//! Progress is checkpointed as consumer group commits in the target offsets store,
+//! every partition is committed once its copied records are flushed, so a mirror
+//! restarted after a crash resumes from the latest checkpoint. Records flushed but
+//! not yet committed are copied again, the delivery is at least once.
+use crate::topic::TopicManager;
+use std::io::{Error, ErrorKind, Result};
+
+pub struct Mirror {
+    group: String,
+}
+
+impl Mirror {
+    /// A mirror checkpointing its progress as the `group` consumer group
+    pub fn new(group: &str) -> Self {
+        Self {
+            group: group.into(),
+        }
+    }
+
+    /// Copy the records appended to `topic` in `source` since the latest checkpoint
+    /// to `target`, returning the number of records copied
+    pub fn mirror_topic(
+        &self,
+        source: &mut TopicManager,
+        target: &mut TopicManager,
+        topic: &str,
+    ) -> Result<usize> {
+        let source_topic = source
+            .topic(topic)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Topic {} not found", topic)))?;
+        let config = source_topic.config().clone();
+        if target.topic(topic).is_none() {
+            target.create_topic(topic, config.clone())?;
+        }
+        let target_partitions = target.topic(topic).unwrap().config().partitions;
+        if target_partitions != config.partitions {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Topic {} has {} partitions in the source and {} in the target",
+                    topic, config.partitions, target_partitions
+                ),
+            ));
+        }
+        let mut copied = 0;
+        for n in 0..config.partitions {
+            let partition = &source_topic.partitions()[n as usize];
+            let from = target
+                .offsets()
+                .committed(&self.group, topic, n)
+                .unwrap_or(partition.start_offset());
+            let to = partition.end_offset();
+            if from >= to {
+                continue;
+            }
+            partition.advise_scan(from, to)?;
+            let records = partition.read_range(from, to)?;
+            let destination = target.topic(topic).unwrap().partition(n).unwrap();
+            for record in &records {
+                if let (true, Some(key)) = (record.attributes.tombstone(), &record.key) {
+                    destination.append_tombstone_at(record.timestamp, key.clone())?;
+                    continue;
+                }
+                destination.append_record_with_headers(
+                    Some(record.timestamp),
+                    record.key.clone(),
+                    &record.value,
+                    record.headers.clone(),
+                )?;
+            }
+            destination.flush()?;
+            target.offsets().commit(&self.group, topic, n, to)?;
+            partition.release_copied(to)?;
+            copied += records.len();
+        }
+        Ok(copied)
+    }
+}
+
+#[cfg(test)]
+mod mirror_tests {
+    use super::Mirror;
+    use crate::topic::{TopicConfig, TopicManager};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_mirror_topic() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut source = TopicManager::open(tmp_dir.path().join("source")).unwrap();
+        let topic = source
+            .create_topic("events", TopicConfig::new(2).with("retention.ms", "1000"))
+            .unwrap();
+        for i in 0..3u8 {
+            topic
+                .partition(0)
+                .unwrap()
+                .append_record(Some(vec![i]), &[i])
+                .unwrap();
+        }
+        let partition = topic.partition(0).unwrap();
+        partition.append_tombstone(vec![1]).unwrap();
+        let traced = vec![("traceparent".to_string(), b"00-01".to_vec())];
+        topic
+            .partition(1)
+            .unwrap()
+            .append_record_with_headers(None, None, b"b", traced.clone())
+            .unwrap();
+
+        let mirror = Mirror::new("mirror");
+        let target_root = tmp_dir.path().join("target");
+        let mut target = TopicManager::open(&target_root).unwrap();
+        assert_eq!(
+            mirror
+                .mirror_topic(&mut source, &mut target, "events")
+                .unwrap(),
+            5
+        );
+        assert_eq!(
+            mirror
+                .mirror_topic(&mut source, &mut target, "events")
+                .unwrap(),
+            0
+        );
+
+        let original = source.topic("events").unwrap().partitions()[0]
+            .read_range(0, 4)
+            .unwrap();
+        let mirrored = target.topic("events").unwrap();
+        assert_eq!(mirrored.config(), source.topic("events").unwrap().config());
+        assert_eq!(mirrored.partitions()[0].read_range(0, 4).unwrap(), original);
+        assert_eq!(mirrored.partitions()[0].find_latest(&[1]).unwrap(), None);
+
+        source
+            .topic("events")
+            .unwrap()
+            .partition(1)
+            .unwrap()
+            .append_record(None, b"c")
+            .unwrap();
+        drop(target);
+        let mut target = TopicManager::open(&target_root).unwrap();
+        assert_eq!(
+            mirror
+                .mirror_topic(&mut source, &mut target, "events")
+                .unwrap(),
+            1
+        );
+        let records = target.topic("events").unwrap().partitions()[1]
+            .read_range(0, 10)
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].headers, traced);
+        assert_eq!(records[1].value, b"c");
+        tmp_dir.close().unwrap();
+    }
+}
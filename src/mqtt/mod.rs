@@ -0,0 +1,218 @@
+//! Bridge ingesting MQTT messages into topics
+//!
+//! An `MqttBridge` subscribes to MQTT topic filters and appends every message
+//! received to the shoju topic each filter is routed to, keyed by the MQTT topic it
+//! was published on. Messages of a same MQTT topic always land in the same
+//! partition, picked by the CRC32 of the key.
+//!
+//! Delivery is at least once: messages are subscribed with QoS 1 and acknowledged
+//! to the broker only once appended and flushed, a message in flight when the
+//! bridge stops is delivered again by the broker. Broken connections are retried
+//! with an exponential backoff, resubscribing on every new connection.
+use crate::partition::AppendInfo;
+use crate::topic::TopicManager;
+use rumqttc::{Client, Event, MqttOptions, Packet, Publish, QoS};
+use std::io::{Error, ErrorKind, Result};
+use std::thread;
+use std::time::Duration;
+
+/// Messages buffered between the connection and the bridge
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Exponential backoff between attempts, from `min` doubling up to `max`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    /// The delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+pub struct MqttBridge {
+    options: MqttOptions,
+    // MQTT topic filters and the topics they're routed to
+    routes: Vec<(String, String)>,
+    backoff: Backoff,
+}
+
+impl MqttBridge {
+    pub fn new(mut options: MqttOptions) -> Self {
+        options.set_manual_acks(true);
+        Self {
+            options,
+            routes: Vec::new(),
+            backoff: Backoff::new(Duration::from_millis(100), Duration::from_secs(30)),
+        }
+    }
+
+    /// Append the messages matching the MQTT topic `filter` to `topic`, the first
+    /// route matching a message wins
+    pub fn route(mut self, filter: &str, topic: &str) -> Self {
+        self.routes.push((filter.into(), topic.into()));
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Bridge messages until appending one fails, the failed message is left
+    /// unacknowledged
+    pub fn run(&self, manager: &mut TopicManager) -> Result<()> {
+        let (client, mut connection) = Client::new(self.options.clone(), CHANNEL_CAPACITY);
+        let mut backoff = self.backoff;
+        for notification in connection.iter() {
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    backoff.reset();
+                    for (filter, _) in &self.routes {
+                        client
+                            .subscribe(filter, QoS::AtLeastOnce)
+                            .map_err(Error::other)?;
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    self.deliver(manager, &publish)?;
+                    client.ack(&publish).map_err(Error::other)?;
+                }
+                Ok(_) => {}
+                // The next iteration reconnects
+                Err(_) => thread::sleep(backoff.next_delay()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Append `publish` to the topic it's routed to and flush it, `None` if no
+    /// route matches it
+    pub fn deliver(
+        &self,
+        manager: &mut TopicManager,
+        publish: &Publish,
+    ) -> Result<Option<AppendInfo>> {
+        let Some((_, name)) = self
+            .routes
+            .iter()
+            .find(|(filter, _)| topic_matches(filter, &publish.topic))
+        else {
+            return Ok(None);
+        };
+        let topic = manager
+            .topic(name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Topic {} not found", name)))?;
+        let key = publish.topic.as_bytes();
+        let n = crc32fast::hash(key) % topic.partitions().len() as u32;
+        let partition = topic
+            .partition(n)
+            .ok_or_else(|| Error::other("Partition out of range"))?;
+        let appended = partition.append_record(Some(key.to_vec()), &publish.payload)?;
+        partition.flush()?;
+        Ok(Some(appended))
+    }
+}
+
+/// Whether the MQTT `topic` matches `filter`, with its `+` and `#` wildcards
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for pattern in filter.split('/') {
+        match (pattern, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (pattern, Some(level)) if pattern == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+#[cfg(test)]
+mod mqtt_tests {
+    use super::{topic_matches, Backoff, MqttBridge};
+    use crate::topic::{TopicConfig, TopicManager};
+    use rumqttc::{MqttOptions, Publish, QoS};
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches(
+            "devices/+/temperature",
+            "devices/a/temperature"
+        ));
+        assert!(!topic_matches(
+            "devices/+/temperature",
+            "devices/a/humidity"
+        ));
+        assert!(!topic_matches("devices/+", "devices/a/temperature"));
+        assert!(topic_matches("devices/#", "devices/a/temperature"));
+        assert!(topic_matches("devices/#", "devices"));
+        assert!(!topic_matches("devices/a", "devices"));
+
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(3));
+        let delays: Vec<u64> = (0..4).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 3, 3]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_deliver() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager
+            .create_topic("telemetry", TopicConfig::new(4))
+            .unwrap();
+        let bridge = MqttBridge::new(MqttOptions::new("bridge", "localhost", 1883))
+            .route("devices/+/telemetry", "telemetry")
+            .route("devices/+/logs", "logs");
+
+        let publish = |topic: &str| Publish::new(topic, QoS::AtLeastOnce, b"21.5".to_vec());
+        let first = bridge
+            .deliver(&mut manager, &publish("devices/a/telemetry"))
+            .unwrap();
+        let second = bridge
+            .deliver(&mut manager, &publish("devices/a/telemetry"))
+            .unwrap();
+        assert_eq!((first.unwrap().offset, second.unwrap().offset), (0, 1));
+        assert_eq!(
+            bridge
+                .deliver(&mut manager, &publish("devices/a/other"))
+                .unwrap(),
+            None
+        );
+        assert!(bridge
+            .deliver(&mut manager, &publish("devices/a/logs"))
+            .is_err());
+
+        let topic = manager.topic("telemetry").unwrap();
+        let stored: Vec<_> = topic
+            .partitions()
+            .iter()
+            .flat_map(|p| p.read_range(0, 2).unwrap())
+            .collect();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].key.as_deref(), Some(&b"devices/a/telemetry"[..]));
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! Async access to a partition, independent of any runtime
+//!
+//! Partition operations block on disk, an `AsyncPartition` hands each of them to a
+//! `Spawn` and returns a future completed once it ran. The futures only rely on
+//! the standard `Waker`, so they can be awaited on any executor, and the `Spawn`
+//! is where the embedder plugs the blocking pool of its runtime:
+//!
+//! ```text
+//! tokio      tokio::task::spawn_blocking(task)
+//! async-std  async_std::task::spawn_blocking(task)
+//! smol       smol::unblock(task).detach()
+//! ```
+//!
+//! `ThreadSpawn` runs every operation on a thread of its own, for the embedders
+//! without a blocking pool.
+use crate::partition::record::Record;
+use crate::partition::{AppendInfo, Partition};
+use std::future::Future;
+use std::io::{Error, Result};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+pub type BlockingTask = Box<dyn FnOnce() + Send>;
+
+/// Runs blocking tasks off the executor threads
+pub trait Spawn: Send + Sync {
+    fn spawn_blocking(&self, task: BlockingTask);
+}
+
+/// Spawn a thread per task
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadSpawn;
+
+impl Spawn for ThreadSpawn {
+    fn spawn_blocking(&self, task: BlockingTask) {
+        thread::spawn(task);
+    }
+}
+
+struct Slot<T> {
+    result: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// The result of a blocking task, ready once the task ran
+pub struct Blocking<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut slot = lock(&self.slot);
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn lock<T>(slot: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A task panicking while holding the lock can't have left a half written slot
+    slot.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Run `task` with `spawn`, the future returned completes with its result
+pub fn unblock<T: Send + 'static>(
+    spawn: &dyn Spawn,
+    task: impl FnOnce() -> T + Send + 'static,
+) -> Blocking<T> {
+    let slot = Arc::new(Mutex::new(Slot {
+        result: None,
+        waker: None,
+    }));
+    let completed = Arc::clone(&slot);
+    spawn.spawn_blocking(Box::new(move || {
+        let result = task();
+        let waker = {
+            let mut slot = lock(&completed);
+            slot.result = Some(result);
+            slot.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }));
+    Blocking { slot }
+}
+
+#[derive(Clone)]
+pub struct AsyncPartition {
+    partition: Arc<Mutex<Partition>>,
+    spawn: Arc<dyn Spawn>,
+}
+
+impl AsyncPartition {
+    pub fn new(partition: Partition, spawn: impl Spawn + 'static) -> Self {
+        Self {
+            partition: Arc::new(Mutex::new(partition)),
+            spawn: Arc::new(spawn),
+        }
+    }
+
+    /// The partition, locked by the operations in flight
+    pub fn partition(&self) -> &Arc<Mutex<Partition>> {
+        &self.partition
+    }
+
+    pub async fn append_record(&self, key: Option<Vec<u8>>, value: Vec<u8>) -> Result<AppendInfo> {
+        self.run(move |p| p.append_record(key, &value)).await
+    }
+
+    pub async fn fetch(&self, from: u64, max_bytes: usize) -> Result<Vec<Record>> {
+        self.run(move |p| p.fetch(from, max_bytes)).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.run(|p| p.flush()).await
+    }
+
+    fn run<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&mut Partition) -> Result<T> + Send + 'static,
+    ) -> Blocking<Result<T>> {
+        let partition = Arc::clone(&self.partition);
+        unblock(self.spawn.as_ref(), move || {
+            let mut partition = partition
+                .lock()
+                .map_err(|_| Error::other("Partition lock poisoned"))?;
+            operation(&mut partition)
+        })
+    }
+}
+
+#[cfg(test)]
+mod nonblocking_tests {
+    use super::{AsyncPartition, ThreadSpawn};
+    use crate::partition::Partition;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+    use tempdir::TempDir;
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_partition() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = AsyncPartition::new(Partition::open(tmp_dir.path()).unwrap(), ThreadSpawn);
+        block_on(async {
+            for i in 0..100u64 {
+                let appended = partition
+                    .append_record(None, i.to_be_bytes().to_vec())
+                    .await
+                    .unwrap();
+                assert_eq!(appended.offset, i);
+            }
+            partition.flush().await.unwrap();
+            let records = partition.fetch(90, usize::MAX).await.unwrap();
+            assert_eq!(records.len(), 10);
+            assert_eq!(records[0].value, 90u64.to_be_bytes());
+        });
+        assert_eq!(partition.partition().lock().unwrap().dirty_bytes(), 0);
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+//! Consumer group offsets storage
+//!
+//! Commits are appended as keyed records to an internal `__offsets` partition,
+//! the key identifying the group, topic and partition and the value holding the
+//! committed offset. The latest commit of every key is rebuilt by replaying the
+//! partition on open, so commits recover through the same storage machinery as any
+//! other record. The partition is compacted once its dirty ratio is reached, so it
+//! grows with the keys rather than with the commits.
+//!
+//! A commit is flushed without waiting for the disk, the latest ones may be lost
+//! in a crash and their records consumed again.
+//!
+//! A committed offset outside of the records of its partition, below the start
+//! offset after retention or past the end offset after the partition got recreated,
+//! is reset by the `OffsetReset` policy of the consumer, as is a missing one.
+use crate::partition::Partition;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::str::FromStr;
+
+pub const OFFSETS_TOPIC: &str = "__offsets";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OffsetKey {
+    pub group: String,
+    pub topic: String,
+    pub partition: u32,
+}
+
+impl OffsetKey {
+    pub fn new(group: &str, topic: &str, partition: u32) -> Self {
+        Self {
+            group: group.into(),
+            topic: topic.into(),
+            partition,
+        }
+    }
+}
+
+/// Where a consumer resumes from when its position is missing or out of range,
+/// the `auto.offset.reset` of the consumer
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OffsetReset {
+    /// The start offset of the partition
+    #[default]
+    Earliest,
+    /// The end offset of the partition, only the records appended from now on
+    Latest,
+    /// Fail with `ErrorKind::InvalidInput`
+    Error,
+}
+
+impl FromStr for OffsetReset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "earliest" => Ok(OffsetReset::Earliest),
+            "latest" => Ok(OffsetReset::Latest),
+            "error" => Ok(OffsetReset::Error),
+            other => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown offset reset policy {:?}", other),
+            )),
+        }
+    }
+}
+
+/// Where an operator moves the committed offsets of a group to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OffsetTarget {
+    Earliest,
+    Latest,
+    Offset(u64),
+    /// The first record at or after the timestamp, the end offset if none
+    Timestamp(u64),
+}
+
+impl OffsetTarget {
+    /// The offset of `log` the target points to, a specific offset is clamped to
+    /// the range of offsets of `log`
+    pub fn resolve(&self, log: &Partition) -> Result<u64> {
+        let (start, end) = (log.start_offset(), log.end_offset());
+        match *self {
+            OffsetTarget::Earliest => Ok(start),
+            OffsetTarget::Latest => Ok(end),
+            OffsetTarget::Offset(offset) => Ok(offset.clamp(start, end)),
+            OffsetTarget::Timestamp(timestamp) => {
+                Ok(log.offset_for_timestamp(timestamp)?.unwrap_or(end))
+            }
+        }
+    }
+}
+
+impl FromStr for OffsetTarget {
+    type Err = Error;
+
+    /// `earliest`, `latest`, `offset:<offset>` or `timestamp:<millis>`
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid offset target {:?}", s),
+            )
+        };
+        match s.split_once(':') {
+            None if s == "earliest" => Ok(OffsetTarget::Earliest),
+            None if s == "latest" => Ok(OffsetTarget::Latest),
+            Some(("offset", n)) => n.parse().map(OffsetTarget::Offset).map_err(|_| invalid()),
+            Some(("timestamp", ms)) => ms
+                .parse()
+                .map(OffsetTarget::Timestamp)
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A position moved by the `OffsetReset` policy
+#[derive(Clone, Debug, PartialEq)]
+pub struct PositionReset {
+    pub key: OffsetKey,
+    /// The committed offset, if any
+    pub committed: Option<u64>,
+    pub position: u64,
+}
+
+pub struct OffsetStore {
+    partition: Partition,
+    offsets: HashMap<OffsetKey, u64>,
+}
+
+impl OffsetStore {
+    /// Open the offsets partition in `dir`, creating it if missing, and replay
+    /// the commits stored in it
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        let partition = Partition::open(dir)?;
+        let mut offsets = HashMap::new();
+        for (key, value) in partition.latest_values()? {
+            let key: OffsetKey =
+                serde_json::from_slice(&key).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let value: [u8; 8] = value[..]
+                .try_into()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Offset commit value not a u64"))?;
+            offsets.insert(key, u64::from_be_bytes(value));
+        }
+        Ok(Self { partition, offsets })
+    }
+
+    /// Close the offsets partition, see `Partition::close`
+    pub fn close(self) -> Result<()> {
+        self.partition.close()
+    }
+
+    /// Record `offset` as the position of `group` on a topic partition, compacting
+    /// the offsets partition if dirty enough
+    pub fn commit(&mut self, group: &str, topic: &str, partition: u32, offset: u64) -> Result<()> {
+        let key = OffsetKey::new(group, topic, partition);
+        let encoded_key = serde_json::to_vec(&key).map_err(Error::other)?;
+        self.partition
+            .append_record(Some(encoded_key), &offset.to_be_bytes())?;
+        self.partition.flush()?;
+        self.partition.clean(None)?;
+        self.offsets.insert(key, offset);
+        Ok(())
+    }
+
+    pub fn committed(&self, group: &str, topic: &str, partition: u32) -> Option<u64> {
+        self.offsets
+            .get(&OffsetKey::new(group, topic, partition))
+            .copied()
+    }
+
+    /// The offset `group` resumes consuming `log`, a topic partition, from. A
+    /// committed offset in the `[start, end]` range of offsets of `log` is returned
+    /// as is, otherwise `reset` applies and `on_reset` is told about it.
+    pub fn position(
+        &self,
+        group: &str,
+        topic: &str,
+        partition: u32,
+        log: &Partition,
+        reset: OffsetReset,
+        on_reset: impl FnOnce(&PositionReset),
+    ) -> Result<u64> {
+        let committed = self.committed(group, topic, partition);
+        let (start, end) = (log.start_offset(), log.end_offset());
+        if let Some(offset) = committed.filter(|o| (start..=end).contains(o)) {
+            return Ok(offset);
+        }
+        let position = match reset {
+            OffsetReset::Earliest => start,
+            OffsetReset::Latest => end,
+            OffsetReset::Error => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Offset {:?} of group {} out of the range {}..={} of {}/{}",
+                        committed, group, start, end, topic, partition
+                    ),
+                ))
+            }
+        };
+        on_reset(&PositionReset {
+            key: OffsetKey::new(group, topic, partition),
+            committed,
+            position,
+        });
+        Ok(position)
+    }
+
+    /// All the latest commits of `group`, sorted by topic and partition
+    pub fn group_offsets(&self, group: &str) -> Vec<(OffsetKey, u64)> {
+        let mut offsets: Vec<(OffsetKey, u64)> = self
+            .offsets
+            .iter()
+            .filter(|(k, _)| k.group == group)
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        offsets.sort_by(|(a, _), (b, _)| (&a.topic, a.partition).cmp(&(&b.topic, b.partition)));
+        offsets
+    }
+}
+
+#[cfg(test)]
+mod offsets_tests {
+    use super::{OffsetKey, OffsetReset, OffsetStore};
+    use crate::partition::Partition;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_commit_and_replay() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let dir = tmp_dir.path().join("__offsets");
+        let mut store = OffsetStore::open(&dir).unwrap();
+
+        store.commit("group-a", "events", 0, 10).unwrap();
+        store.commit("group-a", "events", 1, 3).unwrap();
+        store.commit("group-a", "events", 0, 25).unwrap();
+        store.commit("group-b", "events", 0, 7).unwrap();
+
+        assert_eq!(store.committed("group-a", "events", 0), Some(25));
+        assert_eq!(store.committed("group-a", "orders", 0), None);
+        drop(store);
+
+        let store = OffsetStore::open(&dir).unwrap();
+        assert_eq!(store.committed("group-a", "events", 0), Some(25));
+        assert_eq!(store.committed("group-b", "events", 0), Some(7));
+        assert_eq!(
+            store.group_offsets("group-a"),
+            vec![
+                (OffsetKey::new("group-a", "events", 0), 25),
+                (OffsetKey::new("group-a", "events", 1), 3)
+            ]
+        );
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_compacted_commits() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let dir = tmp_dir.path().join("__offsets");
+        let mut store = OffsetStore::open(&dir).unwrap();
+        for offset in 0..2000 {
+            store
+                .commit("group", "events", (offset % 2) as u32, offset)
+                .unwrap();
+        }
+        let stored = store.partition.read_range(0, 2000).unwrap().len();
+        assert!(stored < 200, "{} commits stored", stored);
+        drop(store);
+
+        let store = OffsetStore::open(&dir).unwrap();
+        assert_eq!(store.committed("group", "events", 0), Some(1998));
+        assert_eq!(store.committed("group", "events", 1), Some(1999));
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_position_reset() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut store = OffsetStore::open(tmp_dir.path().join("__offsets")).unwrap();
+        let dir = tmp_dir.path().join("events");
+        fs::create_dir(&dir).unwrap();
+        let mut log = Partition::open(&dir).unwrap();
+        for i in 0..500u64 {
+            log.append_record_at(0, None, &i.to_be_bytes()).unwrap();
+        }
+        log.delete_expired(Duration::from_secs(1)).unwrap();
+        let (start, end) = (log.start_offset(), log.end_offset());
+        assert!(start > 10);
+
+        let mut resets = Vec::new();
+        store.commit("group", "events", 0, 10).unwrap();
+        let earliest = store
+            .position("group", "events", 0, &log, OffsetReset::Earliest, |r| {
+                resets.push(r.clone())
+            })
+            .unwrap();
+        assert_eq!(earliest, start);
+        assert_eq!(resets[0].committed, Some(10));
+        let err = store
+            .position("group", "events", 0, &log, OffsetReset::Error, |_| {})
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        // Never committed
+        let latest = store
+            .position("other", "events", 0, &log, OffsetReset::Latest, |r| {
+                resets.push(r.clone())
+            })
+            .unwrap();
+        assert_eq!(latest, end);
+        assert_eq!(resets[1].committed, None);
+
+        store.commit("group", "events", 0, end).unwrap();
+        let position = store.position("group", "events", 0, &log, OffsetReset::Error, |_| {});
+        assert_eq!(position.unwrap(), end);
+        assert_eq!(
+            "latest".parse::<OffsetReset>().unwrap(),
+            OffsetReset::Latest
+        );
+        assert!("none".parse::<OffsetReset>().is_err());
+        tmp_dir.close().unwrap();
+    }
+}
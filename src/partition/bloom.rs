@@ -0,0 +1,192 @@
+//! Key filters of sealed segments
+//!
+//! A `KeyFilter` is a bloom filter over the keys of a segment: a key it doesn't
+//! contain is certainly not in the segment, one it contains most likely is. Sealed
+//! segments write theirs next to the index when sealed and load it on open, so
+//! `Partition::find_latest` only reads the segments that may hold the key. Segments
+//! sealed before filters were persisted build theirs on the first lookup by key.
+//!
+//! A filter file is the size of the log it was built from as a big endian u64,
+//! followed by the words of the filter. One written for another log, e.g. left
+//! behind by a crash in the middle of a merge, is ignored.
+use crate::partition::record::Record;
+use crate::partition::Partition;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::Hasher;
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+/// Bits per key, along with `HASHES` a false positive rate around 1%
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+
+#[derive(Debug)]
+pub struct KeyFilter {
+    bits: Vec<u64>,
+}
+
+impl KeyFilter {
+    pub fn new<'a>(keys: impl ExactSizeIterator<Item = &'a [u8]>) -> Self {
+        let words = (keys.len() * BITS_PER_KEY).div_ceil(64).max(1);
+        let mut filter = Self {
+            bits: vec![0; words],
+        };
+        for key in keys {
+            for bit in filter.positions(key) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    pub fn path(dir: &Path, base_offset: u64) -> PathBuf {
+        dir.join(format!("{:020}.filter", base_offset))
+    }
+
+    /// Write the filter of a log of `log_size` bytes to `path`
+    pub fn write(&self, path: &Path, log_size: usize) -> Result<()> {
+        let mut content = Vec::with_capacity(8 * (self.bits.len() + 1));
+        content.extend_from_slice(&(log_size as u64).to_be_bytes());
+        for word in &self.bits {
+            content.extend_from_slice(&word.to_be_bytes());
+        }
+        fs::write(path, content)
+    }
+
+    /// Read the filter at `path`, `None` if it's missing, truncated or was built from
+    /// a log of another size than `log_size`
+    pub fn read(path: &Path, log_size: usize) -> Result<Option<Self>> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let Some((size, words)) = content.split_first_chunk::<8>() else {
+            return Ok(None);
+        };
+        if u64::from_be_bytes(*size) != log_size as u64 || words.is_empty() || words.len() % 8 != 0
+        {
+            return Ok(None);
+        }
+        let bits = words
+            .chunks_exact(8)
+            .map(|w| u64::from_be_bytes(w.try_into().unwrap()))
+            .collect();
+        Ok(Some(Self { bits }))
+    }
+
+    /// Whether `key` may be one of the keys of the filter
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits of `key`, by double hashing
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash & u32::MAX as u64, hash >> 32);
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+impl Partition {
+    /// The latest record of `key`, `None` if there's none or it's a tombstone. The
+    /// segments whose key filter rules the key out aren't read.
+    pub fn find_latest(&self, key: &[u8]) -> Result<Option<Record>> {
+        for segment in self.segments.iter().rev() {
+            if !segment.may_contain_key(key)? {
+                continue;
+            }
+            let latest = segment
+                .records()?
+                .into_iter()
+                .rev()
+                .find(|r| !r.attributes.control() && r.key.as_deref() == Some(key));
+            if let Some(record) = latest {
+                if record.attributes.tombstone() {
+                    return Ok(None);
+                }
+                return record.decompressed().map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod bloom_tests {
+    use super::KeyFilter;
+    use crate::partition::Partition;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_key_filter() {
+        let keys: Vec<_> = (0..1000u32).map(|i| i.to_be_bytes()).collect();
+        let filter = KeyFilter::new(keys.iter().map(|k| k.as_slice()));
+        assert!(keys.iter().all(|k| filter.may_contain(k)));
+        let false_positives = (1000..11_000u32)
+            .filter(|i| filter.may_contain(&i.to_be_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        for i in 0..500u64 {
+            let key = format!("key-{}", i % 50);
+            partition
+                .append_record(Some(key.into()), &i.to_be_bytes())
+                .unwrap();
+        }
+        partition.append_tombstone(b"key-7".to_vec()).unwrap();
+        let latest = partition.find_latest(b"key-3").unwrap().unwrap();
+        assert_eq!(latest.value, 453u64.to_be_bytes());
+        assert_eq!(partition.find_latest(b"key-7").unwrap(), None);
+        assert_eq!(partition.find_latest(b"missing").unwrap(), None);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_persisted_key_filter() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition
+            .append_record(Some(b"first".to_vec()), b"value")
+            .unwrap();
+        for i in 0..500u64 {
+            let key = format!("key-{}", i % 50);
+            partition
+                .append_record(Some(key.into()), &i.to_be_bytes())
+                .unwrap();
+        }
+        let segments = partition.segments();
+        let path = KeyFilter::path(tmp_dir.path(), 0);
+        let last = segments.last().unwrap().base_offset;
+        assert!(!KeyFilter::path(tmp_dir.path(), last).exists());
+        let written = fs::read(&path).unwrap();
+        drop(partition);
+
+        // The filter written is the one used, an empty one rules every key out
+        let mut empty = written.clone();
+        empty[8..].fill(0);
+        fs::write(&path, empty).unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.find_latest(b"first").unwrap(), None);
+        drop(partition);
+
+        // One built from another log is ignored
+        let mut stale = written.clone();
+        stale[..8].fill(0);
+        fs::write(&path, stale).unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        assert!(partition.find_latest(b"first").unwrap().is_some());
+        fs::write(&path, &written[..12]).unwrap();
+        let size = u64::from_be_bytes(written[..8].try_into().unwrap()) as usize;
+        assert!(KeyFilter::read(&path, size).unwrap().is_none());
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+//! Bounded in-memory buffer in front of a partition
+//!
+//! Appends to a `BufferedPartition` are queued in memory and return right away, a
+//! background thread drains the queue every `linger`, or as soon as half of the
+//! buffer is used, appending the whole burst under a single lock of the partition
+//! followed by a single flush. Records are timestamped when buffered, offsets are
+//! assigned when drained.
+//!
+//! With a `CompressionPolicy` set, the values of each drained burst are compressed
+//! when the policy deems the burst worth it, but for the ones not shrinking enough
+//! on their own.
+//!
+//! The buffer holds at most `capacity` bytes of keys and values. When it's full
+//! the `Backpressure` policy decides whether the append parks until the drain
+//! catches up or fails with `ErrorKind::WouldBlock`.
+//!
+//! A record failing to be appended stays buffered along with the ones after it,
+//! the next append reports the error and the drain is retried after `linger`.
+use crate::partition::compression::CompressionPolicy;
+use crate::partition::record::{now_millis, Compression};
+use crate::partition::Partition;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backpressure {
+    /// Park the appending thread until there's room in the buffer
+    Block,
+    /// Fail the append with `ErrorKind::WouldBlock`
+    Fail,
+}
+
+struct Buffered {
+    timestamp: u64,
+    key: Option<Vec<u8>>,
+    value: Vec<u8>,
+}
+
+impl Buffered {
+    fn size(&self) -> usize {
+        self.key.as_ref().map_or(0, Vec::len) + self.value.len()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Buffered>,
+    bytes: usize,
+    stopped: bool,
+    error: Option<Error>,
+    compression: Option<CompressionPolicy>,
+}
+
+struct Shared {
+    partition: Mutex<Partition>,
+    state: Mutex<State>,
+    // Signaled to the drain thread when records pile up
+    filled: Condvar,
+    // Signaled to the parked appenders once the buffer is drained
+    drained: Condvar,
+}
+
+pub struct BufferedPartition {
+    shared: Arc<Shared>,
+    capacity: usize,
+    backpressure: Backpressure,
+    handle: Option<JoinHandle<()>>,
+}
+
+fn poisoned<T>(_: T) -> Error {
+    Error::other("Append buffer lock poisoned")
+}
+
+impl BufferedPartition {
+    pub fn spawn(
+        partition: Partition,
+        capacity: usize,
+        linger: Duration,
+        backpressure: Backpressure,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            partition: Mutex::new(partition),
+            state: Mutex::new(State::default()),
+            filled: Condvar::new(),
+            drained: Condvar::new(),
+        });
+        let background = Arc::clone(&shared);
+        let handle = thread::spawn(move || run(&background, capacity, linger));
+        Self {
+            shared,
+            capacity,
+            backpressure,
+            handle: Some(handle),
+        }
+    }
+
+    /// Queue a record, an error hit draining the previous ones is reported here
+    pub fn append_record(&self, key: Option<Vec<u8>>, value: &[u8]) -> Result<()> {
+        let record = Buffered {
+            timestamp: now_millis(),
+            key,
+            value: value.to_vec(),
+        };
+        let size = record.size();
+        if size > self.capacity {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Record of {} bytes exceeds the buffer capacity of {}",
+                    size, self.capacity
+                ),
+            ));
+        }
+        let mut state = self.shared.state.lock().map_err(poisoned)?;
+        loop {
+            if let Some(e) = state.error.take() {
+                return Err(e);
+            }
+            if state.bytes + size <= self.capacity {
+                break;
+            }
+            self.shared.filled.notify_one();
+            match self.backpressure {
+                Backpressure::Block => {
+                    state = self.shared.drained.wait(state).map_err(poisoned)?;
+                }
+                Backpressure::Fail => {
+                    return Err(Error::new(ErrorKind::WouldBlock, "Append buffer full"));
+                }
+            }
+        }
+        state.bytes += size;
+        state.queue.push_back(record);
+        if state.bytes >= self.capacity / 2 {
+            self.shared.filled.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Compress the values of the bursts deemed worth it by `policy`
+    pub fn set_compression(&self, policy: CompressionPolicy) -> Result<()> {
+        self.shared.state.lock().map_err(poisoned)?.compression = Some(policy);
+        Ok(())
+    }
+
+    /// The bytes of keys and values waiting to be appended to the partition
+    pub fn buffered_bytes(&self) -> usize {
+        self.shared
+            .state
+            .lock()
+            .map(|s| s.bytes)
+            .unwrap_or_default()
+    }
+
+    /// Lock the partition, the records still buffered are not visible through it
+    pub fn partition(&self) -> Result<MutexGuard<'_, Partition>> {
+        self.shared.partition.lock().map_err(poisoned)
+    }
+
+    /// Drain the buffer, stop the background thread and return the partition
+    pub fn stop(mut self) -> Result<Partition> {
+        self.shutdown()?;
+        let shared = Arc::clone(&self.shared);
+        drop(self);
+        let shared = Arc::try_unwrap(shared).map_err(|_| Error::other("Buffer still shared"))?;
+        shared.partition.into_inner().map_err(poisoned)
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        self.shared.state.lock().map_err(poisoned)?.stopped = true;
+        self.shared.filled.notify_one();
+        if let Some(handle) = self.handle.take() {
+            handle
+                .join()
+                .map_err(|_| Error::other("Append buffer thread panicked"))?;
+        }
+        match self.shared.state.lock().map_err(poisoned)?.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for BufferedPartition {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+fn run(shared: &Shared, capacity: usize, linger: Duration) {
+    let mut state = match shared.state.lock() {
+        Ok(state) => state,
+        Err(_) => return,
+    };
+    loop {
+        if !state.stopped && (state.bytes < capacity / 2 || state.error.is_some()) {
+            state = match shared.filled.wait_timeout(state, linger) {
+                Ok((state, _)) => state,
+                Err(_) => return,
+            };
+        }
+        let stopped = state.stopped;
+        let batch = std::mem::take(&mut state.queue);
+        let compression = state.compression;
+        state.bytes = 0;
+        drop(state);
+        let drained = drain(shared, batch, compression);
+        state = match shared.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if let Err((e, undrained)) = drained {
+            state.bytes += undrained.iter().map(Buffered::size).sum::<usize>();
+            for record in undrained.into_iter().rev() {
+                state.queue.push_front(record);
+            }
+            state.error = Some(e);
+        }
+        shared.drained.notify_all();
+        if stopped {
+            return;
+        }
+    }
+}
+
+/// Append `batch` to the partition, failing with the records not appended
+fn drain(
+    shared: &Shared,
+    mut batch: VecDeque<Buffered>,
+    compression: Option<CompressionPolicy>,
+) -> std::result::Result<(), (Error, VecDeque<Buffered>)> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let policy = compression.unwrap_or(CompressionPolicy::new(Compression::None));
+    let values: Vec<&[u8]> = batch.iter().map(|r| &r.value[..]).collect();
+    let codec = policy.choose(&values);
+    let mut partition = match shared.partition.lock() {
+        Ok(partition) => partition,
+        Err(e) => return Err((poisoned(e), batch)),
+    };
+    while let Some(record) = batch.pop_front() {
+        let appended = policy
+            .compress(codec, &record.value)
+            .and_then(|(codec, value)| {
+                partition.append_record_compressed(
+                    record.timestamp,
+                    record.key.clone(),
+                    &value,
+                    codec,
+                )
+            });
+        if let Err(e) = appended {
+            batch.push_front(record);
+            return Err((e, batch));
+        }
+    }
+    partition.flush().map_err(|e| (e, batch))
+}
+
+#[cfg(test)]
+mod buffer_tests {
+    use super::{Backpressure, BufferedPartition};
+    use crate::partition::compression::CompressionPolicy;
+    use crate::partition::record::Compression;
+    use crate::partition::Partition;
+    use std::io::ErrorKind;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_drain_on_stop() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let buffer = BufferedPartition::spawn(
+            partition,
+            1024,
+            Duration::from_secs(3600),
+            Backpressure::Block,
+        );
+        for i in 0..10u8 {
+            buffer.append_record(None, &[i]).unwrap();
+        }
+        assert_eq!(buffer.buffered_bytes(), 10);
+        assert_eq!(buffer.partition().unwrap().end_offset(), 0);
+
+        let partition = buffer.stop().unwrap();
+        let records = partition.read_range(0, 10).unwrap();
+        assert_eq!(records.len(), 10);
+        assert_eq!(records[9].value, &[9]);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let buffer = BufferedPartition::spawn(
+            partition,
+            64,
+            Duration::from_secs(3600),
+            Backpressure::Block,
+        );
+        // Every append past the capacity parks until the drain makes room
+        for _ in 0..100 {
+            buffer.append_record(None, &[0; 16]).unwrap();
+        }
+        assert_eq!(
+            buffer.append_record(None, &[0; 65]).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(buffer.stop().unwrap().end_offset(), 100);
+
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let buffer =
+            BufferedPartition::spawn(partition, 64, Duration::from_secs(3600), Backpressure::Fail);
+        let results: Vec<_> = (0..100)
+            .map(|_| buffer.append_record(None, &[0; 16]))
+            .collect();
+        assert!(results
+            .iter()
+            .any(|r| r.as_ref().is_err_and(|e| e.kind() == ErrorKind::WouldBlock)));
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_failed_drain() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.set_max_record_size(64);
+        let buffer = BufferedPartition::spawn(
+            partition,
+            200,
+            Duration::from_secs(3600),
+            Backpressure::Block,
+        );
+        buffer.append_record(None, &[1]).unwrap();
+        buffer.append_record(None, &[2; 100]).unwrap();
+        // The record too big for the partition stays buffered
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while buffer.buffered_bytes() != 100 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(buffer.partition().unwrap().end_offset(), 1);
+        let err = buffer.append_record(None, &[3]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(buffer.buffered_bytes(), 100);
+
+        buffer.partition().unwrap().set_max_record_size(1024);
+        buffer.append_record(None, &[3]).unwrap();
+        let partition = buffer.stop().unwrap();
+        let records = partition.read_range(0, 3).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            (records[1].value.len(), &records[2].value[..]),
+            (100, &[3][..])
+        );
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_compression() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let buffer = BufferedPartition::spawn(
+            partition,
+            64 * 1024,
+            Duration::from_secs(3600),
+            Backpressure::Block,
+        );
+        buffer
+            .set_compression(CompressionPolicy::new(Compression::Gzip))
+            .unwrap();
+        let value = b"{\"event\":\"created\",\"user\":\"someone\"}".repeat(20);
+        for _ in 0..4 {
+            buffer.append_record(None, &value).unwrap();
+        }
+        // Small values of a compressed burst are stored as is
+        buffer.append_record(None, b"small").unwrap();
+        let partition = buffer.stop().unwrap();
+
+        let stored = partition.segments[0].records().unwrap();
+        assert_eq!(stored.len(), 5);
+        assert!(stored[..4]
+            .iter()
+            .all(|r| r.attributes.compression() == Compression::Gzip && r.value.len() < 100));
+        assert_eq!(stored[4].attributes.compression(), Compression::None);
+        assert_eq!(stored[4].value, b"small");
+        let records = partition.read_range(0, 5).unwrap();
+        assert!(records[..4].iter().all(|r| r.value == value));
+        assert_eq!(records[4].value, b"small");
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,509 @@
+//! Key based compaction of a partition
+//!
+//! Compacting keeps only the latest record of every key stored in the sealed
+//! segments, or the latest few with `set_compaction_versions` for partitions
+//! keeping a bounded history of every key, the active one is left untouched.
+//! Records keep their offsets, the compacted segments simply skip those of the
+//! records removed. Records without a key and control records are always kept.
+//!
+//! Segments holding records appended less than `min_compaction_lag` ago are not
+//! compacted, nor any following them, consumers reading within that window see
+//! every update of a key and not only the latest one.
+//!
+//! The offset up to which the partition has been compacted is checkpointed next to
+//! its segments, the sealed bytes past it are dirty. `Partition::clean` compacts
+//! only once the dirty bytes exceed `min_cleanable_dirty_ratio` of the sealed ones,
+//! so that barely changed partitions aren't rewritten over and over.
+//!
+//! Background compactions take the sealed segments with `next_compaction`, write
+//! the compacted ones without holding the partition and swap them in with
+//! `apply_compaction`, as merges do.
+use crate::partition::events::PartitionEvent;
+use crate::partition::record::Record;
+use crate::partition::segment::Segment;
+use crate::partition::{stage_records, Partition, BACKGROUND_COMPACTION_DIR, MERGE_DIR};
+use crate::scheduler::Throttle;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) const CHECKPOINT_FILE: &str = "cleaner.checkpoint";
+/// Default ratio of dirty to sealed bytes triggering a compaction
+pub const DEFAULT_MIN_CLEANABLE_DIRTY_RATIO: f64 = 0.5;
+
+/// Read the offset up to which the partition in `dir` was compacted, 0 if it
+/// never was
+pub(crate) fn read_checkpoint(dir: &Path) -> Result<u64> {
+    match fs::read_to_string(dir.join(CHECKPOINT_FILE)) {
+        Ok(content) => content.trim().parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid cleaner checkpoint {:?}", content),
+            )
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_checkpoint(dir: &Path, clean_offset: u64) -> Result<()> {
+    let tmp = dir.join(CHECKPOINT_FILE).with_extension("tmp");
+    fs::write(&tmp, clean_offset.to_string())?;
+    fs::rename(tmp, dir.join(CHECKPOINT_FILE))
+}
+
+impl Partition {
+    /// Compact only once the dirty bytes exceed `ratio` of the sealed ones
+    pub fn set_min_cleanable_dirty_ratio(&mut self, ratio: f64) {
+        self.min_cleanable_dirty_ratio = ratio;
+    }
+
+    /// Never compact records more recent than `lag`
+    pub fn set_min_compaction_lag(&mut self, lag: Duration) {
+        self.min_compaction_lag = lag;
+    }
+
+    /// Keep the latest `versions` records of every key when compacting, at least one
+    pub fn set_compaction_versions(&mut self, versions: usize) {
+        self.compaction_versions = versions.max(1);
+    }
+
+    /// Every record of the sealed segments before this offset has been compacted
+    pub fn clean_offset(&self) -> u64 {
+        self.clean_offset
+    }
+
+    /// The ratio of the bytes of the sealed segments not compacted yet to the bytes
+    /// of all the sealed segments
+    pub fn dirty_ratio(&self) -> f64 {
+        let sealed = &self.segments[..self.active_segment_index];
+        let total: usize = sealed.iter().map(|s| s.size()).sum();
+        if total == 0 {
+            return 0.0;
+        }
+        let dirty: usize = sealed
+            .iter()
+            .filter(|s| s.latest_offset() > self.clean_offset)
+            .map(|s| s.size())
+            .sum();
+        dirty as f64 / total as f64
+    }
+
+    /// Compact the partition if its dirty ratio exceeds the minimum cleanable one,
+    /// returning the number of records removed or `None` if it was left as is
+    pub fn clean(&mut self, throttle: Option<&Throttle>) -> Result<Option<usize>> {
+        if self.dirty_ratio() <= self.min_cleanable_dirty_ratio {
+            return Ok(None);
+        }
+        self.compact(throttle).map(Some)
+    }
+
+    /// Keep only the latest records of every key in the sealed segments older than
+    /// the minimum compaction lag, returning the number of records removed. The
+    /// compacted segments are written at the pace of `throttle`.
+    pub fn compact(&mut self, throttle: Option<&Throttle>) -> Result<usize> {
+        let run = self.compaction_run(self.dir.join(MERGE_DIR));
+        let staged = run.write(throttle)?;
+        Ok(self.apply_compaction(staged)?.unwrap_or_default())
+    }
+
+    /// The sealed segments to compact if the dirty ratio exceeds the minimum
+    /// cleanable one, `None` otherwise.
+    ///
+    /// Meant for background compactions, the run is written with
+    /// `CompactionRun::write` without holding the partition and swapped in by
+    /// `apply_compaction`, appends proceed in the meantime.
+    pub fn next_compaction(&self) -> Option<CompactionRun> {
+        if self.dirty_ratio() <= self.min_cleanable_dirty_ratio {
+            return None;
+        }
+        Some(self.compaction_run(self.dir.join(BACKGROUND_COMPACTION_DIR)))
+    }
+
+    fn compaction_run(&self, staging: PathBuf) -> CompactionRun {
+        let lag = self.min_compaction_lag.as_millis() as u64;
+        CompactionRun {
+            staging,
+            segments: self.segments[..self.active_segment_index].to_vec(),
+            sealed_end: self.segments[self.active_segment_index].base_offset,
+            newest_compactable: (lag > 0).then(|| self.clock.now_millis().saturating_sub(lag)),
+            versions: self.compaction_versions,
+            sequential: self.page_cache_hints.sequential_scans,
+        }
+    }
+
+    /// Swap the segments compacted by a run in place of the original ones,
+    /// returning the number of records removed. Returns `None` and discards the
+    /// run if the segments changed since `next_compaction`, e.g. by a merge.
+    pub fn apply_compaction(&mut self, staged: StagedCompaction) -> Result<Option<usize>> {
+        let unchanged = staged.segments.len() <= self.active_segment_index
+            && staged
+                .segments
+                .iter()
+                .zip(&self.segments)
+                .all(|(a, b)| Arc::ptr_eq(a, b));
+        let StagedCompaction {
+            staging,
+            segments,
+            compacted,
+            removed,
+            clean_offset,
+        } = staged;
+        if !unchanged {
+            fs::remove_dir_all(&staging)?;
+            return Ok(None);
+        }
+        // The run no longer references the segments, removed ones are deleted
+        drop(segments);
+        // Last first, the indexes of the ones before stay valid
+        for (i, compacted) in compacted.into_iter().enumerate().rev() {
+            match compacted {
+                Compacted::Unchanged => {}
+                Compacted::Removed => self.remove_segment(i)?,
+                Compacted::Rewritten(base_offset) => {
+                    self.swap_staged(&staging.join(base_offset.to_string()), i, i + 1)?
+                }
+            }
+        }
+        fs::remove_dir_all(&staging)?;
+        self.clean_offset = clean_offset;
+        write_checkpoint(&self.dir, self.clean_offset)?;
+        self.events.publish(PartitionEvent::CompactionFinished {
+            removed,
+            clean_offset: self.clean_offset,
+        });
+        Ok(Some(removed))
+    }
+
+    /// The latest value of every key, keys deleted by a tombstone left out. The
+    /// segments are read whole, newest first, and the records of a key superseded
+    /// by a later one are skipped without being decompressed, a table is rebuilt
+    /// this way much faster than by fetching and applying every record.
+    pub fn latest_values(&self) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+        let mut seen = HashSet::new();
+        let mut latest = HashMap::new();
+        for segment in self.segments.iter().rev() {
+            self.advise_sequential(segment)?;
+            for record in segment.records()?.into_iter().rev() {
+                let Some(key) = compaction_key(&record) else {
+                    continue;
+                };
+                if !seen.insert(key.to_vec()) || record.attributes.tombstone() {
+                    continue;
+                }
+                let record = record.decompressed()?;
+                latest.insert(record.key.unwrap_or_default(), record.value);
+            }
+        }
+        Ok(latest)
+    }
+}
+
+/// Sealed segments of a partition to compact, see `Partition::next_compaction`
+pub struct CompactionRun {
+    staging: PathBuf,
+    segments: Vec<Arc<Segment>>,
+    /// Base offset of the segment active when the run was taken
+    sealed_end: u64,
+    /// Segments holding a record more recent than this are not compacted
+    newest_compactable: Option<u64>,
+    versions: usize,
+    /// Hint the segments are read whole, see `PageCacheHints`
+    sequential: bool,
+}
+
+/// What a compaction does to a segment
+enum Compacted {
+    Unchanged,
+    Removed,
+    /// Rewritten in a staging subdirectory named after its base offset
+    Rewritten(u64),
+}
+
+impl CompactionRun {
+    /// Write the compacted segments in a staging directory, the I/O paced by
+    /// `throttle`
+    pub fn write(self, throttle: Option<&Throttle>) -> Result<StagedCompaction> {
+        // Offsets of the latest records of every key, oldest first
+        let mut latest: HashMap<Vec<u8>, VecDeque<u64>> = HashMap::new();
+        let mut end = 0;
+        while end < self.segments.len() {
+            if self.sequential {
+                self.segments[end].advise_sequential()?;
+            }
+            let records = self.segments[end].records()?;
+            if let Some(newest) = self.newest_compactable {
+                if records.iter().any(|r| r.timestamp > newest) {
+                    break;
+                }
+            }
+            for record in &records {
+                if let Some(key) = compaction_key(record) {
+                    let offsets = latest.entry(key.to_vec()).or_default();
+                    if offsets.len() == self.versions {
+                        offsets.pop_front();
+                    }
+                    offsets.push_back(record.offset);
+                }
+            }
+            end += 1;
+        }
+        // Leftovers of a failed attempt
+        if self.staging.exists() {
+            fs::remove_dir_all(&self.staging)?;
+        }
+        fs::create_dir_all(&self.staging)?;
+        let mut removed = 0;
+        let mut compacted = Vec::with_capacity(end);
+        for segment in &self.segments[..end] {
+            let records = segment.records()?;
+            let count = records.len();
+            let retained: Vec<Record> = records
+                .into_iter()
+                .filter(|r| compaction_key(r).is_none_or(|k| latest[k].contains(&r.offset)))
+                .collect();
+            removed += count - retained.len();
+            compacted.push(if retained.is_empty() {
+                Compacted::Removed
+            } else if retained.len() < count {
+                let base_offset = segment.base_offset;
+                let staging = self.staging.join(base_offset.to_string());
+                stage_records(&staging, base_offset, &retained, false, throttle)?;
+                Compacted::Rewritten(base_offset)
+            } else {
+                Compacted::Unchanged
+            });
+        }
+        let clean_offset = self
+            .segments
+            .get(end)
+            .map_or(self.sealed_end, |s| s.base_offset);
+        Ok(StagedCompaction {
+            staging: self.staging,
+            segments: self.segments,
+            compacted,
+            removed,
+            clean_offset,
+        })
+    }
+}
+
+/// Compacted segments written in the staging directory, see
+/// `Partition::apply_compaction`
+pub struct StagedCompaction {
+    staging: PathBuf,
+    segments: Vec<Arc<Segment>>,
+    /// What happens to each of the first segments, the following ones are left as is
+    compacted: Vec<Compacted>,
+    removed: usize,
+    clean_offset: u64,
+}
+
+/// The key records are compacted by, control records and records without a key
+/// are never removed
+fn compaction_key(record: &Record) -> Option<&[u8]> {
+    if record.attributes.control() {
+        return None;
+    }
+    record.key.as_deref()
+}
+
+#[cfg(test)]
+mod compaction_tests {
+    use crate::partition::record::now_millis;
+    use crate::partition::Partition;
+    use crate::scheduler::Throttle;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    fn fill(partition: &mut Partition, keys: u64, n: u64) {
+        for i in 0..n {
+            let key = (i % keys).to_string();
+            partition
+                .append_record(Some(key.into()), &i.to_be_bytes())
+                .unwrap();
+        }
+        partition.flush().unwrap();
+    }
+
+    #[test]
+    fn test_compaction_versions() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.set_compaction_versions(3);
+        fill(&mut partition, 4, 400);
+        let sealed_end = partition.segments[partition.active_segment_index].base_offset;
+
+        partition.compact(None).unwrap();
+        let compacted = partition.read_range(0, sealed_end).unwrap();
+        assert_eq!(compacted.len(), 12);
+        let values: Vec<_> = compacted
+            .iter()
+            .map(|r| u64::from_be_bytes(r.value[..].try_into().unwrap()))
+            .collect();
+        let expected: Vec<_> = (sealed_end - 12..sealed_end).collect();
+        assert_eq!(values, expected);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_latest_values() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        fill(&mut partition, 7, 500);
+        partition.append_tombstone(b"3".to_vec()).unwrap();
+        partition.append_record(None, b"no key").unwrap();
+        partition.compact(None).unwrap();
+
+        let latest = partition.latest_values().unwrap();
+        assert_eq!(latest.len(), 6);
+        for i in 493..500u64 {
+            let value = latest.get((i % 7).to_string().as_bytes());
+            match i % 7 {
+                3 => assert_eq!(value, None),
+                _ => assert_eq!(value, Some(&i.to_be_bytes().to_vec())),
+            }
+        }
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_compact() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        fill(&mut partition, 4, 400);
+        partition.append_record(None, b"no key").unwrap();
+        let end_offset = partition.end_offset();
+        let sealed_end = partition.segments[partition.active_segment_index].base_offset;
+
+        let removed = partition.compact(None).unwrap();
+
+        assert!(removed > 0);
+        assert_eq!(partition.clean_offset(), sealed_end);
+        assert_eq!(partition.end_offset(), end_offset);
+        let compacted = partition.read_range(0, sealed_end).unwrap();
+        assert_eq!(compacted.len(), 4);
+        assert!(compacted.windows(2).all(|w| w[0].offset < w[1].offset));
+        let last = compacted.last().unwrap();
+        assert_eq!(
+            partition.find_record(last.offset).unwrap().value,
+            last.value
+        );
+        assert!(partition.find_record(0).is_err());
+
+        // Offsets keep growing after a reopen, skipping the removed ones
+        drop(partition);
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.clean_offset(), sealed_end);
+        assert_eq!(partition.end_offset(), end_offset);
+        partition.append_record(Some("0".into()), b"new").unwrap();
+        assert_eq!(partition.find_record(end_offset).unwrap().value, b"new");
+        assert_eq!(partition.read_range(0, sealed_end).unwrap().len(), 4);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_dirty_ratio() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.dirty_ratio(), 0.0);
+        assert_eq!(partition.clean(None).unwrap(), None);
+
+        fill(&mut partition, 1, 400);
+        assert_eq!(partition.dirty_ratio(), 1.0);
+        assert!(partition.clean(None).unwrap().unwrap() > 0);
+        assert_eq!(partition.dirty_ratio(), 0.0);
+
+        // A few more records, not worth another compaction yet
+        fill(&mut partition, 1, 100);
+        let ratio = partition.dirty_ratio();
+        assert!(ratio > 0.0);
+        partition.set_min_cleanable_dirty_ratio(ratio);
+        assert_eq!(partition.clean(None).unwrap(), None);
+        partition.set_min_cleanable_dirty_ratio(ratio / 2.0);
+        assert!(partition.clean(None).unwrap().is_some());
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_min_compaction_lag() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.set_min_compaction_lag(Duration::from_secs(3600));
+        let old = now_millis() - 2 * 3600 * 1000;
+        for i in 0..200u64 {
+            partition
+                .append_record_at(old, Some("key".into()), &i.to_be_bytes())
+                .unwrap();
+        }
+        let recent = partition.end_offset();
+        fill(&mut partition, 1, 200);
+        let sealed_end = partition.segments[partition.active_segment_index].base_offset;
+
+        partition.compact(None).unwrap();
+
+        // Old records were compacted, up to the segment holding the first recent one
+        let records = partition.read_range(0, sealed_end).unwrap();
+        let kept = records.iter().filter(|r| r.offset < recent).count();
+        assert!((1..200).contains(&kept));
+        assert_eq!((records.len() - kept) as u64, sealed_end - recent);
+        assert!(partition.clean_offset() <= recent);
+
+        partition.set_min_compaction_lag(Duration::ZERO);
+        partition.compact(None).unwrap();
+        assert_eq!(partition.read_range(0, sealed_end).unwrap().len(), 2);
+        assert_eq!(partition.clean_offset(), sealed_end);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_compact_throttled() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        // Every sealed segment has a superseded record of the key and is rewritten
+        while partition.segments.len() < 4 {
+            partition.append_record(Some("key".into()), b"old").unwrap();
+            for _ in 0..10 {
+                partition.append_record(None, &[7; 100]).unwrap();
+            }
+        }
+        partition.append_record(Some("key".into()), b"new").unwrap();
+        let sealed = &partition.segments[..partition.active_segment_index];
+        let sealed_size: usize = sealed.iter().map(|s| s.size()).sum();
+
+        // A burst of a second worth of bytes, the rest is paced
+        let throttle = Throttle::new(sealed_size as u64 * 2 / 3);
+        let start = Instant::now();
+        assert!(partition.compact(Some(&throttle)).unwrap() > 0);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_background_compaction() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        assert!(partition.next_compaction().is_none());
+        fill(&mut partition, 4, 400);
+        let sealed_end = partition.segments[partition.active_segment_index].base_offset;
+
+        let staged = partition.next_compaction().unwrap().write(None).unwrap();
+        // Appends proceed while the run is written
+        partition.append_record(Some("0".into()), b"new").unwrap();
+        assert!(partition.apply_compaction(staged).unwrap().unwrap() > 0);
+        assert_eq!(partition.clean_offset(), sealed_end);
+        assert_eq!(partition.read_range(0, sealed_end).unwrap().len(), 4);
+        assert_eq!(partition.find_record(400).unwrap().value, b"new");
+
+        // Segments compacted in the meantime discard the run
+        fill(&mut partition, 4, 400);
+        let staged = partition.next_compaction().unwrap().write(None).unwrap();
+        partition.compact(None).unwrap();
+        assert_eq!(partition.apply_compaction(staged).unwrap(), None);
+        assert!(!tmp_dir.path().join(".compaction.background").exists());
+        tmp_dir.close().unwrap();
+    }
+}
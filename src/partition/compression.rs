@@ -0,0 +1,164 @@
+//! Compression of record values
+//!
+//! Values are compressed one by one with the codec recorded in the record
+//! attributes, the reads through a `Partition` return them decompressed. Whether a
+//! batch of values is worth compressing is decided by a `CompressionPolicy`, small
+//! batches or poorly compressing ones are stored as is. So is every value of a
+//! compressed batch not shrinking enough on its own, e.g. too small to offset the
+//! overhead of the codec.
+use crate::partition::record::{Compression, Record};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+/// Bytes of a batch compressed to estimate the ratio of the whole batch
+const SAMPLE_SIZE: usize = 4096;
+
+impl Compression {
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            codec => Err(unsupported(codec)),
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut decoded = Vec::new();
+                GzDecoder::new(data).read_to_end(&mut decoded)?;
+                Ok(decoded)
+            }
+            codec => Err(unsupported(codec)),
+        }
+    }
+}
+
+impl Record {
+    /// The record with its value decompressed
+    pub fn decompressed(mut self) -> Result<Record> {
+        let compression = self.attributes.compression();
+        if compression != Compression::None {
+            self.value = compression.decompress(&self.value)?;
+            self.attributes = self.attributes.with_compression(Compression::None);
+        }
+        Ok(self)
+    }
+}
+
+fn unsupported(codec: &Compression) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("Unsupported compression codec {:?}", codec),
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompressionPolicy {
+    pub codec: Compression,
+    /// Batches smaller than this, in bytes, are never compressed
+    pub threshold: usize,
+    /// Minimum estimated ratio of the uncompressed to the compressed size
+    pub min_ratio: f64,
+}
+
+impl CompressionPolicy {
+    pub fn new(codec: Compression) -> Self {
+        Self {
+            codec,
+            threshold: 1024,
+            min_ratio: 1.25,
+        }
+    }
+
+    /// The codec to compress the values of a batch with, `Compression::None` if the
+    /// batch is below the threshold or its sample doesn't compress enough
+    pub fn choose(&self, values: &[&[u8]]) -> Compression {
+        let size: usize = values.iter().map(|v| v.len()).sum();
+        if self.codec == Compression::None || size < self.threshold {
+            return Compression::None;
+        }
+        let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+        for value in values {
+            let room = SAMPLE_SIZE - sample.len();
+            sample.extend_from_slice(&value[..value.len().min(room)]);
+            if sample.len() == SAMPLE_SIZE {
+                break;
+            }
+        }
+        match self.codec.compress(&sample) {
+            Ok(compressed) if sample.len() as f64 / compressed.len() as f64 >= self.min_ratio => {
+                self.codec
+            }
+            _ => Compression::None,
+        }
+    }
+
+    /// `value` compressed with `codec` if it shrinks by at least the minimum ratio,
+    /// as is otherwise, along with the codec it's stored with
+    pub fn compress(&self, codec: Compression, value: &[u8]) -> Result<(Compression, Vec<u8>)> {
+        if codec == Compression::None || value.is_empty() {
+            return Ok((Compression::None, value.to_vec()));
+        }
+        let compressed = codec.compress(value)?;
+        if value.len() as f64 / compressed.len() as f64 >= self.min_ratio {
+            Ok((codec, compressed))
+        } else {
+            Ok((Compression::None, value.to_vec()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod compression_tests {
+    use super::CompressionPolicy;
+    use crate::partition::record::Compression;
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u32 = 7;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = b"some value some value some value".repeat(10);
+        let compressed = Compression::Gzip.compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(Compression::Gzip.decompress(&compressed).unwrap(), data);
+        assert!(Compression::Zstd.compress(&data).is_err());
+    }
+
+    #[test]
+    fn test_choose() {
+        let policy = CompressionPolicy::new(Compression::Gzip);
+        let repetitive = b"{\"event\":\"created\"}".repeat(10);
+        assert_eq!(policy.choose(&[&repetitive]), Compression::None);
+        assert_eq!(policy.choose(&[&repetitive[..]; 10]), Compression::Gzip);
+        assert_eq!(policy.choose(&[&noise(2048)]), Compression::None);
+    }
+
+    #[test]
+    fn test_compress_value() {
+        let policy = CompressionPolicy::new(Compression::Gzip);
+        let repetitive = b"{\"event\":\"created\"}".repeat(10);
+        let (codec, compressed) = policy.compress(Compression::Gzip, &repetitive).unwrap();
+        assert_eq!(codec, Compression::Gzip);
+        assert!(compressed.len() < repetitive.len());
+        // Too small to shrink through the gzip header and trailer
+        let (codec, value) = policy.compress(Compression::Gzip, b"small").unwrap();
+        assert_eq!((codec, &value[..]), (Compression::None, &b"small"[..]));
+        let (codec, _) = policy.compress(Compression::None, &repetitive).unwrap();
+        assert_eq!(codec, Compression::None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Named cursors over a partition
+//!
+//! Several independent consumers embedded in the process owning a partition each
+//! read it through a `Cursor` of their own name, its position checkpointed in the
+//! `checkpoints` directory of the partition. A checkpoint holds the position along
+//! with an opaque state of the consumer, the result of its processing so far, and
+//! both are replaced at once: the records processed by `Cursor::poll` are
+//! committed with the state they produced or not at all.
+//!
+//! A checkpoint file is the position as a big endian u64 followed by the state,
+//! synced before it replaces the previous one so a crash leaves either whole.
+use crate::partition::record::Record;
+use crate::partition::Partition;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+pub(crate) const CHECKPOINTS_DIR: &str = "checkpoints";
+
+pub struct Cursor {
+    name: String,
+    dir: PathBuf,
+    position: u64,
+    state: Vec<u8>,
+}
+
+impl Cursor {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The offset of the next record polled
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The state committed along with the position
+    pub fn state(&self) -> &[u8] {
+        &self.state
+    }
+
+    /// Read the records following the position, taking at most `max_bytes`, and
+    /// hand them to `process` along with a copy of the state to update. Once it
+    /// succeeds the position past the records and the updated state are committed
+    /// together, a failure leaves both untouched. Returns the number of records
+    /// processed.
+    pub fn poll(
+        &mut self,
+        partition: &Partition,
+        max_bytes: usize,
+        process: impl FnOnce(&[Record], &mut Vec<u8>) -> Result<()>,
+    ) -> Result<usize> {
+        let records = partition.fetch(self.position, max_bytes)?;
+        let Some(last) = records.last() else {
+            return Ok(0);
+        };
+        let mut state = self.state.clone();
+        process(&records, &mut state)?;
+        self.commit(last.offset + 1, state)?;
+        Ok(records.len())
+    }
+
+    /// Move the cursor to `offset`, keeping its state
+    pub fn seek(&mut self, offset: u64) -> Result<()> {
+        self.commit(offset, self.state.clone())
+    }
+
+    fn commit(&mut self, position: u64, state: Vec<u8>) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(&self.name);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, [&position.to_be_bytes(), &state[..]].concat())?;
+        File::open(&tmp)?.sync_data()?;
+        fs::rename(tmp, path)?;
+        File::open(&self.dir)?.sync_all()?;
+        self.position = position;
+        self.state = state;
+        Ok(())
+    }
+}
+
+impl Partition {
+    /// The cursor `name`, from its checkpoint or the start of the partition
+    pub fn cursor(&self, name: &str) -> Result<Cursor> {
+        let valid = name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if name.is_empty() || !valid {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid cursor name {:?}", name),
+            ));
+        }
+        let dir = self.dir.join(CHECKPOINTS_DIR);
+        let (position, state) = match fs::read(dir.join(name)) {
+            Ok(content) => match content.split_first_chunk() {
+                Some((position, state)) => (u64::from_be_bytes(*position), state.to_vec()),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Truncated checkpoint of cursor {}", name),
+                    ))
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => (self.start_offset(), Vec::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(Cursor {
+            name: name.into(),
+            dir,
+            position,
+            state,
+        })
+    }
+
+    /// The names of the cursors with a checkpoint
+    pub fn cursors(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(self.dir.join(CHECKPOINTS_DIR)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            match name.to_str() {
+                Some(name) if !name.ends_with(".tmp") => names.push(name.to_string()),
+                _ => {}
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod cursor_tests {
+    use crate::partition::record::Record;
+    use crate::partition::Partition;
+    use std::io::{Error, ErrorKind, Result};
+    use tempdir::TempDir;
+
+    /// A running sum of the last byte of the values, committed with the position
+    fn sum(records: &[Record], state: &mut Vec<u8>) -> Result<()> {
+        let total = u64::from_be_bytes(state[..].try_into().unwrap_or_default());
+        let added: u64 = records.iter().map(|r| r.value[7] as u64).sum();
+        *state = (total + added).to_be_bytes().to_vec();
+        Ok(())
+    }
+
+    #[test]
+    fn test_cursors() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        for i in 0..10u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        let mut summer = partition.cursor("summer").unwrap();
+        let mut counter = partition.cursor("counter").unwrap();
+        assert_eq!(summer.poll(&partition, 100, sum).unwrap(), 2);
+        let failed = counter.poll(&partition, 1000, |_, _| Err(Error::other("down")));
+        assert!(failed.is_err());
+        assert_eq!(counter.position(), 0);
+        assert_eq!(counter.poll(&partition, 1000, |_, _| Ok(())).unwrap(), 10);
+
+        drop(partition);
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        assert!(partition.unknown_files().is_empty());
+        assert_eq!(partition.cursors().unwrap(), ["counter", "summer"]);
+        let mut summer = partition.cursor("summer").unwrap();
+        assert_eq!(summer.position(), 2);
+        while summer.poll(&partition, 100, sum).unwrap() > 0 {}
+        assert_eq!(summer.state(), 45u64.to_be_bytes());
+        assert_eq!(partition.cursor("counter").unwrap().position(), 10);
+        let err = partition.cursor("../escape").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        tmp_dir.close().unwrap();
+    }
+}
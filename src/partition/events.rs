@@ -0,0 +1,94 @@
+//! Lifecycle events of a partition
+//!
+//! Changes to the segments of a partition are published to every receiver handed
+//! out by `Partition::subscribe`, so that metrics, tiering or external observers
+//! learn about them as they happen instead of polling the partition directory.
+//! Events are sent in the thread changing the partition, a receiver dropped is
+//! unsubscribed on the next event.
+use crate::partition::Partition;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartitionEvent {
+    /// The active segment was sealed and a new one starting at `base_offset` rolled
+    SegmentRolled { base_offset: u64 },
+    /// A sealed segment was deleted, by retention or because compaction emptied it
+    SegmentDeleted { base_offset: u64 },
+    /// A compaction removed `removed` records, see `Partition::compact`
+    CompactionFinished { removed: usize, clean_offset: u64 },
+    /// The first segment of the partition was deleted
+    StartOffsetAdvanced { start_offset: u64 },
+}
+
+#[derive(Default)]
+pub(crate) struct EventBus {
+    subscribers: Vec<Sender<PartitionEvent>>,
+}
+
+impl EventBus {
+    pub(crate) fn publish(&mut self, event: PartitionEvent) {
+        self.subscribers.retain(|s| s.send(event).is_ok());
+    }
+}
+
+impl Partition {
+    /// Receive the events of the partition from now on
+    pub fn subscribe(&mut self) -> Receiver<PartitionEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.events.subscribers.push(sender);
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod events_tests {
+    use super::PartitionEvent;
+    use crate::partition::record::now_millis;
+    use crate::partition::Partition;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_subscribe() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        let events = partition.subscribe();
+        let dropped = partition.subscribe();
+        drop(dropped);
+        let old = now_millis() - 2 * 3600 * 1000;
+        for i in 0..500u64 {
+            partition
+                .append_record_at(old, Some("key".into()), &i.to_be_bytes())
+                .unwrap();
+        }
+        let rolled: Vec<u64> = events
+            .try_iter()
+            .map(|e| match e {
+                PartitionEvent::SegmentRolled { base_offset } => base_offset,
+                e => panic!("Unexpected event {:?}", e),
+            })
+            .collect();
+        assert!(!rolled.is_empty());
+
+        // Every sealed segment expired, each one advancing the start offset
+        partition.delete_expired(Duration::from_secs(3600)).unwrap();
+        let deleted: Vec<_> = events.try_iter().collect();
+        assert_eq!(deleted.len(), 2 * rolled.len());
+        assert_eq!(
+            deleted[..2],
+            [
+                PartitionEvent::SegmentDeleted { base_offset: 0 },
+                PartitionEvent::StartOffsetAdvanced {
+                    start_offset: rolled[0]
+                },
+            ]
+        );
+        assert_eq!(partition.start_offset(), *rolled.last().unwrap());
+        partition.compact(None).unwrap();
+        assert!(matches!(
+            events.try_recv(),
+            Ok(PartitionEvent::CompactionFinished { removed: 0, .. })
+        ));
+        tmp_dir.close().unwrap();
+    }
+}
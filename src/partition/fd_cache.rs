@@ -0,0 +1,116 @@
+//! Cache of the descriptors of segment files
+//!
+//! Every segment holds its log and index open, for syncs not to reopen them. That's
+//! two descriptors per segment, partitions with many of them can share an `FdCache`
+//! instead, which keeps at most `capacity` files open and closes the least recently
+//! used one past it. Evicted files are opened again on their next sync.
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub struct FdCache {
+    capacity: usize,
+    /// The open files, the least recently used first
+    files: Mutex<VecDeque<(PathBuf, Arc<File>)>>,
+}
+
+fn poisoned<T>(_: T) -> Error {
+    Error::other("FD cache poisoned")
+}
+
+impl FdCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            files: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// The file at `path`, opened unless already cached
+    pub fn get(&self, path: &Path) -> Result<Arc<File>> {
+        let mut files = self.files.lock().map_err(poisoned)?;
+        let file = match files.iter().position(|(p, _)| p == path) {
+            Some(i) => files.remove(i).map(|(_, file)| file).unwrap(),
+            None => Arc::new(File::open(path)?),
+        };
+        if files.len() == self.capacity {
+            files.pop_front();
+        }
+        files.push_back((path.to_path_buf(), file.clone()));
+        Ok(file)
+    }
+
+    /// Close the file at `path` if cached, e.g. once removed or replaced
+    pub fn remove(&self, path: &Path) {
+        if let Ok(mut files) = self.files.lock() {
+            files.retain(|(p, _)| p != path);
+        }
+    }
+
+    /// The number of files open
+    pub fn len(&self) -> usize {
+        self.files
+            .lock()
+            .map(|files| files.len())
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod fd_cache_tests {
+    use super::FdCache;
+    use crate::partition::Partition;
+    use std::fs;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_lru_eviction() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let paths: Vec<_> = (0..3).map(|i| tmp_dir.path().join(i.to_string())).collect();
+        for path in &paths {
+            fs::write(path, b"").unwrap();
+        }
+        let cache = FdCache::new(2);
+        let first = cache.get(&paths[0]).unwrap();
+        let second = cache.get(&paths[1]).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.get(&paths[0]).unwrap()));
+        cache.get(&paths[2]).unwrap();
+        assert_eq!(cache.len(), 2);
+        // The second file was the least recently used
+        assert!(Arc::ptr_eq(&first, &cache.get(&paths[0]).unwrap()));
+        assert!(!Arc::ptr_eq(&second, &cache.get(&paths[1]).unwrap()));
+        cache.remove(&paths[1]);
+        assert_eq!(cache.len(), 1);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_partition_fd_cache() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        let cache = Arc::new(FdCache::new(3));
+        partition.set_fd_cache(cache.clone()).unwrap();
+        let value = [7; 1000];
+        while partition.segments().len() < 4 {
+            partition.append_record(None, &value).unwrap();
+        }
+        assert_eq!(partition.log_files(0).unwrap().len(), 4);
+        assert_eq!(cache.len(), 3);
+        let end_offset = partition.end_offset();
+        // Closing the partition closes its cached files
+        partition.close().unwrap();
+        assert!(cache.is_empty());
+
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.end_offset(), end_offset);
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+//! Byte bounded reads
+//!
+//! A fetch reads the records from an offset onward until a budget of bytes, as
+//! encoded in the log, is spent. Records are never split, a fetch stops before the
+//! record that would exceed the budget, except for the first one: a record bigger
+//! than the budget is returned alone, a consumer would never get past it otherwise.
+use crate::partition::latency::{Operation, SlowCause};
+use crate::partition::record::Record;
+use crate::partition::Partition;
+use std::io::Result;
+use std::time::Instant;
+
+impl Partition {
+    /// Read the records from `from` onward taking at most `max_bytes`, always at
+    /// least one if there's any
+    pub fn fetch(&self, from: u64, max_bytes: usize) -> Result<Vec<Record>> {
+        let started = Instant::now();
+        let mut fetched: Vec<Record> = Vec::new();
+        let mut bytes = 0;
+        let mut segments = 0;
+        'segments: for segment in &self.segments {
+            if segment.latest_offset() <= from {
+                continue;
+            }
+            segments += 1;
+            for record in segment.records()? {
+                if record.offset < from || record.attributes.control() {
+                    continue;
+                }
+                bytes += record.binary_size();
+                if bytes > max_bytes && !fetched.is_empty() {
+                    break 'segments;
+                }
+                fetched.push(record.decompressed()?);
+            }
+        }
+        let end = fetched.last().map_or(from, |r| r.offset + 1);
+        let cause = SlowCause::SegmentScan { segments };
+        self.record_latency(Operation::Fetch, started, from..end, Some(cause));
+        Ok(fetched)
+    }
+}
+
+#[cfg(test)]
+mod fetch_tests {
+    use crate::partition::record::Record;
+    use crate::partition::Partition;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_fetch() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        for i in 0..500u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        let record_size = Record::new(0, None, vec![0; 8]).binary_size();
+
+        let records = partition.fetch(100, 10 * record_size + 1).unwrap();
+        assert_eq!(records.len(), 10);
+        assert_eq!(records[0].offset, 100);
+        // Across segments, up to the end
+        assert_eq!(partition.fetch(100, usize::MAX).unwrap().len(), 400);
+        // A budget smaller than a record still makes progress
+        assert_eq!(partition.fetch(499, 1).unwrap().len(), 1);
+        assert!(partition.fetch(500, 1).unwrap().is_empty());
+        tmp_dir.close().unwrap();
+    }
+}
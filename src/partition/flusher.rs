@@ -0,0 +1,290 @@
+//! Background flushing of a shared partition
+//!
+//! Appends through the `Flusher` only write to the memory mapped segments, a
+//! background thread flushes the partition every `interval`, or as soon as the
+//! bytes appended since the latest flush exceed `max_dirty_bytes`. Durability is
+//! bounded by either of the two instead of paying a flush on every append.
+//!
+//! The partition is only locked to find the files to flush, they're synced
+//! afterwards so appends don't wait for the disk, and they're only counted as
+//! flushed once synced. Writes go through shared mappings, syncing the files
+//! flushes their dirty pages. A flush requested while syncing is kept pending, the
+//! thread starts over right away instead of waiting for the next interval.
+//!
+//! An error hit by the background thread is reported by the next append.
+use crate::partition::{AppendInfo, Partition};
+use std::fs::File;
+use std::io::{Error, Result};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[derive(Default)]
+struct State {
+    stopped: bool,
+    /// A flush was requested since the thread last woke up
+    pending: bool,
+    error: Option<Error>,
+}
+
+/// Files written since the latest flush, along with the dirty bytes and the end
+/// offset they cover once synced
+struct DirtyFiles {
+    files: Vec<Arc<File>>,
+    bytes: usize,
+    end_offset: u64,
+}
+
+struct Shared {
+    partition: Mutex<Partition>,
+    state: Mutex<State>,
+    wakeup: Condvar,
+}
+
+pub struct Flusher {
+    shared: Arc<Shared>,
+    max_dirty_bytes: usize,
+    handle: Option<JoinHandle<()>>,
+}
+
+fn poisoned<T>(_: T) -> Error {
+    Error::other("Flusher lock poisoned")
+}
+
+impl Flusher {
+    pub fn spawn(partition: Partition, interval: Duration, max_dirty_bytes: usize) -> Self {
+        let shared = Arc::new(Shared {
+            partition: Mutex::new(partition),
+            state: Mutex::new(State::default()),
+            wakeup: Condvar::new(),
+        });
+        let background = Arc::clone(&shared);
+        let handle = thread::spawn(move || run(&background, interval));
+        Self {
+            shared,
+            max_dirty_bytes,
+            handle: Some(handle),
+        }
+    }
+
+    /// Append a record without flushing it, the background thread is woken up
+    /// once the dirty bytes exceed the threshold
+    pub fn append_record(&self, key: Option<Vec<u8>>, value: &[u8]) -> Result<AppendInfo> {
+        if let Some(e) = self.shared.state.lock().map_err(poisoned)?.error.take() {
+            return Err(e);
+        }
+        let mut partition = self.partition()?;
+        let appended = partition.append_record(key, value)?;
+        let dirty = partition.dirty_bytes() > self.max_dirty_bytes;
+        drop(partition);
+        if dirty {
+            self.shared.state.lock().map_err(poisoned)?.pending = true;
+            self.shared.wakeup.notify_one();
+        }
+        Ok(appended)
+    }
+
+    /// Lock the partition, to read from it or flush it synchronously
+    pub fn partition(&self) -> Result<MutexGuard<'_, Partition>> {
+        self.shared.partition.lock().map_err(poisoned)
+    }
+
+    /// Stop the background thread, flushing the pending appends, and return the
+    /// partition
+    pub fn stop(mut self) -> Result<Partition> {
+        self.shutdown()?;
+        let shared = Arc::clone(&self.shared);
+        drop(self);
+        let shared = Arc::try_unwrap(shared).map_err(|_| Error::other("Flusher still shared"))?;
+        shared.partition.into_inner().map_err(poisoned)
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        self.shared.state.lock().map_err(poisoned)?.stopped = true;
+        self.shared.wakeup.notify_one();
+        if let Some(handle) = self.handle.take() {
+            handle
+                .join()
+                .map_err(|_| Error::other("Flusher thread panicked"))?;
+        }
+        match self.shared.state.lock().map_err(poisoned)?.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+        if let Ok(mut partition) = self.partition() {
+            let _ = partition.flush();
+        }
+    }
+}
+
+fn run(shared: &Shared, interval: Duration) {
+    loop {
+        {
+            let Ok(mut state) = shared.state.lock() else {
+                return;
+            };
+            if state.stopped {
+                return;
+            }
+            // Released before flushing, appends take it to check for errors
+            if !state.pending {
+                state = match shared.wakeup.wait_timeout(state, interval) {
+                    Ok((state, _)) => state,
+                    Err(_) => return,
+                };
+            }
+            state.pending = false;
+        }
+        let dirty = match shared.partition.lock() {
+            Ok(mut partition) if partition.dirty_bytes() > 0 => partition.dirty_files().map(Some),
+            Ok(_) => Ok(None),
+            Err(e) => Err(poisoned(e)),
+        };
+        let synced = dirty.and_then(|dirty| match dirty {
+            Some(dirty) => {
+                sync(&dirty.files)?;
+                shared.partition.lock().map_err(poisoned)?.synced(&dirty);
+                Ok(())
+            }
+            None => Ok(()),
+        });
+        if let Err(e) = synced {
+            match shared.state.lock() {
+                Ok(mut state) => state.error = Some(e),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Sync `files`, still open even if removed since, e.g. by retention
+fn sync(files: &[Arc<File>]) -> Result<()> {
+    for file in files {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+impl Partition {
+    /// The files of every segment written since the latest flush, to be synced
+    /// without holding the partition
+    fn dirty_files(&mut self) -> Result<DirtyFiles> {
+        self.active_segment().prepare_sync()?;
+        let from = self.flushed_offset;
+        let mut files = Vec::new();
+        for segment in &self.segments {
+            if segment.latest_offset() > from || segment.base_offset >= from {
+                let (log, index) = segment.files()?;
+                files.push(log);
+                files.push(index);
+            }
+        }
+        Ok(DirtyFiles {
+            files,
+            bytes: self.dirty_bytes,
+            end_offset: self.end_offset(),
+        })
+    }
+
+    /// Count the files taken by `dirty_files` as flushed once synced, the appends
+    /// made meanwhile stay dirty
+    fn synced(&mut self, dirty: &DirtyFiles) {
+        self.dirty_bytes = self.dirty_bytes.saturating_sub(dirty.bytes);
+        self.flushed_offset = self.flushed_offset.max(dirty.end_offset);
+    }
+}
+
+#[cfg(test)]
+mod flusher_tests {
+    use super::Flusher;
+    use crate::partition::Partition;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    fn wait_flushed(flusher: &Flusher) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if flusher.partition().unwrap().dirty_bytes() == 0 {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    #[test]
+    fn test_flush_on_dirty_bytes() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let flusher = Flusher::spawn(partition, Duration::from_secs(3600), 64);
+
+        flusher.append_record(None, b"small").unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(flusher.partition().unwrap().dirty_bytes() > 0);
+
+        flusher.append_record(None, &[0; 64]).unwrap();
+        assert!(wait_flushed(&flusher));
+
+        let partition = flusher.stop().unwrap();
+        assert_eq!(partition.end_offset(), 2);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_flush_requested_while_syncing() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let flusher = Flusher::spawn(partition, Duration::from_secs(3600), 64);
+
+        // Whenever the wakeups land, none of them is lost for the full interval
+        for _ in 0..20 {
+            flusher.append_record(None, &[0; 128]).unwrap();
+            assert!(wait_flushed(&flusher));
+        }
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_flush_on_interval() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let flusher = Flusher::spawn(partition, Duration::from_millis(10), usize::MAX);
+
+        flusher.append_record(Some("key".into()), b"value").unwrap();
+        assert!(wait_flushed(&flusher));
+        drop(flusher);
+
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.find_record(0).unwrap().value, b"value");
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_dirty_files_of_rolled_segments() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.append_record(None, b"flushed").unwrap();
+        partition.flush().unwrap();
+        let value = [7; 1000];
+        while partition.segments().len() < 3 {
+            partition.append_record(None, &value).unwrap();
+        }
+        // The log and index of every segment written since the flush
+        let dirty = partition.dirty_files().unwrap();
+        assert_eq!(dirty.files.len(), 6);
+        // Until synced they're taken again, appends made meanwhile stay dirty
+        assert_eq!(partition.dirty_files().unwrap().files.len(), 6);
+        partition.append_record(None, b"meanwhile").unwrap();
+        partition.synced(&dirty);
+        assert!(partition.dirty_bytes() > 0);
+        assert_eq!(partition.dirty_files().unwrap().files.len(), 2);
+        tmp_dir.close().unwrap();
+    }
+}
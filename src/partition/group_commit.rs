@@ -0,0 +1,135 @@
+//! Synchronous appends with coalesced syncs
+//!
+//! Every `GroupCommit::append_record` returns only once its record is durable on
+//! disk. Instead of syncing once per append, the first caller finding no sync in
+//! flight becomes the leader and syncs the log files on behalf of every record
+//! appended so far, the others wait for a sync covering their offset. Appends are
+//! not blocked while a sync is running, they're covered by the next one.
+use crate::partition::Partition;
+use std::io::{Error, Result};
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+struct SyncState {
+    /// Every record with a lower offset is durable
+    durable_offset: u64,
+    syncing: bool,
+    syncs: usize,
+}
+
+pub struct GroupCommit {
+    partition: Mutex<Partition>,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+fn poisoned<T>(_: T) -> Error {
+    Error::other("Group commit lock poisoned")
+}
+
+impl GroupCommit {
+    /// Wrap `partition`, the records already in it are synced along with the first
+    /// append
+    pub fn new(partition: Partition) -> Self {
+        let durable_offset = partition.start_offset();
+        Self {
+            partition: Mutex::new(partition),
+            state: Mutex::new(SyncState {
+                durable_offset,
+                syncing: false,
+                syncs: 0,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// Append a record and wait until it's durable, returning its offset
+    pub fn append_record(&self, key: Option<Vec<u8>>, value: &[u8]) -> Result<u64> {
+        let offset = self.partition()?.append_record(key, value)?.offset;
+        let mut state = self.state.lock().map_err(poisoned)?;
+        while state.durable_offset <= offset {
+            if state.syncing {
+                state = self.synced.wait(state).map_err(poisoned)?;
+                continue;
+            }
+            state.syncing = true;
+            let from = state.durable_offset;
+            drop(state);
+            let synced = self.sync(from);
+            state = self.state.lock().map_err(poisoned)?;
+            state.syncing = false;
+            self.synced.notify_all();
+            let end_offset = synced?;
+            state.durable_offset = state.durable_offset.max(end_offset);
+            state.syncs += 1;
+        }
+        Ok(offset)
+    }
+
+    /// The number of syncs performed so far
+    pub fn syncs(&self) -> usize {
+        self.state.lock().map(|s| s.syncs).unwrap_or_default()
+    }
+
+    /// Lock the partition, to read from it
+    pub fn partition(&self) -> Result<MutexGuard<'_, Partition>> {
+        self.partition.lock().map_err(poisoned)
+    }
+
+    pub fn into_inner(self) -> Result<Partition> {
+        self.partition.into_inner().map_err(poisoned)
+    }
+
+    /// Sync the log files holding the records from `from` onward, returning the
+    /// offset every record before which is now durable.
+    ///
+    /// The records are written through a shared mapping, syncing the file flushes
+    /// its dirty pages without holding the partition lock.
+    fn sync(&self, from: u64) -> Result<u64> {
+        let (end_offset, files) = {
+            let partition = self.partition()?;
+            (partition.end_offset(), partition.log_files(from)?)
+        };
+        for file in files {
+            file.sync_data()?;
+        }
+        Ok(end_offset)
+    }
+}
+
+#[cfg(test)]
+mod group_commit_tests {
+    use super::GroupCommit;
+    use crate::partition::Partition;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_concurrent_appends() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let commit = Arc::new(GroupCommit::new(partition));
+
+        let handles: Vec<_> = (0..8u8)
+            .map(|t| {
+                let commit = Arc::clone(&commit);
+                thread::spawn(move || {
+                    (0..50)
+                        .map(|_| commit.append_record(None, &[t]).unwrap())
+                        .collect::<Vec<u64>>()
+                })
+            })
+            .collect();
+        let offsets: HashSet<u64> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect();
+
+        assert_eq!(offsets, (0..400).collect());
+        assert!(commit.syncs() > 0 && commit.syncs() <= 400);
+        let partition = Arc::into_inner(commit).unwrap().into_inner().unwrap();
+        assert_eq!(partition.end_offset(), 400);
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+//! Fixed size header written at the start of every log and index file
+//!
+//! The header identifies the kind of data stored in the file, the version of its
+//! format, the base offset of the segment it belongs to and when it was created,
+//! so a misnamed or foreign file is rejected on open instead of being silently
+//! interpreted as segment data.
+//!
+//! ```text
+//! | magic (4 bytes) | version (u8) | reserved (3 bytes) | base offset (u64) | created at (u64) |
+//! ```
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+pub const HEADER_SIZE: usize = 24;
+pub const FILE_FORMAT_VERSION: u8 = 1;
+pub const LOG_MAGIC: [u8; 4] = *b"SHJL";
+pub const INDEX_MAGIC: [u8; 4] = *b"SHJI";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileHeader {
+    pub magic: [u8; 4],
+    pub version: u8,
+    pub base_offset: u64,
+    pub created_at: u64,
+}
+
+impl FileHeader {
+    pub fn new(magic: [u8; 4], base_offset: u64) -> Self {
+        Self {
+            magic,
+            version: FILE_FORMAT_VERSION,
+            base_offset,
+            created_at: std::time::UNIX_EPOCH.elapsed().unwrap().as_millis() as u64,
+        }
+    }
+
+    pub fn write(&self, buf: &mut impl Write) -> Result<()> {
+        buf.write_all(&self.magic)?;
+        buf.write_u8(self.version)?;
+        buf.write_all(&[0; 3])?;
+        buf.write_u64::<NetworkEndian>(self.base_offset)?;
+        buf.write_u64::<NetworkEndian>(self.created_at)
+    }
+
+    pub fn from_binary(buf: &mut impl Read) -> Result<Self> {
+        let mut magic = [0; 4];
+        buf.read_exact(&mut magic)?;
+        let version = buf.read_u8()?;
+        buf.read_exact(&mut [0; 3])?;
+        let base_offset = buf.read_u64::<NetworkEndian>()?;
+        let created_at = buf.read_u64::<NetworkEndian>()?;
+        Ok(Self {
+            magic,
+            version,
+            base_offset,
+            created_at,
+        })
+    }
+
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Decode the header in `buf` checking that it belongs to a file of the
+    /// expected kind and segment
+    pub fn validate(buf: &[u8], magic: [u8; 4], base_offset: u64) -> Result<Self> {
+        Self::validate_versions(buf, magic, base_offset, &[FILE_FORMAT_VERSION])
+    }
+
+    /// Like `validate`, for kinds of files with several supported format versions
+    pub fn validate_versions(
+        mut buf: &[u8],
+        magic: [u8; 4],
+        base_offset: u64,
+        versions: &[u8],
+    ) -> Result<Self> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+        let header =
+            Self::from_binary(&mut buf).map_err(|_| invalid("Missing file header".into()))?;
+        if header.magic != magic {
+            return Err(invalid(format!("Unexpected file magic {:?}", header.magic)));
+        }
+        if !versions.contains(&header.version) {
+            return Err(invalid(format!(
+                "Unsupported file format version {}",
+                header.version
+            )));
+        }
+        if header.base_offset != base_offset {
+            return Err(invalid(format!(
+                "Header base offset {} doesn't match the file name {}",
+                header.base_offset, base_offset
+            )));
+        }
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod header_tests {
+    use super::{FileHeader, HEADER_SIZE, INDEX_MAGIC, LOG_MAGIC};
+
+    #[test]
+    fn test_write() {
+        let header = FileHeader::new(LOG_MAGIC, 48);
+        let mut buffer = vec![];
+        header.write(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), HEADER_SIZE);
+        assert_eq!(&buffer[..5], b"SHJL\x01");
+        assert_eq!(FileHeader::from_binary(&mut &buffer[..]).unwrap(), header);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut buffer = vec![];
+        FileHeader::new(LOG_MAGIC, 48).write(&mut buffer).unwrap();
+
+        assert!(FileHeader::validate(&buffer, LOG_MAGIC, 48).is_ok());
+        assert!(FileHeader::validate(&buffer, INDEX_MAGIC, 48).is_err());
+        assert!(FileHeader::validate(&buffer, LOG_MAGIC, 0).is_err());
+        assert!(FileHeader::validate(&buffer[..10], LOG_MAGIC, 48).is_err());
+        buffer[4] = 9;
+        assert!(FileHeader::validate(&buffer, LOG_MAGIC, 48).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Page cache hints on the storage files
+//!
+//! Segments are read through their mappings, the kernel guesses how to cache them
+//! from the faults alone. A partition tells it what it knows instead: index lookups
+//! are binary searches and jump around the file, compactions, merges and bulk reads
+//! scan the logs from start to end, and the segments copied elsewhere, by a mirror
+//! for instance, won't be read again anytime soon.
+//!
+//! Each hint can be turned off, a partition living on a filesystem already caching
+//! on its own terms, like tmpfs, has nothing to gain from them.
+use crate::partition::segment::Segment;
+use crate::partition::Partition;
+use std::io::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PageCacheHints {
+    /// Map the indexes for random access, no read-ahead around the faults
+    pub random_index: bool,
+    /// Read the logs ahead aggressively when scanning them whole
+    pub sequential_scans: bool,
+    /// Drop the pages of the sealed segments once copied elsewhere
+    pub drop_after_copy: bool,
+}
+
+impl PageCacheHints {
+    /// No hints at all, the kernel defaults apply
+    pub fn none() -> Self {
+        Self {
+            random_index: false,
+            sequential_scans: false,
+            drop_after_copy: false,
+        }
+    }
+}
+
+impl Default for PageCacheHints {
+    fn default() -> Self {
+        Self {
+            random_index: true,
+            sequential_scans: true,
+            drop_after_copy: true,
+        }
+    }
+}
+
+impl Partition {
+    pub fn set_page_cache_hints(&mut self, hints: PageCacheHints) -> Result<()> {
+        self.page_cache_hints = hints;
+        for segment in &self.segments {
+            segment.advise_index(hints.random_index)?;
+        }
+        Ok(())
+    }
+
+    pub fn page_cache_hints(&self) -> PageCacheHints {
+        self.page_cache_hints
+    }
+
+    /// Hint that the sealed segments holding the `[from, to)` range are about to be
+    /// read whole, meant for bulk reads like exports
+    pub fn advise_scan(&self, from: u64, to: u64) -> Result<()> {
+        for segment in &self.segments[..self.active_segment_index] {
+            if segment.latest_offset() > from && segment.base_offset < to {
+                self.advise_sequential(segment)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the pages of the sealed segments whose records, up to `to`, were copied
+    /// elsewhere, returning the number of segments released
+    pub fn release_copied(&self, to: u64) -> Result<usize> {
+        if !self.page_cache_hints.drop_after_copy {
+            return Ok(0);
+        }
+        let mut released = 0;
+        for segment in &self.segments[..self.active_segment_index] {
+            if segment.latest_offset() > to {
+                break;
+            }
+            segment.release_pages()?;
+            released += 1;
+        }
+        Ok(released)
+    }
+
+    pub(crate) fn advise_sequential(&self, segment: &Segment) -> Result<()> {
+        match self.page_cache_hints.sequential_scans {
+            true => segment.advise_sequential(),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod hints_tests {
+    use super::PageCacheHints;
+    use crate::partition::Partition;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_release_copied() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        for i in 0..500u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        let sealed_end = partition.segments[partition.active_segment_index].base_offset;
+        partition.advise_scan(0, 500).unwrap();
+        assert_eq!(partition.read_range(0, 500).unwrap().len(), 500);
+
+        assert_eq!(partition.release_copied(0).unwrap(), 0);
+        let released = partition.release_copied(sealed_end).unwrap();
+        assert_eq!(released, partition.active_segment_index);
+        assert!(partition.segments[..released]
+            .iter()
+            .all(|s| s.last_read().is_none()));
+        assert_eq!(partition.find_record(0).unwrap().offset, 0);
+
+        partition
+            .set_page_cache_hints(PageCacheHints::none())
+            .unwrap();
+        assert_eq!(partition.release_copied(sealed_end).unwrap(), 0);
+        assert_eq!(partition.read_range(0, 500).unwrap().len(), 500);
+        tmp_dir.close().unwrap();
+    }
+}
@@ -1,28 +1,65 @@
+use crate::partition::header::{FileHeader, HEADER_SIZE, INDEX_MAGIC};
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
-use memmap2::MmapMut;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Result, Write};
-use std::path::PathBuf;
-
-const ENTRY_SIZE: usize = 8;
+use memmap2::{Advice, MmapMut, UncheckedAdvice};
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// Index format with varint entries, each one encoded as the delta from the
+/// previous entry but for periodic restart points encoded in full
+pub const INDEX_VERSION: u8 = 3;
+/// Index format with fixed size entries holding 64 bit log positions
+const WIDE_POSITION_VERSION: u8 = 2;
+/// Index format with 32 bit log positions, capping segments at 4 GiB
+const NARROW_POSITION_VERSION: u8 = 1;
+const ENTRY_SIZE: usize = 12;
+const NARROW_ENTRY_SIZE: usize = 8;
+/// Largest encoding of a varint entry, a u32 and a u64
+const MAX_VARINT_ENTRY_SIZE: usize = 15;
+/// Every this many varint entries one is a restart point, lookups binary search
+/// the restart points and decode the entries following one
+const RESTART_INTERVAL: usize = 16;
+/// The last bytes of the file hold the number of entries, the size of the log they
+/// were flushed along with and their CRC, written on flush and verified on load
+const FOOTER_SIZE: usize = 16;
+/// Footer of the varint format, holding the size of the variable length entries too
+const VARINT_FOOTER_SIZE: usize = 20;
+/// Footer of the first format version, lacking the size of the log
+const NARROW_FOOTER_SIZE: usize = 8;
+
+/// When a new entry is added to the sparse index of a segment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexInterval {
+    /// Every given number of records
+    Records(usize),
+    /// Once the given number of bytes has been written since the latest entry
+    Bytes(usize),
+}
 
 #[derive(Debug)]
 pub struct Index {
-    file: File,
+    // The file is closed once mapped, the segment holds its own handle
     mmap: MmapMut,
     size: usize,
+    // Entries start right after the file header, indexes predating headers have none
+    header_size: usize,
+    version: u8,
     base_offset: u64,
-    offset_interval: usize,
+    log_size: usize,
+    entries: usize,
+    last: Option<Position>,
+    /// The restart points of a varint index and where they're stored
+    restarts: Vec<(Position, usize)>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Position {
     pub relative_offset: u32,
-    pub position: u32,
+    pub position: u64,
 }
 
 impl Position {
-    pub fn new(relative_offset: u32, position: u32) -> Self {
+    pub fn new(relative_offset: u32, position: u64) -> Self {
         Self {
             relative_offset,
             position,
@@ -31,17 +68,93 @@ impl Position {
 
     pub fn write(&self, buf: &mut impl Write) -> Result<()> {
         buf.write_u32::<NetworkEndian>(self.relative_offset)?;
-        buf.write_u32::<NetworkEndian>(self.position)
+        buf.write_u64::<NetworkEndian>(self.position)
     }
 
     pub fn from_binary(buf: &mut impl Read) -> Result<Self> {
         let relative_offset = buf.read_u32::<NetworkEndian>()?;
-        let position = buf.read_u32::<NetworkEndian>()?;
+        let position = buf.read_u64::<NetworkEndian>()?;
         Ok(Self {
             relative_offset,
             position,
         })
     }
+
+    /// Encode the entry with a 32 bit position, failing if it doesn't fit
+    pub fn write_narrow(&self, buf: &mut impl Write) -> Result<()> {
+        let position = u32::try_from(self.position).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Position {} overflows the index format", self.position),
+            )
+        })?;
+        buf.write_u32::<NetworkEndian>(self.relative_offset)?;
+        buf.write_u32::<NetworkEndian>(position)
+    }
+
+    /// Decode an entry of the format with 32 bit positions
+    pub fn from_binary_narrow(buf: &mut impl Read) -> Result<Self> {
+        let relative_offset = buf.read_u32::<NetworkEndian>()?;
+        let position = buf.read_u32::<NetworkEndian>()?;
+        Ok(Self {
+            relative_offset,
+            position: position as u64,
+        })
+    }
+
+    /// Encode the entry as varints of the deltas from `previous`
+    pub fn write_varint(&self, previous: &Position, buf: &mut impl Write) -> Result<()> {
+        let (Some(offset), Some(position)) = (
+            self.relative_offset.checked_sub(previous.relative_offset),
+            self.position.checked_sub(previous.position),
+        ) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Index entry {:?} precedes {:?}", self, previous),
+            ));
+        };
+        write_varint(buf, offset as u64)?;
+        write_varint(buf, position)
+    }
+
+    /// Decode an entry encoded as varints of the deltas from `previous`
+    pub fn from_varint(previous: &Position, buf: &mut impl Read) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "Index entry overflows");
+        let offset = u32::try_from(read_varint(buf)?).map_err(|_| invalid())?;
+        let position = read_varint(buf)?;
+        Ok(Self {
+            relative_offset: previous
+                .relative_offset
+                .checked_add(offset)
+                .ok_or_else(invalid)?,
+            position: previous
+                .position
+                .checked_add(position)
+                .ok_or_else(invalid)?,
+        })
+    }
+}
+
+/// LEB128 encoding, 7 bits per byte with the highest one set on all bytes but
+/// the last
+fn write_varint(buf: &mut impl Write, mut value: u64) -> Result<()> {
+    while value >= 0x80 {
+        buf.write_u8(value as u8 | 0x80)?;
+        value >>= 7;
+    }
+    buf.write_u8(value as u8)
+}
+
+fn read_varint(buf: &mut impl Read) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = buf.read_u8()?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "Varint too long"))
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -57,101 +170,405 @@ impl OffsetRange {
 }
 
 impl Index {
-    pub fn new(
-        path: &PathBuf,
-        base_offset: u64,
-        offset_interval: usize,
-        max_size: usize,
-    ) -> Result<Self> {
+    pub fn path(dir: &Path, base_offset: u64) -> PathBuf {
+        dir.join(format!("{:020}.index", base_offset))
+    }
+
+    pub fn new(path: &PathBuf, base_offset: u64, max_size: usize) -> Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .append(true)
             .create(true)
-            .open(path.join(format!("{:020}.index", base_offset)))?;
+            .open(Self::path(path, base_offset))?;
 
-        file.set_len(max_size as u64)?;
-        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        // Room for the footer at least, even for the tiniest segment
+        let max_size = max_size.max(MAX_VARINT_ENTRY_SIZE + VARINT_FOOTER_SIZE);
+        file.set_len((HEADER_SIZE + max_size) as u64)?;
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        mmap.advise(Advice::Random)?;
+        FileHeader::new(INDEX_MAGIC, base_offset)
+            .with_version(INDEX_VERSION)
+            .write(&mut &mut mmap[..HEADER_SIZE])?;
 
         Ok(Self {
-            file,
             mmap,
             size: 0,
+            header_size: HEADER_SIZE,
+            version: INDEX_VERSION,
             base_offset,
-            offset_interval,
+            log_size: 0,
+            entries: 0,
+            last: None,
+            restarts: Vec::new(),
         })
     }
 
+    /// Load an existing index, `headerless` ones are the indexes of logs written before
+    /// the introduction of file headers and carry no footer either. Both them and the
+    /// indexes of the first format version hold 32 bit positions. Entries appended to
+    /// an index of an older format version keep that format.
+    ///
+    /// Fails with `ErrorKind::InvalidData` if the header or the footer don't match
+    /// the content, in which case the index should be rebuilt from its log.
     pub fn load_from_disk(
         path: &PathBuf,
         base_offset: u64,
         latest_offset: u64,
         offset_interval: usize,
         max_size: usize,
+        headerless: bool,
     ) -> Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .create(false)
             .append(true)
-            .open(path.join(format!("{:020}.index", base_offset)))?;
-        file.set_len(max_size as u64)?;
+            .open(Self::path(path, base_offset))?;
+        let (header_size, version) = if headerless {
+            (0, NARROW_POSITION_VERSION)
+        } else {
+            let mut header = Vec::with_capacity(HEADER_SIZE);
+            (&file).take(HEADER_SIZE as u64).read_to_end(&mut header)?;
+            let versions = [
+                NARROW_POSITION_VERSION,
+                WIDE_POSITION_VERSION,
+                INDEX_VERSION,
+            ];
+            let header =
+                FileHeader::validate_versions(&header, INDEX_MAGIC, base_offset, &versions)?;
+            (HEADER_SIZE, header.version)
+        };
+        let file_size = file.metadata()?.len() as usize;
+        let max_size = max_size.max(file_size - header_size);
+        file.set_len((header_size + max_size) as u64)?;
         let mmap = unsafe { MmapMut::map_mut(&file)? };
-        let size = ((latest_offset - base_offset) / offset_interval as u64) * ENTRY_SIZE as u64;
-
-        Ok(Self {
-            file,
+        mmap.advise(Advice::Random)?;
+        let mut index = Self {
             mmap,
-            size: size as usize,
+            size: 0,
+            header_size,
+            version,
             base_offset,
-            offset_interval,
-        })
+            log_size: 0,
+            entries: 0,
+            last: None,
+            restarts: Vec::new(),
+        };
+        if headerless {
+            index.entries = ((latest_offset - base_offset) / offset_interval as u64) as usize;
+            index.size = index.entries * NARROW_ENTRY_SIZE;
+        } else {
+            // The footer was written at the end of the file before it got extended
+            let footer_position = file_size.checked_sub(index.footer_size()).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidData, "Index too small for its footer")
+            })?;
+            (index.entries, index.size, index.log_size) = index.verify_footer(footer_position)?;
+        }
+        if index.version == INDEX_VERSION {
+            index.scan_restarts()?;
+        } else if index.entries > 0 {
+            index.last = Some(index.entry(index.entries - 1)?);
+        }
+        Ok(index)
+    }
+
+    fn footer_position(&self) -> usize {
+        self.mmap.len() - self.footer_size()
+    }
+
+    fn checksum(&self, size: usize) -> u32 {
+        crc32fast::hash(&self.mmap[self.header_size..self.header_size + size])
+    }
+
+    /// Check the footer at `position` against the entries it covers, returning their
+    /// number, their size in bytes and the size of the log they were flushed along
+    /// with, 0 if the footer doesn't record it
+    fn verify_footer(&self, position: usize) -> Result<(usize, usize, usize)> {
+        let mut footer = &self.mmap[position..position + self.footer_size()];
+        let entries = footer.read_u32::<NetworkEndian>()? as usize;
+        let size = match self.version {
+            INDEX_VERSION => footer.read_u32::<NetworkEndian>()? as usize,
+            _ => entries * self.entry_size(),
+        };
+        let log_size = match self.version {
+            NARROW_POSITION_VERSION => 0,
+            _ => footer.read_u64::<NetworkEndian>()? as usize,
+        };
+        let crc = footer.read_u32::<NetworkEndian>()?;
+        if self.header_size + size > position || self.checksum(size) != crc {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Index footer doesn't match its entries",
+            ));
+        }
+        Ok((entries, size, log_size))
+    }
+
+    fn write_footer(&mut self) -> Result<()> {
+        let crc = self.checksum(self.size);
+        let entries = self.entries as u32;
+        let size = self.size as u32;
+        let version = self.version;
+        let log_size = self.log_size as u64;
+        let begin = self.footer_position();
+        let mut footer = &mut self.mmap[begin..];
+        footer.write_u32::<NetworkEndian>(entries)?;
+        if version == INDEX_VERSION {
+            footer.write_u32::<NetworkEndian>(size)?;
+        }
+        if version != NARROW_POSITION_VERSION {
+            footer.write_u64::<NetworkEndian>(log_size)?;
+        }
+        footer.write_u32::<NetworkEndian>(crc)
+    }
+
+    /// Decode the varint entries, collecting the restart points and the latest entry
+    fn scan_restarts(&mut self) -> Result<()> {
+        let mut entries = &self.mmap[self.header_size..self.header_size + self.size];
+        let mut previous = Position::new(0, 0);
+        for i in 0..self.entries {
+            let at = self.size - entries.len();
+            let restart = i.is_multiple_of(RESTART_INTERVAL);
+            if restart {
+                previous = Position::new(0, 0);
+            }
+            let entry = Position::from_varint(&previous, &mut entries)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Truncated index entry"))?;
+            if restart {
+                self.restarts.push((entry, at));
+            }
+            previous = entry;
+        }
+        if !entries.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Index entries don't match their size",
+            ));
+        }
+        self.last = (self.entries > 0).then_some(previous);
+        Ok(())
     }
 
-    pub fn flush(&mut self) -> Result<()> {
+    /// Flush the entries, recording the size of the log they cover
+    pub fn flush(&mut self, log_size: usize) -> Result<()> {
+        self.record_log_size(log_size)?;
         self.mmap.flush_async()
     }
 
-    pub fn append_position(&mut self, offset: u32, log_size: u32) -> Result<()> {
+    /// Record the size of the log the entries cover in the footer, without flushing
+    pub fn record_log_size(&mut self, log_size: usize) -> Result<()> {
+        self.log_size = log_size;
+        if self.header_size > 0 {
+            self.write_footer()?;
+        }
+        Ok(())
+    }
+
+    /// The bytes of the entries
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Drop the pages of the mapping, see `Log::release_pages`
+    pub fn release_pages(&self) -> Result<()> {
+        unsafe { self.mmap.unchecked_advise(UncheckedAdvice::DontNeed) }
+    }
+
+    /// Lookups are binary searches, `random` ones skip the read-ahead around faults
+    pub fn advise(&self, random: bool) -> Result<()> {
+        self.mmap.advise(match random {
+            true => Advice::Random,
+            false => Advice::Normal,
+        })
+    }
+
+    /// The size of the log as of the latest flush, entries for the records appended
+    /// past it may be missing
+    pub fn log_size(&self) -> usize {
+        self.log_size
+    }
+
+    /// The latest entry, if any
+    pub fn last_position(&self) -> Option<Position> {
+        self.last
+    }
+
+    /// The absolute offset of the latest entry, if any
+    pub fn last_offset(&self) -> Option<u64> {
+        self.last_position()
+            .map(|p| self.base_offset + p.relative_offset as u64)
+    }
+
+    /// Whether one more entry fits before the footer, whatever its encoded size
+    pub fn can_fit_entry(&self) -> bool {
+        let entry_size = match self.version {
+            NARROW_POSITION_VERSION => NARROW_ENTRY_SIZE,
+            WIDE_POSITION_VERSION => ENTRY_SIZE,
+            _ => MAX_VARINT_ENTRY_SIZE,
+        };
+        self.header_size + self.size + entry_size <= self.footer_position()
+    }
+
+    pub fn append_position(&mut self, offset: u32, log_size: u64) -> Result<()> {
         let relative_offset = offset as u64 - self.base_offset;
         let new_row = Position::new(relative_offset as u32, log_size);
-        let mut buffer = Vec::with_capacity(ENTRY_SIZE);
-        new_row.write(&mut buffer)?;
-        (&mut self.mmap[self.size..self.size + ENTRY_SIZE]).write(&buffer)?;
-        self.size += ENTRY_SIZE;
+        let restart = self.entries.is_multiple_of(RESTART_INTERVAL);
+        let mut buffer = Vec::with_capacity(MAX_VARINT_ENTRY_SIZE);
+        match (self.version, self.last) {
+            (NARROW_POSITION_VERSION, _) => new_row.write_narrow(&mut buffer)?,
+            (WIDE_POSITION_VERSION, _) => new_row.write(&mut buffer)?,
+            (_, Some(last)) if !restart => new_row.write_varint(&last, &mut buffer)?,
+            _ => new_row.write_varint(&Position::new(0, 0), &mut buffer)?,
+        }
+        let begin = self.header_size + self.size;
+        if begin + buffer.len() > self.footer_position() {
+            return Err(Error::other("Index is full"));
+        }
+        (&mut self.mmap[begin..begin + buffer.len()]).write_all(&buffer)?;
+        if self.version == INDEX_VERSION && restart {
+            self.restarts.push((new_row, self.size));
+        }
+        self.size += buffer.len();
+        self.entries += 1;
+        self.last = Some(new_row);
         Ok(())
     }
 
+    /// The entries surrounding `offset`, the latest one not past it and the next.
+    ///
+    /// Entries are looked up by binary search, a compacted log has no record at some
+    /// of the offsets and its entries are not evenly spaced.
     pub fn find_offset(&self, offset: u32) -> Result<OffsetRange> {
         if self.size == 0 {
             return Ok(OffsetRange::new(Position::new(0, 0), Position::new(0, 0)));
         }
         let relative_offset = (offset as u64 - self.base_offset) as u32;
-        let starting_offset =
-            ((relative_offset as usize / self.offset_interval) * ENTRY_SIZE) as usize;
-        let starting_offset = if starting_offset == 0 {
-            starting_offset
-        } else {
-            starting_offset - ENTRY_SIZE
-        };
-        let end_offset = if self.size >= (starting_offset + (ENTRY_SIZE * 2)) {
-            starting_offset + (ENTRY_SIZE * 2)
-        } else {
-            self.size
-        };
+        if self.version == INDEX_VERSION {
+            return self.find_varint(relative_offset);
+        }
+        let entries = self.entries;
+        // Index of the first entry past the offset
+        let (mut low, mut high) = (0, entries);
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.entry(middle)?.relative_offset <= relative_offset {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        match low {
+            0 => Ok(OffsetRange::new(Position::new(0, 0), self.entry(0)?)),
+            n if n == entries => {
+                let last = self.entry(n - 1)?;
+                Ok(OffsetRange::new(last, last))
+            }
+            n => Ok(OffsetRange::new(self.entry(n - 1)?, self.entry(n)?)),
+        }
+    }
 
-        // let mmap = unsafe { MmapOptions::new().map(&self.file)? };
-        let positions: Vec<Position> = self.mmap[starting_offset..end_offset]
-            .chunks(ENTRY_SIZE)
-            .map(|mut c| Position::from_binary(&mut c).unwrap())
-            .collect();
+    /// The log position of the first entry past `position`, where decoding can
+    /// resume after a corrupt record
+    pub fn next_position(&self, position: usize) -> Result<Option<usize>> {
+        Ok(self
+            .positions()?
+            .into_iter()
+            .map(|p| p.position as usize)
+            .find(|p| *p > position))
+    }
 
-        if offset < positions[0].relative_offset {
-            Ok(OffsetRange::new(Position::new(0, 0), positions[0]))
-        } else {
-            if positions.len() > 1 {
-                Ok(OffsetRange::new(positions[0], positions[1]))
-            } else {
-                Ok(OffsetRange::new(positions[0], positions[0].clone()))
+    /// Check the entries point to increasing offsets at increasing positions within
+    /// a log of `log_size` bytes, the checksum in the footer doesn't cover the
+    /// indexes of headerless logs nor a corruption after the latest flush.
+    ///
+    /// Fails with `ErrorKind::InvalidData`, the index should be rebuilt from its log.
+    pub fn check_positions(&self, log_size: usize) -> Result<()> {
+        let positions = self.positions()?;
+        let increasing = positions
+            .windows(2)
+            .all(|w| w[0].relative_offset < w[1].relative_offset && w[0].position < w[1].position);
+        if !increasing
+            || positions
+                .last()
+                .is_some_and(|p| p.position as usize >= log_size)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Index entries out of order or past the end of the log",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Decode every entry
+    fn positions(&self) -> Result<Vec<Position>> {
+        let mut entries = &self.mmap[self.header_size..self.header_size + self.size];
+        let mut previous = Position::new(0, 0);
+        let mut positions = Vec::with_capacity(self.entries);
+        for i in 0..self.entries {
+            let entry = match self.version {
+                INDEX_VERSION => {
+                    if i.is_multiple_of(RESTART_INTERVAL) {
+                        previous = Position::new(0, 0);
+                    }
+                    Position::from_varint(&previous, &mut entries)?
+                }
+                _ => self.entry(i)?,
+            };
+            positions.push(entry);
+            previous = entry;
+        }
+        Ok(positions)
+    }
+
+    /// `find_offset` on varint entries, decoding the ones following the latest
+    /// restart point not past the offset
+    fn find_varint(&self, relative_offset: u32) -> Result<OffsetRange> {
+        let following = self
+            .restarts
+            .partition_point(|(p, _)| p.relative_offset <= relative_offset);
+        if following == 0 {
+            return Ok(OffsetRange::new(Position::new(0, 0), self.restarts[0].0));
+        }
+        let (mut begin, at) = self.restarts[following - 1];
+        let mut entries = &self.mmap[self.header_size + at..self.header_size + self.size];
+        Position::from_varint(&Position::new(0, 0), &mut entries)?;
+        for i in (following - 1) * RESTART_INTERVAL + 1..self.entries {
+            let previous = match i % RESTART_INTERVAL {
+                0 => Position::new(0, 0),
+                _ => begin,
+            };
+            let next = Position::from_varint(&previous, &mut entries)?;
+            if next.relative_offset > relative_offset {
+                return Ok(OffsetRange::new(begin, next));
             }
+            begin = next;
+        }
+        Ok(OffsetRange::new(begin, begin))
+    }
+
+    /// The `i`th entry of an index with fixed size entries
+    fn entry(&self, i: usize) -> Result<Position> {
+        let entry_size = self.entry_size();
+        let mut entry = &self.mmap[self.header_size + i * entry_size..];
+        if self.version == NARROW_POSITION_VERSION {
+            Position::from_binary_narrow(&mut entry)
+        } else {
+            Position::from_binary(&mut entry)
+        }
+    }
+
+    fn entry_size(&self) -> usize {
+        match self.version {
+            NARROW_POSITION_VERSION => NARROW_ENTRY_SIZE,
+            _ => ENTRY_SIZE,
+        }
+    }
+
+    fn footer_size(&self) -> usize {
+        match self.version {
+            NARROW_POSITION_VERSION => NARROW_FOOTER_SIZE,
+            WIDE_POSITION_VERSION => FOOTER_SIZE,
+            _ => VARINT_FOOTER_SIZE,
         }
     }
 }
@@ -187,7 +604,8 @@ mod position_tests {
 #[cfg(test)]
 mod index_tests {
 
-    use super::{Index, OffsetRange, Position, ENTRY_SIZE};
+    use super::{Index, OffsetRange, Position, RESTART_INTERVAL};
+    use crate::partition::header::{FileHeader, HEADER_SIZE, INDEX_MAGIC};
     use std::fs;
     use std::path::Path;
     use tempdir::TempDir;
@@ -197,11 +615,10 @@ mod index_tests {
         let tmp_dir = TempDir::new("test_tempdir").unwrap();
         let expected_file = tmp_dir.path().join("00000000000000000000.index");
 
-        let index = Index::new(&tmp_dir.path().to_path_buf(), 0, 10, 256).unwrap();
+        let index = Index::new(&tmp_dir.path().to_path_buf(), 0, 256).unwrap();
 
         assert!(expected_file.as_path().exists());
         assert_eq!(index.base_offset, 0);
-        assert_eq!(index.offset_interval, 10);
         assert_eq!(index.size, 0);
         tmp_dir.close().unwrap();
     }
@@ -210,21 +627,97 @@ mod index_tests {
     fn test_load_from_disk() {
         let tmp_dir = TempDir::new("test_tempdir").unwrap();
         let expected_file = tmp_dir.path().join("00000000000000000048.index");
-        fs::File::create(&expected_file).unwrap();
+        let mut index = Index::new(&tmp_dir.path().to_path_buf(), 48, 256).unwrap();
+        index.append_position(58, 100).unwrap();
+        index.append_position(68, 200).unwrap();
+        index.flush(0).unwrap();
+        drop(index);
 
-        let index = Index::load_from_disk(&tmp_dir.path().to_path_buf(), 48, 68, 10, 256).unwrap();
+        let index =
+            Index::load_from_disk(&tmp_dir.path().to_path_buf(), 48, 69, 10, 256, false).unwrap();
 
         assert!(expected_file.as_path().exists());
         assert_eq!(index.base_offset, 48);
-        assert_eq!(index.offset_interval, 10);
+        assert_eq!(index.size, 4);
+        assert_eq!(index.last_offset(), Some(68));
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_load_narrow_positions() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let path = tmp_dir.path().to_path_buf();
+        // Index of the first format version, with 32 bit positions
+        let mut content = vec![];
+        FileHeader::new(INDEX_MAGIC, 0).write(&mut content).unwrap();
+        let entries = [0, 0, 0, 10, 0, 0, 0, 100, 0, 0, 0, 20, 0, 0, 0, 200];
+        content.extend_from_slice(&entries);
+        content.resize(HEADER_SIZE + 248, 0);
+        content.extend_from_slice(&2u32.to_be_bytes());
+        content.extend_from_slice(&crc32fast::hash(&entries).to_be_bytes());
+        fs::write(Index::path(&path, 0), content).unwrap();
+
+        let mut index = Index::load_from_disk(&path, 0, 21, 10, 256, false).unwrap();
         assert_eq!(index.size, 16);
+        assert_eq!(
+            index.find_offset(15).unwrap(),
+            OffsetRange::new(Position::new(10, 100), Position::new(20, 200))
+        );
+        index.append_position(30, 300).unwrap();
+        assert!(index.append_position(40, u64::MAX).is_err());
+        index.flush(0).unwrap();
+        drop(index);
+
+        let index = Index::load_from_disk(&path, 0, 31, 10, 256, false).unwrap();
+        assert_eq!(index.size, 24);
+        assert_eq!(index.last_position(), Some(Position::new(30, 300)));
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_load_from_disk_corrupted() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let expected_file = tmp_dir.path().join("00000000000000000000.index");
+        let mut index = Index::new(&tmp_dir.path().to_path_buf(), 0, 256).unwrap();
+        index.append_position(10, 100).unwrap();
+        index.append_position(20, 200).unwrap();
+        index.flush(0).unwrap();
+        // Entry appended after the latest flush, not covered by the footer
+        index.append_position(30, 300).unwrap();
+        drop(index);
+
+        let index =
+            Index::load_from_disk(&tmp_dir.path().to_path_buf(), 0, 31, 10, 256, false).unwrap();
+        assert_eq!(index.size, 4);
+
+        let mut content = fs::read(&expected_file).unwrap();
+        content[HEADER_SIZE + 1] ^= 0xff;
+        fs::write(&expected_file, content).unwrap();
+        let err = Index::load_from_disk(&tmp_dir.path().to_path_buf(), 0, 31, 10, 256, false)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_load_from_disk_foreign_file() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let expected_file = tmp_dir.path().join("00000000000000000048.index");
+        fs::write(&expected_file, [0; 64]).unwrap();
+
+        assert!(
+            Index::load_from_disk(&tmp_dir.path().to_path_buf(), 48, 68, 10, 256, false).is_err()
+        );
+        assert!(
+            Index::load_from_disk(&tmp_dir.path().to_path_buf(), 48, 68, 10, 256, true).is_ok()
+        );
         tmp_dir.close().unwrap();
     }
 
     #[test]
     #[should_panic]
     fn test_invalid_load_from_disk() {
-        Index::new(&Path::new("dont-exist-dir").to_path_buf(), 0, 10, 256).unwrap();
+        Index::new(&Path::new("dont-exist-dir").to_path_buf(), 0, 256).unwrap();
     }
 
     #[test]
@@ -233,19 +726,20 @@ mod index_tests {
         let expected_file = tmp_dir.path().join("00000000000000000000.index");
         fs::File::create(&expected_file).unwrap();
 
-        let mut index = Index::new(&tmp_dir.path().to_path_buf(), 0, 12, 256).unwrap();
+        let mut index = Index::new(&tmp_dir.path().to_path_buf(), 0, 256).unwrap();
 
         index.append_position(12, 400).unwrap();
 
-        assert_eq!(index.size, ENTRY_SIZE);
+        assert_eq!(index.size, 3);
 
+        index.append_position(24, 1011).unwrap();
+        assert_eq!(index.size, 6);
+        // A restart point in full then the deltas from it, as varints
         assert_eq!(
-            &fs::read(expected_file).unwrap()[..8],
-            &[0, 0, 0, 12, 0, 0, 1, 144]
+            &fs::read(expected_file).unwrap()[HEADER_SIZE..HEADER_SIZE + 6],
+            &[12, 144, 3, 12, 227, 4]
         );
-
-        index.append_position(24, 1011).unwrap();
-        assert_eq!(index.size, ENTRY_SIZE * 2);
+        assert!(index.append_position(36, 1000).is_err());
         tmp_dir.close().unwrap();
     }
 
@@ -255,7 +749,7 @@ mod index_tests {
         let expected_file = tmp_dir.path().join("00000000000000000000.index");
         fs::File::create(&expected_file).unwrap();
 
-        let mut index = Index::new(&tmp_dir.path().to_path_buf(), 0, 20, 256).unwrap();
+        let mut index = Index::new(&tmp_dir.path().to_path_buf(), 0, 256).unwrap();
 
         assert_eq!(
             index.find_offset(0).unwrap(),
@@ -344,4 +838,61 @@ mod index_tests {
         );
         tmp_dir.close().unwrap();
     }
+
+    #[test]
+    fn test_find_offset_restarts() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let path = tmp_dir.path().to_path_buf();
+        let mut index = Index::new(&path, 100, 1024).unwrap();
+        let entries = 3 * RESTART_INTERVAL as u32 + 5;
+        for i in 1..=entries {
+            index
+                .append_position(100 + i * 10, i as u64 * 1000)
+                .unwrap();
+        }
+        index.flush(0).unwrap();
+        drop(index);
+
+        let index = Index::load_from_disk(&path, 100, 0, 10, 1024, false).unwrap();
+        assert_eq!(index.restarts.len(), 4);
+        assert_eq!(
+            index.find_offset(105).unwrap(),
+            OffsetRange::new(Position::new(0, 0), Position::new(10, 1000))
+        );
+        for i in 1..entries {
+            assert_eq!(
+                index.find_offset(100 + i * 10 + 5).unwrap(),
+                OffsetRange::new(
+                    Position::new(i * 10, i as u64 * 1000),
+                    Position::new((i + 1) * 10, (i + 1) as u64 * 1000)
+                )
+            );
+        }
+        let last = Position::new(entries * 10, entries as u64 * 1000);
+        assert_eq!(
+            index.find_offset(100 + entries * 10).unwrap(),
+            OffsetRange::new(last, last)
+        );
+        assert_eq!(index.last_position(), Some(last));
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_check_positions() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut index = Index::new(&tmp_dir.path().to_path_buf(), 0, 256).unwrap();
+        index.append_position(10, 100).unwrap();
+        index.append_position(20, 200).unwrap();
+        assert!(index.check_positions(300).is_ok());
+        assert_eq!(
+            index.check_positions(200).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        // A flipped bit not covered by the footer yet, the second entry swallows the
+        // delta of its position and points at the same record as the first
+        index.mmap[HEADER_SIZE + 2] ^= 0x80;
+        assert!(index.check_positions(300).is_err());
+        tmp_dir.close().unwrap();
+    }
 }
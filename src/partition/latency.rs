@@ -0,0 +1,252 @@
+//! Latency of partition operations
+//!
+//! Every append, fetch, flush and index lookup of a partition is timed into a
+//! histogram of its operation, exposing percentiles since the partition was opened.
+//! Buckets are log-linear, 8 per power of two of microseconds, so a percentile is
+//! off by at most an eighth of its value.
+//!
+//! Operations slower than the threshold set with `Partition::log_slow_operations`
+//! are also reported one by one, along with the offsets they covered and what made
+//! them slow when known, e.g. the records scanned after the sparse index entry
+//! preceding a lookup.
+use crate::partition::Partition;
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Buckets covering up to 2^40 microseconds, about 12 days
+const BUCKETS: usize = 312;
+/// Buckets per power of two, as bits
+const PRECISION: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Append,
+    Fetch,
+    Flush,
+    IndexLookup,
+}
+
+impl Operation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Append => "append",
+            Operation::Fetch => "fetch",
+            Operation::Flush => "flush",
+            Operation::IndexLookup => "index_lookup",
+        }
+    }
+}
+
+pub struct Histogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    /// The duration under which a `quantile` of the operations completed, rounded up
+    /// to the bound of its bucket, zero without any operation
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((quantile * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (i, b) in self.buckets.iter().enumerate() {
+            seen += b.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Duration::from_micros(lower_bound(i + 1));
+            }
+        }
+        Duration::from_micros(lower_bound(BUCKETS))
+    }
+}
+
+fn bucket(micros: u64) -> usize {
+    let linear = 1 << PRECISION;
+    if micros < linear {
+        return micros as usize;
+    }
+    let exp = 63 - micros.leading_zeros();
+    let sub = (micros >> (exp - PRECISION)) & (linear - 1);
+    (((exp - PRECISION + 1) as u64 * linear + sub) as usize).min(BUCKETS - 1)
+}
+
+fn lower_bound(bucket: usize) -> u64 {
+    let linear = 1 << PRECISION;
+    if bucket < linear {
+        return bucket as u64;
+    }
+    let exp = bucket as u32 / linear as u32 + PRECISION - 1;
+    (linear as u64 + (bucket % linear) as u64) << (exp - PRECISION)
+}
+
+/// What made an operation slow
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlowCause {
+    /// Records decoded between the index entry preceding the offset and its record
+    IndexMissScan { scanned: usize },
+    /// Segments decoded in full to serve a read
+    SegmentScan { segments: usize },
+    /// The append rolled a new active segment
+    SegmentRoll,
+}
+
+/// An operation that exceeded the slow threshold of its partition
+#[derive(Debug, PartialEq)]
+pub struct SlowOperation<'a> {
+    pub operation: Operation,
+    pub partition: &'a Path,
+    pub offsets: Range<u64>,
+    pub duration: Duration,
+    pub cause: Option<SlowCause>,
+}
+
+/// Formatted as `key=value` pairs, a line of a structured log
+impl fmt::Display for SlowOperation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "slow_operation={} partition={} offsets={}..{} duration_ms={:.3}",
+            self.operation.name(),
+            self.partition.display(),
+            self.offsets.start,
+            self.offsets.end,
+            self.duration.as_secs_f64() * 1000.0
+        )?;
+        match self.cause {
+            Some(SlowCause::IndexMissScan { scanned }) => {
+                write!(f, " cause=index-miss-scan scanned={}", scanned)
+            }
+            Some(SlowCause::SegmentScan { segments }) => {
+                write!(f, " cause=segment-scan segments={}", segments)
+            }
+            Some(SlowCause::SegmentRoll) => write!(f, " cause=segment-roll"),
+            None => Ok(()),
+        }
+    }
+}
+
+type SlowLog = Box<dyn Fn(&SlowOperation) + Send>;
+
+#[derive(Default)]
+pub(crate) struct Latencies {
+    append: Histogram,
+    fetch: Histogram,
+    flush: Histogram,
+    index_lookup: Histogram,
+    slow: Option<(Duration, SlowLog)>,
+}
+
+impl Partition {
+    /// The latencies of `operation` since the partition was opened
+    pub fn latency(&self, operation: Operation) -> &Histogram {
+        match operation {
+            Operation::Append => &self.latencies.append,
+            Operation::Fetch => &self.latencies.fetch,
+            Operation::Flush => &self.latencies.flush,
+            Operation::IndexLookup => &self.latencies.index_lookup,
+        }
+    }
+
+    /// Call `log` with every operation taking longer than `threshold`
+    pub fn log_slow_operations(
+        &mut self,
+        threshold: Duration,
+        log: impl Fn(&SlowOperation) + Send + 'static,
+    ) {
+        self.latencies.slow = Some((threshold, Box::new(log)));
+    }
+
+    pub(crate) fn record_latency(
+        &self,
+        operation: Operation,
+        started: Instant,
+        offsets: Range<u64>,
+        cause: Option<SlowCause>,
+    ) {
+        let duration = started.elapsed();
+        self.latency(operation).record(duration);
+        match &self.latencies.slow {
+            Some((threshold, log)) if duration > *threshold => log(&SlowOperation {
+                operation,
+                partition: &self.dir,
+                offsets,
+                duration,
+                cause,
+            }),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod latency_tests {
+    use super::{Histogram, Operation, SlowCause};
+    use crate::partition::Partition;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_latencies() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.percentile(0.5), Duration::ZERO);
+        for _ in 0..98 {
+            histogram.record(Duration::from_millis(1));
+        }
+        histogram.record(Duration::from_millis(100));
+        histogram.record(Duration::from_millis(100));
+        let (p50, p99) = (histogram.percentile(0.5), histogram.percentile(0.99));
+        assert!(p50 >= Duration::from_millis(1) && p50 <= Duration::from_micros(1125));
+        assert!(p99 >= Duration::from_millis(100) && p99 <= Duration::from_micros(112500));
+
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        let slow = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&slow);
+        partition.log_slow_operations(Duration::ZERO, move |operation| {
+            log.lock()
+                .unwrap()
+                .push((operation.cause, operation.to_string()));
+        });
+        for i in 0..500u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        partition.flush().unwrap();
+        assert_eq!(partition.find_record(3).unwrap().offset, 3);
+        assert_eq!(partition.fetch(0, 1).unwrap().len(), 1);
+
+        assert_eq!(partition.latency(Operation::Append).count(), 500);
+        assert_eq!(partition.latency(Operation::IndexLookup).count(), 1);
+        let slow = slow.lock().unwrap();
+        assert!(slow.iter().any(|(c, _)| *c == Some(SlowCause::SegmentRoll)));
+        let (cause, line) = &slow[slow.len() - 2];
+        assert_eq!(*cause, Some(SlowCause::IndexMissScan { scanned: 3 }));
+        assert!(line.starts_with("slow_operation=index_lookup partition="));
+        assert!(line.contains(" offsets=3..4 ") && line.ends_with("scanned=3"));
+        assert!(slow[slow.len() - 1]
+            .1
+            .contains("cause=segment-scan segments=1"));
+        tmp_dir.close().unwrap();
+    }
+}
@@ -1,73 +1,123 @@
-use crate::partition::record::Record;
-use memmap2::MmapMut;
+use crate::partition::header::{FileHeader, HEADER_SIZE, LOG_MAGIC};
+use crate::partition::record::{Record, MAGIC_BYTE};
+use memmap2::{Advice, MmapMut, UncheckedAdvice};
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Result, Write};
-use std::path::PathBuf;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub struct Log {
-    file: File,
+    // The file is closed once mapped, the segment holds its own handle to sync it
     mmap: MmapMut,
     max_size: usize,
+    // Records start right after the file header, logs predating headers have none
+    header_size: usize,
     pub size: usize,
     pub base_offset: u64,
     pub current_offset: u64,
 }
 
 impl Log {
+    pub fn path(dir: &Path, base_offset: u64) -> PathBuf {
+        dir.join(format!("{:020}.log", base_offset))
+    }
+
     pub fn new(path: &PathBuf, base_offset: u64, max_size: usize) -> Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .append(true)
             .create(true)
-            .open(path.join(format!("{:020}.log", base_offset)))?;
+            .open(Self::path(path, base_offset))?;
 
-        file.set_len(max_size as u64)?;
-        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        preallocate(&file, HEADER_SIZE + max_size)?;
+        let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+        FileHeader::new(LOG_MAGIC, base_offset).write(&mut &mut mmap[..HEADER_SIZE])?;
 
         Ok(Self {
-            file,
             mmap,
             size: 0,
             max_size,
+            header_size: HEADER_SIZE,
             base_offset,
             current_offset: base_offset,
         })
     }
 
     pub fn load_from_disk(path: &PathBuf, base_offset: u64, max_size: usize) -> Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .create(false)
-            .append(true)
-            .open(path.join(format!("{:020}.log", base_offset)))?;
-        let mut log_size = 0;
-        let mut record_count = 0;
-        let mut reader = BufReader::new(&file);
-        // We read all the records from the log file till EOF and count them.
+        let mut log = Self::map(path, base_offset, max_size)?;
+        let mut reader = &log.mmap[log.header_size..];
+        // We read all the records from the log file till the first invalid one, the
+        // size is tracked by the bytes consumed as older record formats may differ in
+        // size from the current one. The next offset follows the latest record, the
+        // offsets of a compacted log are not contiguous.
         //
         // TODO read the index file last offset and read only the remaining bytes from
         // the log file.
-        loop {
-            match Record::from_binary(&mut reader) {
-                Ok(r) => {
-                    log_size += r.binary_size();
-                    record_count += 1;
-                }
-                Err(_) => break,
-            }
+        while let Ok(record) = Record::from_slice(&mut reader) {
+            log.size = log.max_size - reader.len();
+            log.current_offset = record.offset + 1;
         }
+        Ok(log)
+    }
 
-        file.set_len(max_size as u64)?;
-        let mmap = unsafe { MmapMut::map_mut(&file)? };
+    /// Load a log whose `size` and next offset were recorded at a clean shutdown,
+    /// without reading its records
+    pub fn load_clean(
+        path: &Path,
+        base_offset: u64,
+        max_size: usize,
+        size: usize,
+        current_offset: u64,
+    ) -> Result<Self> {
+        let mut log = Self::map(path, base_offset, max_size)?;
+        if size > log.max_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Log of {} bytes recorded as {}", log.max_size, size),
+            ));
+        }
+        log.size = size;
+        log.current_offset = current_offset;
+        Ok(log)
+    }
+
+    /// Whether the log of `base_offset` holds neither a header nor a record, the log
+    /// of a segment created right before a crash, its header never reaching the disk
+    pub fn is_unwritten(path: &Path, base_offset: u64) -> Result<bool> {
+        let file = File::open(Self::path(path, base_offset))?;
+        let mut start = Vec::with_capacity(HEADER_SIZE + 1);
+        file.take(HEADER_SIZE as u64 + 1).read_to_end(&mut start)?;
+        Ok(start.iter().all(|&b| b == 0))
+    }
 
+    /// Map an existing log, still empty as far as the returned `Log` knows
+    fn map(path: &Path, base_offset: u64, max_size: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .create(false)
+            .append(true)
+            .open(Self::path(path, base_offset))?;
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        (&file).take(HEADER_SIZE as u64).read_to_end(&mut header)?;
+        // Logs written before the introduction of headers start directly with a record
+        let header_size = if header.first() == Some(&MAGIC_BYTE) {
+            0
+        } else {
+            FileHeader::validate(&header, LOG_MAGIC, base_offset)?;
+            HEADER_SIZE
+        };
+        // Merged segments are bigger than the default size, never shrink them
+        let file_size = file.metadata()?.len() as usize;
+        let max_size = max_size.max(file_size - header_size);
+        file.set_len((header_size + max_size) as u64)?;
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
         Ok(Self {
-            file,
             mmap,
-            size: log_size as usize,
+            size: 0,
             max_size,
+            header_size,
             base_offset,
-            current_offset: base_offset + record_count,
+            current_offset: base_offset,
         })
     }
 
@@ -75,31 +125,87 @@ impl Log {
         self.mmap.flush_async()
     }
 
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn has_header(&self) -> bool {
+        self.header_size > 0
+    }
+
+    /// Drop the pages of the mapping, the ones written are already in the file
+    pub fn release_pages(&self) -> Result<()> {
+        // A shared file mapping reads the released pages back from the file
+        unsafe { self.mmap.unchecked_advise(UncheckedAdvice::DontNeed) }
+    }
+
+    /// Ask the kernel to read the records ahead, in the background
+    pub fn prefetch(&self) -> Result<()> {
+        self.mmap
+            .advise_range(Advice::WillNeed, 0, self.header_size + self.size)
+    }
+
+    /// Hint that the records are about to be read from start to end
+    pub fn advise_sequential(&self) -> Result<()> {
+        self.mmap
+            .advise_range(Advice::Sequential, 0, self.header_size + self.size)
+    }
+
+    /// Where the records start in the file
+    pub fn header_size(&self) -> usize {
+        self.header_size
+    }
+
     pub fn can_fit(&self, buffer_size: usize) -> bool {
         (self.max_size - self.size) >= buffer_size
     }
 
-    pub fn append_record(&mut self, record_data: &[u8]) -> Result<(u64, u32)> {
+    pub fn append_record(&mut self, record_data: &[u8]) -> Result<(u64, usize)> {
         let data_size = record_data.len();
-        let written_bytes =
-            (&mut self.mmap[(self.size)..(self.size + data_size)]).write(record_data)?;
+        let begin = self.header_size + self.size;
+        let written_bytes = (&mut self.mmap[begin..(begin + data_size)]).write(record_data)?;
         let size = self.size;
 
         self.size += written_bytes;
         let latest_offset = self.current_offset;
         self.current_offset += 1;
-        Ok((latest_offset, size as u32))
+        Ok((latest_offset, size))
     }
 
     pub fn read_at(&self, offset: usize, size: usize) -> Result<&[u8]> {
-        Ok(&self.mmap[offset..size])
+        self.mmap
+            .get((self.header_size + offset)..(self.header_size + size))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Read of {}..{} past the end of the log", offset, size),
+                )
+            })
+    }
+}
+
+/// Extend the file to `len` bytes writing zeros instead of leaving it sparse, a
+/// full disk is reported here rather than faulting on the first write through the
+/// mapping
+fn preallocate(mut file: &File, len: usize) -> Result<()> {
+    let current = file.metadata()?.len() as usize;
+    if current < len {
+        let zeros = vec![0u8; (len - current).min(64 * 1024)];
+        let mut remaining = len - current;
+        while remaining > 0 {
+            let chunk = remaining.min(zeros.len());
+            file.write_all(&zeros[..chunk])?;
+            remaining -= chunk;
+        }
     }
+    file.set_len(len as u64)
 }
 
 #[cfg(test)]
 mod log_tests {
 
     use super::Log;
+    use crate::partition::header::HEADER_SIZE;
     use std::fs;
     use std::path::Path;
     use tempdir::TempDir;
@@ -122,7 +228,7 @@ mod log_tests {
     fn test_load_from_disk() {
         let tmp_dir = TempDir::new("test_tempdir").unwrap();
         let expected_file = tmp_dir.path().join("00000000000000000048.log");
-        fs::File::create(&expected_file).unwrap();
+        Log::new(&tmp_dir.path().to_path_buf(), 48, 10).unwrap();
 
         let log = Log::load_from_disk(&tmp_dir.path().to_path_buf(), 48, 10).unwrap();
 
@@ -130,6 +236,30 @@ mod log_tests {
         assert_eq!(log.base_offset, 48);
         assert_eq!(log.current_offset, 48);
         assert_eq!(log.size, 0);
+        assert!(log.has_header());
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_load_from_disk_foreign_file() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let expected_file = tmp_dir.path().join("00000000000000000048.log");
+        fs::write(&expected_file, b"not a log file").unwrap();
+
+        assert!(Log::load_from_disk(&tmp_dir.path().to_path_buf(), 48, 10).is_err());
+        assert_eq!(fs::read(&expected_file).unwrap(), b"not a log file");
+
+        fs::File::create(&expected_file).unwrap();
+        assert!(Log::load_from_disk(&tmp_dir.path().to_path_buf(), 48, 10).is_err());
+
+        // Misnamed log of another segment
+        Log::new(&tmp_dir.path().to_path_buf(), 0, 10).unwrap();
+        fs::rename(
+            tmp_dir.path().join("00000000000000000000.log"),
+            &expected_file,
+        )
+        .unwrap();
+        assert!(Log::load_from_disk(&tmp_dir.path().to_path_buf(), 48, 10).is_err());
         tmp_dir.close().unwrap();
     }
 
@@ -142,8 +272,7 @@ mod log_tests {
     #[test]
     fn test_can_fit() {
         let tmp_dir = TempDir::new("test_tempdir").unwrap();
-        let expected_file = tmp_dir.path().join("00000000000000000000.log");
-        fs::File::create(&expected_file).unwrap();
+        Log::new(&tmp_dir.path().to_path_buf(), 0, 10).unwrap();
 
         let log = Log::load_from_disk(&tmp_dir.path().to_path_buf(), 0, 10).unwrap();
 
@@ -165,7 +294,7 @@ mod log_tests {
         assert_eq!(log.current_offset, 1);
 
         assert_eq!(
-            fs::read_to_string(expected_file)
+            String::from_utf8(fs::read(expected_file).unwrap()[HEADER_SIZE..].to_vec())
                 .unwrap()
                 .replace("\u{0}", ""),
             String::from("test-record-data")
@@ -0,0 +1,196 @@
+//! Bootstrap of a partition directory
+//!
+//! `Partition::create` lays out a new partition, writing a manifest holding its
+//! configuration next to the segments, `Partition::open_existing` applies it back
+//! when the partition is opened again. Partitions created before manifests existed
+//! open with the defaults, or adopt the configuration given to
+//! `Partition::open_or_create`.
+use crate::partition::index::IndexInterval;
+use crate::partition::{Partition, TimestampType};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::time::Duration;
+
+pub(crate) const MANIFEST_FILE: &str = "partition.json";
+
+/// Settings of a partition, the unset ones keep their default
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PartitionConfig {
+    pub max_record_size: Option<usize>,
+    pub max_size: Option<usize>,
+    /// Delete the oldest segments instead of failing appends past `max_size`
+    pub evict_oldest: Option<bool>,
+    pub timestamp_type: Option<TimestampType>,
+    pub index_interval_bytes: Option<usize>,
+    pub min_cleanable_dirty_ratio: Option<f64>,
+    pub min_compaction_lag_ms: Option<u64>,
+    pub compaction_versions: Option<usize>,
+}
+
+impl PartitionConfig {
+    fn apply(&self, partition: &mut Partition) {
+        if let Some(size) = self.max_record_size {
+            partition.set_max_record_size(size);
+        }
+        if let Some(max_size) = self.max_size {
+            partition.set_max_size(max_size);
+        }
+        if let Some(evict) = self.evict_oldest {
+            partition.set_evict_oldest(evict);
+        }
+        if let Some(timestamp_type) = self.timestamp_type {
+            partition.set_timestamp_type(timestamp_type);
+        }
+        if let Some(bytes) = self.index_interval_bytes {
+            partition.set_index_interval(IndexInterval::Bytes(bytes));
+        }
+        if let Some(ratio) = self.min_cleanable_dirty_ratio {
+            partition.set_min_cleanable_dirty_ratio(ratio);
+        }
+        if let Some(lag) = self.min_compaction_lag_ms {
+            partition.set_min_compaction_lag(Duration::from_millis(lag));
+        }
+        if let Some(versions) = self.compaction_versions {
+            partition.set_compaction_versions(versions);
+        }
+    }
+}
+
+fn read_manifest(dir: &Path) -> Result<PartitionConfig> {
+    match fs::read(dir.join(MANIFEST_FILE)) {
+        Ok(content) => {
+            serde_json::from_slice(&content).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(PartitionConfig::default()),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_manifest(dir: &Path, config: &PartitionConfig) -> Result<()> {
+    let content = serde_json::to_vec_pretty(config).map_err(Error::other)?;
+    let tmp = dir.join(MANIFEST_FILE).with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, dir.join(MANIFEST_FILE))
+}
+
+/// Whether `dir` holds anything, a partition or otherwise
+fn is_populated(dir: &Path) -> Result<bool> {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => Ok(entries.next().is_some()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+impl Partition {
+    /// Create a new partition in `dir`, along with any missing parent directory.
+    ///
+    /// Fails with `ErrorKind::AlreadyExists` if `dir` is not empty.
+    pub fn create(dir: impl AsRef<Path>, config: &PartitionConfig) -> Result<Self> {
+        let dir = dir.as_ref();
+        if is_populated(dir)? {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Partition directory {} is not empty", dir.display()),
+            ));
+        }
+        fs::create_dir_all(dir)?;
+        write_manifest(dir, config)?;
+        let mut partition = Self::open(dir)?;
+        config.apply(&mut partition);
+        Ok(partition)
+    }
+
+    /// Open the partition in `dir` with the configuration it was created with.
+    ///
+    /// Fails with `ErrorKind::NotFound` if `dir` doesn't exist.
+    pub fn open_existing(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Partition directory {} not found", dir.display()),
+            ));
+        }
+        let config = read_manifest(dir)?;
+        let mut partition = Self::open(dir)?;
+        config.apply(&mut partition);
+        Ok(partition)
+    }
+
+    /// Open the partition in `dir`, creating it with `config` if `dir` is missing
+    /// or empty, or writing it as the manifest of a partition lacking one
+    pub fn open_or_create(dir: impl AsRef<Path>, config: &PartitionConfig) -> Result<Self> {
+        let dir = dir.as_ref();
+        if is_populated(dir)? {
+            if !dir.join(MANIFEST_FILE).exists() {
+                write_manifest(dir, config)?;
+            }
+            Self::open_existing(dir)
+        } else {
+            Self::create(dir, config)
+        }
+    }
+}
+
+#[cfg(test)]
+mod manifest_tests {
+    use super::PartitionConfig;
+    use crate::partition::{Partition, TimestampType};
+    use std::io::ErrorKind;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_create_and_open() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let dir = tmp_dir.path().join("topic").join("0");
+        assert_eq!(
+            Partition::open_existing(&dir).err().unwrap().kind(),
+            ErrorKind::NotFound
+        );
+        let config = PartitionConfig {
+            max_record_size: Some(64),
+            timestamp_type: Some(TimestampType::LogAppendTime),
+            ..Default::default()
+        };
+
+        let mut partition = Partition::create(&dir, &config).unwrap();
+        assert!(partition.append_record(None, &[0; 128]).is_err());
+        partition.append_record_at(42, None, b"value").unwrap();
+        drop(partition);
+        assert_eq!(
+            Partition::create(&dir, &config).err().unwrap().kind(),
+            ErrorKind::AlreadyExists
+        );
+
+        // The configuration of the manifest wins over the one passed
+        let mut partition = Partition::open_or_create(&dir, &PartitionConfig::default()).unwrap();
+        assert_eq!(partition.end_offset(), 1);
+        assert!(partition.append_record(None, &[0; 128]).is_err());
+        assert!(partition.find_record(0).unwrap().timestamp > 42);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_adopt_config_without_manifest() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.append_record(None, b"value").unwrap();
+        drop(partition);
+        let config = PartitionConfig {
+            max_record_size: Some(64),
+            ..Default::default()
+        };
+
+        let mut partition = Partition::open_or_create(tmp_dir.path(), &config).unwrap();
+        assert_eq!(partition.end_offset(), 1);
+        assert!(partition.append_record(None, &[0; 128]).is_err());
+        drop(partition);
+        let mut partition = Partition::open_existing(tmp_dir.path()).unwrap();
+        assert!(partition.append_record(None, &[0; 128]).is_err());
+        tmp_dir.close().unwrap();
+    }
+}
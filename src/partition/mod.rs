@@ -1,118 +1,1581 @@
+pub mod bloom;
+pub mod buffer;
+pub mod compaction;
+pub mod compression;
+pub mod cursor;
+pub mod events;
+pub mod fd_cache;
+pub mod fetch;
+pub mod flusher;
+pub mod group_commit;
+pub mod header;
+pub mod hints;
 pub mod index;
+pub mod latency;
 pub mod log;
+pub mod manifest;
+pub mod observer;
 mod pager;
+pub mod read_only;
+pub mod reader;
 pub mod record;
+pub mod retention;
 pub mod segment;
+pub mod shutdown;
+pub mod stats;
+pub mod transaction;
 
-use record::Record;
-use segment::Segment;
+use crate::scheduler::Throttle;
+use crate::sim::{Clock, SystemClock};
+use events::{EventBus, PartitionEvent};
+use fd_cache::FdCache;
+use hints::PageCacheHints;
+use index::{Index, IndexInterval};
+use latency::{Latencies, Operation, SlowCause};
+use log::Log;
+use observer::AppendObserver;
+use record::{Compression, ControlType, Record};
 use segment::SegmentError;
+use segment::{sealed_paths, CorruptRange, Segment};
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::HashSet;
-use std::fs;
-use std::io::Result;
-use std::path::Path;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const LOG_PATH: &str = "logdir";
 const LOG_MAX_SIZE: usize = 4096;
 const OFFSET_INTERVAL: usize = 16;
+/// Default bound on the encoded size of a single record
+pub const MAX_RECORD_SIZE: usize = 1024 * 1024;
+const MERGE_DIR: &str = ".merge";
+const BACKGROUND_MERGE_DIR: &str = ".merge.background";
+const BACKGROUND_COMPACTION_DIR: &str = ".compaction.background";
+/// Non segment files of a partition
+const KNOWN_FILES: [&str; 3] = [
+    compaction::CHECKPOINT_FILE,
+    manifest::MANIFEST_FILE,
+    cursor::CHECKPOINTS_DIR,
+];
+/// Extension of a partition directory being destroyed
+pub const DELETED_EXTENSION: &str = "deleted";
+
+/// Offsets of a replicated batch not lining up with the local log
+#[derive(Debug, PartialEq)]
+pub enum OffsetError {
+    /// The batch doesn't start right at the end of the log, or skips offsets
+    Gap { expected: u64, found: u64 },
+    /// An offset in the batch not greater than the previous one
+    NonMonotonic { previous: u64, found: u64 },
+}
+
+impl std::error::Error for OffsetError {}
+
+impl fmt::Display for OffsetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OffsetError::Gap { expected, found } => {
+                write!(f, "Offset gap, expected {} found {}", expected, found)
+            }
+            OffsetError::NonMonotonic { previous, found } => {
+                write!(f, "Offset {} not greater than {}", found, previous)
+            }
+        }
+    }
+}
+
+/// Which timestamp the records appended with one of their own end up with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TimestampType {
+    /// Keep the timestamp set by the producer
+    #[default]
+    CreateTime,
+    /// Overwrite it with the time of the append
+    LogAppendTime,
+}
+
+impl std::str::FromStr for TimestampType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "CreateTime" => Ok(TimestampType::CreateTime),
+            "LogAppendTime" => Ok(TimestampType::LogAppendTime),
+            other => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown timestamp type {}", other),
+            )),
+        }
+    }
+}
+
+/// Where an appended record ended up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AppendInfo {
+    pub offset: u64,
+    /// The timestamp the record was stored with, the append time for a partition
+    /// of `TimestampType::LogAppendTime`
+    pub timestamp: u64,
+}
 
 pub struct Partition {
-    segments: Vec<Segment>,
+    dir: PathBuf,
+    segments: Vec<Arc<Segment>>,
     active_segment_index: usize,
+    dirty_bytes: usize,
+    /// The end offset at the latest flush, segments past it hold dirty bytes
+    flushed_offset: u64,
+    max_record_size: usize,
+    timestamp_type: TimestampType,
+    max_size: usize,
+    evict_oldest: bool,
+    read_only: bool,
+    clean_offset: u64,
+    min_cleanable_dirty_ratio: f64,
+    min_compaction_lag: Duration,
+    compaction_versions: usize,
+    index_interval: IndexInterval,
+    unknown_files: Vec<PathBuf>,
+    publisher: Option<reader::Publisher>,
+    page_cache_hints: PageCacheHints,
+    fd_cache: Option<Arc<FdCache>>,
+    clock: Arc<dyn Clock>,
+    observers: Vec<AppendObserver>,
+    events: EventBus,
+    latencies: Latencies,
+}
+
+/// A point in time view of a partition.
+///
+/// Sealed segments are shared with the partition, a merge or an upgrade happening
+/// after the snapshot was taken swaps them out of the partition only, the files
+/// being removed once the last snapshot referencing them is dropped. The records of
+/// the active segment are copied at the time the snapshot is taken.
+pub struct Snapshot {
+    segments: Vec<Arc<Segment>>,
+    tail: Vec<Record>,
+    end_offset: u64,
+}
+
+impl Snapshot {
+    pub fn start_offset(&self) -> u64 {
+        self.segments
+            .first()
+            .map_or_else(|| self.tail_offset(), |s| s.base_offset)
+    }
+
+    pub fn end_offset(&self) -> u64 {
+        self.end_offset
+    }
+
+    /// Read all the records with an offset in the `[from, to)` range, control
+    /// records excluded
+    pub fn read_range(&self, from: u64, to: u64) -> Result<Vec<Record>> {
+        let mut records = read_segments(&self.segments, from, to, false)?;
+        records.extend(
+            self.tail
+                .iter()
+                .filter(|r| r.offset >= from && r.offset < to && !r.attributes.control())
+                .map(|r| r.clone().decompressed())
+                .collect::<Result<Vec<_>>>()?,
+        );
+        Ok(records)
+    }
+
+    fn tail_offset(&self) -> u64 {
+        self.tail.first().map_or(self.end_offset, |r| r.offset)
+    }
+}
+
+fn read_segments(
+    segments: &[Arc<Segment>],
+    from: u64,
+    to: u64,
+    include_control: bool,
+) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for segment in segments {
+        if segment.latest_offset() <= from || segment.base_offset >= to {
+            continue;
+        }
+        records.extend(in_range(segment.records()?, from, to, include_control)?);
+    }
+    Ok(records)
+}
+
+/// The records in the `[from, to)` range, decompressed
+fn in_range(
+    records: Vec<Record>,
+    from: u64,
+    to: u64,
+    include_control: bool,
+) -> Result<Vec<Record>> {
+    records
+        .into_iter()
+        .filter(|r| r.offset >= from && r.offset < to)
+        .filter(|r| include_control || !r.attributes.control())
+        .map(Record::decompressed)
+        .collect()
+}
+
+/// The base offset part of the name of a segment file, a log or an index named
+/// after its zero padded base offset
+fn segment_name(path: &Path) -> Option<&str> {
+    let extension = path.extension()?;
+    let stem = path.file_stem()?.to_str()?;
+    let is_segment = (extension == "log" || extension == "index")
+        && stem.len() == 20
+        && stem.bytes().all(|b| b.is_ascii_digit());
+    (is_segment && path.is_file()).then_some(stem)
+}
+
+/// Write the records of `segments` in a single new segment in the `staging`
+/// directory, encoded with the current record format. An `active` segment keeps
+/// room for further appends.
+fn stage_segments(
+    staging: &Path,
+    segments: &[Arc<Segment>],
+    active: bool,
+    throttle: Option<&Throttle>,
+) -> Result<()> {
+    let records = segments
+        .iter()
+        .map(|s| s.records())
+        .collect::<Result<Vec<_>>>()?
+        .concat();
+    stage_records(staging, segments[0].base_offset, &records, active, throttle)
+}
+
+/// Write `records` in a new segment starting at `base_offset` in the `staging`
+/// directory, see `stage_segments`
+fn stage_records(
+    staging: &Path,
+    base_offset: u64,
+    records: &[Record],
+    active: bool,
+    throttle: Option<&Throttle>,
+) -> Result<()> {
+    let mut size = records.iter().map(Record::binary_size).sum();
+    if active {
+        size = LOG_MAX_SIZE.max(size);
+    }
+    // Leftovers of a failed attempt
+    if staging.exists() {
+        fs::remove_dir_all(staging)?;
+    }
+    fs::create_dir_all(staging)?;
+    let mut rewritten = Segment::new(staging, base_offset, OFFSET_INTERVAL, size, false)?;
+    // The records were already accepted, whatever the current bound
+    rewritten.set_max_record_size(usize::MAX);
+    for record in records {
+        if let Some(throttle) = throttle {
+            throttle.acquire(record.binary_size());
+        }
+        rewritten.append(record).map_err(|e| match e {
+            SegmentError::Io(e) => e,
+            _ => Error::other("Rewritten segment overflow"),
+        })?;
+    }
+    if active {
+        rewritten.flush()
+    } else {
+        rewritten.seal()
+    }
+}
+
+/// A run of sealed segments to merge in the background
+pub struct MergeRun {
+    dir: PathBuf,
+    segments: Vec<Arc<Segment>>,
+    /// Hint the segments are read whole, see `PageCacheHints`
+    sequential: bool,
+}
+
+impl MergeRun {
+    /// Write the merged segment in a staging directory, the I/O paced by `throttle`
+    pub fn write(self, throttle: Option<&Throttle>) -> Result<StagedMerge> {
+        let staging = self.dir.join(BACKGROUND_MERGE_DIR);
+        if self.sequential {
+            for segment in &self.segments {
+                segment.advise_sequential()?;
+            }
+        }
+        stage_segments(&staging, &self.segments, false, throttle)?;
+        Ok(StagedMerge { run: self })
+    }
+}
+
+/// A merged segment written in the staging directory, see `Partition::apply_merge`
+pub struct StagedMerge {
+    run: MergeRun,
 }
 
 impl Partition {
     pub fn init() -> Result<Self> {
-        let mut paths = fs::read_dir(LOG_PATH)?
-            .into_iter()
-            .flat_map(|f| f.map(|entry| entry.file_name()))
-            .map(|name| {
-                Path::new(&name)
-                    .with_extension("")
-                    .to_str()
-                    .unwrap()
-                    .to_owned()
-            })
-            .collect::<HashSet<_>>()
-            .into_iter()
-            .collect::<Vec<_>>();
+        Self::open(LOG_PATH)
+    }
+
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        // A leftover staging directory means a merge was interrupted. Until its log
+        // is renamed the original segments are intact. Past that the merged log sits
+        // next to the index of the first original segment, rebuilt on load as it
+        // doesn't match the size of the log, and the segments it covers are removed
+        // below.
+        for staging in [MERGE_DIR, BACKGROUND_MERGE_DIR, BACKGROUND_COMPACTION_DIR] {
+            let staging = dir.join(staging);
+            if staging.exists() {
+                fs::remove_dir_all(&staging)?;
+            }
+        }
+        let clean_offset = compaction::read_checkpoint(&dir)?;
+        let mut clean_segments = shutdown::take_marker(&dir)?;
+        let mut paths = HashSet::new();
+        let mut unknown_files = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match segment_name(&path) {
+                Some(name) => {
+                    paths.insert(name.to_owned());
+                }
+                // The cleaner checkpoint, the manifest and the cursor checkpoints live next to
+                // the segments
+                None if path
+                    .file_name()
+                    .is_some_and(|n| KNOWN_FILES.iter().any(|k| n == *k)) => {}
+                None => unknown_files.push(path),
+            }
+        }
+        unknown_files.sort();
+        let mut paths = paths.into_iter().collect::<Vec<_>>();
+        // Segments are trusted as recorded only if the shutdown recorded them all
+        if clean_segments.len() != paths.len() {
+            clean_segments.clear();
+        }
 
         if paths.len() == 0 {
+            let segment = Segment::new(&dir, 0, OFFSET_INTERVAL, LOG_MAX_SIZE, true)?;
             Ok(Partition {
-                segments: vec![Segment::new(LOG_PATH, 0, OFFSET_INTERVAL, true)?],
+                dir,
+                segments: vec![Arc::new(segment)],
                 active_segment_index: 0,
+                dirty_bytes: 0,
+                flushed_offset: 0,
+                max_record_size: MAX_RECORD_SIZE,
+                timestamp_type: TimestampType::default(),
+                max_size: usize::MAX,
+                evict_oldest: false,
+                read_only: false,
+                clean_offset,
+                min_cleanable_dirty_ratio: compaction::DEFAULT_MIN_CLEANABLE_DIRTY_RATIO,
+                min_compaction_lag: Duration::ZERO,
+                compaction_versions: 1,
+                index_interval: IndexInterval::Records(OFFSET_INTERVAL),
+                unknown_files,
+                publisher: None,
+                page_cache_hints: PageCacheHints::default(),
+                fd_cache: None,
+                clock: Arc::new(SystemClock),
+                observers: Vec::new(),
+                events: EventBus::default(),
+                latencies: Latencies::default(),
             })
         } else {
             paths.sort();
-            let active_segment_index = paths.len();
 
-            let segments: Vec<Segment> = paths
-                .into_iter()
-                .map(|name| {
-                    let base_offset = name.parse::<u64>().expect("Log file name not compliant");
-                    Segment::load_from_disk(LOG_PATH, base_offset, OFFSET_INTERVAL, false).unwrap()
-                })
-                .collect();
+            let mut segments: Vec<Arc<Segment>> = Vec::with_capacity(paths.len());
+            let last = paths.len() - 1;
+            for (i, name) in paths.into_iter().enumerate() {
+                let base_offset = name.parse::<u64>().map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Segment name {} out of range", name),
+                    )
+                })?;
+                let clean = clean_segments.iter().find(|s| s.base_offset == base_offset);
+                // The last segment is the active one, its keys and timestamps aren't
+                // settled. Rolled right before a crash, it may have lost its header,
+                // with no record written it's created afresh
+                let segment = if i == last && Log::is_unwritten(&dir, base_offset)? {
+                    fs::remove_file(Log::path(&dir, base_offset))?;
+                    match fs::remove_file(Index::path(&dir, base_offset)) {
+                        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                    Segment::new(&dir, base_offset, OFFSET_INTERVAL, LOG_MAX_SIZE, true)?
+                } else {
+                    Segment::load_from_disk(&dir, base_offset, OFFSET_INTERVAL, i == last, clean)?
+                };
+                // A merge interrupted after the swap leaves behind segments already
+                // covered by the merged one preceding them
+                match segments.last() {
+                    Some(prev) if segment.base_offset < prev.latest_offset() => {
+                        segment.remove(&dir)?
+                    }
+                    _ => segments.push(Arc::new(segment)),
+                }
+            }
+            let active_segment_index = segments.len() - 1;
+            let flushed_offset = segments[active_segment_index].latest_offset();
             Ok(Partition {
+                dir,
                 segments,
-                active_segment_index: active_segment_index - 1,
+                active_segment_index,
+                dirty_bytes: 0,
+                flushed_offset,
+                max_record_size: MAX_RECORD_SIZE,
+                timestamp_type: TimestampType::default(),
+                max_size: usize::MAX,
+                evict_oldest: false,
+                read_only: false,
+                clean_offset,
+                min_cleanable_dirty_ratio: compaction::DEFAULT_MIN_CLEANABLE_DIRTY_RATIO,
+                min_compaction_lag: Duration::ZERO,
+                compaction_versions: 1,
+                index_interval: IndexInterval::Records(OFFSET_INTERVAL),
+                unknown_files,
+                publisher: None,
+                page_cache_hints: PageCacheHints::default(),
+                fd_cache: None,
+                clock: Arc::new(SystemClock),
+                observers: Vec::new(),
+                events: EventBus::default(),
+                latencies: Latencies::default(),
             })
         }
     }
 
+    /// The entries of the partition directory found at open that are neither
+    /// segments nor files of the partition, left alone
+    pub fn unknown_files(&self) -> &[PathBuf] {
+        &self.unknown_files
+    }
+
     pub fn flush(&mut self) -> Result<()> {
-        self.active_segment().flush()
+        let started = Instant::now();
+        self.active_segment().flush()?;
+        self.dirty_bytes = 0;
+        self.flushed_offset = self.end_offset();
+        let offsets = self.active_segment().base_offset..self.end_offset();
+        self.record_latency(Operation::Flush, started, offsets, None);
+        Ok(())
+    }
+
+    /// Bound the encoded size of the records appended, records bigger than a
+    /// segment get a segment of their own
+    pub fn set_max_record_size(&mut self, max_record_size: usize) {
+        self.max_record_size = max_record_size;
+        self.active_segment().set_max_record_size(max_record_size);
+    }
+
+    /// How often the records appended from now on get an entry in the sparse index
+    /// of their segment
+    pub fn set_index_interval(&mut self, interval: IndexInterval) {
+        self.index_interval = interval;
+        self.active_segment().set_index_interval(interval);
+    }
+
+    /// Bound the bytes of records stored in the partition, appends past it fail
+    /// with `ErrorKind::StorageFull` unless evicting the oldest segments
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    /// Make room for the appends past the maximum size by deleting the oldest sealed
+    /// segments instead of failing, keeping the partition as a ring buffer of the
+    /// latest records. Appends still fail once only the active segment is left.
+    pub fn set_evict_oldest(&mut self, evict: bool) {
+        self.evict_oldest = evict;
+    }
+
+    /// The bytes of records stored in the partition
+    pub fn size(&self) -> usize {
+        self.segments.iter().map(|s| s.size()).sum()
     }
 
-    pub fn append_record(&mut self, key: Option<Vec<u8>>, value: &[u8]) -> Result<()> {
-        match self.active_segment().append_record(key.clone(), value) {
-            Ok(()) => Ok(()),
+    /// Whether appends are rejected after the storage filled up
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Accept appends again once space has been reclaimed, fails if the partition
+    /// is still over its maximum size
+    pub fn resume(&mut self) -> Result<()> {
+        if self.size() >= self.max_size {
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                "Partition still over its maximum size",
+            ));
+        }
+        self.read_only = false;
+        Ok(())
+    }
+
+    /// Close the partition and remove its directory with every file in it.
+    ///
+    /// The directory is renamed with the `deleted` extension appended first, a
+    /// removal interrupted midway leaves no partially removed partition behind to
+    /// be opened. Snapshots still held keep reading the segments they reference.
+    pub fn destroy(self) -> Result<()> {
+        let dir = self.dir.clone();
+        drop(self);
+        let mut name = dir
+            .file_name()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Partition without a name"))?
+            .to_os_string();
+        // Appended rather than replacing an extension, `events.0` and `events.1`
+        // don't both become `events.deleted`
+        name.push(format!(".{}", DELETED_EXTENSION));
+        let deleted = dir.with_file_name(name);
+        fs::rename(&dir, &deleted)?;
+        fs::remove_dir_all(deleted)
+    }
+
+    pub fn set_timestamp_type(&mut self, timestamp_type: TimestampType) {
+        self.timestamp_type = timestamp_type;
+    }
+
+    /// Open the segment files through `cache`, shared with other partitions, instead
+    /// of holding them all open
+    pub fn set_fd_cache(&mut self, cache: Arc<FdCache>) -> Result<()> {
+        for segment in &self.segments {
+            segment.use_fd_cache(cache.clone())?;
+        }
+        self.fd_cache = Some(cache);
+        Ok(())
+    }
+
+    /// Read the time of the appends, retention and compaction from `clock`
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// The bytes appended since the latest flush
+    pub fn dirty_bytes(&self) -> usize {
+        self.dirty_bytes
+    }
+
+    /// The offset of the first record stored in the partition
+    pub fn start_offset(&self) -> u64 {
+        self.segments[0].base_offset
+    }
+
+    /// The offset the next appended record will be assigned
+    pub fn end_offset(&self) -> u64 {
+        self.segments[self.active_segment_index].latest_offset()
+    }
+
+    pub fn append_record(&mut self, key: Option<Vec<u8>>, value: &[u8]) -> Result<AppendInfo> {
+        let timestamp = self.clock.now_millis();
+        let record = Record::with_timestamp(self.end_offset(), timestamp, key, value.to_vec());
+        self.append(&record)
+    }
+
+    /// Append a record produced at `timestamp`, kept or replaced by the append
+    /// time depending on the `TimestampType` of the partition
+    pub fn append_record_at(
+        &mut self,
+        timestamp: u64,
+        key: Option<Vec<u8>>,
+        value: &[u8],
+    ) -> Result<AppendInfo> {
+        self.append_record_compressed(timestamp, key, value, Compression::None)
+    }
+
+    /// Append a record whose value is already compressed with `compression`, reads
+    /// return it decompressed
+    pub fn append_record_compressed(
+        &mut self,
+        timestamp: u64,
+        key: Option<Vec<u8>>,
+        value: &[u8],
+        compression: Compression,
+    ) -> Result<AppendInfo> {
+        let record = self.produced_record(timestamp, key, value, compression);
+        self.append(&record)
+    }
+
+    /// Append a record along with its `headers`, produced at `timestamp` or now
+    pub fn append_record_with_headers(
+        &mut self,
+        timestamp: Option<u64>,
+        key: Option<Vec<u8>>,
+        value: &[u8],
+        headers: Vec<(String, Vec<u8>)>,
+    ) -> Result<AppendInfo> {
+        let timestamp = timestamp.unwrap_or_else(|| self.clock.now_millis());
+        let mut record = self.produced_record(timestamp, key, value, Compression::None);
+        record.headers = headers;
+        self.append(&record)
+    }
+
+    /// Append a tombstone of `key`, telling the readers of a compacted partition the
+    /// key was deleted
+    pub fn append_tombstone(&mut self, key: Vec<u8>) -> Result<AppendInfo> {
+        let timestamp = self.clock.now_millis();
+        self.append_tombstone_at(timestamp, key)
+    }
+
+    /// Append a tombstone of `key` produced at `timestamp`, see `append_record_at`
+    pub fn append_tombstone_at(&mut self, timestamp: u64, key: Vec<u8>) -> Result<AppendInfo> {
+        let mut record = self.produced_record(timestamp, Some(key), &[], Compression::None);
+        record.attributes = record.attributes.with_tombstone(true);
+        self.append(&record)
+    }
+
+    fn produced_record(
+        &self,
+        timestamp: u64,
+        key: Option<Vec<u8>>,
+        value: &[u8],
+        compression: Compression,
+    ) -> Record {
+        let timestamp = match self.timestamp_type {
+            TimestampType::CreateTime => timestamp,
+            TimestampType::LogAppendTime => self.clock.now_millis(),
+        };
+        let mut record = Record::with_timestamp(self.end_offset(), timestamp, key, value.to_vec());
+        record.attributes = record
+            .attributes
+            .with_compression(compression)
+            .with_log_append_time(self.timestamp_type == TimestampType::LogAppendTime);
+        record
+    }
+
+    /// Append a control record, a marker of the protocol invisible to normal reads
+    pub fn append_control(
+        &mut self,
+        control_type: ControlType,
+        value: &[u8],
+    ) -> Result<AppendInfo> {
+        let record = Record::control(self.end_offset(), control_type, value.to_vec());
+        self.append(&record)
+    }
+
+    /// Append a batch of records replicated from a leader, keeping their offsets
+    /// and timestamps.
+    ///
+    /// The batch must start at the end offset of the partition and be contiguous,
+    /// otherwise nothing is appended and an `InvalidData` error wrapping an
+    /// `OffsetError` is returned, the logs have diverged. Returns the range of
+    /// offsets appended.
+    pub fn append_replicated(&mut self, records: &[Record]) -> Result<Range<u64>> {
+        let end_offset = self.end_offset();
+        for (expected, record) in (end_offset..).zip(records) {
+            let found = record.offset;
+            let error = if found < expected && expected > end_offset {
+                OffsetError::NonMonotonic {
+                    previous: expected - 1,
+                    found,
+                }
+            } else if found != expected {
+                OffsetError::Gap { expected, found }
+            } else {
+                continue;
+            };
+            return Err(Error::new(ErrorKind::InvalidData, error));
+        }
+        for record in records {
+            self.append(record)?;
+        }
+        Ok(end_offset..end_offset + records.len() as u64)
+    }
+
+    fn append(&mut self, record: &Record) -> Result<AppendInfo> {
+        if self.read_only {
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                "Partition is read-only until resumed",
+            ));
+        }
+        let fits = |p: &Self| p.size().saturating_add(record.binary_size()) <= p.max_size;
+        while self.evict_oldest && self.active_segment_index > 0 && !fits(self) {
+            self.remove_segment(0)?;
+        }
+        if !fits(self) {
+            self.read_only = true;
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                format!("Partition reached its maximum size of {}", self.max_size),
+            ));
+        }
+        let started = Instant::now();
+        let mut rolled = false;
+        let appended = match self.active_segment().append(record) {
             Err(SegmentError::FullSegment) => {
-                match self.new_active_segment()?.append_record(key, value) {
-                    Ok(()) => Ok(()),
-                    Err(_) => panic!(),
+                rolled = true;
+                match self.new_active_segment(record.binary_size()) {
+                    Ok(segment) => segment.append(record),
+                    Err(e) => Err(SegmentError::Io(e)),
+                }
+            }
+            appended => appended,
+        };
+        match appended {
+            Ok(()) => {
+                self.dirty_bytes += record.binary_size();
+                if let Some(publisher) = &self.publisher {
+                    publisher.appended(&self.segments[self.active_segment_index]);
                 }
+                self.notify_appended(record);
+                let cause = rolled.then_some(SlowCause::SegmentRoll);
+                let offsets = record.offset..record.offset + 1;
+                self.record_latency(Operation::Append, started, offsets, cause);
+                Ok(AppendInfo {
+                    offset: record.offset,
+                    timestamp: record.timestamp,
+                })
             }
-            Err(SegmentError::Io(e)) => Err(e),
+            Err(SegmentError::Io(e)) => {
+                // Stop writing on a full disk, before anything gets half written
+                if e.kind() == ErrorKind::StorageFull {
+                    self.read_only = true;
+                }
+                Err(e)
+            }
+            Err(SegmentError::RecordTooLarge(size)) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Record of {} bytes exceeds the maximum of {}",
+                    size, self.max_record_size
+                ),
+            )),
+            Err(SegmentError::FullSegment) => Err(Error::other("Record overflows a new segment")),
         }
     }
 
     pub fn find_record(&mut self, offset: u64) -> Result<Record> {
-        match offset {
-            v if v == self.active_segment().base_offset => self.active_segment().read_at(v),
-            v if self.segments.len() > 0 && v < self.segments[0].base_offset => {
-                self.active_segment().read_at(v)
-            }
+        let started = Instant::now();
+        let (record, scanned) = match offset {
+            v if v == self.active_segment().base_offset => self.active_segment().locate(v),
+            // Removed by a compaction
+            v if !self.segments.is_empty() && v < self.segments[0].base_offset => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No record at offset {}", v),
+            )),
             v => {
                 match self
                     .segments
                     .binary_search_by(|s| s.base_offset.cmp(&v).then(Ordering::Less))
                 {
-                    Ok(i) => self.segments[i].read_at(v),
+                    Ok(i) => self.segments[i].locate(v),
                     Err(0) => {
                         if self.segments.len() == 0 {
-                            self.active_segment().read_at(v)
+                            self.active_segment().locate(v)
                         } else {
-                            self.segments[0].read_at(v)
+                            self.segments[0].locate(v)
                         }
                     }
-                    Err(n) => self.segments[n - 1].read_at(v),
+                    Err(n) => self.segments[n - 1].locate(v),
                 }
             }
+        }?;
+        let cause = (scanned > 0).then_some(SlowCause::IndexMissScan { scanned });
+        self.record_latency(Operation::IndexLookup, started, offset..offset + 1, cause);
+        record.decompressed()
+    }
+
+    /// The offset of the first record with a timestamp at or after `timestamp`,
+    /// `None` if every record is older
+    pub fn offset_for_timestamp(&self, timestamp: u64) -> Result<Option<u64>> {
+        for segment in &self.segments {
+            if segment
+                .time_bounds()?
+                .is_none_or(|(_, latest)| latest < timestamp)
+            {
+                continue;
+            }
+            let records = segment.records()?;
+            if let Some(record) = records.iter().find(|r| r.timestamp >= timestamp) {
+                return Ok(Some(record.offset));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read all the records with an offset in the `[from, to)` range, control
+    /// records excluded
+    pub fn read_range(&self, from: u64, to: u64) -> Result<Vec<Record>> {
+        read_segments(&self.segments, from, to, false)
+    }
+
+    /// Read all the records with a timestamp in the `[from, to)` range, control
+    /// records excluded. Only the segments with records in the range are read.
+    pub fn read_between(&self, from: u64, to: u64) -> Result<Vec<Record>> {
+        let mut records = Vec::new();
+        for segment in &self.segments {
+            if segment
+                .time_bounds()?
+                .is_none_or(|(earliest, latest)| latest < from || earliest >= to)
+            {
+                continue;
+            }
+            for record in segment.records()? {
+                if !record.attributes.control() && (from..to).contains(&record.timestamp) {
+                    records.push(record.decompressed()?);
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Read all the records with an offset in the `[from, to)` range like
+    /// `read_range`, skipping the corrupt ones instead of failing. Decoding resumes
+    /// at the index entry following a corrupt record, the records in between are
+    /// lost. Returns the ranges of bytes skipped along with the records.
+    pub fn read_range_skipping_corrupt(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<(Vec<Record>, Vec<CorruptRange>)> {
+        let mut records = Vec::new();
+        let mut skipped = Vec::new();
+        for segment in &self.segments {
+            if segment.latest_offset() <= from || segment.base_offset >= to {
+                continue;
+            }
+            let (decoded, corrupt) = segment.records_skipping_corrupt()?;
+            records.extend(in_range(decoded, from, to, false)?);
+            skipped.extend(corrupt);
+        }
+        Ok((records, skipped))
+    }
+
+    /// Read all the records with an offset in the `[from, to)` range, control
+    /// records included
+    pub fn read_range_with_control(&self, from: u64, to: u64) -> Result<Vec<Record>> {
+        read_segments(&self.segments, from, to, true)
+    }
+
+    /// The log files holding records with an offset from `from` onward
+    pub fn log_files(&self, from: u64) -> Result<Vec<Arc<File>>> {
+        self.segments
+            .iter()
+            .filter(|s| s.latest_offset() > from || s.base_offset >= from)
+            .map(|s| s.files().map(|(log, _)| log))
+            .collect()
+    }
+
+    /// Take a snapshot of the partition, reads through it are not affected by
+    /// merges and upgrades performed afterwards
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let active = &self.segments[self.active_segment_index];
+        Ok(Snapshot {
+            segments: self.segments[..self.active_segment_index].to_vec(),
+            tail: active.records()?,
+            end_offset: active.latest_offset(),
+        })
+    }
+
+    /// Merge runs of consecutive sealed segments into bigger ones of at most
+    /// `target_size` bytes, reducing the number of files and lookups to perform.
+    ///
+    /// Each run is first written into a staging directory and then swapped in
+    /// place of the first segment of the run, the others are removed right after.
+    /// Returns the number of segments removed by the merge.
+    pub fn merge_segments(&mut self, target_size: usize) -> Result<usize> {
+        let mut removed = 0;
+        let mut begin = 0;
+        while begin < self.active_segment_index {
+            let end = self.merge_run_end(begin, target_size);
+            if end - begin > 1 {
+                self.rewrite_range(begin, end)?;
+                removed += end - begin - 1;
+            }
+            begin += 1;
+        }
+        Ok(removed)
+    }
+
+    /// The first run of sealed segments `merge_segments` would merge.
+    ///
+    /// Meant for background merges, the run is written with `MergeRun::write`
+    /// without holding the partition and swapped in by `apply_merge`, appends
+    /// proceed in the meantime.
+    pub fn next_merge(&self, target_size: usize) -> Option<MergeRun> {
+        (0..self.active_segment_index)
+            .map(|begin| (begin, self.merge_run_end(begin, target_size)))
+            .find(|(begin, end)| end - begin > 1)
+            .map(|(begin, end)| MergeRun {
+                dir: self.dir.clone(),
+                segments: self.segments[begin..end].to_vec(),
+                sequential: self.page_cache_hints.sequential_scans,
+            })
+    }
+
+    /// Swap a merged run written in the background in place of its segments,
+    /// returns false and discards it if they changed since `next_merge`
+    pub fn apply_merge(&mut self, staged: StagedMerge) -> Result<bool> {
+        let run = &staged.run;
+        let begin = self
+            .segments
+            .iter()
+            .position(|s| Arc::ptr_eq(s, &run.segments[0]));
+        let end = begin.map(|b| b + run.segments.len());
+        match (begin, end) {
+            (Some(begin), Some(end))
+                if end <= self.active_segment_index
+                    && self.segments[begin..end]
+                        .iter()
+                        .zip(&run.segments)
+                        .all(|(a, b)| Arc::ptr_eq(a, b)) =>
+            {
+                let staging = self.dir.join(BACKGROUND_MERGE_DIR);
+                drop(staged);
+                self.swap_staged(&staging, begin, end)?;
+                Ok(true)
+            }
+            _ => {
+                fs::remove_dir_all(self.dir.join(BACKGROUND_MERGE_DIR))?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// End of the run of sealed segments starting at `begin` fitting `target_size`
+    fn merge_run_end(&self, begin: usize, target_size: usize) -> usize {
+        let mut end = begin;
+        let mut size = 0;
+        while end < self.active_segment_index && size + self.segments[end].size() <= target_size {
+            size += self.segments[end].size();
+            end += 1;
+        }
+        end
+    }
+
+    /// Rewrite every segment holding records written with an older binary format
+    /// using the current one, returning the number of segments upgraded
+    pub fn upgrade_format(&mut self) -> Result<usize> {
+        let mut upgraded = 0;
+        for i in 0..self.segments.len() {
+            if !self.segments[i].is_current_format()? {
+                self.rewrite_range(i, i + 1)?;
+                upgraded += 1;
+            }
+        }
+        Ok(upgraded)
+    }
+
+    /// Rewrite the records of the segments in the `[begin, end)` range into a single
+    /// new segment, encoded with the current record format
+    fn rewrite_range(&mut self, begin: usize, end: usize) -> Result<()> {
+        let staging = self.dir.join(MERGE_DIR);
+        let active = end > self.active_segment_index;
+        for segment in &self.segments[begin..end] {
+            self.advise_sequential(segment)?;
+        }
+        stage_segments(&staging, &self.segments[begin..end], active, None)?;
+        self.swap_staged(&staging, begin, end)
+    }
+
+    /// Replace the segments in the `[begin, end)` range with the one written by
+    /// `stage_segments` in `staging`
+    fn swap_staged(&mut self, staging: &Path, begin: usize, end: usize) -> Result<()> {
+        let active = end > self.active_segment_index;
+        let base_offset = self.segments[begin].base_offset;
+        // The first segment files are replaced by the rename, a snapshot still
+        // holding it keeps reading the unlinked files through its mapping
+        let mut replaced: Vec<Arc<Segment>> = self.segments.drain(begin..end).collect();
+        let covered = replaced.split_off(1);
+        drop(replaced);
+        fs::rename(
+            Log::path(staging, base_offset),
+            Log::path(&self.dir, base_offset),
+        )?;
+        #[cfg(feature = "fail")]
+        fail::fail_point!("partition::swap_staged", |_| Err(Error::other(
+            "Failpoint partition::swap_staged"
+        )));
+        fs::rename(
+            Index::path(staging, base_offset),
+            Index::path(&self.dir, base_offset),
+        )?;
+        // The files the replaced segment wrote when sealed don't cover the staged one
+        let paths = sealed_paths(staging, base_offset)
+            .into_iter()
+            .zip(sealed_paths(&self.dir, base_offset));
+        for (staged, path) in paths {
+            match fs::rename(staged, &path) {
+                Err(e) if e.kind() == ErrorKind::NotFound => match fs::remove_file(path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                },
+                result => result?,
+            }
+        }
+        for segment in covered {
+            match Arc::try_unwrap(segment) {
+                Ok(segment) => segment.remove(&self.dir)?,
+                Err(shared) => shared.retire(&self.dir),
+            }
+        }
+        fs::remove_dir(staging)?;
+
+        let mut rewritten =
+            Segment::load_from_disk(&self.dir, base_offset, OFFSET_INTERVAL, active, None)?;
+        rewritten.set_max_record_size(self.max_record_size);
+        rewritten.set_index_interval(self.index_interval);
+        rewritten.advise_index(self.page_cache_hints.random_index)?;
+        if let Some(cache) = &self.fd_cache {
+            rewritten.use_fd_cache(cache.clone())?;
+        }
+        self.segments.insert(begin, Arc::new(rewritten));
+        self.active_segment_index -= end - begin - 1;
+        self.republish()
+    }
+
+    /// Drop the sealed segment at `i`, once no snapshot references it
+    fn remove_segment(&mut self, i: usize) -> Result<()> {
+        let segment = self.segments.remove(i);
+        self.active_segment_index -= 1;
+        let base_offset = segment.base_offset;
+        match Arc::try_unwrap(segment) {
+            Ok(segment) => segment.remove(&self.dir)?,
+            Err(shared) => shared.retire(&self.dir),
+        }
+        self.republish()?;
+        self.events
+            .publish(PartitionEvent::SegmentDeleted { base_offset });
+        if i == 0 {
+            self.events.publish(PartitionEvent::StartOffsetAdvanced {
+                start_offset: self.start_offset(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Every segment, the active one last
+    pub(crate) fn mapped_segments(&self) -> &[Arc<Segment>] {
+        &self.segments
+    }
+
+    /// Publish the current segments to the readers, if any
+    fn republish(&mut self) -> Result<()> {
+        match &mut self.publisher {
+            Some(publisher) => publisher.republish(
+                &self.dir,
+                &self.segments[..self.active_segment_index],
+                &self.segments[self.active_segment_index],
+            ),
+            None => Ok(()),
         }
     }
 
     fn active_segment(&mut self) -> &mut Segment {
-        &mut self.segments[self.active_segment_index]
+        // Snapshots only share sealed segments
+        Arc::get_mut(&mut self.segments[self.active_segment_index]).expect("Active segment shared")
     }
 
-    fn new_active_segment(&mut self) -> Result<&mut Segment> {
+    /// Roll a new active segment big enough to hold a record of `record_size` bytes
+    fn new_active_segment(&mut self, record_size: usize) -> Result<&mut Segment> {
         let latest_offset = self.segments[self.active_segment_index].latest_offset();
-        let new_segment = Segment::new(LOG_PATH, latest_offset, OFFSET_INTERVAL, true)?;
-        self.segments[self.active_segment_index].seal();
-        self.segments.push(new_segment);
+        let mut new_segment = Segment::new(
+            &self.dir,
+            latest_offset,
+            OFFSET_INTERVAL,
+            LOG_MAX_SIZE.max(record_size),
+            true,
+        )?;
+        new_segment.set_max_record_size(self.max_record_size);
+        new_segment.set_index_interval(self.index_interval);
+        new_segment.advise_index(self.page_cache_hints.random_index)?;
+        if let Some(cache) = &self.fd_cache {
+            new_segment.use_fd_cache(cache.clone())?;
+        }
+        #[cfg(feature = "fail")]
+        fail::fail_point!("partition::segment_roll", |_| Err(Error::other(
+            "Failpoint partition::segment_roll"
+        )));
+        self.active_segment().seal()?;
+        self.segments.push(Arc::new(new_segment));
         self.active_segment_index += 1;
+        self.republish()?;
+        self.events.publish(PartitionEvent::SegmentRolled {
+            base_offset: latest_offset,
+        });
         Ok(self.active_segment())
     }
 }
+
+#[cfg(test)]
+mod partition_tests {
+    use super::header::HEADER_SIZE;
+    use super::record::{ControlType, Record};
+    use super::{
+        AppendInfo, Index, IndexInterval, Log, OffsetError, Partition, TimestampType, LOG_MAX_SIZE,
+    };
+    use std::fs;
+    use std::io::{ErrorKind, Seek, SeekFrom, Write};
+    use tempdir::TempDir;
+
+    fn generate(partition: &mut Partition, n: u64) {
+        for i in 0..n {
+            partition
+                .append_record(Some("key".into()), &i.to_be_bytes())
+                .unwrap();
+        }
+        partition.flush().unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_format() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        // Records in the format predating the version byte
+        let mut legacy = vec![];
+        for offset in 0..10u64 {
+            legacy.push(35);
+            legacy.extend_from_slice(&offset.to_be_bytes());
+            legacy.extend_from_slice(&1000u128.to_be_bytes());
+            legacy.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, offset as u8]);
+        }
+        fs::write(Log::path(tmp_dir.path(), 0), &legacy).unwrap();
+        fs::File::create(Index::path(tmp_dir.path(), 0)).unwrap();
+
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.find_record(7).unwrap().value, &[7]);
+        assert!(!partition.segments[0].is_current_format().unwrap());
+
+        assert_eq!(partition.upgrade_format().unwrap(), 1);
+
+        assert!(partition.segments[0].is_current_format().unwrap());
+        assert_eq!(partition.upgrade_format().unwrap(), 0);
+        let records = partition.read_range(0, 10).unwrap();
+        assert_eq!(records.len(), 10);
+        assert!(records.iter().all(|r| r.timestamp == 1000));
+        generate(&mut partition, 10);
+        assert_eq!(partition.find_record(12).unwrap().offset, 12);
+        let log = fs::read(Log::path(tmp_dir.path(), 0)).unwrap();
+        assert_eq!(&log[..4], b"SHJL");
+        assert_eq!(&log[HEADER_SIZE..HEADER_SIZE + 2], &[35, 3]);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_index() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        generate(&mut partition, 60);
+        drop(partition);
+        let index_path = Index::path(tmp_dir.path(), 0);
+        let mut content = fs::read(&index_path).unwrap();
+        content[HEADER_SIZE + 4..HEADER_SIZE + 8].copy_from_slice(&[0xff; 4]);
+        fs::write(&index_path, content).unwrap();
+
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        for offset in 0..60 {
+            assert_eq!(partition.find_record(offset).unwrap().offset, offset);
+        }
+        assert_ne!(
+            &fs::read(&index_path).unwrap()[HEADER_SIZE + 4..HEADER_SIZE + 8],
+            &[0xff; 4]
+        );
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_index_interval_bytes() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.set_index_interval(IndexInterval::Bytes(512));
+        for i in 0..100u8 {
+            partition.append_record(None, &[i; 100]).unwrap();
+        }
+        partition.flush().unwrap();
+        for offset in 0..100 {
+            assert_eq!(
+                partition.find_record(offset).unwrap().value,
+                [offset as u8; 100]
+            );
+        }
+        drop(partition);
+
+        // An entry every few records instead of one every OFFSET_INTERVAL
+        let index_path = Index::path(tmp_dir.path(), 0);
+        let index = fs::read(&index_path).unwrap();
+        let footer = &index[index.len() - 20..];
+        let entries = u32::from_be_bytes(footer[..4].try_into().unwrap());
+        assert!(entries > 4);
+
+        // The index covers the whole log and is not rebuilt
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(fs::read(&index_path).unwrap(), index);
+        assert_eq!(partition.find_record(3).unwrap().value, [3; 100]);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_merge_segments() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        generate(&mut partition, 500);
+        let segments = partition.segments.len();
+        assert!(segments > 4);
+
+        let removed = partition.merge_segments(LOG_MAX_SIZE * 2).unwrap();
+
+        assert!(removed > 0);
+        assert_eq!(partition.segments.len(), segments - removed);
+        assert_eq!(partition.active_segment_index, partition.segments.len() - 1);
+        assert!(!tmp_dir.path().join(".merge").exists());
+        for offset in 0..500 {
+            let record = partition.find_record(offset).unwrap();
+            assert_eq!(record.offset, offset);
+            assert_eq!(record.value, offset.to_be_bytes());
+        }
+
+        generate(&mut partition, 10);
+        drop(partition);
+        // A log and an index per segment, and a key filter and time bounds per
+        // sealed one
+        let files = fs::read_dir(tmp_dir.path()).unwrap().count();
+        assert_eq!(files, (segments - removed) * 4 - 2);
+
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.segments.len(), segments - removed);
+        for offset in 0..510 {
+            assert_eq!(partition.find_record(offset).unwrap().offset, offset);
+        }
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_foreign_files() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        generate(&mut partition, 10);
+        partition.compact(None).unwrap();
+        drop(partition);
+        fs::write(tmp_dir.path().join(".DS_Store"), b"").unwrap();
+        fs::write(tmp_dir.path().join("notes.log"), b"").unwrap();
+        fs::create_dir(tmp_dir.path().join("lost+found")).unwrap();
+
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+
+        assert_eq!(partition.end_offset(), 10);
+        let unknown: Vec<_> = partition
+            .unknown_files()
+            .iter()
+            .map(|p| p.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(unknown, [".DS_Store", "lost+found", "notes.log"]);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_unwritten_segment() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        generate(&mut partition, 10);
+        drop(partition);
+
+        // Segments rolled right before a crash, with a header lost or never written
+        fs::write(Log::path(tmp_dir.path(), 10), [0; LOG_MAX_SIZE]).unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.end_offset(), 10);
+        partition.append_record(None, b"after").unwrap();
+        assert_eq!(partition.find_record(10).unwrap().value, b"after");
+        drop(partition);
+        fs::File::create(Log::path(tmp_dir.path(), 11)).unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.end_offset(), 11);
+        assert_eq!(partition.read_range(0, 11).unwrap().len(), 11);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_destroy() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let dir = tmp_dir.path().join("events.0");
+        fs::create_dir(&dir).unwrap();
+        let mut partition = Partition::open(&dir).unwrap();
+        generate(&mut partition, 500);
+        let snapshot = partition.snapshot().unwrap();
+        // Leftovers of `events.1`, had its extension been replaced
+        let sibling = tmp_dir.path().join("events.deleted");
+        fs::create_dir(&sibling).unwrap();
+        fs::write(sibling.join("leftover"), b"").unwrap();
+
+        partition.destroy().unwrap();
+
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 1);
+        assert!(sibling.join("leftover").exists());
+        assert_eq!(snapshot.read_range(0, 500).unwrap().len(), 500);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_during_merge() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        generate(&mut partition, 500);
+        let segments = partition.segments.len();
+        let snapshot = partition.snapshot().unwrap();
+
+        let removed = partition.merge_segments(LOG_MAX_SIZE * 2).unwrap();
+        assert!(removed > 0);
+        generate(&mut partition, 10);
+
+        assert_eq!(snapshot.start_offset(), 0);
+        assert_eq!(snapshot.end_offset(), 500);
+        let records = snapshot.read_range(0, 1000).unwrap();
+        assert_eq!(records.len(), 500);
+        for (offset, record) in records.iter().enumerate() {
+            assert_eq!(record.value, (offset as u64).to_be_bytes());
+        }
+        // Covered segments are removed once the snapshot is gone
+        let files = || fs::read_dir(tmp_dir.path()).unwrap().count();
+        assert_eq!(files(), segments * 4 - 2);
+        drop(snapshot);
+        assert_eq!(files(), (segments - removed) * 4 - 2);
+        assert_eq!(partition.read_range(0, 510).unwrap().len(), 510);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_max_record_size() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.append_record(None, &[1; 4000]).unwrap();
+        // Too big for the space left, rolled to a new segment
+        partition.append_record(None, &[2; 1000]).unwrap();
+        // Bigger than a whole segment, gets one sized to fit it
+        partition
+            .append_record(None, &[3; LOG_MAX_SIZE * 2])
+            .unwrap();
+        assert_eq!(partition.segments.len(), 3);
+
+        partition.set_max_record_size(LOG_MAX_SIZE);
+        let err = partition
+            .append_record(None, &[4; LOG_MAX_SIZE])
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        partition.append_record(None, &[5; 10]).unwrap();
+
+        assert_eq!(
+            partition.find_record(2).unwrap().value.len(),
+            LOG_MAX_SIZE * 2
+        );
+        assert_eq!(partition.find_record(3).unwrap().value, &[5; 10]);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_append_replicated() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        generate(&mut partition, 3);
+        let batch = |offsets: &[u64]| -> Vec<Record> {
+            offsets
+                .iter()
+                .map(|&o| Record::with_timestamp(o, 1000 + o, None, vec![o as u8]))
+                .collect()
+        };
+        let offset_error = |offsets: &[u64]| {
+            let mut partition = Partition::open(tmp_dir.path()).unwrap();
+            let err = partition.append_replicated(&batch(offsets)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert_eq!(partition.end_offset(), 3);
+            *err.into_inner().unwrap().downcast::<OffsetError>().unwrap()
+        };
+        drop(partition);
+
+        assert_eq!(
+            offset_error(&[4, 5]),
+            OffsetError::Gap {
+                expected: 3,
+                found: 4
+            }
+        );
+        assert_eq!(
+            offset_error(&[3, 5]),
+            OffsetError::Gap {
+                expected: 4,
+                found: 5
+            }
+        );
+        assert_eq!(
+            offset_error(&[3, 4, 4]),
+            OffsetError::NonMonotonic {
+                previous: 4,
+                found: 4
+            }
+        );
+
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(
+            partition.append_replicated(&batch(&[3, 4, 5])).unwrap(),
+            3..6
+        );
+        assert_eq!(partition.end_offset(), 6);
+        assert_eq!(partition.find_record(4).unwrap().timestamp, 1004);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_read_skipping_corrupt() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        for i in 0..500u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        partition.flush().unwrap();
+        // Clear the magic byte of the record at offset 20, through the file
+        let record_size = Record::new(0, None, vec![0; 8]).binary_size();
+        let mut log = fs::OpenOptions::new()
+            .write(true)
+            .open(Log::path(tmp_dir.path(), 0))
+            .unwrap();
+        log.seek(SeekFrom::Start((HEADER_SIZE + 20 * record_size) as u64))
+            .unwrap();
+        log.write_all(&[0]).unwrap();
+
+        assert!(partition.read_range(0, 500).is_err());
+        let (records, corrupt) = partition.read_range_skipping_corrupt(0, 500).unwrap();
+        assert_eq!(corrupt.len(), 1);
+        assert_eq!(corrupt[0].base_offset, 0);
+        assert_eq!(corrupt[0].begin, 20 * record_size);
+        let lost = (corrupt[0].end - corrupt[0].begin) / record_size;
+        assert!(lost > 0);
+        assert_eq!(records.len(), 500 - lost);
+        assert_eq!(records[20].offset, 20 + lost as u64);
+        assert_eq!(records.last().unwrap().offset, 499);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_append_info() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        generate(&mut partition, 3);
+
+        let appended = partition.append_record_at(42, None, b"value").unwrap();
+        assert_eq!(
+            appended,
+            AppendInfo {
+                offset: 3,
+                timestamp: 42
+            }
+        );
+        partition.set_timestamp_type(TimestampType::LogAppendTime);
+        let appended = partition.append_record_at(42, None, b"value").unwrap();
+        assert_eq!(appended.offset, 4);
+        assert_eq!(
+            partition.find_record(4).unwrap().timestamp,
+            appended.timestamp
+        );
+        assert!(appended.timestamp > 42);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_read_between() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        for i in 0..500u64 {
+            partition
+                .append_record_at(i * 10, None, &i.to_be_bytes())
+                .unwrap();
+        }
+        // Out of order timestamps widen the bounds of their segment
+        partition.append_record_at(5, None, b"late").unwrap();
+        assert!(partition.segments.len() > 2);
+        let sealed_latest = (partition.segments[1].base_offset - 1) * 10;
+        let bounds = partition.segments[0].time_bounds().unwrap();
+        assert_eq!(bounds, Some((0, sealed_latest)));
+
+        let records = partition.read_between(1995, 2040).unwrap();
+        let offsets: Vec<_> = records.iter().map(|r| r.offset).collect();
+        assert_eq!(offsets, [200, 201, 202, 203]);
+        assert_eq!(partition.read_between(0, 10).unwrap().len(), 2);
+        assert_eq!(partition.offset_for_timestamp(2001).unwrap(), Some(201));
+        assert_eq!(partition.offset_for_timestamp(5000).unwrap(), None);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_control_records() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        generate(&mut partition, 2);
+        partition
+            .append_control(ControlType::TransactionCommit, &[])
+            .unwrap();
+        generate(&mut partition, 1);
+
+        let records = partition.read_range(0, 4).unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.offset).collect::<Vec<_>>(),
+            vec![0, 1, 3]
+        );
+        let records = partition.read_range_with_control(0, 4).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(
+            records[2].control_type(),
+            Some(ControlType::TransactionCommit)
+        );
+        assert_eq!(
+            partition
+                .snapshot()
+                .unwrap()
+                .read_range(0, 4)
+                .unwrap()
+                .len(),
+            3
+        );
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_storage_full() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.set_max_size(1000);
+        let err = loop {
+            if let Err(e) = partition.append_record(None, &[0; 50]) {
+                break e;
+            }
+        };
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert!(partition.is_read_only());
+        let appended = partition.end_offset();
+        assert!(partition.size() <= 1000);
+
+        assert_eq!(
+            partition.append_record(None, &[0]).unwrap_err().kind(),
+            ErrorKind::StorageFull
+        );
+        assert_eq!(
+            partition.read_range(0, appended).unwrap().len() as u64,
+            appended
+        );
+
+        partition.set_max_size(2000);
+        partition.resume().unwrap();
+        partition.append_record(None, &[0; 50]).unwrap();
+        assert_eq!(partition.end_offset(), appended + 1);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_evict_oldest() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.set_max_size(3 * LOG_MAX_SIZE);
+        partition.set_evict_oldest(true);
+        for i in 0..2000u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        assert!(partition.size() <= 3 * LOG_MAX_SIZE);
+        assert!(partition.start_offset() > 0);
+        let start = partition.start_offset();
+        let records = partition.read_range(start, 2000).unwrap();
+        assert_eq!(records.len() as u64, 2000 - start);
+        assert_eq!(records.last().unwrap().value, 1999u64.to_be_bytes());
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+//! Observers of the records appended to a partition
+//!
+//! Observers registered with `Partition::on_append` are called in the appending
+//! thread, right after each record is written and before the append returns, in
+//! the order they were registered. They're meant to be cheap, maintaining metrics
+//! or an in-process secondary index for example, anything slower should hand the
+//! work off to another thread.
+use crate::partition::record::Record;
+use crate::partition::Partition;
+
+/// A record just appended to a partition
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Appended<'a> {
+    pub offset: u64,
+    /// The encoded size of the record, in bytes
+    pub size: usize,
+    pub key: Option<&'a [u8]>,
+}
+
+pub(crate) type AppendObserver = Box<dyn Fn(&Appended) + Send>;
+
+impl Partition {
+    /// Call `observer` with every record appended from now on, observers aren't
+    /// kept across a reopen
+    pub fn on_append(&mut self, observer: impl Fn(&Appended) + Send + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    pub(crate) fn notify_appended(&self, record: &Record) {
+        let appended = Appended {
+            offset: record.offset,
+            size: record.binary_size(),
+            key: record.key.as_deref(),
+        };
+        for observer in &self.observers {
+            observer(&appended);
+        }
+    }
+}
+
+#[cfg(test)]
+mod observer_tests {
+    use crate::partition::Partition;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_on_append() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.append_record(None, b"before").unwrap();
+        // A secondary index of the latest offset of every key
+        let latest = Arc::new(Mutex::new(HashMap::new()));
+        let index = Arc::clone(&latest);
+        partition.on_append(move |appended| {
+            if let Some(key) = appended.key {
+                index.lock().unwrap().insert(key.to_vec(), appended.offset);
+            }
+        });
+
+        for i in 0..10u64 {
+            let key = (i % 3).to_string();
+            partition
+                .append_record(Some(key.into()), &i.to_be_bytes())
+                .unwrap();
+        }
+        partition.append_record(None, b"no key").unwrap();
+
+        let latest = latest.lock().unwrap();
+        assert_eq!(latest.len(), 3);
+        assert_eq!(latest[&b"0"[..]], 10);
+        assert_eq!(latest[&b"2"[..]], 9);
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+//! Read only access to a partition owned by another process
+//!
+//! `Partition::open_read_only` maps the logs of a partition read only and nothing
+//! else: no file is created, extended or repaired, the index files aren't touched
+//! and the writer owning the partition keeps running undisturbed. The logs are
+//! decoded up to their first invalid record. Records carry no checksum and the
+//! writer publishes no size to other processes, a record it's still copying can be
+//! read half written. Only a partition its writer is done with reads consistently.
+//!
+//! The segments are listed when opening, the ones rolled afterwards aren't seen
+//! while those removed by the writer stay readable until closing.
+use crate::partition::header::{FileHeader, HEADER_SIZE, LOG_MAGIC};
+use crate::partition::record::{Record, MAGIC_BYTE};
+use crate::partition::{in_range, segment_name, Partition};
+use memmap2::Mmap;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+struct MappedLog {
+    base_offset: u64,
+    mmap: Mmap,
+    header_size: usize,
+}
+
+impl MappedLog {
+    fn open(path: &Path, base_offset: u64) -> Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        // Logs written before the introduction of headers start directly with a record
+        let header_size = match mmap.first() {
+            Some(&MAGIC_BYTE) | None => 0,
+            Some(_) => {
+                let header = mmap.get(..HEADER_SIZE).unwrap_or(&mmap);
+                FileHeader::validate(header, LOG_MAGIC, base_offset)?;
+                HEADER_SIZE
+            }
+        };
+        Ok(Self {
+            base_offset,
+            mmap,
+            header_size,
+        })
+    }
+
+    fn records(&self) -> Vec<Record> {
+        let mut slice = &self.mmap[self.header_size..];
+        let mut records = Vec::new();
+        while let Ok(record) = Record::from_slice(&mut slice) {
+            records.push(record);
+        }
+        records
+    }
+}
+
+/// A partition opened with `Partition::open_read_only`
+pub struct ReadOnlyPartition {
+    dir: PathBuf,
+    logs: Vec<MappedLog>,
+}
+
+impl ReadOnlyPartition {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn start_offset(&self) -> u64 {
+        self.logs
+            .iter()
+            .find_map(|log| log.records().first().map(|r| r.offset))
+            .unwrap_or_else(|| self.end_offset())
+    }
+
+    /// The offset following the latest record
+    pub fn end_offset(&self) -> u64 {
+        self.logs
+            .iter()
+            .rev()
+            .find_map(|log| log.records().last().map(|r| r.offset + 1))
+            .or_else(|| self.logs.last().map(|log| log.base_offset))
+            .unwrap_or(0)
+    }
+
+    /// Read all the records with an offset in the `[from, to)` range, control
+    /// records excluded
+    pub fn read_range(&self, from: u64, to: u64) -> Result<Vec<Record>> {
+        let mut records = Vec::new();
+        for (i, log) in self.logs.iter().enumerate() {
+            let next_base = self.logs.get(i + 1).map_or(u64::MAX, |l| l.base_offset);
+            if next_base <= from || log.base_offset >= to {
+                continue;
+            }
+            records.extend(in_range(log.records(), from, to, false)?);
+        }
+        Ok(records)
+    }
+
+    pub fn find_record(&self, offset: u64) -> Result<Record> {
+        let i = self
+            .logs
+            .partition_point(|log| log.base_offset <= offset)
+            .checked_sub(1);
+        i.and_then(|i| {
+            self.logs[i]
+                .records()
+                .into_iter()
+                .find(|r| r.offset == offset)
+        })
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Offset {} not found", offset)))?
+        .decompressed()
+    }
+}
+
+impl Partition {
+    /// Open the partition in `dir` for reading only, without writing anything to
+    /// the directory, see `read_only`
+    pub fn open_read_only(dir: impl AsRef<Path>) -> Result<ReadOnlyPartition> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut logs = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "log") {
+                continue;
+            }
+            let Some(base_offset) = segment_name(&path).and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+            logs.push(MappedLog::open(&path, base_offset)?);
+        }
+        logs.sort_by_key(|log| log.base_offset);
+        Ok(ReadOnlyPartition { dir, logs })
+    }
+}
+
+#[cfg(test)]
+mod read_only_tests {
+    use crate::partition::Partition;
+    use std::fs;
+    use std::io::ErrorKind;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_open_read_only() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        for i in 0..500u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        let files = |dir| {
+            let mut files: Vec<_> = fs::read_dir(dir)
+                .unwrap()
+                .map(|e| {
+                    let e = e.unwrap();
+                    (e.file_name(), e.metadata().unwrap().len())
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        let before = files(tmp_dir.path());
+
+        // The writer keeps the partition open and appending
+        let reader = Partition::open_read_only(tmp_dir.path()).unwrap();
+        assert_eq!((reader.start_offset(), reader.end_offset()), (0, 500));
+        assert_eq!(reader.read_range(250, 260).unwrap().len(), 10);
+        assert_eq!(reader.find_record(499).unwrap().value, 499u64.to_be_bytes());
+        let err = reader.find_record(600).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(files(tmp_dir.path()), before);
+        partition.append_record(None, b"after").unwrap();
+        assert_eq!(reader.end_offset(), 501);
+
+        let empty = TempDir::new("test_tempdir").unwrap();
+        let reader = Partition::open_read_only(empty.path()).unwrap();
+        assert_eq!(reader.end_offset(), 0);
+        assert!(fs::read_dir(empty.path()).unwrap().next().is_none());
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! Reads running alongside the writer
+//!
+//! A `PartitionReader` reads the records of a partition from any thread while the
+//! partition keeps appending, taking no lock on the way. The partition publishes a
+//! view of its segments, the sealed ones are shared as is and the log of the
+//! active one is mapped a second time, read only. Every append publishes the size
+//! of the active log and the end offset through atomics of the view, readers never
+//! look past the published size.
+//!
+//! Rolling, merging or compacting segments publishes a new view, readers pick it up
+//! on their next read, holding the previous one until then.
+//!
+//! Each reader tracks where its latest read ended, a read starting right there is
+//! sequential and has the sealed segment holding the following records read ahead
+//! by the kernel, so catching up consumers don't wait on the disk at every read.
+use crate::partition::log::Log;
+use crate::partition::record::Record;
+use crate::partition::segment::Segment;
+use crate::partition::{read_segments, Partition};
+use memmap2::Mmap;
+use std::cmp::Ordering as OffsetOrdering;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+struct View {
+    generation: u64,
+    sealed: Vec<Arc<Segment>>,
+    active: Mmap,
+    header_size: usize,
+    active_size: AtomicUsize,
+    end_offset: AtomicU64,
+}
+
+impl View {
+    fn new(generation: u64, dir: &Path, sealed: &[Arc<Segment>], active: &Segment) -> Result<Self> {
+        let file = File::open(Log::path(dir, active.base_offset))?;
+        Ok(Self {
+            generation,
+            sealed: sealed.to_vec(),
+            active: unsafe { Mmap::map(&file)? },
+            header_size: active.log_header_size(),
+            active_size: AtomicUsize::new(active.size()),
+            end_offset: AtomicU64::new(active.latest_offset()),
+        })
+    }
+
+    /// The records of the active segment published so far
+    fn active_records(&self) -> Result<Vec<Record>> {
+        let size = self.active_size.load(Ordering::Acquire);
+        let mut slice = &self.active[self.header_size..self.header_size + size];
+        let mut records = Vec::new();
+        while !slice.is_empty() {
+            records.push(Record::from_slice(&mut slice)?);
+        }
+        Ok(records)
+    }
+}
+
+struct Shared {
+    generation: AtomicU64,
+    view: RwLock<Arc<View>>,
+}
+
+/// The writer side, held by the partition once a reader was asked for
+pub(crate) struct Publisher {
+    shared: Arc<Shared>,
+    view: Arc<View>,
+}
+
+impl Publisher {
+    /// Publish the bytes appended to the active segment
+    pub(crate) fn appended(&self, active: &Segment) {
+        self.view
+            .active_size
+            .store(active.size(), Ordering::Release);
+        self.view
+            .end_offset
+            .store(active.latest_offset(), Ordering::Release);
+    }
+
+    /// Publish a new set of segments
+    pub(crate) fn republish(
+        &mut self,
+        dir: &Path,
+        sealed: &[Arc<Segment>],
+        active: &Segment,
+    ) -> Result<()> {
+        let view = Arc::new(View::new(self.view.generation + 1, dir, sealed, active)?);
+        *self.shared.view.write().map_err(poisoned)? = Arc::clone(&view);
+        self.shared
+            .generation
+            .store(view.generation, Ordering::Release);
+        self.view = view;
+        Ok(())
+    }
+}
+
+fn poisoned<T>(_: T) -> Error {
+    Error::other("Partition view lock poisoned")
+}
+
+/// Handle reading a partition from another thread, see the module documentation
+#[derive(Clone)]
+pub struct PartitionReader {
+    shared: Arc<Shared>,
+    view: Arc<View>,
+    /// Where the latest read ended
+    next_offset: Option<u64>,
+    prefetches: usize,
+}
+
+impl PartitionReader {
+    /// Switch to the latest view, only if the partition published a new one
+    fn refresh(&mut self) -> Result<&View> {
+        if self.shared.generation.load(Ordering::Acquire) != self.view.generation {
+            let view = Arc::clone(&*self.shared.view.read().map_err(poisoned)?);
+            self.view = view;
+        }
+        Ok(&self.view)
+    }
+
+    /// The offset the next appended record will be assigned, as of the latest
+    /// published append
+    pub fn end_offset(&mut self) -> Result<u64> {
+        Ok(self.refresh()?.end_offset.load(Ordering::Acquire))
+    }
+
+    /// Read all the records with an offset in the `[from, to)` range, control
+    /// records excluded
+    pub fn read_range(&mut self, from: u64, to: u64) -> Result<Vec<Record>> {
+        let view = self.refresh()?;
+        let mut records = read_segments(&view.sealed, from, to, false)?;
+        for record in view.active_records()? {
+            if record.offset >= from && record.offset < to && !record.attributes.control() {
+                records.push(record.decompressed()?);
+            }
+        }
+        let next_offset = records.last().map_or(from, |r| r.offset + 1);
+        if self.next_offset == Some(from) {
+            self.prefetch(next_offset)?;
+        }
+        self.next_offset = Some(next_offset);
+        Ok(records)
+    }
+
+    /// The number of segments read ahead so far
+    pub fn prefetches(&self) -> usize {
+        self.prefetches
+    }
+
+    /// Read ahead the sealed segment holding `offset`, the active one is being
+    /// written and in memory already
+    fn prefetch(&mut self, offset: u64) -> Result<()> {
+        if let Some(segment) = self.view.sealed.iter().find(|s| s.latest_offset() > offset) {
+            segment.prefetch()?;
+            self.prefetches += 1;
+        }
+        Ok(())
+    }
+
+    pub fn find_record(&mut self, offset: u64) -> Result<Record> {
+        let view = self.refresh()?;
+        let sealed = view
+            .sealed
+            .binary_search_by(|s| s.base_offset.cmp(&offset).then(OffsetOrdering::Less))
+            .unwrap_err();
+        let record = match sealed.checked_sub(1).map(|i| &view.sealed[i]) {
+            Some(segment) if offset < segment.latest_offset() => segment.read_at(offset),
+            _ => view
+                .active_records()?
+                .into_iter()
+                .find(|r| r.offset == offset)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("No record at offset {}", offset),
+                    )
+                }),
+        }?;
+        record.decompressed()
+    }
+}
+
+impl Partition {
+    /// A handle reading the partition from other threads while it keeps appending
+    pub fn reader(&mut self) -> Result<PartitionReader> {
+        if self.publisher.is_none() {
+            let view = Arc::new(View::new(
+                0,
+                &self.dir,
+                &self.segments[..self.active_segment_index],
+                &self.segments[self.active_segment_index],
+            )?);
+            let shared = Arc::new(Shared {
+                generation: AtomicU64::new(0),
+                view: RwLock::new(Arc::clone(&view)),
+            });
+            self.publisher = Some(Publisher { shared, view });
+        }
+        let publisher = self.publisher.as_ref().expect("Publisher just set");
+        Ok(PartitionReader {
+            shared: Arc::clone(&publisher.shared),
+            view: Arc::clone(&publisher.view),
+            next_offset: None,
+            prefetches: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod reader_tests {
+    use crate::partition::Partition;
+    use std::thread;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_concurrent_reads() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.append_record(None, &0u64.to_be_bytes()).unwrap();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut reader = partition.reader().unwrap();
+                thread::spawn(move || {
+                    let mut seen = 0;
+                    while seen < 1000 {
+                        let end_offset = reader.end_offset().unwrap();
+                        let records = reader.read_range(0, end_offset).unwrap();
+                        // Whatever was published is there, in order
+                        assert_eq!(records.len() as u64, end_offset);
+                        for (offset, record) in records.iter().enumerate() {
+                            assert_eq!(record.value, (offset as u64).to_be_bytes());
+                        }
+                        let last = reader.find_record(end_offset - 1).unwrap();
+                        assert_eq!(last.offset, end_offset - 1);
+                        seen = end_offset;
+                    }
+                })
+            })
+            .collect();
+        for i in 1..1000u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        partition.merge_segments(8192).unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let mut reader = partition.reader().unwrap();
+        assert_eq!(reader.read_range(0, 1000).unwrap().len(), 1000);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        for i in 0..500u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        let mut reader = partition.reader().unwrap();
+
+        reader.read_range(100, 110).unwrap();
+        reader.read_range(300, 310).unwrap();
+        assert_eq!(reader.prefetches(), 0);
+        // Sequential from here on
+        for from in (310..400).step_by(10) {
+            reader.read_range(from, from + 10).unwrap();
+        }
+        assert!(reader.prefetches() > 0);
+        tmp_dir.close().unwrap();
+    }
+}
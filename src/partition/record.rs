@@ -3,117 +3,439 @@
 //! A `Record` is formed by an offset, a timestamp and the content information
 //! defining the event. An event can be appended to a segment and persisted in a log file. It's
 //! the smallest abstractiion in the system.
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
-use chrono::{DateTime, NaiveDateTime, Utc};
+//!
+//! Every record starts with a magic byte followed by the version of its binary
+//! format, decoding dispatches on the version so older records stay readable.
+//! Since version 3 the version is followed by an attributes byte, flags and small
+//! enums are packed in it instead of requiring new format versions:
+//!
+//! ```text
+//! | headers (7) | control (6) | tombstone (5) | transactional (4) | timestamp type (3) | compression (2-0) |
+//! ```
+//!
+//! The headers flag is set on write when a record carries headers, they follow its
+//! value as a count and then the name and value of each:
+//!
+//! ```text
+//! | count (u16) | name size (u16) | name | value size (u32) | value | ...
+//! ```
+//!
+//! Control records carry protocol markers rather than user data, their key holds a
+//! version byte and the `ControlType`.
+//!
+//! Records are encoded by `shoju_codec`, usable without std by clients framing
+//! records of their own, decoding here also covers the previous versions.
+//!
+//! Length fields are not trusted when decoding, keys and values are read no bigger
+//! than a bound and only as far as the bytes actually are there, a corrupt length
+//! fails the decoding with `RecordError::CorruptRecord` instead of allocating it.
+use byteorder::{NetworkEndian, ReadBytesExt};
+use chrono::DateTime;
 use std::error::Error;
 use std::fmt;
-use std::io::{self, Error as IOError, ErrorKind, Read, Write};
-use std::mem::size_of;
+use std::io::{self, Error as IOError, Read, Write};
 
-const MAGIC_BYTE: u8 = 35;
+use shoju_codec::{Frame, HEADERS_FLAG};
+pub use shoju_codec::{MAGIC_BYTE, RECORD_VERSION};
+/// Records predating the attributes byte
+pub const NO_ATTRIBUTES_VERSION: u8 = 2;
+/// Records with a 16 bytes timestamp, the current version shrinks it to 8 bytes
+pub const WIDE_TIMESTAMP_VERSION: u8 = 1;
+/// Records written before the introduction of the version byte. The byte after
+/// their magic is the most significant one of the offset, always 0 below 2^56,
+/// that's why versioned formats start from 1.
+pub const LEGACY_VERSION: u8 = 0;
+/// Largest key plus value `Record::from_binary` decodes, whatever the lengths say
+pub const MAX_DECODED_SIZE: usize = 64 * 1024 * 1024;
 
 #[derive(Debug)]
 pub enum RecordError {
     MissingMagicByte,
+    UnsupportedVersion(u8),
+    /// A length field exceeding the bound or the bytes left to decode
+    CorruptRecord {
+        field: &'static str,
+        size: usize,
+    },
 }
 
 impl Error for RecordError {}
 
 impl fmt::Display for RecordError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Missing magic byte")
+        match self {
+            RecordError::MissingMagicByte => write!(f, "Missing magic byte"),
+            RecordError::UnsupportedVersion(v) => write!(f, "Unsupported record version {}", v),
+            RecordError::CorruptRecord { field, size } => {
+                write!(
+                    f,
+                    "Corrupt record, {} of {} bytes out of bounds",
+                    field, size
+                )
+            }
+        }
+    }
+}
+
+/// Compression codec of a record payload
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+const COMPRESSION_MASK: u8 = 0x07;
+const TIMESTAMP_TYPE_FLAG: u8 = 0x08;
+const TRANSACTIONAL_FLAG: u8 = 0x10;
+const TOMBSTONE_FLAG: u8 = 0x20;
+const CONTROL_FLAG: u8 = 0x40;
+const CONTROL_KEY_VERSION: u8 = 0;
+
+/// Kind of marker carried by a control record
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlType {
+    TransactionCommit,
+    TransactionAbort,
+    LeaderEpoch,
+}
+
+impl ControlType {
+    fn key(&self) -> Vec<u8> {
+        vec![CONTROL_KEY_VERSION, *self as u8]
+    }
+
+    fn from_key(key: &[u8]) -> Option<Self> {
+        match key {
+            [CONTROL_KEY_VERSION, 0] => Some(ControlType::TransactionCommit),
+            [CONTROL_KEY_VERSION, 1] => Some(ControlType::TransactionAbort),
+            [CONTROL_KEY_VERSION, 2] => Some(ControlType::LeaderEpoch),
+            _ => None,
+        }
+    }
+}
+
+/// Flags and settings of a record packed in a single byte
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Attributes(u8);
+
+impl Attributes {
+    pub fn from_byte(byte: u8) -> io::Result<Self> {
+        let attributes = Self(byte);
+        attributes.try_compression()?;
+        Ok(attributes)
+    }
+
+    pub fn byte(&self) -> u8 {
+        self.0
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.try_compression().unwrap_or_default()
+    }
+
+    pub fn with_compression(self, compression: Compression) -> Self {
+        Self((self.0 & !COMPRESSION_MASK) | compression as u8)
+    }
+
+    /// Whether the timestamp was set on append rather than by the producer
+    pub fn log_append_time(&self) -> bool {
+        self.0 & TIMESTAMP_TYPE_FLAG != 0
+    }
+
+    pub fn with_log_append_time(self, set: bool) -> Self {
+        self.with_flag(TIMESTAMP_TYPE_FLAG, set)
+    }
+
+    pub fn transactional(&self) -> bool {
+        self.0 & TRANSACTIONAL_FLAG != 0
+    }
+
+    pub fn with_transactional(self, set: bool) -> Self {
+        self.with_flag(TRANSACTIONAL_FLAG, set)
+    }
+
+    /// Whether the record marks the deletion of its key
+    pub fn tombstone(&self) -> bool {
+        self.0 & TOMBSTONE_FLAG != 0
+    }
+
+    pub fn with_tombstone(self, set: bool) -> Self {
+        self.with_flag(TOMBSTONE_FLAG, set)
+    }
+
+    /// Whether the record is a protocol marker instead of user data
+    pub fn control(&self) -> bool {
+        self.0 & CONTROL_FLAG != 0
+    }
+
+    pub fn with_control(self, set: bool) -> Self {
+        self.with_flag(CONTROL_FLAG, set)
+    }
+
+    fn with_flag(self, flag: u8, set: bool) -> Self {
+        if set {
+            Self(self.0 | flag)
+        } else {
+            Self(self.0 & !flag)
+        }
+    }
+
+    fn try_compression(&self) -> io::Result<Compression> {
+        match self.0 & COMPRESSION_MASK {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Gzip),
+            2 => Ok(Compression::Snappy),
+            3 => Ok(Compression::Lz4),
+            4 => Ok(Compression::Zstd),
+            c => Err(IOError::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown compression codec {}", c),
+            )),
+        }
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Record {
+    pub attributes: Attributes,
     pub offset: u64,
-    pub timestamp: u128,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
     pub key: Option<Vec<u8>>,
     pub value: Vec<u8>,
+    /// Metadata of the record alongside its value, like tracing context
+    pub headers: Vec<(String, Vec<u8>)>,
 }
 
 impl fmt::Display for Record {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let ts_secs = self.timestamp / 1000;
-        let ts_ns = (self.timestamp % 1000) * 1_000_000;
-        let dt = DateTime::<Utc>::from_naive_utc_and_offset(
-            NaiveDateTime::from_timestamp_opt(ts_secs.try_into().unwrap(), ts_ns as u32).unwrap(),
-            Utc,
-        );
-        write!(
-            f,
-            "{} - offset: {} ({} bytes)",
-            dt,
-            self.offset,
-            self.value.len()
-        )
+        // Producer timestamps may be out of the range of a date, shown as is
+        match i64::try_from(self.timestamp)
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)
+        {
+            Some(dt) => write!(f, "{}", dt)?,
+            None => write!(f, "{}ms", self.timestamp)?,
+        }
+        write!(f, " - offset: {} ({} bytes)", self.offset, self.value.len())
     }
 }
 
+/// Milliseconds elapsed since the unix epoch
+pub fn now_millis() -> u64 {
+    std::time::UNIX_EPOCH.elapsed().unwrap().as_millis() as u64
+}
+
 impl Record {
     pub fn new(offset: u64, key: Option<Vec<u8>>, value: Vec<u8>) -> Record {
+        Self::with_timestamp(offset, now_millis(), key, value)
+    }
+
+    /// A record carrying a timestamp assigned elsewhere, e.g. by the producer
+    pub fn with_timestamp(
+        offset: u64,
+        timestamp: u64,
+        key: Option<Vec<u8>>,
+        value: Vec<u8>,
+    ) -> Record {
         Self {
+            attributes: Attributes::default(),
             offset,
-            timestamp: std::time::UNIX_EPOCH.elapsed().unwrap().as_millis(),
+            timestamp,
             key,
             value,
+            headers: Vec::new(),
+        }
+    }
+
+    /// The value of the first header named `name`
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// A control record, skipped by normal reads
+    pub fn control(offset: u64, control_type: ControlType, value: Vec<u8>) -> Record {
+        let mut record = Self::new(offset, Some(control_type.key()), value);
+        record.attributes = record.attributes.with_control(true);
+        record
+    }
+
+    /// The marker type of a control record, `None` for user records
+    pub fn control_type(&self) -> Option<ControlType> {
+        if !self.attributes.control() {
+            return None;
+        }
+        self.key.as_deref().and_then(ControlType::from_key)
+    }
+
+    /// The record as encoded by the codec
+    pub fn frame(&self) -> Frame<'_> {
+        Frame {
+            attributes: self.attributes.byte(),
+            offset: self.offset,
+            timestamp: self.timestamp,
+            key: self.key.as_deref(),
+            value: &self.value,
+            headers: self
+                .headers
+                .iter()
+                .map(|(n, v)| (n.as_str(), v.as_slice()))
+                .collect(),
         }
     }
 
     pub fn binary_size(&self) -> usize {
-        size_of::<u8>()
-            + size_of::<u64>()
-            + size_of::<u128>()
-            + size_of::<u32>()
-            + self.value.len()
-            + size_of::<u32>()
-            + self.key.as_ref().map_or(0, |k| k.len())
+        self.frame().encoded_size()
     }
 
     pub fn write(&self, buf: &mut impl Write) -> io::Result<usize> {
-        buf.write_u8(MAGIC_BYTE)?;
-        buf.write_u64::<NetworkEndian>(self.offset)?;
-        buf.write_u128::<NetworkEndian>(self.timestamp)?;
-        match &self.key {
-            Some(k) => {
-                buf.write_u32::<NetworkEndian>(k.len() as u32)?;
-                buf.write_all(&k)?;
-            }
-            None => buf.write_u32::<NetworkEndian>(0)?,
-        };
-        buf.write_u32::<NetworkEndian>(self.value.len() as u32)?;
-        buf.write_all(&self.value)?;
-        Ok(self.binary_size())
+        let mut bytes = Vec::new();
+        let size = self
+            .frame()
+            .encode(&mut bytes)
+            .map_err(|e| IOError::new(io::ErrorKind::InvalidInput, e))?;
+        buf.write_all(&bytes)?;
+        Ok(size)
     }
 
     pub fn from_binary(buf: &mut impl Read) -> io::Result<Self> {
+        Self::from_binary_bounded(buf, MAX_DECODED_SIZE)
+    }
+
+    /// Decode a record from the bytes of a segment, its key and value can't be
+    /// bigger than what's left of them
+    pub fn from_slice(buf: &mut &[u8]) -> io::Result<Self> {
+        let remaining = buf.len();
+        Self::from_binary_bounded(buf, remaining)
+    }
+
+    /// Decode a record whose key plus value take at most `max_size` bytes
+    pub fn from_binary_bounded(buf: &mut impl Read, max_size: usize) -> io::Result<Self> {
+        Self::read_versioned(buf, max_size).map(|(record, _)| record)
+    }
+
+    /// Decode a record returning the version of the format it was written with
+    pub fn from_binary_versioned(buf: &mut impl Read) -> io::Result<(Self, u8)> {
+        Self::read_versioned(buf, MAX_DECODED_SIZE)
+    }
+
+    fn read_versioned(buf: &mut impl Read, max_size: usize) -> io::Result<(Self, u8)> {
         let magic_byte = buf.read_u8()?;
         if magic_byte != MAGIC_BYTE {
-            return Err(IOError::new(
-                ErrorKind::Other,
-                RecordError::MissingMagicByte,
-            ));
+            return Err(IOError::other(RecordError::MissingMagicByte));
         }
-        let offset = buf.read_u64::<NetworkEndian>()?;
+        let version = buf.read_u8()?;
+        let attributes = match version {
+            RECORD_VERSION => Attributes::from_byte(buf.read_u8()?)?,
+            _ => Attributes::default(),
+        };
+        let (offset, timestamp) = match version {
+            LEGACY_VERSION => (
+                buf.read_uint::<NetworkEndian>(7)?,
+                Self::read_wide_timestamp(buf)?,
+            ),
+            WIDE_TIMESTAMP_VERSION => (
+                buf.read_u64::<NetworkEndian>()?,
+                Self::read_wide_timestamp(buf)?,
+            ),
+            NO_ATTRIBUTES_VERSION | RECORD_VERSION => (
+                buf.read_u64::<NetworkEndian>()?,
+                buf.read_u64::<NetworkEndian>()?,
+            ),
+            v => return Err(IOError::other(RecordError::UnsupportedVersion(v))),
+        };
+        let mut record = Self::read_fields(buf, offset, timestamp, max_size)?;
+        if attributes.0 & HEADERS_FLAG != 0 {
+            let max_size = max_size - record.key.as_ref().map_or(0, Vec::len) - record.value.len();
+            record.headers = Self::read_headers(buf, max_size)?;
+        }
+        // The flag only tells the headers apart on disk
+        record.attributes = attributes.with_flag(HEADERS_FLAG, false);
+        Ok((record, version))
+    }
+
+    /// Read a field of `size` bytes, growing the buffer with the bytes read rather
+    /// than allocating the size upfront
+    fn read_field(
+        buf: &mut impl Read,
+        field: &'static str,
+        size: u32,
+        max_size: usize,
+    ) -> io::Result<Vec<u8>> {
+        let size = size as usize;
+        let corrupt = || {
+            IOError::new(
+                io::ErrorKind::InvalidData,
+                RecordError::CorruptRecord { field, size },
+            )
+        };
+        if size > max_size {
+            return Err(corrupt());
+        }
+        let mut bytes = Vec::new();
+        buf.take(size as u64).read_to_end(&mut bytes)?;
+        if bytes.len() < size {
+            return Err(corrupt());
+        }
+        Ok(bytes)
+    }
+
+    fn read_headers(
+        buf: &mut impl Read,
+        mut max_size: usize,
+    ) -> io::Result<Vec<(String, Vec<u8>)>> {
+        let count = buf.read_u16::<NetworkEndian>()?;
+        let mut headers = Vec::new();
+        for _ in 0..count {
+            let name_size = buf.read_u16::<NetworkEndian>()?;
+            let name = Self::read_field(buf, "header name", name_size as u32, max_size)?;
+            max_size -= name.len();
+            let name = String::from_utf8(name).map_err(|_| {
+                IOError::new(io::ErrorKind::InvalidData, "Header name not valid UTF-8")
+            })?;
+            let value_size = buf.read_u32::<NetworkEndian>()?;
+            let value = Self::read_field(buf, "header value", value_size, max_size)?;
+            max_size -= value.len();
+            headers.push((name, value));
+        }
+        Ok(headers)
+    }
+
+    fn read_wide_timestamp(buf: &mut impl Read) -> io::Result<u64> {
         let timestamp = buf.read_u128::<NetworkEndian>()?;
+        timestamp
+            .try_into()
+            .map_err(|_| IOError::new(io::ErrorKind::InvalidData, "Timestamp out of range"))
+    }
+
+    fn read_fields(
+        buf: &mut impl Read,
+        offset: u64,
+        timestamp: u64,
+        max_size: usize,
+    ) -> io::Result<Self> {
         let key_size = buf.read_u32::<NetworkEndian>()?;
         let key_binary = if key_size > 0 {
-            let mut key_b = vec![0u8; key_size as usize];
-            buf.read_exact(&mut key_b)?;
-            Some(key_b)
+            Some(Self::read_field(buf, "key", key_size, max_size)?)
         } else {
             None
         };
+        let max_size = max_size - key_binary.as_ref().map_or(0, Vec::len);
         let value_size = buf.read_u32::<NetworkEndian>()?;
-        let mut payload_binary = vec![0u8; value_size as usize];
-        buf.read_exact(&mut payload_binary)?;
-        Ok(Self {
+        let payload_binary = Self::read_field(buf, "value", value_size, max_size)?;
+        Ok(Self::with_timestamp(
             offset,
             timestamp,
-            key: key_binary,
-            value: payload_binary,
-        })
+            key_binary,
+            payload_binary,
+        ))
     }
 }
 
@@ -132,10 +454,24 @@ mod record_tests {
         );
     }
 
+    #[test]
+    fn test_display() {
+        let record = Record::with_timestamp(7, 1500, None, b"value".to_vec());
+        assert_eq!(
+            record.to_string(),
+            "1970-01-01 00:00:01.500 UTC - offset: 7 (5 bytes)"
+        );
+        let record = Record::with_timestamp(7, u64::MAX, None, b"value".to_vec());
+        assert_eq!(
+            record.to_string(),
+            "18446744073709551615ms - offset: 7 (5 bytes)"
+        );
+    }
+
     #[test]
     fn test_binary_size() {
         let record = Record::new(0, Some("test_key".into()), "test_value".into());
-        assert_eq!(record.binary_size(), 51);
+        assert_eq!(record.binary_size(), 45);
     }
 
     #[test]
@@ -146,5 +482,111 @@ mod record_tests {
         let mut reader = BufReader::new(&buffer[..]);
         let expected = Record::from_binary(&mut reader).unwrap();
         assert_eq!(record, expected,);
+        assert_eq!(&buffer[..3], &[MAGIC_BYTE, RECORD_VERSION, 0]);
+    }
+
+    #[test]
+    fn test_attributes() {
+        let attributes = Attributes::default()
+            .with_compression(Compression::Zstd)
+            .with_tombstone(true)
+            .with_log_append_time(true);
+        assert_eq!(attributes.byte(), 0x2c);
+        assert_eq!(attributes.compression(), Compression::Zstd);
+        assert!(attributes.tombstone() && attributes.log_append_time());
+        assert!(!attributes.transactional());
+        assert!(!attributes.with_tombstone(false).tombstone());
+
+        let mut record = Record::new(3, None, "value".into());
+        record.attributes = attributes;
+        let mut buffer = vec![];
+        record.write(&mut buffer).unwrap();
+        assert_eq!(Record::from_binary(&mut &buffer[..]).unwrap(), record);
+
+        let control = Record::control(4, ControlType::LeaderEpoch, vec![0, 0, 0, 7]);
+        assert_eq!(control.control_type(), Some(ControlType::LeaderEpoch));
+        assert_eq!(record.control_type(), None);
+
+        buffer[2] = 0x07;
+        assert!(Record::from_binary(&mut &buffer[..]).is_err());
+    }
+
+    #[test]
+    fn test_from_binary_legacy() {
+        let mut buffer = vec![MAGIC_BYTE, 0, 0, 0, 0, 0, 0, 0, 12];
+        buffer.extend_from_slice(&1000u128.to_be_bytes());
+        buffer.extend_from_slice(&[0, 0, 0, 1, b'k', 0, 0, 0, 2, b'v', b'1']);
+        let (record, version) = Record::from_binary_versioned(&mut &buffer[..]).unwrap();
+        assert_eq!(version, LEGACY_VERSION);
+        assert_eq!(record.offset, 12);
+        assert_eq!(record.timestamp, 1000);
+        assert_eq!(record.key, Some("k".into()));
+        assert_eq!(record.value, b"v1");
+    }
+
+    #[test]
+    fn test_from_binary_wide_timestamp() {
+        let mut buffer = vec![MAGIC_BYTE, WIDE_TIMESTAMP_VERSION];
+        buffer.extend_from_slice(&12u64.to_be_bytes());
+        buffer.extend_from_slice(&1000u128.to_be_bytes());
+        buffer.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, b'v', b'1']);
+        let (record, version) = Record::from_binary_versioned(&mut &buffer[..]).unwrap();
+        assert_eq!(version, WIDE_TIMESTAMP_VERSION);
+        assert_eq!(record.offset, 12);
+        assert_eq!(record.timestamp, 1000);
+        assert_eq!(record.key, None);
+        assert_eq!(record.value, b"v1");
+    }
+
+    #[test]
+    fn test_from_binary_unsupported_version() {
+        let buffer = [MAGIC_BYTE, 200, 0, 0, 0, 0, 0, 0, 0, 0];
+        let err = Record::from_binary(&mut &buffer[..]).unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported record version 200");
+    }
+
+    #[test]
+    fn test_from_binary_corrupt_length() {
+        let record = Record::new(3, Some("key".into()), "value".into());
+        let mut buffer = Vec::new();
+        record.write(&mut buffer).unwrap();
+        assert_eq!(Record::from_slice(&mut &buffer[..]).unwrap(), record);
+
+        // A value length of 4 GB, with 5 bytes to read
+        let value_size = buffer.len() - 9;
+        buffer[value_size..value_size + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = Record::from_binary(&mut &buffer[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Corrupt record, value of 4294967295 bytes out of bounds"
+        );
+        // Within the bound but past the bytes left
+        buffer[value_size..value_size + 4].copy_from_slice(&1000u32.to_be_bytes());
+        assert!(Record::from_slice(&mut &buffer[..]).is_err());
+        let err = Record::from_binary_bounded(&mut &buffer[..], 2000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut record = Record::new(3, None, "value".into());
+        let mut plain = Vec::new();
+        record.write(&mut plain).unwrap();
+        record.headers = vec![
+            ("traceparent".into(), b"00-01".to_vec()),
+            ("empty".into(), vec![]),
+        ];
+        let mut buffer = Vec::new();
+        assert_eq!(record.write(&mut buffer).unwrap(), buffer.len());
+
+        assert_eq!(buffer[2], HEADERS_FLAG);
+        assert_eq!(buffer[..plain.len()][3..], plain[3..]);
+        let decoded = Record::from_slice(&mut &buffer[..]).unwrap();
+        assert_eq!(decoded, record);
+        assert_eq!(decoded.header("traceparent"), Some(&b"00-01"[..]));
+        assert_eq!(decoded.header("missing"), None);
+        // A header value past the bytes left
+        assert!(Record::from_slice(&mut &buffer[..buffer.len() - 1]).is_err());
     }
 }
@@ -0,0 +1,67 @@
+//! Time based retention of a partition
+//!
+//! Sealed segments are deleted from the head of the partition once all of their
+//! records are older than the retention, the active segment is always kept.
+use crate::partition::Partition;
+use std::io::Result;
+use std::time::Duration;
+
+impl Partition {
+    /// Delete the oldest sealed segments whose records are all older than
+    /// `retention`, returning the number of segments deleted. Unlinking doesn't
+    /// write anything, unlike merges and compactions it isn't throttled.
+    pub fn delete_expired(&mut self, retention: Duration) -> Result<usize> {
+        let oldest_retained = self
+            .clock
+            .now_millis()
+            .saturating_sub(retention.as_millis() as u64);
+        let mut deleted = 0;
+        while self.active_segment_index > 0 {
+            let bounds = self.segments[0].time_bounds()?;
+            if bounds.is_some_and(|(_, latest)| latest >= oldest_retained) {
+                break;
+            }
+            self.remove_segment(0)?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod retention_tests {
+    use crate::partition::record::now_millis;
+    use crate::partition::Partition;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_delete_expired() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        let old = now_millis() - 2 * 3600 * 1000;
+        for i in 0..200u64 {
+            partition
+                .append_record_at(old, None, &i.to_be_bytes())
+                .unwrap();
+        }
+        let recent = partition.end_offset();
+        for i in 0..200u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+
+        let deleted = partition.delete_expired(Duration::from_secs(3600)).unwrap();
+
+        assert!(deleted > 0);
+        assert!(partition.start_offset() > 0 && partition.start_offset() <= recent);
+        assert_eq!(partition.read_range(recent, 400).unwrap().len(), 200);
+        assert_eq!(
+            partition.delete_expired(Duration::from_secs(3600)).unwrap(),
+            0
+        );
+        drop(partition);
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        assert!(partition.start_offset() > 0);
+        tmp_dir.close().unwrap();
+    }
+}
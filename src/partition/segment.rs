@@ -1,76 +1,218 @@
-use crate::partition::index::Index;
+use crate::partition::bloom::KeyFilter;
+use crate::partition::fd_cache::FdCache;
+use crate::partition::index::{Index, IndexInterval};
 use crate::partition::log::Log;
-use crate::partition::record::Record;
-use crate::partition::LOG_MAX_SIZE;
-use std::path::Path;
+use crate::partition::record::{now_millis, Record, RECORD_VERSION};
+use crate::partition::stats::SegmentInfo;
+use crate::partition::{LOG_MAX_SIZE, MAX_RECORD_SIZE};
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, OnceLock};
 
 #[derive(Debug)]
 pub enum SegmentError {
     Io(std::io::Error),
     FullSegment,
+    /// The record encoded size exceeds the maximum allowed
+    RecordTooLarge(usize),
+}
+
+/// Bytes of a log skipped over as they don't decode to records
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorruptRange {
+    /// Base offset of the segment
+    pub base_offset: u64,
+    /// Positions in the log, from the first byte of the records
+    pub begin: usize,
+    pub end: usize,
+}
+
+/// Handles of the log and the index, held to sync them without reopening them
+#[derive(Debug)]
+enum SegmentFiles {
+    Open {
+        log: Arc<File>,
+        index: Arc<File>,
+    },
+    /// Opened on demand through a cache shared with other segments
+    Cached(Arc<FdCache>),
+}
+
+impl SegmentFiles {
+    fn open(base_dir: &Path, base_offset: u64) -> std::io::Result<Self> {
+        Ok(Self::Open {
+            log: Arc::new(File::open(Log::path(base_dir, base_offset))?),
+            index: Arc::new(File::open(Index::path(base_dir, base_offset))?),
+        })
+    }
 }
 
 #[derive(Debug)]
 pub struct Segment {
     log: Log,
     index: Index,
+    dir: PathBuf,
+    files: Mutex<SegmentFiles>,
     pub base_offset: u64,
+    /// Offset and log position of the latest index entry
     prev_offset: u64,
-    offset_interval: usize,
+    prev_position: usize,
+    index_interval: IndexInterval,
     active: bool,
+    max_record_size: usize,
+    /// Directory to remove the files from once the segment is dropped
+    retired: OnceLock<PathBuf>,
+    /// Time of the latest read, 0 once the pages of its files were released
+    last_read: AtomicU64,
+    /// Earliest and latest timestamps of the records, written when sealed
+    time_bounds: OnceLock<Option<(u64, u64)>>,
+    /// Filter of the keys of the records, written when sealed
+    key_filter: OnceLock<KeyFilter>,
 }
 
 impl Segment {
     pub fn new(
-        base_dir: &str,
+        base_dir: &Path,
         base_offset: u64,
         offset_interval: usize,
+        max_size: usize,
         active: bool,
     ) -> std::io::Result<Self> {
-        let path = Path::new(base_dir).to_path_buf();
-        let log = Log::new(&path, base_offset, LOG_MAX_SIZE)?;
-        let index = Index::new(&path, base_offset, offset_interval, LOG_MAX_SIZE / 2)?;
+        let path = base_dir.to_path_buf();
+        let log = Log::new(&path, base_offset, max_size)?;
+        let index = Index::new(&path, base_offset, max_size / 2)?;
         Ok(Self {
             log,
             index,
+            files: Mutex::new(SegmentFiles::open(&path, base_offset)?),
+            dir: path,
             base_offset,
             prev_offset: base_offset,
-            offset_interval,
+            prev_position: 0,
+            index_interval: IndexInterval::Records(offset_interval),
             active,
+            max_record_size: MAX_RECORD_SIZE,
+            retired: OnceLock::new(),
+            last_read: AtomicU64::new(now_millis()),
+            time_bounds: OnceLock::new(),
+            key_filter: OnceLock::new(),
         })
     }
 
+    /// Load an existing segment, a `clean` one as recorded at a clean shutdown
+    /// skips reading its records to find where the log ends
     pub fn load_from_disk(
-        base_dir: &str,
+        base_dir: &Path,
         base_offset: u64,
         offset_interval: usize,
         active: bool,
+        clean: Option<&SegmentInfo>,
     ) -> std::io::Result<Self> {
-        let path = Path::new(base_dir).to_path_buf();
-        let log = Log::load_from_disk(&path, base_offset, LOG_MAX_SIZE)?;
+        let path = base_dir.to_path_buf();
+        let log = match clean {
+            Some(info) => {
+                Log::load_clean(&path, base_offset, LOG_MAX_SIZE, info.size, info.end_offset)?
+            }
+            None => Log::load_from_disk(&path, base_offset, LOG_MAX_SIZE)?,
+        };
         let latest_offset = log.current_offset;
-        let prev_offset = match latest_offset {
-            0 => 0,
-            n if n < offset_interval as u64 => n,
-            n if n % offset_interval as u64 == 0 => n - offset_interval as u64,
-            n => n - (n % offset_interval as u64),
+        let index = match Index::load_from_disk(
+            &path,
+            base_offset,
+            latest_offset,
+            offset_interval,
+            log.max_size() / 2,
+            !log.has_header(),
+        ) {
+            // An index flushed along with a smaller log is missing the entries of the
+            // records appended after its latest flush
+            Ok(index) if log.has_header() && index.log_size() != log.size => {
+                Self::rebuild_index(&path, &log, base_offset, offset_interval)?
+            }
+            Ok(index) if index.check_positions(log.size).is_err() => {
+                Self::rebuild_index(&path, &log, base_offset, offset_interval)?
+            }
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                Self::rebuild_index(&path, &log, base_offset, offset_interval)?
+            }
+            index => index?,
         };
+        let last_entry = index.last_position();
+        let key_filter = OnceLock::new();
+        let time_bounds = OnceLock::new();
+        if !active {
+            if let Some(filter) = KeyFilter::read(&KeyFilter::path(&path, base_offset), log.size)? {
+                let _ = key_filter.set(filter);
+            }
+            if let Some(bounds) = read_time_bounds(&time_bounds_path(&path, base_offset), log.size)?
+            {
+                let _ = time_bounds.set(bounds);
+            }
+        }
         Ok(Self {
             log,
-            index: Index::load_from_disk(
-                &path,
-                base_offset,
-                latest_offset,
-                offset_interval,
-                LOG_MAX_SIZE / 2,
-            )?,
+            index,
+            files: Mutex::new(SegmentFiles::open(&path, base_offset)?),
+            dir: path,
             base_offset,
-            prev_offset,
-            offset_interval,
+            prev_offset: last_entry.map_or(base_offset, |p| base_offset + p.relative_offset as u64),
+            prev_position: last_entry.map_or(0, |p| p.position as usize),
+            index_interval: IndexInterval::Records(offset_interval),
             active,
+            max_record_size: MAX_RECORD_SIZE,
+            retired: OnceLock::new(),
+            last_read: AtomicU64::new(now_millis()),
+            time_bounds,
+            key_filter,
         })
     }
 
+    /// Whether the record at `offset` and `position` in the log gets an index entry,
+    /// given the latest one
+    fn needs_entry(
+        interval: IndexInterval,
+        (prev_offset, prev_position): (u64, usize),
+        offset: u64,
+        position: usize,
+    ) -> bool {
+        match interval {
+            IndexInterval::Records(records) => offset - prev_offset >= records as u64,
+            IndexInterval::Bytes(bytes) => position - prev_position >= bytes,
+        }
+    }
+
+    /// Whether `record`, appended next, gets an index entry
+    fn needs_entry_for(&self, record: &Record) -> bool {
+        let prev = (self.prev_offset, self.prev_position);
+        Self::needs_entry(self.index_interval, prev, record.offset, self.log.size)
+    }
+
+    /// Recreate the index from scratch by walking all the records of the log
+    fn rebuild_index(
+        path: &PathBuf,
+        log: &Log,
+        base_offset: u64,
+        offset_interval: usize,
+    ) -> std::io::Result<Index> {
+        let interval = IndexInterval::Records(offset_interval);
+        let mut index = Index::new(path, base_offset, log.max_size() / 2)?;
+        let mut slice = log.read_at(0, log.size)?;
+        let mut prev = (base_offset, 0);
+        while !slice.is_empty() {
+            let position = log.size - slice.len();
+            let record = Record::from_slice(&mut slice)?;
+            if Self::needs_entry(interval, prev, record.offset, position) {
+                index.append_position(record.offset as u32, position as u64)?;
+                prev = (record.offset, position);
+            }
+        }
+        index.flush(log.size)?;
+        Ok(index)
+    }
+
     pub fn latest_offset(&self) -> u64 {
         self.log.current_offset
     }
@@ -79,13 +221,113 @@ impl Segment {
         self.log.size
     }
 
-    pub fn seal(&mut self) {
+    /// The bytes of the log and the index, kept in memory once read
+    pub fn mapped_size(&self) -> usize {
+        self.log.size + self.index.size()
+    }
+
+    /// Time of the latest read, `None` if none happened since the pages of the
+    /// segment were released
+    pub fn last_read(&self) -> Option<u64> {
+        match self.last_read.load(AtomicOrdering::Relaxed) {
+            0 => None,
+            millis => Some(millis),
+        }
+    }
+
+    /// Drop the pages of the files from memory, they're read back from the files on
+    /// the next access
+    pub fn release_pages(&self) -> std::io::Result<()> {
+        self.log.release_pages()?;
+        self.index.release_pages()?;
+        self.last_read.store(0, AtomicOrdering::Relaxed);
+        Ok(())
+    }
+
+    /// Read the log ahead of an upcoming sequential read, in the background
+    pub fn prefetch(&self) -> std::io::Result<()> {
+        self.log.prefetch()
+    }
+
+    /// Hint that the log is about to be read whole
+    pub fn advise_sequential(&self) -> std::io::Result<()> {
+        self.log.advise_sequential()
+    }
+
+    /// Map the index for `random` access or the default one, indexes start random
+    pub fn advise_index(&self, random: bool) -> std::io::Result<()> {
+        self.index.advise(random)
+    }
+
+    fn touch(&self) {
+        self.last_read.store(now_millis(), AtomicOrdering::Relaxed);
+    }
+
+    /// Where the records start in the log file
+    pub fn log_header_size(&self) -> usize {
+        self.log.header_size()
+    }
+
+    pub fn set_max_record_size(&mut self, max_record_size: usize) {
+        self.max_record_size = max_record_size;
+    }
+
+    /// Index the records appended from now on every `interval`
+    pub fn set_index_interval(&mut self, interval: IndexInterval) {
+        self.index_interval = interval;
+    }
+
+    /// Stop appending to the segment and write its key filter and time bounds
+    pub fn seal(&mut self) -> std::io::Result<()> {
         self.active = false;
+        self.flush()?;
+        let records = self.records()?;
+        let filter = key_filter(&records);
+        filter.write(&KeyFilter::path(&self.dir, self.base_offset), self.log.size)?;
+        let bounds = time_bounds(&records);
+        let path = time_bounds_path(&self.dir, self.base_offset);
+        write_time_bounds(&path, self.log.size, bounds)?;
+        let _ = self.key_filter.set(filter);
+        let _ = self.time_bounds.set(bounds);
+        Ok(())
     }
 
     pub fn flush(&mut self) -> std::io::Result<()> {
         self.log.flush()?;
-        self.index.flush()
+        self.index.flush(self.log.size)
+    }
+
+    /// Bring the index footer up to date with the log, for the files to be synced
+    /// by the caller
+    pub fn prepare_sync(&mut self) -> std::io::Result<()> {
+        self.index.record_log_size(self.log.size)
+    }
+
+    /// Open the files through `cache` from now on, closing the ones held
+    pub fn use_fd_cache(&self, cache: Arc<FdCache>) -> std::io::Result<()> {
+        // Descriptors cached before a merge replaced the files are stale
+        cache.remove(&Log::path(&self.dir, self.base_offset));
+        cache.remove(&Index::path(&self.dir, self.base_offset));
+        *self.files.lock().map_err(poisoned)? = SegmentFiles::Cached(cache);
+        Ok(())
+    }
+
+    /// The open log and index files, to sync them
+    pub fn files(&self) -> std::io::Result<(Arc<File>, Arc<File>)> {
+        match &*self.files.lock().map_err(poisoned)? {
+            SegmentFiles::Open { log, index } => Ok((log.clone(), index.clone())),
+            SegmentFiles::Cached(cache) => Ok((
+                cache.get(&Log::path(&self.dir, self.base_offset))?,
+                cache.get(&Index::path(&self.dir, self.base_offset))?,
+            )),
+        }
+    }
+
+    /// Sync the data of the log and the index to disk
+    pub fn sync_data(&self) -> std::io::Result<()> {
+        let (log, index) = self.files()?;
+        log.sync_data()?;
+        index.sync_data()
     }
 
     pub fn append_record(
@@ -94,20 +336,39 @@ impl Segment {
         value: &[u8],
     ) -> Result<(), SegmentError> {
         let record = Record::new(self.latest_offset(), key, value.to_vec());
-        if !self.log.can_fit(record.binary_size()) {
+        self.append(&record)
+    }
+
+    /// Append an already formed record as is, preserving its offset and timestamp
+    pub fn append(&mut self, record: &Record) -> Result<(), SegmentError> {
+        if record.binary_size() > self.max_record_size {
+            Err(SegmentError::RecordTooLarge(record.binary_size()))
+        } else if !self.log.can_fit(record.binary_size()) {
+            Err(SegmentError::FullSegment)
+        } else if self.needs_entry_for(record) && !self.index.can_fit_entry() {
+            // Checked before writing, a record in the log but missing its index entry
+            // would still be there on reload
             Err(SegmentError::FullSegment)
         } else {
             let mut buffer = Vec::with_capacity(record.binary_size());
             record
                 .write(&mut buffer)
                 .map_err(|err| SegmentError::Io(err))?;
+            // Offsets are skipped when rewriting a compacted segment
+            self.log.current_offset = record.offset;
             match self.log.append_record(&buffer) {
-                Ok((last_offset, log_size)) => {
-                    if last_offset - self.prev_offset >= self.offset_interval as u64 {
+                Ok((last_offset, position)) => {
+                    #[cfg(feature = "fail")]
+                    fail::fail_point!("segment::log_appended", |_| Err(SegmentError::Io(
+                        std::io::Error::other("Failpoint segment::log_appended")
+                    )));
+                    let prev = (self.prev_offset, self.prev_position);
+                    if Self::needs_entry(self.index_interval, prev, last_offset, position) {
                         self.index
-                            .append_position(last_offset as u32, log_size)
+                            .append_position(last_offset as u32, position as u64)
                             .map_err(|err| SegmentError::Io(err))?;
                         self.prev_offset = last_offset;
+                        self.prev_position = position;
                     }
                     Ok(())
                 }
@@ -116,41 +377,324 @@ impl Segment {
         }
     }
 
-    pub fn read_at(&mut self, offset: u64) -> std::io::Result<Record> {
-        match self.index.find_offset(offset as u32) {
-            Ok(offset_range) => {
-                let begin_relative_offset = offset_range.begin.relative_offset;
-                let begin_position = offset_range.begin.position;
-                let begin = if begin_relative_offset as u64 > (offset - self.base_offset) {
-                    0
-                } else {
-                    begin_position as usize
-                };
-                let end = if offset_range.begin == offset_range.end {
-                    self.size()
-                } else {
-                    offset_range.end.position as usize
-                };
-                let mut slice = self.log.read_at(begin, end)?;
-
-                let mut offset_count = match offset {
-                    0 => 1,
-                    lesser if lesser < self.base_offset + begin_relative_offset as u64 => {
-                        lesser - self.base_offset + 1
-                    }
-                    equal if equal == self.base_offset + begin_relative_offset as u64 => 1,
-                    greater => (greater - self.base_offset - begin_relative_offset as u64) + 1,
-                };
-
-                let mut records: Vec<Record> = Vec::new();
-                while offset_count != 0 {
-                    let r = Record::from_binary(&mut slice)?;
-                    records.push(r);
-                    offset_count -= 1;
+    /// Decode every record stored in the segment, in offset order
+    pub fn records(&self) -> std::io::Result<Vec<Record>> {
+        self.touch();
+        let mut slice = self.log.read_at(0, self.size())?;
+        let mut records = Vec::new();
+        while !slice.is_empty() {
+            records.push(Record::from_slice(&mut slice)?);
+        }
+        Ok(records)
+    }
+
+    /// Decode the records stored in the segment, a record failing to decode is
+    /// skipped along with the following ones up to the next index entry
+    pub fn records_skipping_corrupt(&self) -> std::io::Result<(Vec<Record>, Vec<CorruptRange>)> {
+        self.touch();
+        let size = self.size();
+        let mut slice = self.log.read_at(0, size)?;
+        let mut records = Vec::new();
+        let mut corrupt = Vec::new();
+        while !slice.is_empty() {
+            let position = size - slice.len();
+            match Record::from_slice(&mut slice) {
+                Ok(record) => records.push(record),
+                Err(_) => {
+                    let end = self.index.next_position(position)?.unwrap_or(size);
+                    corrupt.push(CorruptRange {
+                        base_offset: self.base_offset,
+                        begin: position,
+                        end,
+                    });
+                    slice = self.log.read_at(end, size)?;
                 }
-                Ok(records.last().unwrap().clone())
             }
-            Err(e) => Err(e),
         }
+        Ok((records, corrupt))
+    }
+
+    /// The earliest and latest timestamps of the records, `None` if there's none.
+    /// A sealed segment has them written along with it, or reads its records once
+    /// for them, the active one reads them every time.
+    pub fn time_bounds(&self) -> std::io::Result<Option<(u64, u64)>> {
+        if let Some(bounds) = self.time_bounds.get() {
+            return Ok(*bounds);
+        }
+        let bounds = time_bounds(&self.records()?);
+        if !self.active {
+            let _ = self.time_bounds.set(bounds);
+        }
+        Ok(bounds)
+    }
+
+    /// Whether a record of the segment may have `key`, always for the active one
+    pub fn may_contain_key(&self, key: &[u8]) -> std::io::Result<bool> {
+        if self.active {
+            return Ok(true);
+        }
+        if let Some(filter) = self.key_filter.get() {
+            return Ok(filter.may_contain(key));
+        }
+        let filter = key_filter(&self.records()?);
+        Ok(self.key_filter.get_or_init(|| filter).may_contain(key))
+    }
+
+    /// The first record stored in the segment, if any
+    pub fn first_record(&self) -> std::io::Result<Option<Record>> {
+        let mut slice = self.log.read_at(0, self.size())?;
+        if slice.is_empty() {
+            return Ok(None);
+        }
+        Record::from_slice(&mut slice).map(Some)
+    }
+
+    /// The latest record stored in the segment, if any, decoding the records
+    /// following the latest index entry only
+    pub fn last_record(&self) -> std::io::Result<Option<Record>> {
+        let begin = self
+            .index
+            .last_position()
+            .map_or(0, |p| p.position as usize);
+        let mut slice = self.log.read_at(begin, self.size())?;
+        let mut last = None;
+        while !slice.is_empty() {
+            last = Some(Record::from_slice(&mut slice)?);
+        }
+        Ok(last)
+    }
+
+    /// Whether the files and all the records are written with the current binary format
+    pub fn is_current_format(&self) -> std::io::Result<bool> {
+        if !self.log.has_header() {
+            return Ok(false);
+        }
+        let mut slice = self.log.read_at(0, self.size())?;
+        while !slice.is_empty() {
+            let (_, version) = Record::from_binary_versioned(&mut slice)?;
+            if version != RECORD_VERSION {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Unmap the segment and remove its log, index and the files written when
+    /// sealed from `base_dir`
+    pub fn remove(self, base_dir: &Path) -> std::io::Result<()> {
+        let base_offset = self.base_offset;
+        drop(self);
+        fs::remove_file(Log::path(base_dir, base_offset))?;
+        fs::remove_file(Index::path(base_dir, base_offset))?;
+        for path in sealed_paths(base_dir, base_offset) {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark the segment files in `base_dir` to be removed once the last reference
+    /// to the segment is dropped, readers still holding it keep a valid view
+    pub fn retire(&self, base_dir: &Path) {
+        let _ = self.retired.set(base_dir.to_path_buf());
+    }
+
+    /// Read the record at `offset`, `ErrorKind::NotFound` if there's none, e.g.
+    /// it was removed by a compaction
+    pub fn read_at(&self, offset: u64) -> std::io::Result<Record> {
+        self.locate(offset).map(|(record, _)| record)
+    }
+
+    /// Read the record at `offset` like `read_at`, along with the number of records
+    /// decoded before it, past the index entry preceding it
+    pub(crate) fn locate(&self, offset: u64) -> std::io::Result<(Record, usize)> {
+        self.touch();
+        let offset_range = self.index.find_offset(offset as u32)?;
+        let begin = if offset_range.begin.relative_offset as u64 > offset - self.base_offset {
+            0
+        } else {
+            offset_range.begin.position as usize
+        };
+        let end = if offset_range.begin == offset_range.end {
+            self.size()
+        } else {
+            offset_range.end.position as usize
+        };
+        // The record an index entry points to must be the one it was added for
+        let mut entry_offset = (begin > 0).then_some(offset_range.begin.relative_offset as u64);
+        let mut slice = self.log.read_at(begin, end)?;
+        let mut scanned = 0;
+        while !slice.is_empty() {
+            let record = Record::from_slice(&mut slice)?;
+            if entry_offset
+                .take()
+                .is_some_and(|o| record.offset != self.base_offset + o)
+            {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Index entry doesn't point to its record",
+                ));
+            }
+            match record.offset.cmp(&offset) {
+                Ordering::Less => scanned += 1,
+                Ordering::Equal => return Ok((record, scanned)),
+                Ordering::Greater => break,
+            }
+        }
+        Err(std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("No record at offset {}", offset),
+        ))
+    }
+}
+
+/// The files written along with the log and the index when a segment is sealed,
+/// its key filter and time bounds
+pub(crate) fn sealed_paths(dir: &Path, base_offset: u64) -> [PathBuf; 2] {
+    [
+        KeyFilter::path(dir, base_offset),
+        time_bounds_path(dir, base_offset),
+    ]
+}
+
+fn key_filter(records: &[Record]) -> KeyFilter {
+    let keys: Vec<&[u8]> = records.iter().filter_map(|r| r.key.as_deref()).collect();
+    KeyFilter::new(keys.into_iter())
+}
+
+fn time_bounds(records: &[Record]) -> Option<(u64, u64)> {
+    records.iter().fold(None, |bounds, r| {
+        let (earliest, latest) = bounds.unwrap_or((r.timestamp, r.timestamp));
+        Some((earliest.min(r.timestamp), latest.max(r.timestamp)))
+    })
+}
+
+fn time_bounds_path(dir: &Path, base_offset: u64) -> PathBuf {
+    dir.join(format!("{:020}.timestamps", base_offset))
+}
+
+/// Write the time bounds of a log of `log_size` bytes to `path`, the size as a big
+/// endian u64 followed by the earliest and latest timestamps, if any
+fn write_time_bounds(
+    path: &Path,
+    log_size: usize,
+    bounds: Option<(u64, u64)>,
+) -> std::io::Result<()> {
+    let mut content = (log_size as u64).to_be_bytes().to_vec();
+    if let Some((earliest, latest)) = bounds {
+        content.extend_from_slice(&earliest.to_be_bytes());
+        content.extend_from_slice(&latest.to_be_bytes());
+    }
+    fs::write(path, content)
+}
+
+/// Read the time bounds at `path`, `None` if they're missing, truncated or were
+/// written for a log of another size than `log_size`
+fn read_time_bounds(path: &Path, log_size: usize) -> std::io::Result<Option<Option<(u64, u64)>>> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let Some((size, bounds)) = content.split_first_chunk::<8>() else {
+        return Ok(None);
+    };
+    if u64::from_be_bytes(*size) != log_size as u64 {
+        return Ok(None);
+    }
+    match bounds.split_first_chunk::<8>() {
+        None => Ok(Some(None)),
+        Some((earliest, latest)) => match <[u8; 8]>::try_from(latest) {
+            Ok(latest) => Ok(Some(Some((
+                u64::from_be_bytes(*earliest),
+                u64::from_be_bytes(latest),
+            )))),
+            Err(_) => Ok(None),
+        },
+    }
+}
+
+fn poisoned<T>(_: T) -> Error {
+    Error::other("Segment files lock poisoned")
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        if let Ok(SegmentFiles::Cached(cache)) = self.files.get_mut() {
+            cache.remove(&Log::path(&self.dir, self.base_offset));
+            cache.remove(&Index::path(&self.dir, self.base_offset));
+        }
+        if let Some(base_dir) = self.retired.get() {
+            let _ = fs::remove_file(Log::path(base_dir, self.base_offset));
+            let _ = fs::remove_file(Index::path(base_dir, self.base_offset));
+            for path in sealed_paths(base_dir, self.base_offset) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod segment_tests {
+    use super::{read_time_bounds, time_bounds_path, write_time_bounds, Segment, SegmentError};
+    use crate::partition::index::IndexInterval;
+    use crate::partition::record::Record;
+    use crate::partition::OFFSET_INTERVAL;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_full_index_tiny_bytes_interval() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut segment = Segment::new(tmp_dir.path(), 0, OFFSET_INTERVAL, 1000, true).unwrap();
+        segment.set_index_interval(IndexInterval::Bytes(1));
+        segment.append_record(None, b"first").unwrap();
+        segment.append_record(None, b"second").unwrap();
+        let mut position = segment.size() as u64;
+        while segment.index.can_fit_entry() {
+            segment.index.append_position(1, position).unwrap();
+            position += 1;
+        }
+        let size = segment.size();
+
+        // The log has room left, the index doesn't
+        assert!(matches!(
+            segment.append_record(None, b"third"),
+            Err(SegmentError::FullSegment)
+        ));
+        assert_eq!(segment.size(), size);
+        assert_eq!(segment.latest_offset(), 2);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_persisted_time_bounds() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut segment = Segment::new(tmp_dir.path(), 0, OFFSET_INTERVAL, 1000, true).unwrap();
+        for timestamp in [20, 10, 30] {
+            let record = Record::with_timestamp(segment.latest_offset(), timestamp, None, vec![]);
+            segment.append(&record).unwrap();
+        }
+        segment.seal().unwrap();
+        let size = segment.size();
+        drop(segment);
+        let load = || Segment::load_from_disk(tmp_dir.path(), 0, OFFSET_INTERVAL, false, None);
+        assert_eq!(load().unwrap().time_bounds().unwrap(), Some((10, 30)));
+
+        // The bounds written are the ones used rather than read from the records
+        let path = time_bounds_path(tmp_dir.path(), 0);
+        write_time_bounds(&path, size, Some((1, 2))).unwrap();
+        assert_eq!(load().unwrap().time_bounds().unwrap(), Some((1, 2)));
+        write_time_bounds(&path, size, None).unwrap();
+        assert_eq!(load().unwrap().time_bounds().unwrap(), None);
+
+        // Ones written for another log or truncated are ignored
+        write_time_bounds(&path, size + 1, Some((1, 2))).unwrap();
+        assert_eq!(load().unwrap().time_bounds().unwrap(), Some((10, 30)));
+        fs::write(&path, [&(size as u64).to_be_bytes()[..], &[0; 12]].concat()).unwrap();
+        assert_eq!(read_time_bounds(&path, size).unwrap(), None);
+        tmp_dir.close().unwrap();
     }
 }
@@ -0,0 +1,104 @@
+//! Clean shutdown of a partition
+//!
+//! `Partition::close` syncs every segment and records where each of their logs
+//! ends in a marker file. Opening the partition removes the marker right away and
+//! trusts it instead of reading every record to find the end of the logs, a
+//! partition not closed cleanly is scanned in full as before.
+use crate::partition::stats::SegmentInfo;
+use crate::partition::Partition;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+const MARKER_FILE: &str = ".clean_shutdown";
+
+/// Read and remove the marker left by a clean shutdown, no segment if there's
+/// none or it can't be decoded
+pub(crate) fn take_marker(dir: &Path) -> Result<Vec<SegmentInfo>> {
+    let path = dir.join(MARKER_FILE);
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    // Gone before anything gets appended, a stale marker would hide the appends
+    fs::remove_file(&path)?;
+    File::open(dir)?.sync_all()?;
+    Ok(serde_json::from_slice(&content).unwrap_or_default())
+}
+
+impl Partition {
+    /// Flush and sync every segment, then record their state so that the next
+    /// open skips reading the logs
+    pub fn close(mut self) -> Result<()> {
+        self.flush()?;
+        for segment in &self.segments {
+            segment.sync_data()?;
+        }
+        let content = serde_json::to_vec(&self.segments()).map_err(Error::other)?;
+        let tmp = self.dir.join(format!("{}.tmp", MARKER_FILE));
+        fs::write(&tmp, content)?;
+        File::open(&tmp)?.sync_data()?;
+        fs::rename(tmp, self.dir.join(MARKER_FILE))?;
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod shutdown_tests {
+    use super::MARKER_FILE;
+    use crate::partition::index::Index;
+    use crate::partition::log::Log;
+    use crate::partition::Partition;
+    use std::fs;
+    use tempdir::TempDir;
+
+    fn fill(partition: &mut Partition, n: u64) {
+        for i in 0..n {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_clean_shutdown() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        fill(&mut partition, 300);
+        let segments = partition.segments();
+        partition.close().unwrap();
+        assert!(tmp_dir.path().join(MARKER_FILE).exists());
+
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        assert!(!tmp_dir.path().join(MARKER_FILE).exists());
+        assert_eq!(partition.segments(), segments);
+        assert_eq!(partition.read_range(0, 300).unwrap().len(), 300);
+        fill(&mut partition, 10);
+        assert_eq!(partition.end_offset(), 310);
+        drop(partition);
+
+        // Without a marker the logs are scanned again
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.end_offset(), 310);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_inconsistent_marker() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        fill(&mut partition, 300);
+        partition.close().unwrap();
+        // A segment removed after the shutdown
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let removed = partition.segments().remove(0);
+        partition.close().unwrap();
+        fs::remove_file(Log::path(tmp_dir.path(), removed.base_offset)).unwrap();
+        fs::remove_file(Index::path(tmp_dir.path(), removed.base_offset)).unwrap();
+
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        assert_eq!(partition.start_offset(), removed.end_offset);
+        assert_eq!(partition.end_offset(), 300);
+        assert!(partition.segments()[0].size > 0);
+        tmp_dir.close().unwrap();
+    }
+}
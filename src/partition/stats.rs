@@ -0,0 +1,115 @@
+//! Introspection of a partition
+//!
+//! `PartitionStats` sums up the state of a partition and `SegmentInfo` describes
+//! each of its segments, without going through the files on disk.
+use crate::partition::Partition;
+use serde::{Deserialize, Serialize};
+use std::io::Result;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SegmentInfo {
+    pub base_offset: u64,
+    /// The offset the next record appended to the segment would be assigned
+    pub end_offset: u64,
+    /// The bytes of records stored
+    pub size: usize,
+    pub active: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartitionStats {
+    /// The bytes of records stored
+    pub size: usize,
+    /// The number of offsets assigned, an upper bound of the number of records as
+    /// compacted segments skip the offsets of the records removed
+    pub records: u64,
+    pub segments: usize,
+    pub start_offset: u64,
+    pub end_offset: u64,
+    /// Timestamps of the first and the latest record stored, `None` if empty
+    pub oldest_timestamp: Option<u64>,
+    pub newest_timestamp: Option<u64>,
+}
+
+impl Partition {
+    /// Describe every segment, in offset order
+    pub fn segments(&self) -> Vec<SegmentInfo> {
+        self.segments
+            .iter()
+            .enumerate()
+            .map(|(i, s)| SegmentInfo {
+                base_offset: s.base_offset,
+                end_offset: s.latest_offset(),
+                size: s.size(),
+                active: i == self.active_segment_index,
+            })
+            .collect()
+    }
+
+    /// Sum up the state of the partition, reading its first and latest record
+    pub fn stats(&self) -> Result<PartitionStats> {
+        let mut oldest_timestamp = None;
+        for segment in &self.segments {
+            if let Some(record) = segment.first_record()? {
+                oldest_timestamp = Some(record.timestamp);
+                break;
+            }
+        }
+        let mut newest_timestamp = None;
+        for segment in self.segments.iter().rev() {
+            if let Some(record) = segment.last_record()? {
+                newest_timestamp = Some(record.timestamp);
+                break;
+            }
+        }
+        Ok(PartitionStats {
+            size: self.size(),
+            records: self
+                .segments
+                .iter()
+                .map(|s| s.latest_offset() - s.base_offset)
+                .sum(),
+            segments: self.segments.len(),
+            start_offset: self.start_offset(),
+            end_offset: self.end_offset(),
+            oldest_timestamp,
+            newest_timestamp,
+        })
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use crate::partition::Partition;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_stats() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        let stats = partition.stats().unwrap();
+        assert_eq!((stats.records, stats.segments), (0, 1));
+        assert_eq!(stats.oldest_timestamp, None);
+
+        for i in 0..300u64 {
+            partition
+                .append_record_at(1000 + i, None, &i.to_be_bytes())
+                .unwrap();
+        }
+        let stats = partition.stats().unwrap();
+        let segments = partition.segments();
+        assert!(segments.len() > 1);
+        assert_eq!(stats.segments, segments.len());
+        assert_eq!(stats.size, segments.iter().map(|s| s.size).sum::<usize>());
+        assert_eq!((stats.start_offset, stats.end_offset), (0, 300));
+        assert_eq!(stats.records, 300);
+        assert_eq!(stats.oldest_timestamp, Some(1000));
+        assert_eq!(stats.newest_timestamp, Some(1299));
+        assert!(segments
+            .windows(2)
+            .all(|w| w[0].end_offset == w[1].base_offset));
+        assert!(segments.last().unwrap().active);
+        assert!(segments.iter().rev().skip(1).all(|s| !s.active));
+        tmp_dir.close().unwrap();
+    }
+}
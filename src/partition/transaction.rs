@@ -0,0 +1,162 @@
+//! Transactions of a single writer
+//!
+//! `Partition::append_transaction` appends records flagged transactional followed
+//! by a `TransactionCommit` control record, the marker committing them along with
+//! an opaque value of the writer. Records left without a marker by a crash are
+//! aborted with a `TransactionAbort` marker by `abort_open_transaction`.
+//!
+//! `fetch` returns transactional records as soon as appended, `fetch_committed`
+//! only once their marker commits them, dropping the aborted ones and stopping at
+//! a transaction still open.
+use crate::partition::record::{Compression, ControlType, Record};
+use crate::partition::Partition;
+use std::io::Result;
+use std::ops::Range;
+
+impl Partition {
+    /// Append `records` as a transaction committed by a marker holding `value`.
+    /// Returns the range of offsets of the records, the marker follows them.
+    pub fn append_transaction(
+        &mut self,
+        records: &[(Option<Vec<u8>>, Vec<u8>)],
+        value: &[u8],
+    ) -> Result<Range<u64>> {
+        let start = self.end_offset();
+        for (key, record_value) in records {
+            let timestamp = self.clock.now_millis();
+            let mut record =
+                self.produced_record(timestamp, key.clone(), record_value, Compression::None);
+            record.attributes = record.attributes.with_transactional(true);
+            self.append(&record)?;
+        }
+        let end = self.end_offset();
+        self.append_control(ControlType::TransactionCommit, value)?;
+        Ok(start..end)
+    }
+
+    /// Abort the transactional records following the latest marker, the ones of a
+    /// writer stopped before committing. Returns whether there was any.
+    pub fn abort_open_transaction(&mut self) -> Result<bool> {
+        for segment in self.segments.iter().rev() {
+            for record in segment.records()?.into_iter().rev() {
+                if record.control_type().is_some_and(is_marker) {
+                    return Ok(false);
+                }
+                if record.attributes.transactional() {
+                    self.append_control(ControlType::TransactionAbort, &[])?;
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// The value of the latest committed transaction, if any
+    pub fn last_commit(&self) -> Result<Option<Vec<u8>>> {
+        for segment in self.segments.iter().rev() {
+            let marker = segment
+                .records()?
+                .into_iter()
+                .rev()
+                .find(|r| r.control_type() == Some(ControlType::TransactionCommit));
+            if let Some(marker) = marker {
+                return Ok(Some(marker.value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the committed records from `from`, like `fetch` though a transaction is
+    /// read whole even past `max_bytes`. Returns them along with the offset to read
+    /// the next ones from, past the markers and aborted records but before an open
+    /// transaction.
+    pub fn fetch_committed(&self, from: u64, max_bytes: usize) -> Result<(Vec<Record>, u64)> {
+        let mut fetched = Vec::new();
+        let mut pending: Vec<Record> = Vec::new();
+        let mut bytes = 0;
+        let mut next = from;
+        'segments: for segment in &self.segments {
+            if segment.latest_offset() <= from {
+                continue;
+            }
+            for record in segment.records()? {
+                if record.offset < from {
+                    continue;
+                }
+                if pending.is_empty() && bytes > max_bytes && !fetched.is_empty() {
+                    break 'segments;
+                }
+                let offset = record.offset;
+                match record.control_type() {
+                    Some(ControlType::TransactionCommit) => fetched.append(&mut pending),
+                    // Records of other writers interleaved with the transaction stay
+                    Some(ControlType::TransactionAbort) => {
+                        pending.retain(|r| !r.attributes.transactional());
+                        fetched.append(&mut pending);
+                    }
+                    Some(_) => {}
+                    None if record.attributes.transactional() || !pending.is_empty() => {
+                        bytes += record.binary_size();
+                        pending.push(record.decompressed()?);
+                        continue;
+                    }
+                    None => {
+                        bytes += record.binary_size();
+                        fetched.push(record.decompressed()?);
+                    }
+                }
+                if pending.is_empty() {
+                    next = offset + 1;
+                }
+            }
+        }
+        Ok((fetched, next))
+    }
+}
+
+fn is_marker(control_type: ControlType) -> bool {
+    matches!(
+        control_type,
+        ControlType::TransactionCommit | ControlType::TransactionAbort
+    )
+}
+
+#[cfg(test)]
+mod transaction_tests {
+    use crate::partition::record::Compression;
+    use crate::partition::Partition;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_transactions() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.append_record(None, b"plain").unwrap();
+        let records = [(None, b"a".to_vec()), (Some(b"k".to_vec()), b"b".to_vec())];
+        assert_eq!(
+            partition.append_transaction(&records, b"first").unwrap(),
+            1..3
+        );
+        assert!(!partition.abort_open_transaction().unwrap());
+
+        // A writer crashing midway leaves an open transaction
+        let mut open = partition.produced_record(0, None, b"lost", Compression::None);
+        open.attributes = open.attributes.with_transactional(true);
+        partition.append(&open).unwrap();
+        let (committed, next) = partition.fetch_committed(0, 1000).unwrap();
+        let values: Vec<_> = committed.iter().map(|r| r.value.clone()).collect();
+        assert_eq!(values, [&b"plain"[..], b"a", b"b"]);
+        assert_eq!(next, 4);
+        assert_eq!(partition.fetch(0, 1000).unwrap().len(), 4);
+
+        assert!(partition.abort_open_transaction().unwrap());
+        partition
+            .append_transaction(&records[..1], b"second")
+            .unwrap();
+        let (committed, next) = partition.fetch_committed(next, 1000).unwrap();
+        assert_eq!(committed.len(), 1);
+        assert_eq!((committed[0].offset, next), (6, partition.end_offset()));
+        assert_eq!(partition.last_commit().unwrap().unwrap(), b"second");
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//! Partitions as durable buffers between shell processes
+//!
+//! `ingest` appends the frames read from an input to a partition, `drain` writes
+//! the values of its records to an output, and `pipe` does both at once, writing
+//! the records as they're appended so a slow reader doesn't hold back the writer:
+//!
+//! ```text
+//! producer | shoju pipe | consumer
+//! ```
+//!
+//! Values are delimited on both ends by the same `Framing`, shared with clients
+//! through `shoju_codec`.
+use crate::partition::Partition;
+use byteorder::{NetworkEndian, ReadBytesExt};
+pub use shoju_codec::framing::Framing;
+use std::io::{BufRead, Error, ErrorKind, Result, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Frames appended between flushes of the partition
+const FLUSH_INTERVAL: u64 = 1024;
+/// Pause of the output when caught up with the input
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The next value of `input`, `None` at its end
+pub fn read_frame(framing: Framing, input: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let Some(delimiter) = framing.delimiter() else {
+        if input.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let len = input.read_u32::<NetworkEndian>()?;
+        let mut value = vec![0; len as usize];
+        input.read_exact(&mut value)?;
+        return Ok(Some(value));
+    };
+    let mut value = Vec::new();
+    if input.read_until(delimiter, &mut value)? == 0 {
+        return Ok(None);
+    }
+    if value.last() == Some(&delimiter) {
+        value.pop();
+    }
+    Ok(Some(value))
+}
+
+pub fn write_frame(framing: Framing, output: &mut impl Write, value: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(value.len() + 4);
+    framing
+        .encode(value, &mut frame)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    output.write_all(&frame)
+}
+
+/// Append every value of `input` to the partition, returning the number appended
+pub fn ingest(partition: &mut Partition, framing: Framing, mut input: impl BufRead) -> Result<u64> {
+    let mut appended = 0;
+    while let Some(value) = read_frame(framing, &mut input)? {
+        partition.append_record(None, &value)?;
+        appended += 1;
+        if appended % FLUSH_INTERVAL == 0 {
+            partition.flush()?;
+        }
+    }
+    partition.flush()?;
+    Ok(appended)
+}
+
+/// Write the values of the records from `from` to the end of the partition,
+/// returning the number written
+pub fn drain(
+    partition: &Partition,
+    framing: Framing,
+    from: u64,
+    output: &mut impl Write,
+) -> Result<u64> {
+    let records = partition.read_range(from, partition.end_offset())?;
+    for record in &records {
+        write_frame(framing, output, &record.value)?;
+    }
+    output.flush()?;
+    Ok(records.len() as u64)
+}
+
+/// Append the values of `input` to the partition in the background while writing
+/// the records from `from` to `output`, until `input` ends and every record
+/// appended is written. Returns the partition, flushed.
+pub fn pipe(
+    mut partition: Partition,
+    framing: Framing,
+    from: u64,
+    input: impl BufRead + Send + 'static,
+    output: &mut impl Write,
+) -> Result<Partition> {
+    let mut reader = partition.reader()?;
+    let partition = Arc::new(Mutex::new(partition));
+    let writer = Arc::clone(&partition);
+    let handle = thread::spawn(move || -> Result<()> {
+        let mut appended = 0;
+        let mut input = input;
+        while let Some(value) = read_frame(framing, &mut input)? {
+            let mut partition = writer.lock().map_err(|_| poisoned())?;
+            partition.append_record(None, &value)?;
+            appended += 1;
+            if appended % FLUSH_INTERVAL == 0 {
+                partition.flush()?;
+            }
+        }
+        writer.lock().map_err(|_| poisoned())?.flush()
+    });
+    let mut next = from;
+    loop {
+        // Checked first, so that the read below covers every record appended
+        let finished = handle.is_finished();
+        let end = reader.end_offset()?;
+        if next < end {
+            for record in reader.read_range(next, end)? {
+                write_frame(framing, output, &record.value)?;
+            }
+            output.flush()?;
+            next = end;
+        } else if finished {
+            break;
+        } else {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    handle
+        .join()
+        .map_err(|_| Error::other("Pipe input thread panicked"))??;
+    let partition =
+        Arc::try_unwrap(partition).map_err(|_| Error::other("Partition still shared"))?;
+    partition.into_inner().map_err(|_| poisoned())
+}
+
+fn poisoned() -> Error {
+    Error::other("Partition lock poisoned")
+}
+
+#[cfg(test)]
+mod pipe_tests {
+    use super::{drain, ingest, pipe, read_frame, write_frame, Framing};
+    use crate::partition::Partition;
+    use std::io::Cursor;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_framing() {
+        let values: [&[u8]; 3] = [b"a", b"", b"line\nwith\0bytes"];
+        let mut encoded = Vec::new();
+        for value in values {
+            write_frame(Framing::LengthPrefixed, &mut encoded, value).unwrap();
+        }
+        let mut input = Cursor::new(encoded);
+        for value in values {
+            let frame = read_frame(Framing::LengthPrefixed, &mut input).unwrap();
+            assert_eq!(frame.as_deref(), Some(value));
+        }
+        assert_eq!(
+            read_frame(Framing::LengthPrefixed, &mut input).unwrap(),
+            None
+        );
+
+        let mut input = Cursor::new(b"a\0b".to_vec());
+        assert_eq!(
+            read_frame(Framing::Null, &mut input).unwrap().unwrap(),
+            b"a"
+        );
+        assert_eq!(
+            read_frame(Framing::Null, &mut input).unwrap().unwrap(),
+            b"b"
+        );
+        assert_eq!(read_frame(Framing::Null, &mut input).unwrap(), None);
+        assert!("length".parse::<Framing>().is_ok() && "csv".parse::<Framing>().is_err());
+    }
+
+    #[test]
+    fn test_pipe() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        let input = Cursor::new(b"earlier\n".to_vec());
+        assert_eq!(ingest(&mut partition, Framing::Newline, input).unwrap(), 1);
+
+        let lines: String = (0..1000).map(|i| format!("{}\n", i)).collect();
+        let mut output = Vec::new();
+        let input = Cursor::new(lines.clone().into_bytes());
+        let partition = pipe(partition, Framing::Newline, 1, input, &mut output).unwrap();
+        assert_eq!(output, lines.as_bytes());
+
+        let mut output = Vec::new();
+        assert_eq!(
+            drain(&partition, Framing::Null, 0, &mut output).unwrap(),
+            1001
+        );
+        assert!(output.starts_with(b"earlier\x000\x00"));
+        tmp_dir.close().unwrap();
+    }
+}
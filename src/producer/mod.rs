@@ -0,0 +1,200 @@
+//! Embedded producers
+//!
+//! A `Producer` appends records to the partitions of a `TopicManager`, passing them
+//! through its `ProducerInterceptor`s first. Interceptors see every record before
+//! it's appended, and may rewrite or reject it, then the outcome of the append once
+//! it's acknowledged by the partition.
+//!
+//! With a `DiskMonitor` set, the records are rejected with `ErrorKind::StorageFull`
+//! instead of being appended while its breaker is tripped.
+pub mod validation;
+
+use crate::disk::DiskMonitor;
+use crate::partition::AppendInfo;
+use crate::topic::TopicManager;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use validation::{OnInvalid, Validator};
+
+/// A record to append to a topic partition
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProducerRecord {
+    pub topic: String,
+    pub partition: u32,
+    pub key: Option<Vec<u8>>,
+    pub value: Vec<u8>,
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl ProducerRecord {
+    pub fn new(topic: &str, partition: u32, key: Option<Vec<u8>>, value: &[u8]) -> Self {
+        Self {
+            topic: topic.into(),
+            partition,
+            key,
+            value: value.to_vec(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &[u8]) -> Self {
+        self.headers.push((name.into(), value.to_vec()));
+        self
+    }
+}
+
+pub trait ProducerInterceptor: Send {
+    /// Called before `record` is appended, the record returned is appended in its
+    /// place and an error fails the send
+    fn on_send(&self, record: ProducerRecord) -> Result<ProducerRecord> {
+        Ok(record)
+    }
+
+    /// Called with the outcome of appending `record`
+    fn on_ack(&self, _record: &ProducerRecord, _result: std::result::Result<&AppendInfo, &Error>) {}
+}
+
+#[derive(Default)]
+pub struct Producer {
+    interceptors: Vec<Box<dyn ProducerInterceptor>>,
+    validators: Vec<(Box<dyn Validator>, OnInvalid)>,
+    disk_monitor: Option<Arc<DiskMonitor>>,
+}
+
+impl Producer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Intercept the records sent, after the interceptors added before
+    pub fn add_interceptor(&mut self, interceptor: impl ProducerInterceptor + 'static) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    /// Validate the records sent, after the interceptors and the validators added
+    /// before, handling the invalid ones with `on_invalid`
+    pub fn add_validator(&mut self, validator: impl Validator + 'static, on_invalid: OnInvalid) {
+        self.validators.push((Box::new(validator), on_invalid));
+    }
+
+    /// Check `monitor` before every append, failing the sends while the data volume
+    /// is short on space
+    pub fn set_disk_monitor(&mut self, monitor: Arc<DiskMonitor>) {
+        self.disk_monitor = Some(monitor);
+    }
+
+    pub fn send(&self, manager: &mut TopicManager, record: ProducerRecord) -> Result<AppendInfo> {
+        let record = self
+            .interceptors
+            .iter()
+            .try_fold(record, |record, i| i.on_send(record))?;
+        let record = validation::apply(&self.validators, record)?;
+        let result = match &self.disk_monitor {
+            Some(monitor) => monitor.check_write().and_then(|_| append(manager, &record)),
+            None => append(manager, &record),
+        };
+        for interceptor in &self.interceptors {
+            interceptor.on_ack(&record, result.as_ref());
+        }
+        result
+    }
+}
+
+fn append(manager: &mut TopicManager, record: &ProducerRecord) -> Result<AppendInfo> {
+    let partition = manager
+        .topic(&record.topic)
+        .and_then(|t| t.partition(record.partition))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Partition {} of topic {} not found",
+                    record.partition, record.topic
+                ),
+            )
+        })?;
+    partition.append_record_with_headers(
+        None,
+        record.key.clone(),
+        &record.value,
+        record.headers.clone(),
+    )
+}
+
+#[cfg(test)]
+mod producer_tests {
+    use super::{Producer, ProducerInterceptor, ProducerRecord};
+    use crate::disk::DiskMonitor;
+    use crate::partition::AppendInfo;
+    use crate::topic::{TopicConfig, TopicManager};
+    use std::io::{Error, ErrorKind, Result};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    struct Redact;
+
+    impl ProducerInterceptor for Redact {
+        fn on_send(&self, mut record: ProducerRecord) -> Result<ProducerRecord> {
+            if record.value.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidInput, "Empty value"));
+            }
+            record.value = record.value.to_ascii_uppercase();
+            Ok(record)
+        }
+    }
+
+    struct CountAcks(Arc<AtomicUsize>);
+
+    impl ProducerInterceptor for CountAcks {
+        fn on_ack(&self, _: &ProducerRecord, result: std::result::Result<&AppendInfo, &Error>) {
+            if result.is_ok() {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[test]
+    fn test_interceptors() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager.create_topic("events", TopicConfig::new(1)).unwrap();
+        let acks = Arc::new(AtomicUsize::new(0));
+        let mut producer = Producer::new();
+        producer.add_interceptor(Redact);
+        producer.add_interceptor(CountAcks(Arc::clone(&acks)));
+
+        let sent = ProducerRecord::new("events", 0, None, b"secret");
+        assert_eq!(producer.send(&mut manager, sent).unwrap().offset, 0);
+        let empty = ProducerRecord::new("events", 0, None, b"");
+        assert!(producer.send(&mut manager, empty).is_err());
+        let missing = ProducerRecord::new("events", 1, None, b"value");
+        assert!(producer.send(&mut manager, missing).is_err());
+
+        assert_eq!(acks.load(Ordering::SeqCst), 1);
+        let records = manager.partition("events", 0).unwrap().read_range(0, 2);
+        assert_eq!(records.unwrap()[0].value, b"SECRET");
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_disk_monitor() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager.create_topic("events", TopicConfig::new(1)).unwrap();
+        let acks = Arc::new(AtomicUsize::new(0));
+        let mut producer = Producer::new();
+        producer.add_interceptor(CountAcks(Arc::clone(&acks)));
+        // No volume has that much space left, the breaker trips on the first sample
+        let monitor = DiskMonitor::spawn(tmp_dir.path(), u64::MAX, Duration::from_secs(3600));
+        producer.set_disk_monitor(Arc::new(monitor));
+
+        let sent = ProducerRecord::new("events", 0, None, b"value");
+        let err = producer.send(&mut manager, sent).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(acks.load(Ordering::SeqCst), 0);
+        assert_eq!(manager.partition("events", 0).unwrap().end_offset(), 0);
+        tmp_dir.close().unwrap();
+    }
+}
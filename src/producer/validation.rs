@@ -0,0 +1,176 @@
+//! Validation of the records produced
+//!
+//! `Validator`s added to a `Producer` check every record after the interceptors,
+//! before it's appended. A record failing one is handled by the `OnInvalid` action
+//! the validator was added with: rejected with an `ErrorKind::InvalidInput` error,
+//! appended to a dead letter topic instead, or appended anyway with the reason in
+//! its `validation.error` header.
+use crate::producer::ProducerRecord;
+use crate::typed::schema::Envelope;
+use std::io::{Error, ErrorKind, Result};
+
+/// Header holding why a record failed its validation
+pub const VALIDATION_ERROR_HEADER: &str = "validation.error";
+/// Header holding the topic a dead lettered record was produced to
+pub const ORIGINAL_TOPIC_HEADER: &str = "validation.topic";
+
+pub trait Validator: Send {
+    /// Check `record`, the error describes why it's invalid
+    fn validate(&self, record: &ProducerRecord) -> Result<()>;
+}
+
+impl<F: Fn(&ProducerRecord) -> Result<()> + Send> Validator for F {
+    fn validate(&self, record: &ProducerRecord) -> Result<()> {
+        self(record)
+    }
+}
+
+/// What happens to a record failing a validator
+#[derive(Clone, Debug, PartialEq)]
+pub enum OnInvalid {
+    Reject,
+    /// Append it to the partition of the same number of `topic` instead
+    DeadLetter {
+        topic: String,
+    },
+    Annotate,
+}
+
+/// Bound the size of key plus value
+pub struct MaxSize(pub usize);
+
+impl Validator for MaxSize {
+    fn validate(&self, record: &ProducerRecord) -> Result<()> {
+        let size = record.key.as_ref().map_or(0, Vec::len) + record.value.len();
+        if size > self.0 {
+            return Err(invalid(format!(
+                "Record of {} bytes exceeds the maximum of {}",
+                size, self.0
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Require headers by name
+pub struct RequiredHeaders(pub Vec<String>);
+
+impl Validator for RequiredHeaders {
+    fn validate(&self, record: &ProducerRecord) -> Result<()> {
+        match self
+            .0
+            .iter()
+            .find(|name| !record.headers.iter().any(|(n, _)| n == *name))
+        {
+            Some(missing) => Err(invalid(format!("Missing header {}", missing))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Require values wrapped in a schema envelope, with one of the schema ids
+pub struct SchemaIds(pub Vec<u32>);
+
+impl Validator for SchemaIds {
+    fn validate(&self, record: &ProducerRecord) -> Result<()> {
+        let (envelope, _) = Envelope::unwrap(&record.value)?;
+        if !self.0.contains(&envelope.schema_id) {
+            return Err(invalid(format!("Unknown schema id {}", envelope.schema_id)));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(reason: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, reason)
+}
+
+/// Run `validators` over `record`, returning the record to append
+pub(crate) fn apply(
+    validators: &[(Box<dyn Validator>, OnInvalid)],
+    mut record: ProducerRecord,
+) -> Result<ProducerRecord> {
+    for (validator, on_invalid) in validators {
+        let Err(e) = validator.validate(&record) else {
+            continue;
+        };
+        let reason = e.to_string().into_bytes();
+        match on_invalid {
+            OnInvalid::Reject => return Err(invalid(format!("Invalid record: {}", e))),
+            OnInvalid::DeadLetter { topic } => {
+                let original = std::mem::replace(&mut record.topic, topic.clone());
+                record
+                    .headers
+                    .push((VALIDATION_ERROR_HEADER.into(), reason));
+                record
+                    .headers
+                    .push((ORIGINAL_TOPIC_HEADER.into(), original.into_bytes()));
+                // Dead letters aren't validated again
+                return Ok(record);
+            }
+            OnInvalid::Annotate => record
+                .headers
+                .push((VALIDATION_ERROR_HEADER.into(), reason)),
+        }
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod validation_tests {
+    use super::{MaxSize, OnInvalid, RequiredHeaders, SchemaIds};
+    use crate::producer::{Producer, ProducerRecord};
+    use crate::topic::{TopicConfig, TopicManager};
+    use crate::typed::schema::Envelope;
+    use std::io::ErrorKind;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_validators() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager.create_topic("events", TopicConfig::new(1)).unwrap();
+        manager
+            .create_topic("events.dlq", TopicConfig::new(1))
+            .unwrap();
+        let mut producer = Producer::new();
+        producer.add_validator(MaxSize(8), OnInvalid::Reject);
+        producer.add_validator(
+            SchemaIds(vec![7]),
+            OnInvalid::DeadLetter {
+                topic: "events.dlq".into(),
+            },
+        );
+        producer.add_validator(RequiredHeaders(vec!["source".into()]), OnInvalid::Annotate);
+
+        let valid = Envelope::wrap(7, b"ok").unwrap();
+        let sent = ProducerRecord::new("events", 0, None, &valid).with_header("source", b"test");
+        assert_eq!(producer.send(&mut manager, sent).unwrap().offset, 0);
+        let large = ProducerRecord::new("events", 0, None, b"too large");
+        let err = producer.send(&mut manager, large).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let unknown = Envelope::wrap(8, b"ok").unwrap();
+        let dead = ProducerRecord::new("events", 0, None, &unknown);
+        producer.send(&mut manager, dead).unwrap();
+        producer
+            .send(&mut manager, ProducerRecord::new("events", 0, None, &valid))
+            .unwrap();
+
+        let events = manager.partition("events", 0).unwrap().read_range(0, 10);
+        let events = events.unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].header("validation.error"), None);
+        assert_eq!(
+            events[1].header("validation.error"),
+            Some(&b"Missing header source"[..])
+        );
+        let dead = manager
+            .partition("events.dlq", 0)
+            .unwrap()
+            .read_range(0, 10);
+        let dead = dead.unwrap();
+        assert_eq!(dead[0].value, unknown);
+        assert_eq!(dead[0].header("validation.topic"), Some(&b"events"[..]));
+        tmp_dir.close().unwrap();
+    }
+}
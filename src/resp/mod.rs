@@ -0,0 +1,601 @@
+//! Redis protocol façade over a `TopicManager`
+//!
+//! A small subset of the Redis Streams commands is served over RESP, enough for
+//! the Redis clients to append and read records in simple cases:
+//!
+//! ```text
+//! XADD <topic> * [key <key>] value <value>
+//! XRANGE <topic> <start> <end> [COUNT <count>]
+//! XREAD [COUNT <count>] [BLOCK <millis>] STREAMS <topic>... <id>...
+//! XLEN <topic>
+//! PING
+//! ```
+//!
+//! A stream is the first partition of the topic of the same name, the ID of an
+//! entry is its offset plus one followed by `-0`, as Redis never assigns `0-0` and
+//! reading after it returns the first entry. Entries only have the `key` and
+//! `value` fields of their record, IDs are always assigned by the partition.
+//!
+//! Every connection is served by its own thread, a blocking `XREAD` polls the
+//! partitions until records show up or it times out.
+//!
+//! Once the `Shutdown` of the server is triggered it stops accepting connections
+//! and reading commands, blocking reads return nothing, and the commands running
+//! get `signal::DRAIN_TIMEOUT` to finish before `serve` returns.
+//! `RespServer::close` then closes the topics.
+use crate::partition::record::Record;
+use crate::partition::Partition;
+use crate::signal::{self, Shutdown, SHUTDOWN_POLL_INTERVAL};
+use crate::topic::TopicManager;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::net::{self, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Pause of a blocking read between polls of its partitions
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Bound of the length of the bulk strings and arrays read
+const MAX_LENGTH: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Value>>),
+}
+
+impl Value {
+    fn bulk(bytes: &[u8]) -> Self {
+        Value::Bulk(Some(bytes.to_vec()))
+    }
+
+    /// Read the next value of `reader`, `None` once it's closed
+    pub fn read(reader: &mut impl BufRead) -> Result<Option<Value>> {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.ends_with(b"\r\n") || line.len() < 3 {
+            return Err(invalid("Unterminated line"));
+        }
+        let payload = String::from_utf8_lossy(&line[1..line.len() - 2]).into_owned();
+        let length = || -> Result<Option<usize>> {
+            match payload.parse::<i64>() {
+                Ok(-1) => Ok(None),
+                Ok(n) if (0..=MAX_LENGTH as i64).contains(&n) => Ok(Some(n as usize)),
+                _ => Err(invalid("Invalid length")),
+            }
+        };
+        let value = match line[0] {
+            b'+' => Value::Simple(payload),
+            b'-' => Value::Error(payload),
+            b':' => Value::Integer(payload.parse().map_err(|_| invalid("Invalid integer"))?),
+            b'$' => match length()? {
+                None => Value::Bulk(None),
+                Some(n) => {
+                    let mut bytes = vec![0; n + 2];
+                    reader.read_exact(&mut bytes)?;
+                    bytes.truncate(n);
+                    Value::Bulk(Some(bytes))
+                }
+            },
+            b'*' => match length()? {
+                None => Value::Array(None),
+                Some(n) => {
+                    let mut values = Vec::with_capacity(n.min(1024));
+                    for _ in 0..n {
+                        values
+                            .push(Value::read(reader)?.ok_or_else(|| invalid("Truncated array"))?);
+                    }
+                    Value::Array(Some(values))
+                }
+            },
+            _ => return Err(invalid("Unknown value type")),
+        };
+        Ok(Some(value))
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> Result<()> {
+        match self {
+            Value::Simple(s) => write!(writer, "+{}\r\n", s),
+            Value::Error(e) => write!(writer, "-{}\r\n", e),
+            Value::Integer(n) => write!(writer, ":{}\r\n", n),
+            Value::Bulk(None) => write!(writer, "$-1\r\n"),
+            Value::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Value::Array(None) => write!(writer, "*-1\r\n"),
+            Value::Array(Some(values)) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                values.iter().try_for_each(|v| v.write(writer))
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RespServer {
+    manager: Arc<Mutex<TopicManager>>,
+    shutdown: Shutdown,
+}
+
+impl RespServer {
+    pub fn new(manager: TopicManager) -> Self {
+        Self {
+            manager: Arc::new(Mutex::new(manager)),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// Stop serving once `shutdown` is triggered
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.shutdown = shutdown;
+    }
+
+    /// Serve connections on `addr` until the listener fails or the server is shut
+    /// down
+    pub fn serve(&self, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let mut connections = Vec::new();
+        while !self.shutdown.is_triggered() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    let client = stream.try_clone()?;
+                    let server = self.clone();
+                    connections.push((client, thread::spawn(move || server.connection(stream))));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL)
+                }
+                Err(e) => return Err(e),
+            }
+            connections.retain(|(_, handle)| !handle.is_finished());
+        }
+        drop(listener);
+        // The commands being run are answered, the next reads find the end of the stream
+        let mut handles = Vec::with_capacity(connections.len());
+        for (client, handle) in connections {
+            let _ = client.shutdown(net::Shutdown::Read);
+            handles.push(handle);
+        }
+        signal::drain(handles)
+    }
+
+    /// Close the topics served once `serve` returned, see `TopicManager::close`
+    pub fn close(self) -> Result<()> {
+        let manager = Arc::try_unwrap(self.manager)
+            .map_err(|_| Error::other("Topic manager still shared by a connection"))?;
+        manager
+            .into_inner()
+            .map_err(|_| Error::other("Topic manager lock poisoned"))?
+            .close()
+    }
+
+    fn connection(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        while let Some(value) = Value::read(&mut reader)? {
+            let reply = match value {
+                Value::Array(Some(args)) => {
+                    let args: Option<Vec<Vec<u8>>> = args
+                        .into_iter()
+                        .map(|v| match v {
+                            Value::Bulk(Some(bytes)) => Some(bytes),
+                            _ => None,
+                        })
+                        .collect();
+                    match args {
+                        Some(args) => self.execute(&args),
+                        None => Value::Error("ERR commands are arrays of bulk strings".into()),
+                    }
+                }
+                _ => Value::Error("ERR commands are arrays of bulk strings".into()),
+            };
+            reply.write(&mut writer)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Run the command `args`, returning its reply
+    pub fn execute(&self, args: &[Vec<u8>]) -> Value {
+        let Some(name) = args.first() else {
+            return Value::Error("ERR empty command".into());
+        };
+        let args = &args[1..];
+        let reply = match name.to_ascii_uppercase().as_slice() {
+            b"PING" => Ok(Value::Simple("PONG".into())),
+            b"XADD" => self.xadd(args),
+            b"XRANGE" => self.xrange(args),
+            b"XREAD" => self.xread(args),
+            b"XLEN" => self.xlen(args),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown command '{}'", String::from_utf8_lossy(name)),
+            )),
+        };
+        reply.unwrap_or_else(|e| Value::Error(format!("ERR {}", e)))
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, TopicManager>> {
+        self.manager
+            .lock()
+            .map_err(|_| Error::other("Topic manager lock poisoned"))
+    }
+
+    fn xadd(&self, args: &[Vec<u8>]) -> Result<Value> {
+        let [topic, id, fields @ ..] = args else {
+            return Err(syntax());
+        };
+        if id != b"*" || fields.is_empty() || fields.len() % 2 != 0 {
+            return Err(syntax());
+        }
+        let (mut key, mut value) = (None, None);
+        for pair in fields.chunks(2) {
+            match pair[0].as_slice() {
+                b"key" => key = Some(pair[1].clone()),
+                b"value" => value = Some(&pair[1]),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "only the key and value fields are supported",
+                    ))
+                }
+            }
+        }
+        let value =
+            value.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "missing value field"))?;
+        let mut manager = self.lock()?;
+        let partition = manager
+            .topic(&text(topic)?)
+            .and_then(|t| t.partition(0))
+            .ok_or_else(|| no_stream(topic))?;
+        let appended = partition.append_record(key, value)?;
+        Ok(Value::bulk(&entry_id(appended.offset)))
+    }
+
+    fn xrange(&self, args: &[Vec<u8>]) -> Result<Value> {
+        let (topic, start, end, count) = match args {
+            [topic, start, end] => (topic, start, end, None),
+            [topic, start, end, option, count] if option.eq_ignore_ascii_case(b"COUNT") => {
+                (topic, start, end, Some(number(count)?))
+            }
+            _ => return Err(syntax()),
+        };
+        let manager = self.lock()?;
+        let partition = stream(&manager, topic)?;
+        // Both ends are inclusive
+        let from = match start.as_slice() {
+            b"-" => partition.start_offset(),
+            id => match parse_id(id)? {
+                (id, 0) => id.saturating_sub(1),
+                (id, _) => id,
+            },
+        };
+        let to = match end.as_slice() {
+            b"+" => partition.end_offset(),
+            id => parse_id(id)?.0,
+        };
+        let records = read(partition, from, to, count)?;
+        Ok(Value::Array(Some(records.iter().map(entry).collect())))
+    }
+
+    fn xread(&self, args: &[Vec<u8>]) -> Result<Value> {
+        let mut count = None;
+        let mut block = None;
+        let mut rest = args;
+        let streams = loop {
+            match rest {
+                [option, n, tail @ ..] if option.eq_ignore_ascii_case(b"COUNT") => {
+                    count = Some(number(n)?);
+                    rest = tail;
+                }
+                [option, ms, tail @ ..] if option.eq_ignore_ascii_case(b"BLOCK") => {
+                    block = Some(Duration::from_millis(number(ms)? as u64));
+                    rest = tail;
+                }
+                [option, tail @ ..] if option.eq_ignore_ascii_case(b"STREAMS") => break tail,
+                _ => return Err(syntax()),
+            }
+        };
+        if streams.is_empty() || streams.len() % 2 != 0 {
+            return Err(syntax());
+        }
+        let (topics, ids) = streams.split_at(streams.len() / 2);
+        // Offsets to read from, `$` only reads what's appended from now on
+        let froms = {
+            let manager = self.lock()?;
+            topics
+                .iter()
+                .zip(ids)
+                .map(|(topic, id)| match id.as_slice() {
+                    b"$" => Ok(stream(&manager, topic)?.end_offset()),
+                    id => Ok(parse_id(id)?.0),
+                })
+                .collect::<Result<Vec<u64>>>()?
+        };
+        let deadline = block.map(|b| Instant::now() + b);
+        loop {
+            let mut replies = Vec::new();
+            {
+                let manager = self.lock()?;
+                for (topic, &from) in topics.iter().zip(&froms) {
+                    let partition = stream(&manager, topic)?;
+                    let records = read(partition, from, partition.end_offset(), count)?;
+                    if !records.is_empty() {
+                        let entries = records.iter().map(entry).collect();
+                        replies.push(Value::Array(Some(vec![
+                            Value::bulk(topic),
+                            Value::Array(Some(entries)),
+                        ])));
+                    }
+                }
+            }
+            // BLOCK 0 waits forever
+            let expired = match (block, deadline) {
+                (Some(b), Some(deadline)) => !b.is_zero() && Instant::now() >= deadline,
+                _ => true,
+            };
+            if !replies.is_empty() {
+                return Ok(Value::Array(Some(replies)));
+            }
+            if expired || self.shutdown.is_triggered() {
+                return Ok(Value::Array(None));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn xlen(&self, args: &[Vec<u8>]) -> Result<Value> {
+        let [topic] = args else {
+            return Err(syntax());
+        };
+        let manager = self.lock()?;
+        let partition = stream(&manager, topic)?;
+        let len = partition.end_offset() - partition.start_offset();
+        Ok(Value::Integer(len as i64))
+    }
+}
+
+fn stream<'a>(manager: &'a TopicManager, topic: &[u8]) -> Result<&'a Partition> {
+    manager
+        .partition(&text(topic)?, 0)
+        .map_err(|_| no_stream(topic))
+}
+
+/// The records in the `[from, to)` range, at most `count` of them
+fn read(partition: &Partition, from: u64, to: u64, count: Option<usize>) -> Result<Vec<Record>> {
+    let to = match count {
+        Some(count) => to.min(from.saturating_add(count as u64)),
+        None => to,
+    };
+    partition.read_range(from, to)
+}
+
+fn entry(record: &Record) -> Value {
+    let mut fields = Vec::with_capacity(4);
+    if let Some(key) = &record.key {
+        fields.extend([Value::bulk(b"key"), Value::bulk(key)]);
+    }
+    fields.extend([Value::bulk(b"value"), Value::bulk(&record.value)]);
+    Value::Array(Some(vec![
+        Value::bulk(&entry_id(record.offset)),
+        Value::Array(Some(fields)),
+    ]))
+}
+
+/// The ID of the entry of the record at `offset`, strictly greater than `0-0`
+fn entry_id(offset: u64) -> Vec<u8> {
+    format!("{}-0", offset + 1).into_bytes()
+}
+
+/// The two parts of an `<id>-<sequence>` ID, the sequence is optional. The entry
+/// at offset `id - 1` has this ID with a sequence of 0, so `id` is the offset of
+/// the first entry after it.
+fn parse_id(id: &[u8]) -> Result<(u64, u64)> {
+    let invalid_id = || Error::new(ErrorKind::InvalidInput, "Invalid stream ID specified");
+    let id = text(id)?;
+    let (id, sequence) = id.split_once('-').unwrap_or((id.as_str(), "0"));
+    let id = id.parse().map_err(|_| invalid_id())?;
+    let sequence = sequence.parse().map_err(|_| invalid_id())?;
+    Ok((id, sequence))
+}
+
+fn number(arg: &[u8]) -> Result<usize> {
+    text(arg)?
+        .parse()
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "value is not an integer"))
+}
+
+fn text(arg: &[u8]) -> Result<String> {
+    String::from_utf8(arg.to_vec()).map_err(|_| invalid("Invalid UTF-8"))
+}
+
+fn no_stream(topic: &[u8]) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("no such stream '{}'", String::from_utf8_lossy(topic)),
+    )
+}
+
+fn syntax() -> Error {
+    Error::new(ErrorKind::InvalidInput, "syntax error")
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod resp_tests {
+    use super::{RespServer, Value};
+    use crate::signal::Shutdown;
+    use crate::topic::{TopicConfig, TopicManager};
+    use std::io::{BufReader, Cursor};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn command(server: &RespServer, line: &str) -> Value {
+        let args: Vec<Vec<u8>> = line.split(' ').map(|a| a.as_bytes().to_vec()).collect();
+        server.execute(&args)
+    }
+
+    fn bulk(s: &str) -> Value {
+        Value::Bulk(Some(s.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn test_value_roundtrip() {
+        let value = Value::Array(Some(vec![
+            bulk("XADD"),
+            Value::Bulk(None),
+            Value::Integer(-3),
+            Value::Simple("OK".into()),
+            Value::Array(None),
+        ]));
+        let mut encoded = Vec::new();
+        value.write(&mut encoded).unwrap();
+        let mut reader = Cursor::new(encoded);
+        assert_eq!(Value::read(&mut reader).unwrap(), Some(value));
+        assert_eq!(Value::read(&mut reader).unwrap(), None);
+        assert!(Value::read(&mut Cursor::new(b"$5\r\nab".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_streams() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager.create_topic("events", TopicConfig::new(1)).unwrap();
+        let server = RespServer::new(manager);
+
+        assert_eq!(command(&server, "XADD events * value a"), bulk("1-0"));
+        assert_eq!(command(&server, "XADD events * key k value b"), bulk("2-0"));
+        assert!(matches!(
+            command(&server, "XADD events * other c"),
+            Value::Error(_)
+        ));
+        assert!(matches!(command(&server, "XLEN missing"), Value::Error(_)));
+        assert_eq!(command(&server, "XLEN events"), Value::Integer(2));
+
+        let first = Value::Array(Some(vec![
+            bulk("1-0"),
+            Value::Array(Some(vec![bulk("value"), bulk("a")])),
+        ]));
+        let second = Value::Array(Some(vec![
+            bulk("2-0"),
+            Value::Array(Some(vec![bulk("key"), bulk("k"), bulk("value"), bulk("b")])),
+        ]));
+        assert_eq!(
+            command(&server, "XRANGE events 2 +"),
+            Value::Array(Some(vec![second.clone()]))
+        );
+        assert_eq!(
+            command(&server, "XRANGE events 1-1 +"),
+            Value::Array(Some(vec![second.clone()]))
+        );
+        assert_eq!(
+            command(&server, "XRANGE events - 1-0"),
+            Value::Array(Some(vec![first.clone()]))
+        );
+        let Value::Array(Some(all)) = command(&server, "XRANGE events - + COUNT 1") else {
+            panic!("Not an array");
+        };
+        assert_eq!(all.len(), 1);
+
+        assert_eq!(
+            command(&server, "XREAD COUNT 10 STREAMS events 0"),
+            Value::Array(Some(vec![Value::Array(Some(vec![
+                bulk("events"),
+                Value::Array(Some(vec![first, second.clone()])),
+            ]))]))
+        );
+        assert_eq!(
+            command(&server, "XREAD COUNT 10 STREAMS events 1-0"),
+            Value::Array(Some(vec![Value::Array(Some(vec![
+                bulk("events"),
+                Value::Array(Some(vec![second])),
+            ]))]))
+        );
+        assert_eq!(
+            command(&server, "XREAD BLOCK 20 STREAMS events $"),
+            Value::Array(None)
+        );
+
+        // A blocking read returns once a record is appended
+        let appender = server.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            command(&appender, "XADD events * value c")
+        });
+        let Value::Array(Some(streams)) = command(&server, "XREAD BLOCK 0 STREAMS events $") else {
+            panic!("Blocking read timed out");
+        };
+        assert_eq!(streams.len(), 1);
+        assert_eq!(handle.join().unwrap(), bulk("3-0"));
+        tmp_dir.close().unwrap();
+    }
+
+    /// Send `args` as a command over `stream`, returning the reply
+    fn send(stream: &TcpStream, args: &[&str]) -> Value {
+        let args = args.iter().map(|a| Value::bulk(a.as_bytes())).collect();
+        Value::Array(Some(args)).write(&mut &*stream).unwrap();
+        Value::read(&mut BufReader::new(stream)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager.create_topic("events", TopicConfig::new(1)).unwrap();
+        let mut server = RespServer::new(manager);
+        let shutdown = Shutdown::new();
+        server.set_shutdown(shutdown.clone());
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let served = server.clone();
+        let serve_addr = addr.clone();
+        let serving = thread::spawn(move || served.serve(&serve_addr));
+        let connect = || loop {
+            match TcpStream::connect(&addr) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        let client = connect();
+        assert_eq!(
+            send(&client, &["XADD", "events", "*", "value", "a"]),
+            bulk("1-0")
+        );
+        let blocked = connect();
+        let reading = thread::spawn(move || {
+            send(&blocked, &["XREAD", "BLOCK", "0", "STREAMS", "events", "$"])
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        // The blocking read returns nothing, the idle connection is closed
+        shutdown.trigger();
+        serving.join().unwrap().unwrap();
+        assert_eq!(reading.join().unwrap(), Value::Array(None));
+        assert!(Value::read(&mut BufReader::new(&client)).unwrap().is_none());
+        assert!(TcpStream::connect(&addr).is_err());
+        server.close().unwrap();
+        assert!(tmp_dir.path().join("events/0/.clean_shutdown").exists());
+
+        let manager = TopicManager::open(tmp_dir.path()).unwrap();
+        assert_eq!(manager.partition("events", 0).unwrap().end_offset(), 1);
+        tmp_dir.close().unwrap();
+    }
+}
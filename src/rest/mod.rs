@@ -0,0 +1,971 @@
+//! Minimal REST layer over a `TopicManager`
+//!
+//! Meant for low throughput integrations and for poking at topics with curl, the
+//! routes served are:
+//!
+//! ```text
+//! GET    /topics
+//! POST   /topics
+//! GET    /topics/{topic}
+//! DELETE /topics/{topic}
+//! POST /topics/{topic}/records
+//! GET  /topics/{topic}/partitions/{partition}/records?offset=&max_bytes=
+//! GET  /healthz
+//! GET  /readyz
+//! ```
+//!
+//! A JSON produce body holds a record or an array of records, with base64 encoded
+//! keys and values like the JSON export, an `application/octet-stream` one is the
+//! value of a single record whose partition and key come from the `partition` and
+//! `key` query parameters. Fetched records are returned as a JSON array, or as
+//! their binary encoding one after the other when accepting
+//! `application/octet-stream`.
+//!
+//! Fetching records while accepting `text/event-stream` tails the partition
+//! instead, every record from the offset on is sent as a server-sent event with
+//! the record in JSON as data and its offset as id, a client reconnecting with a
+//! `Last-Event-ID` resumes after it. Each stream reads the partition through its
+//! own `PartitionReader` on a thread of its own, as stored, without the fetch
+//! transforms, and ends once the client goes away, found out at the latest by the
+//! comment sent after an idle interval.
+//!
+//! The admin routes list the topics, create one out of a JSON body holding its
+//! name, number of partitions and settings, describe the offsets of its partitions
+//! and delete it.
+//!
+//! Requests are served by a pool of workers, every request on a topic goes to the
+//! same worker so that the ones on a partition are handled in the order received.
+//! Workers read the bodies and write the responses on their own, a slow client only
+//! holds up its topic, and take turns to handle the requests against the topics.
+//! Every record goes through the `Producer` of the API and its interceptors. Bodies
+//! over the maximum size are answered with a 413, a request failing midway, e.g. a
+//! client disconnecting, is logged and the next one served. The `traceparent`
+//! header of a produce request is added to each of its records, fetched records
+//! return their headers.
+//!
+//! Records posted and fetched can be reshaped by chains of `Transform`s, a
+//! record posted and dropped by them gets a null offset in the response.
+//!
+//! `/healthz` answers as long as requests are served. The partitions are all
+//! recovered once the `TopicManager` is open, `/readyz` checks the root directory
+//! is writable and runs the readiness checks added, e.g. the one of a
+//! `DiskMonitor`, answering 503 with the failed ones. With a `DiskMonitor` set,
+//! records posted while the data volume is short on space are answered with a 507.
+//!
+//! Once the `Shutdown` of the API is triggered `serve` stops taking requests, lets
+//! the workers handle the ones received, waits for the streams to end,
+//! `signal::DRAIN_TIMEOUT` at most, and returns, `RestApi::close` then closes the
+//! topics.
+use crate::disk::DiskMonitor;
+use crate::export::json::JsonRecord;
+use crate::partition::reader::PartitionReader;
+use crate::producer::{Producer, ProducerRecord};
+use crate::signal::{self, Shutdown, SHUTDOWN_POLL_INTERVAL};
+use crate::topic::{TopicConfig, TopicManager};
+use crate::trace::TraceContext;
+use crate::transform::Transforms;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const JSON: &str = "application/json";
+const BINARY: &str = "application/octet-stream";
+const EVENT_STREAM: &str = "text/event-stream";
+/// Bytes fetched when the request doesn't say
+const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+/// Threads handling the requests by default
+const DEFAULT_WORKERS: usize = 4;
+/// Largest request body read by default
+const DEFAULT_MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+/// File written in the root directory by `/readyz`, never a topic name
+const READY_PROBE_FILE: &str = ".readyz";
+/// How often a tailing stream looks for new records
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Idle time after which a tailing stream sends a comment, failing once the client
+/// is gone
+const TAIL_KEEPALIVE: Duration = Duration::from_secs(15);
+/// Records read at once by a tailing stream
+const TAIL_BATCH: u64 = 512;
+
+type ReadinessCheck = Box<dyn Fn() -> Result<()> + Send>;
+
+pub struct Request<'a> {
+    pub method: &'a str,
+    /// Path and query string
+    pub url: &'a str,
+    pub content_type: Option<&'a str>,
+    pub accept: Option<&'a str>,
+    pub traceparent: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: &serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: JSON,
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn error(e: &Error) -> Self {
+        let status = match e.kind() {
+            ErrorKind::NotFound => 404,
+            ErrorKind::AlreadyExists => 409,
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => 400,
+            ErrorKind::StorageFull => 507,
+            _ => 500,
+        };
+        Self::json(status, &serde_json::json!({ "error": e.to_string() }))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProduceBody {
+    One(JsonProduce),
+    Many(Vec<JsonProduce>),
+}
+
+#[derive(Deserialize)]
+struct NewTopic {
+    name: String,
+    #[serde(flatten)]
+    config: TopicConfig,
+}
+
+#[derive(Deserialize)]
+struct JsonProduce {
+    #[serde(default)]
+    partition: u32,
+    key: Option<String>,
+    value: String,
+}
+
+pub struct RestApi {
+    manager: TopicManager,
+    producer: Producer,
+    produce_transforms: Transforms,
+    fetch_transforms: Transforms,
+    max_body_size: usize,
+    readiness_checks: Vec<(String, ReadinessCheck)>,
+    shutdown: Shutdown,
+    /// Threads of the tailing streams
+    tails: Vec<JoinHandle<()>>,
+    workers: usize,
+}
+
+impl RestApi {
+    pub fn new(manager: TopicManager) -> Self {
+        Self {
+            manager,
+            producer: Producer::new(),
+            produce_transforms: Transforms::new(),
+            fetch_transforms: Transforms::new(),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            readiness_checks: Vec::new(),
+            shutdown: Shutdown::new(),
+            tails: Vec::new(),
+            workers: DEFAULT_WORKERS,
+        }
+    }
+
+    /// Stop serving once `shutdown` is triggered
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.shutdown = shutdown;
+    }
+
+    /// Add a check run by `/readyz`, the API isn't ready while it fails
+    pub fn add_readiness_check(
+        &mut self,
+        name: &str,
+        check: impl Fn() -> Result<()> + Send + 'static,
+    ) {
+        self.readiness_checks.push((name.into(), Box::new(check)));
+    }
+
+    /// Reject the requests with a body over `max_body_size` bytes
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// The producer appending the records posted, to add interceptors to
+    pub fn producer(&mut self) -> &mut Producer {
+        &mut self.producer
+    }
+
+    /// Reject the records posted with a 507 while `monitor` finds the data volume
+    /// short on space
+    pub fn set_disk_monitor(&mut self, monitor: Arc<DiskMonitor>) {
+        self.producer.set_disk_monitor(monitor);
+    }
+
+    /// Transforms of the records posted, before the producer
+    pub fn produce_transforms(&mut self) -> &mut Transforms {
+        &mut self.produce_transforms
+    }
+
+    /// Transforms of the records fetched
+    pub fn fetch_transforms(&mut self) -> &mut Transforms {
+        &mut self.fetch_transforms
+    }
+
+    pub fn handle(&mut self, request: &Request) -> Response {
+        let (path, query) = request.url.split_once('?').unwrap_or((request.url, ""));
+        let query = parse_query(query);
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let handled = match (request.method, &segments[..]) {
+            ("GET", ["healthz"]) => Ok(Response::json(200, &serde_json::json!({ "status": "ok" }))),
+            ("GET", ["readyz"]) => Ok(self.ready()),
+            ("GET", ["topics"]) => Ok(Response::json(200, &self.manager.list_topics().into())),
+            ("POST", ["topics"]) => self.create_topic(request),
+            ("GET", ["topics", topic]) => self.describe_topic(topic),
+            ("DELETE", ["topics", topic]) => self
+                .manager
+                .delete_topic(topic)
+                .map(|_| Response::json(200, &serde_json::json!({ "deleted": topic }))),
+            ("POST", ["topics", topic, "records"]) => self.produce(topic, &query, request),
+            ("GET", ["topics", topic, "partitions", partition, "records"]) => {
+                self.fetch(topic, partition, &query, request)
+            }
+            _ => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No route for {} {}", request.method, path),
+            )),
+        };
+        handled.unwrap_or_else(|e| Response::error(&e))
+    }
+
+    /// Handle the requests on `workers` threads
+    pub fn set_workers(&mut self, workers: usize) {
+        self.workers = workers.max(1);
+    }
+
+    /// Serve the API on `addr` until the listener fails or the API is shut down
+    pub fn serve(&mut self, addr: &str) -> Result<()> {
+        let server = tiny_http::Server::http(addr).map_err(Error::other)?;
+        let workers = self.workers;
+        let shutdown = self.shutdown.clone();
+        let api = Mutex::new(&mut *self);
+        thread::scope(|scope| {
+            let senders: Vec<Sender<tiny_http::Request>> = (0..workers)
+                .map(|_| {
+                    let (sender, receiver) = mpsc::channel::<tiny_http::Request>();
+                    let api = &api;
+                    scope.spawn(move || {
+                        for request in receiver {
+                            let url = request.url().to_string();
+                            if let Err(e) = respond(api, request) {
+                                eprintln!("Failed to serve {}: {}", url, e);
+                            }
+                        }
+                    });
+                    sender
+                })
+                .collect();
+            let mut next = 0;
+            while !shutdown.is_triggered() {
+                let Some(request) = server.recv_timeout(SHUTDOWN_POLL_INTERVAL)? else {
+                    continue;
+                };
+                let worker = match topic(request.url()) {
+                    Some(topic) => topic_worker(topic, workers),
+                    None => {
+                        next = (next + 1) % workers;
+                        next
+                    }
+                };
+                let _ = senders[worker].send(request);
+            }
+            // The workers handle the requests already received before leaving the scope
+            Ok::<_, Error>(())
+        })?;
+        signal::drain(std::mem::take(&mut self.tails))
+    }
+
+    /// Close the topics served once `serve` returned, see `TopicManager::close`
+    pub fn close(self) -> Result<()> {
+        self.manager.close()
+    }
+
+    /// Stream the records of the partition requested as server-sent events, on a
+    /// thread of its own
+    fn tail(&mut self, request: tiny_http::Request) -> Result<()> {
+        self.tails.retain(|tail| !tail.is_finished());
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let query = parse_query(query);
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let last_event_id = header(&request, "Last-Event-ID");
+        let tailed = match &segments[..] {
+            ["topics", topic, "partitions", partition, "records"] => {
+                let offset = match (last_event_id, query.get("offset")) {
+                    (Some(id), _) => parse::<u64>(&id, "Last-Event-ID").map(|id| id + 1),
+                    (None, Some(o)) => parse(o, "offset"),
+                    (None, None) => Ok(0),
+                };
+                offset.and_then(|offset| Ok((self.reader(topic, partition)?, offset)))
+            }
+            _ => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No route for GET {}", path),
+            )),
+        };
+        match tailed {
+            Ok((reader, offset)) => {
+                let writer = request.into_writer();
+                let shutdown = self.shutdown.clone();
+                self.tails.push(thread::spawn(move || {
+                    if let Err(e) = stream_events(reader, offset, writer, &shutdown) {
+                        eprintln!("Stopped tailing {}: {}", url, e);
+                    }
+                }));
+                Ok(())
+            }
+            Err(e) => send(request, Response::error(&e)),
+        }
+    }
+
+    fn reader(&mut self, topic: &str, partition: &str) -> Result<PartitionReader> {
+        let partition = parse(partition, "partition")?;
+        self.manager
+            .topic(topic)
+            .and_then(|t| t.partition(partition))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("Partition {} of topic {} not found", partition, topic),
+                )
+            })?
+            .reader()
+    }
+
+    fn create_topic(&mut self, request: &Request) -> Result<Response> {
+        let topic: NewTopic = serde_json::from_slice(request.body)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let offsets = self
+            .manager
+            .create_topic(&topic.name, topic.config)?
+            .offsets();
+        Ok(Response::json(201, &serde_json::to_value(offsets)?))
+    }
+
+    fn describe_topic(&self, topic: &str) -> Result<Response> {
+        let offsets = self.manager.describe_topic(topic)?;
+        Ok(Response::json(200, &serde_json::to_value(offsets)?))
+    }
+
+    fn ready(&self) -> Response {
+        let mut failed = serde_json::Map::new();
+        let probe = self.manager.root().join(READY_PROBE_FILE);
+        let writable = fs::write(&probe, b"")
+            .and_then(|_| File::open(&probe)?.sync_data())
+            .and_then(|_| fs::remove_file(&probe));
+        if let Err(e) = writable {
+            failed.insert("disk".into(), e.to_string().into());
+        }
+        for (name, check) in &self.readiness_checks {
+            if let Err(e) = check() {
+                failed.insert(name.clone(), e.to_string().into());
+            }
+        }
+        let status = if failed.is_empty() { 200 } else { 503 };
+        Response::json(
+            status,
+            &serde_json::json!({ "ready": failed.is_empty(), "failed": failed }),
+        )
+    }
+
+    fn produce(
+        &mut self,
+        topic: &str,
+        query: &HashMap<String, String>,
+        request: &Request,
+    ) -> Result<Response> {
+        let mut records = if is(request.content_type, BINARY) {
+            let partition = match query.get("partition") {
+                Some(p) => parse(p, "partition")?,
+                None => 0,
+            };
+            let key = query.get("key").map(|k| k.clone().into_bytes());
+            vec![ProducerRecord::new(topic, partition, key, request.body)]
+        } else {
+            let body: ProduceBody = serde_json::from_slice(request.body)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            let records = match body {
+                ProduceBody::One(record) => vec![record],
+                ProduceBody::Many(records) => records,
+            };
+            records
+                .into_iter()
+                .map(|r| {
+                    let key = r.key.map(|k| decode(&k)).transpose()?;
+                    Ok(ProducerRecord::new(
+                        topic,
+                        r.partition,
+                        key,
+                        &decode(&r.value)?,
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+        if let Some(context) = request
+            .traceparent
+            .and_then(|t| t.parse::<TraceContext>().ok())
+        {
+            for record in &mut records {
+                context.inject(&mut record.headers);
+            }
+        }
+        let mut appended = Vec::with_capacity(records.len());
+        for mut record in records {
+            let partition = record.partition;
+            if !self.produce_transforms.apply(&mut record) {
+                appended.push(serde_json::json!({ "partition": partition, "offset": null }));
+                continue;
+            }
+            let info = self.producer.send(&mut self.manager, record)?;
+            appended.push(serde_json::json!({ "partition": partition, "offset": info.offset }));
+        }
+        Ok(Response::json(200, &serde_json::Value::Array(appended)))
+    }
+
+    fn fetch(
+        &self,
+        topic: &str,
+        partition: &str,
+        query: &HashMap<String, String>,
+        request: &Request,
+    ) -> Result<Response> {
+        let partition = parse(partition, "partition")?;
+        let offset = match query.get("offset") {
+            Some(o) => parse(o, "offset")?,
+            None => 0,
+        };
+        let max_bytes = match query.get("max_bytes") {
+            Some(m) => parse(m, "max_bytes")?,
+            None => DEFAULT_MAX_BYTES,
+        };
+        let mut records = self
+            .manager
+            .partition(topic, partition)?
+            .fetch(offset, max_bytes)?;
+        records.retain_mut(|r| self.fetch_transforms.apply(r));
+        if is(request.accept, BINARY) {
+            let mut body = Vec::new();
+            for record in &records {
+                record.write(&mut body)?;
+            }
+            return Ok(Response {
+                status: 200,
+                content_type: BINARY,
+                body,
+            });
+        }
+        let records: Vec<JsonRecord> = records.iter().map(JsonRecord::from).collect();
+        Ok(Response::json(200, &serde_json::to_value(records)?))
+    }
+}
+
+type SharedApi<'a> = Mutex<&'a mut RestApi>;
+
+fn lock<'a, 'b>(api: &'a SharedApi<'b>) -> Result<MutexGuard<'a, &'b mut RestApi>> {
+    api.lock()
+        .map_err(|_| Error::other("REST API lock poisoned"))
+}
+
+/// Read the body of `request` and answer it, the API is only locked to handle it
+fn respond(api: &SharedApi, mut request: tiny_http::Request) -> Result<()> {
+    let content_type = header(&request, "Content-Type");
+    let accept = header(&request, "Accept");
+    let traceparent = header(&request, "traceparent");
+    if *request.method() == tiny_http::Method::Get && is(accept.as_deref(), EVENT_STREAM) {
+        return lock(api)?.tail(request);
+    }
+    let mut body = Vec::new();
+    let limit = lock(api)?.max_body_size as u64;
+    let response = if request.body_length().is_some_and(|n| n as u64 > limit)
+        || request.as_reader().take(limit + 1).read_to_end(&mut body)? as u64 > limit
+    {
+        let error = format!("Body over {} bytes", limit);
+        Response::json(413, &serde_json::json!({ "error": error }))
+    } else {
+        let method = request.method().to_string();
+        lock(api)?.handle(&Request {
+            method: &method,
+            url: request.url(),
+            content_type: content_type.as_deref(),
+            accept: accept.as_deref(),
+            traceparent: traceparent.as_deref(),
+            body: &body,
+        })
+    };
+    send(request, response)
+}
+
+/// The topic a request is about, if any
+fn topic(url: &str) -> Option<&str> {
+    let path = url.split('?').next().unwrap_or_default();
+    let mut segments = path.trim_matches('/').split('/');
+    match (segments.next(), segments.next()) {
+        (Some("topics"), Some(topic)) => Some(topic),
+        _ => None,
+    }
+}
+
+/// The worker handling every request on `topic`, so that they're handled in the
+/// order received
+fn topic_worker(topic: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    topic.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+fn header(request: &tiny_http::Request, name: &str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str().to_string())
+}
+
+fn send(request: tiny_http::Request, response: Response) -> Result<()> {
+    let content_type = tiny_http::Header::from_bytes("Content-Type", response.content_type)
+        .map_err(|_| Error::other("Invalid content type header"))?;
+    request.respond(
+        tiny_http::Response::from_data(response.body)
+            .with_status_code(response.status)
+            .with_header(content_type),
+    )
+}
+
+/// Write the records of `reader` from `offset` on as server-sent events, along
+/// with the head of the response, until writing fails or `shutdown` is triggered
+fn stream_events(
+    mut reader: PartitionReader,
+    mut offset: u64,
+    mut writer: impl Write,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\n\
+        Connection: close\r\n\r\n",
+        EVENT_STREAM
+    )?;
+    writer.flush()?;
+    let mut written = Instant::now();
+    while !shutdown.is_triggered() {
+        let to = reader.end_offset()?.min(offset.saturating_add(TAIL_BATCH));
+        if to <= offset {
+            if written.elapsed() >= TAIL_KEEPALIVE {
+                writer.write_all(b": keepalive\n\n")?;
+                writer.flush()?;
+                written = Instant::now();
+            }
+            thread::sleep(TAIL_POLL_INTERVAL);
+            continue;
+        }
+        for record in reader.read_range(offset, to)? {
+            let data = serde_json::to_string(&JsonRecord::from(&record))?;
+            write!(writer, "id: {}\ndata: {}\n\n", record.offset, data)?;
+        }
+        writer.flush()?;
+        written = Instant::now();
+        offset = to;
+    }
+    Ok(())
+}
+
+fn is(header: Option<&str>, media_type: &str) -> bool {
+    header.is_some_and(|h| h.split(';').next().is_some_and(|t| t.trim() == media_type))
+}
+
+fn parse<T: std::str::FromStr>(value: &str, name: &str) -> Result<T> {
+    value.parse().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid {} {:?}", name, value),
+        )
+    })
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(value)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (unescape(k), unescape(v)))
+        .collect()
+}
+
+/// Decode the percent escapes of a query component, left as is when malformed
+fn unescape(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod rest_tests {
+    use super::{topic_worker, Request, RestApi};
+    use crate::disk::DiskMonitor;
+    use crate::partition::record::Record;
+    use crate::signal::Shutdown;
+    use crate::topic::{TopicConfig, TopicManager};
+    use crate::transform::{AddHeader, Fields, Filter};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use std::io::{BufRead, BufReader, Error, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn request<'a>(method: &'a str, url: &'a str, body: &'a [u8]) -> Request<'a> {
+        Request {
+            method,
+            url,
+            content_type: Some("application/json"),
+            accept: None,
+            traceparent: None,
+            body,
+        }
+    }
+
+    #[test]
+    fn test_produce_fetch() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager.create_topic("events", TopicConfig::new(2)).unwrap();
+        let mut api = RestApi::new(manager);
+
+        // "a" and "b" base64 encoded
+        let body = br#"[{"value":"YQ=="},{"partition":1,"key":"YQ==","value":"Yg=="}]"#;
+        let response = api.handle(&request("POST", "/topics/events/records", body));
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.body,
+            br#"[{"offset":0,"partition":0},{"offset":0,"partition":1}]"#
+        );
+        let mut binary = request("POST", "/topics/events/records?partition=1&key=a%20b", b"c");
+        binary.content_type = Some("application/octet-stream");
+        binary.traceparent = Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        assert_eq!(api.handle(&binary).status, 200);
+
+        let fetched = api.handle(&request(
+            "GET",
+            "/topics/events/partitions/1/records?offset=1",
+            b"",
+        ));
+        let json: serde_json::Value = serde_json::from_slice(&fetched.body).unwrap();
+        assert_eq!(json[0]["offset"], 1);
+        assert_eq!(json[0]["value"], "Yw==");
+        let traceparent = json[0]["headers"]["traceparent"].as_str().unwrap();
+        assert_eq!(
+            STANDARD.decode(traceparent).unwrap(),
+            b"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+        let mut binary = request("GET", "/topics/events/partitions/1/records", b"");
+        binary.accept = Some("application/octet-stream");
+        let fetched = api.handle(&binary);
+        let mut body = &fetched.body[..];
+        assert_eq!(Record::from_slice(&mut body).unwrap().value, b"b");
+        let record = Record::from_slice(&mut body).unwrap();
+        assert_eq!(
+            (record.key.unwrap(), record.value),
+            (b"a b".to_vec(), b"c".to_vec())
+        );
+        assert!(body.is_empty());
+
+        let missing = "/topics/events/partitions/2/records";
+        assert_eq!(api.handle(&request("GET", missing, b"")).status, 404);
+        let invalid = api.handle(&request("POST", "/topics/events/records", b"{"));
+        assert_eq!(invalid.status, 400);
+
+        api.produce_transforms()
+            .push(Filter(|r: &dyn Fields| !r.value().is_empty()));
+        api.fetch_transforms().push(AddHeader {
+            name: "via".into(),
+            value: b"rest".to_vec(),
+        });
+        let body = br#"[{"value":""},{"value":"ZA=="}]"#;
+        let response = api.handle(&request("POST", "/topics/events/records", body));
+        assert_eq!(
+            response.body,
+            br#"[{"offset":null,"partition":0},{"offset":1,"partition":0}]"#
+        );
+        let fetched = api.handle(&request("GET", "/topics/events/partitions/0/records", b""));
+        let json: serde_json::Value = serde_json::from_slice(&fetched.body).unwrap();
+        assert_eq!(json[1]["headers"]["via"], "cmVzdA==");
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_admin() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let mut api = RestApi::new(manager);
+        let body = br#"{"name":"events","partitions":2,"configs":{"retention.ms":"1000"}}"#;
+        let created = api.handle(&request("POST", "/topics", body));
+        assert_eq!(created.status, 201);
+        assert_eq!(api.handle(&request("POST", "/topics", body)).status, 409);
+        let body = br#"{"value":"YQ=="}"#;
+        api.handle(&request("POST", "/topics/events/records", body));
+
+        let topics = api.handle(&request("GET", "/topics", b""));
+        assert_eq!(topics.body, br#"["events"]"#);
+        let described = api.handle(&request("GET", "/topics/events", b""));
+        let json: serde_json::Value = serde_json::from_slice(&described.body).unwrap();
+        assert_eq!(json[0]["end_offset"], 1);
+        assert_eq!(json[1]["end_offset"], 0);
+        assert_eq!(
+            api.handle(&request("DELETE", "/topics/events", b"")).status,
+            200
+        );
+        assert_eq!(
+            api.handle(&request("GET", "/topics/events", b"")).status,
+            404
+        );
+        assert_eq!(api.handle(&request("GET", "/topics", b"")).body, b"[]");
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_health() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let mut api = RestApi::new(manager);
+        assert_eq!(api.handle(&request("GET", "/healthz", b"")).status, 200);
+        let ready = api.handle(&request("GET", "/readyz", b""));
+        assert_eq!(ready.body, br#"{"failed":{},"ready":true}"#);
+        assert!(!tmp_dir.path().join(".readyz").exists());
+
+        api.add_readiness_check("flusher", || Err(Error::other("stuck")));
+        let ready = api.handle(&request("GET", "/readyz", b""));
+        assert_eq!(ready.status, 503);
+        assert_eq!(
+            ready.body,
+            br#"{"failed":{"flusher":"stuck"},"ready":false}"#
+        );
+
+        let monitor = DiskMonitor::spawn(tmp_dir.path(), u64::MAX, Duration::from_secs(3600));
+        api.set_disk_monitor(Arc::new(monitor));
+        api.handle(&request(
+            "POST",
+            "/topics",
+            br#"{"name":"events","partitions":1}"#,
+        ));
+        let body = br#"{"value":"YQ=="}"#;
+        let rejected = api.handle(&request("POST", "/topics/events/records", body));
+        assert_eq!(rejected.status, 507);
+        tmp_dir.close().unwrap();
+    }
+
+    /// Send `request` to the server at `addr`, returning the status line
+    fn send(addr: &str, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn test_serve() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager.create_topic("events", TopicConfig::new(1)).unwrap();
+        let mut api = RestApi::new(manager);
+        api.set_max_body_size(16);
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let served = addr.clone();
+        thread::spawn(move || api.serve(&served));
+        let stream = loop {
+            match TcpStream::connect(&addr) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        // A client going away before sending its whole body
+        (&stream)
+            .write_all(b"POST /topics/events/records HTTP/1.1\r\nContent-Length: 10\r\n\r\n{")
+            .unwrap();
+        drop(stream);
+        let large = b"POST /topics/events/records HTTP/1.1\r\nContent-Length: 20\r\n\
+            Connection: close\r\n\r\n{\"value\":\"YWJjZA==\"}";
+        assert_eq!(send(&addr, large), "HTTP/1.1 413 Payload Too Large");
+        let small = b"POST /topics/events/records HTTP/1.1\r\nContent-Length: 16\r\n\
+            Connection: close\r\n\r\n{\"value\":\"YWJj\"}";
+        assert_eq!(send(&addr, small), "HTTP/1.1 200 OK");
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_slow_client() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        // Topics handled by different workers
+        let other = ["b", "c", "d", "e", "f"]
+            .into_iter()
+            .find(|t| topic_worker(t, 2) != topic_worker("a", 2))
+            .unwrap();
+        manager.create_topic("a", TopicConfig::new(1)).unwrap();
+        manager.create_topic(other, TopicConfig::new(1)).unwrap();
+        let mut api = RestApi::new(manager);
+        api.set_workers(2);
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let served = addr.clone();
+        thread::spawn(move || api.serve(&served));
+        let stream = loop {
+            match TcpStream::connect(&addr) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        // Still sending a body too large to be read ahead while a request on another
+        // topic is answered
+        let body = format!(r#"{{"value":"{}"}}"#, STANDARD.encode([7; 2048]));
+        let head = format!(
+            "POST /topics/a/records HTTP/1.1\r\nContent-Length: {}\r\n\
+            Connection: close\r\n\r\n",
+            body.len()
+        );
+        (&stream).write_all(head.as_bytes()).unwrap();
+        (&stream).write_all(&body.as_bytes()[..16]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let request = format!(
+            "POST /topics/{}/records HTTP/1.1\r\nContent-Length: 16\r\n\
+            Connection: close\r\n\r\n{{\"value\":\"YWJj\"}}",
+            other
+        );
+        assert_eq!(send(&addr, request.as_bytes()), "HTTP/1.1 200 OK");
+        (&stream).write_all(&body.as_bytes()[16..]).unwrap();
+        let mut response = String::new();
+        (&stream).read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_tail() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let topic = manager.create_topic("events", TopicConfig::new(1)).unwrap();
+        let partition = topic.partition(0).unwrap();
+        partition.append_record(None, b"a").unwrap();
+        partition.append_record(None, b"b").unwrap();
+        let mut api = RestApi::new(manager);
+        let shutdown = Shutdown::new();
+        api.set_shutdown(shutdown.clone());
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let served = addr.clone();
+        let serving = thread::spawn(move || {
+            api.serve(&served)?;
+            api.close()
+        });
+        let stream = loop {
+            match TcpStream::connect(&addr) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        (&stream)
+            .write_all(
+                b"GET /topics/events/partitions/0/records HTTP/1.1\r\n\
+                Accept: text/event-stream\r\nLast-Event-ID: 0\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut events = BufReader::new(&stream);
+        let mut line = String::new();
+        events.read_line(&mut line).unwrap();
+        assert_eq!(line, "HTTP/1.1 200 OK\r\n");
+        while line != "\r\n" {
+            line.clear();
+            events.read_line(&mut line).unwrap();
+        }
+        let mut next_event = || {
+            let mut event = String::new();
+            while !event.ends_with("\n\n") {
+                events.read_line(&mut event).unwrap();
+            }
+            event
+        };
+        // Resuming after the last event seen, "b" base64 encoded
+        let event = next_event();
+        assert!(event.starts_with("id: 1\ndata: {"));
+        assert!(event.contains(r#""value":"Yg==""#));
+
+        // The server keeps serving while tailing, "c" base64 encoded
+        let produce = b"POST /topics/events/records HTTP/1.1\r\nContent-Length: 16\r\n\
+            Connection: close\r\n\r\n{\"value\":\"Yw==\"}";
+        assert_eq!(send(&addr, produce), "HTTP/1.1 200 OK");
+        assert!(next_event().starts_with("id: 2\ndata: {"));
+
+        let missing = b"GET /topics/missing/partitions/0/records HTTP/1.1\r\n\
+            Accept: text/event-stream\r\nConnection: close\r\n\r\n";
+        assert_eq!(send(&addr, missing), "HTTP/1.1 404 Not Found");
+
+        // Shutting down ends the stream and closes the partitions
+        shutdown.trigger();
+        serving.join().unwrap().unwrap();
+        let mut rest = String::new();
+        events.read_to_string(&mut rest).unwrap();
+        assert!(tmp_dir.path().join("events/0/.clean_shutdown").exists());
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+//! Background maintenance scheduling
+//!
+//! Maintenance work, like merging segments, is submitted to a `Scheduler` running
+//! at most `max_concurrent` tasks at once on its own workers. Every task shares
+//! the same `Throttle`, bounding the bytes per second the background work as a
+//! whole writes, so it doesn't starve the foreground appends and reads on a loaded
+//! node.
+//!
+//! Recurring work is registered with `every`, a single timer thread of the
+//! scheduler submits it to the workers on its interval, skipping a run while the
+//! previous one is still pending. `schedule_maintenance` registers the flushing,
+//! retention, compaction and merging of a partition this way, so subsystems share
+//! the workers instead of spawning threads of their own.
+//! Compactions and merges write their segments without holding the partition,
+//! locking it only to pick the segments and to swap the rewritten ones in.
+//!
+//! Shutting down stops the timer, then waits for the tasks already submitted.
+use crate::partition::Partition;
+use std::io::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Wait of the timer thread without any recurring task
+const IDLE_WAIT: Duration = Duration::from_secs(3600);
+
+/// Token bucket pacing I/O to a rate of bytes per second, with bursts of up to
+/// a second worth of bytes
+pub struct Throttle {
+    bytes_per_sec: u64,
+    // Available budget, as of the instant
+    state: Mutex<(Instant, f64)>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            state: Mutex::new((Instant::now(), bytes_per_sec as f64)),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(u64::MAX)
+    }
+
+    /// Block until `bytes` can be spent
+    pub fn acquire(&self, bytes: usize) {
+        if self.bytes_per_sec == u64::MAX {
+            return;
+        }
+        let rate = self.bytes_per_sec as f64;
+        let wait = {
+            let Ok(mut state) = self.state.lock() else {
+                return;
+            };
+            let now = Instant::now();
+            let (last, budget) = *state;
+            let budget = (budget + now.duration_since(last).as_secs_f64() * rate).min(rate);
+            let budget = budget - bytes as f64;
+            *state = (now, budget);
+            // A negative budget is a debt paid by sleeping, outside of the lock
+            if budget < 0.0 {
+                Duration::from_secs_f64(-budget / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+type Task = Box<dyn FnOnce(&Throttle) -> Result<()> + Send>;
+type RecurringTask = Arc<dyn Fn(&Throttle) -> Result<()> + Send + Sync>;
+
+struct Recurring {
+    next: Instant,
+    interval: Duration,
+    task: RecurringTask,
+    /// Submitted and not yet run
+    pending: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct TimerState {
+    stopped: bool,
+    recurring: Vec<Recurring>,
+}
+
+#[derive(Default)]
+struct Timer {
+    state: Mutex<TimerState>,
+    wakeup: Condvar,
+}
+
+/// Background maintenance of a partition, every task is optional
+#[derive(Clone, Debug, Default)]
+pub struct Maintenance {
+    /// Flush the partition this often
+    pub flush_interval: Option<Duration>,
+    /// How often retention, compaction and merges are checked
+    pub check_interval: Duration,
+    /// Delete the sealed segments older than this
+    pub retention: Option<Duration>,
+    /// Compact the partition once its dirty ratio exceeds the minimum cleanable one
+    pub compact: bool,
+    /// Merge the sealed segments into segments of at most this size
+    pub merge_target_size: Option<usize>,
+}
+
+pub struct Scheduler {
+    sender: Option<Sender<Task>>,
+    workers: Vec<JoinHandle<()>>,
+    errors: Arc<Mutex<Vec<Error>>>,
+    timer: Arc<Timer>,
+    timer_handle: Option<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub fn new(max_concurrent: usize, throttle: Throttle) -> Self {
+        let (sender, receiver) = mpsc::channel::<Task>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let throttle = Arc::new(throttle);
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let workers = (0..max_concurrent.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let throttle = Arc::clone(&throttle);
+                let errors = Arc::clone(&errors);
+                thread::spawn(move || work(&receiver, &throttle, &errors))
+            })
+            .collect();
+        let timer = Arc::new(Timer::default());
+        let background = Arc::clone(&timer);
+        let timer_sender = sender.clone();
+        let timer_handle = thread::spawn(move || tick(&background, &timer_sender));
+        Self {
+            sender: Some(sender),
+            workers,
+            errors,
+            timer,
+            timer_handle: Some(timer_handle),
+        }
+    }
+
+    pub fn submit(&self, task: impl FnOnce(&Throttle) -> Result<()> + Send + 'static) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(Box::new(task));
+        }
+    }
+
+    /// Submit `task` every `interval`, starting an interval from now
+    pub fn every(
+        &self,
+        interval: Duration,
+        task: impl Fn(&Throttle) -> Result<()> + Send + Sync + 'static,
+    ) {
+        if let Ok(mut state) = self.timer.state.lock() {
+            state.recurring.push(Recurring {
+                next: Instant::now() + interval,
+                interval,
+                task: Arc::new(task),
+                pending: Arc::new(AtomicBool::new(false)),
+            });
+        }
+        self.timer.wakeup.notify_one();
+    }
+
+    /// Merge the sealed segments of `partition` into segments of at most
+    /// `target_size` bytes, the partition is locked only to swap the merged runs
+    pub fn submit_merge(&self, partition: Arc<Mutex<Partition>>, target_size: usize) {
+        self.submit(move |throttle| merge(&partition, target_size, throttle));
+    }
+
+    /// Run the `maintenance` of `partition` on the workers until shutdown
+    pub fn schedule_maintenance(
+        &self,
+        partition: Arc<Mutex<Partition>>,
+        maintenance: &Maintenance,
+    ) {
+        if let Some(interval) = maintenance.flush_interval {
+            let partition = Arc::clone(&partition);
+            self.every(interval, move |_| lock(&partition)?.flush());
+        }
+        let check = maintenance.check_interval;
+        if let Some(retention) = maintenance.retention {
+            let partition = Arc::clone(&partition);
+            self.every(check, move |_| {
+                lock(&partition)?.delete_expired(retention).map(drop)
+            });
+        }
+        if maintenance.compact {
+            let partition = Arc::clone(&partition);
+            self.every(check, move |throttle| compact(&partition, throttle));
+        }
+        if let Some(target_size) = maintenance.merge_target_size {
+            self.every(check, move |throttle| {
+                merge(&partition, target_size, throttle)
+            });
+        }
+    }
+
+    /// Wait for every submitted task, returning the errors they failed with
+    pub fn shutdown(mut self) -> Vec<Error> {
+        self.stop();
+        self.errors
+            .lock()
+            .map(|mut e| std::mem::take(&mut *e))
+            .unwrap_or_default()
+    }
+
+    fn stop(&mut self) {
+        if let Ok(mut state) = self.timer.state.lock() {
+            state.stopped = true;
+        }
+        self.timer.wakeup.notify_one();
+        if let Some(handle) = self.timer_handle.take() {
+            let _ = handle.join();
+        }
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn lock(partition: &Mutex<Partition>) -> Result<MutexGuard<'_, Partition>> {
+    partition
+        .lock()
+        .map_err(|_| Error::other("Partition lock poisoned"))
+}
+
+fn merge(partition: &Mutex<Partition>, target_size: usize, throttle: &Throttle) -> Result<()> {
+    loop {
+        // Not in the loop condition, the guard would be held for the whole body
+        let next = lock(partition)?.next_merge(target_size);
+        let Some(run) = next else {
+            return Ok(());
+        };
+        let staged = run.write(Some(throttle))?;
+        lock(partition)?.apply_merge(staged)?;
+    }
+}
+
+fn compact(partition: &Mutex<Partition>, throttle: &Throttle) -> Result<()> {
+    let next = lock(partition)?.next_compaction();
+    let Some(run) = next else {
+        return Ok(());
+    };
+    let staged = run.write(Some(throttle))?;
+    lock(partition)?.apply_compaction(staged).map(drop)
+}
+
+fn tick(timer: &Timer, sender: &Sender<Task>) {
+    let Ok(mut state) = timer.state.lock() else {
+        return;
+    };
+    while !state.stopped {
+        let now = Instant::now();
+        for recurring in state.recurring.iter_mut().filter(|r| r.next <= now) {
+            recurring.next = now + recurring.interval;
+            if recurring.pending.swap(true, Ordering::SeqCst) {
+                continue;
+            }
+            let task = Arc::clone(&recurring.task);
+            let pending = Arc::clone(&recurring.pending);
+            let _ = sender.send(Box::new(move |throttle| {
+                pending.store(false, Ordering::SeqCst);
+                task(throttle)
+            }));
+        }
+        let wait = state
+            .recurring
+            .iter()
+            .map(|r| r.next.saturating_duration_since(now))
+            .min()
+            .unwrap_or(IDLE_WAIT);
+        state = match timer.wakeup.wait_timeout(state, wait) {
+            Ok((state, _)) => state,
+            Err(_) => return,
+        };
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Task>>, throttle: &Throttle, errors: &Mutex<Vec<Error>>) {
+    loop {
+        let task = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        let Ok(task) = task else {
+            return;
+        };
+        if let Err(e) = task(throttle) {
+            if let Ok(mut errors) = errors.lock() {
+                errors.push(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod scheduler_tests {
+    use super::{Maintenance, Scheduler, Throttle};
+    use crate::partition::record::now_millis;
+    use crate::partition::Partition;
+    use std::fs;
+    use std::io::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(10_000);
+        let start = Instant::now();
+        // The first second worth of bytes is a burst, the rest is paced
+        for _ in 0..15 {
+            throttle.acquire(1000);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_concurrency_limit() {
+        let scheduler = Scheduler::new(2, Throttle::unlimited());
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        for i in 0..8 {
+            let running = Arc::clone(&running);
+            let peak = Arc::clone(&peak);
+            scheduler.submit(move |_| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                match i {
+                    3 => Err(Error::other("failed")),
+                    _ => Ok(()),
+                }
+            });
+        }
+        let errors = scheduler.shutdown();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_background_merge() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        for i in 0..500u64 {
+            partition.append_record(None, &i.to_be_bytes()).unwrap();
+        }
+        let files = || fs::read_dir(tmp_dir.path()).unwrap().count();
+        let before = files();
+        let partition = Arc::new(Mutex::new(partition));
+
+        let scheduler = Scheduler::new(1, Throttle::unlimited());
+        scheduler.submit_merge(Arc::clone(&partition), 8192);
+        assert!(scheduler.shutdown().is_empty());
+
+        assert!(files() < before);
+        assert!(!tmp_dir.path().join(".merge.background").exists());
+        let mut partition = partition.lock().unwrap();
+        for offset in 0..500 {
+            assert_eq!(partition.find_record(offset).unwrap().offset, offset);
+        }
+        assert!(partition.next_merge(8192).is_none());
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        let old = now_millis() - 2 * 3600 * 1000;
+        for i in 0..500u64 {
+            partition
+                .append_record_at(old, None, &i.to_be_bytes())
+                .unwrap();
+        }
+        let partition = Arc::new(Mutex::new(partition));
+
+        let scheduler = Scheduler::new(2, Throttle::unlimited());
+        let maintenance = Maintenance {
+            flush_interval: Some(Duration::from_millis(5)),
+            check_interval: Duration::from_millis(5),
+            retention: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        scheduler.schedule_maintenance(Arc::clone(&partition), &maintenance);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let maintained = |p: &Partition| p.start_offset() > 0 && p.dirty_bytes() == 0;
+        while !maintained(&partition.lock().unwrap()) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(scheduler.shutdown().is_empty());
+
+        let partition = partition.lock().unwrap();
+        assert!(partition.start_offset() > 0);
+        assert_eq!(partition.dirty_bytes(), 0);
+        tmp_dir.close().unwrap();
+    }
+}
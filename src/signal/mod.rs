@@ -0,0 +1,92 @@
+//! Graceful termination of the servers
+//!
+//! A `Shutdown` is triggered once to stop a server, which stops accepting, lets
+//! the requests in flight finish within a bounded time and leaves its partitions to
+//! be closed cleanly, see `Partition::close`, so the next start skips the recovery
+//! scans.
+//!
+//! `Shutdown::on_termination` triggers it on SIGINT or SIGTERM. Both signals are
+//! blocked in the calling thread and the threads it spawns afterwards, a thread of
+//! its own waits for them instead, a second one exits right away.
+use std::io::{Error, ErrorKind, Result};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Exit status after a second termination signal, as a shell reports SIGINT
+const FORCED_EXIT_STATUS: i32 = 130;
+/// How long the requests in flight get to finish once a shutdown is triggered
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How often the servers check for a shutdown while waiting for clients
+pub(crate) const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Handle stopping a server, shared by the server and whoever stops it
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A shutdown triggered by the first SIGINT or SIGTERM received, to be called
+    /// before spawning any other thread
+    pub fn on_termination() -> Result<Self> {
+        let shutdown = Self::new();
+        let mut signals: libc::sigset_t = unsafe { std::mem::zeroed() };
+        unsafe {
+            libc::sigemptyset(&mut signals);
+            libc::sigaddset(&mut signals, libc::SIGINT);
+            libc::sigaddset(&mut signals, libc::SIGTERM);
+        }
+        let errno =
+            unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+        if errno != 0 {
+            return Err(Error::from_raw_os_error(errno));
+        }
+        let triggered = shutdown.clone();
+        thread::spawn(move || {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                return;
+            }
+            triggered.trigger();
+            if unsafe { libc::sigwait(&signals, &mut signal) } == 0 {
+                process::exit(FORCED_EXIT_STATUS);
+            }
+        });
+        Ok(shutdown)
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::Release);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::Acquire)
+    }
+}
+
+/// Wait for the threads still serving requests once a shutdown is triggered, for
+/// `DRAIN_TIMEOUT` at most
+pub(crate) fn drain<T>(handles: Vec<JoinHandle<T>>) -> Result<()> {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while handles.iter().any(|h| !h.is_finished()) {
+        if Instant::now() >= deadline {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("Requests still running after {:?}", DRAIN_TIMEOUT),
+            ));
+        }
+        thread::sleep(DRAIN_POLL_INTERVAL);
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! Durable local state
+//!
+//! A `StateStore` maps keys to values, ordered by key. The `ChangelogStore` keeps
+//! its entries in memory and appends every mutation to a partition of its own, a
+//! put as a record of the key and a delete as a tombstone, restoring the entries
+//! from it when opened. Flushing compacts the partition once its dirty ratio is
+//! reached, so it grows with the keys rather than with the mutations.
+//!
+//! Mutations are durable once flushed, the ones after the latest flush may be lost
+//! in a crash.
+use crate::partition::Partition;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Result;
+use std::ops::Bound;
+use std::path::Path;
+
+/// Entries of a store, along with their keys
+pub type Entries<'a> = Box<dyn Iterator<Item = (&'a [u8], &'a [u8])> + 'a>;
+
+pub trait StateStore: Send {
+    fn get(&self, key: &[u8]) -> Option<&[u8]>;
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    fn delete(&mut self, key: &[u8]) -> Result<()>;
+
+    /// The entries with a key between `from` and `to`, in key order
+    fn range(&self, from: Bound<&[u8]>, to: Bound<&[u8]>) -> Entries<'_>;
+
+    /// Make the mutations so far durable
+    fn flush(&mut self) -> Result<()>;
+}
+
+/// A store without durability, for tests and state rebuilt on every start
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.entries.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.entries.remove(key);
+        Ok(())
+    }
+
+    fn range(&self, from: Bound<&[u8]>, to: Bound<&[u8]>) -> Entries<'_> {
+        Box::new(
+            self.entries
+                .range::<[u8], _>((from, to))
+                .map(|(k, v)| (k.as_slice(), v.as_slice())),
+        )
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct ChangelogStore {
+    changelog: Partition,
+    entries: MemoryStore,
+}
+
+impl ChangelogStore {
+    /// Open the store with its changelog in `dir`, created if missing, restoring its
+    /// entries
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Self::restore(Partition::open(dir)?)
+    }
+
+    /// Restore a store from the records of `changelog`
+    pub fn restore(changelog: Partition) -> Result<Self> {
+        let entries = MemoryStore {
+            entries: changelog.latest_values()?.into_iter().collect(),
+        };
+        Ok(Self { changelog, entries })
+    }
+
+    pub fn changelog(&self) -> &Partition {
+        &self.changelog
+    }
+}
+
+impl StateStore for ChangelogStore {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.changelog.append_record(Some(key.to_vec()), value)?;
+        self.entries.put(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        if self.entries.get(key).is_none() {
+            return Ok(());
+        }
+        self.changelog.append_tombstone(key.to_vec())?;
+        self.entries.delete(key)
+    }
+
+    fn range(&self, from: Bound<&[u8]>, to: Bound<&[u8]>) -> Entries<'_> {
+        self.entries.range(from, to)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.changelog.flush()?;
+        self.changelog.clean(None).map(drop)
+    }
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::{ChangelogStore, StateStore};
+    use std::ops::Bound;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_changelog_store() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut store = ChangelogStore::open(tmp_dir.path()).unwrap();
+        for i in 0..200u32 {
+            let key = format!("key-{:02}", i % 20);
+            store.put(key.as_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        store.delete(b"key-03").unwrap();
+        store.delete(b"missing").unwrap();
+        store.flush().unwrap();
+        assert_eq!(store.get(b"key-01"), Some(&181u32.to_be_bytes()[..]));
+        assert!(store.changelog().clean_offset() > 0);
+        drop(store);
+
+        let store = ChangelogStore::open(tmp_dir.path()).unwrap();
+        assert_eq!(store.get(b"key-01"), Some(&181u32.to_be_bytes()[..]));
+        assert_eq!(store.get(b"key-03"), None);
+        let keys: Vec<_> = store
+            .range(Bound::Included(b"key-02"), Bound::Excluded(b"key-06"))
+            .map(|(k, _)| k.to_vec())
+            .collect();
+        assert_eq!(keys, [&b"key-02"[..], b"key-04", b"key-05"]);
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,549 @@
+//! In-process stream processing
+//!
+//! A `Stream` reads the records of a topic and passes them through its operators,
+//! like `filter` and `map`, then `to` appends what comes out to another topic. The
+//! resulting `Pipeline`s run in a `Streams` runner, a pass at a time or recurring
+//! on the workers of a `Scheduler`.
+//!
+//! A pipeline consumes as the group of its name. A pass appends the records it
+//! produced and flushes the output topic before committing the input offsets, so
+//! delivery is at least once: the records after the latest commit are processed
+//! again after a crash, and some of their outputs appended twice.
+//!
+//! A stream joins a `Table` materialized from another topic by key, the table
+//! brought up to date with that topic before every lookup. Records read before a
+//! key is in the table don't see it, joining them again isn't attempted.
+//!
+//! Aggregating over time windows keeps its state in a `StateStore`, see `window`.
+//! The aggregates are appended to the output topic as their windows close.
+//!
+//! Records keep the timestamp they were read with. Keyed records are spread over
+//! the partitions of the output topic by the hash of their key, the others go to
+//! the partition of the number they were read from, modulo the partition count.
+pub mod table;
+pub mod window;
+
+use crate::consumer::Consumer;
+use crate::group::assignor::TopicPartition;
+use crate::offsets::OffsetReset;
+use crate::partition::record::Record;
+use crate::scheduler::Scheduler;
+use crate::state::StateStore;
+use crate::topic::TopicManager;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use table::Table;
+use window::{Aggregate, Windows};
+
+/// Bytes polled for a pipeline on each pass
+const POLL_BYTES: usize = 1024 * 1024;
+
+pub(crate) trait Operator: Send {
+    /// Process `record`, returning the records to pass on
+    fn process(&mut self, manager: &mut TopicManager, record: Record) -> Result<Vec<Record>>;
+
+    /// Make the state of the operator durable, called before committing the
+    /// records processed
+    fn flush(&mut self, _manager: &mut TopicManager) -> Result<()> {
+        Ok(())
+    }
+
+    /// Discard the state changed since the latest flush, called when a pass fails
+    /// before its records are processed again
+    fn rollback(&mut self) {}
+}
+
+struct Filter<P>(P);
+
+impl<P: Fn(&Record) -> bool + Send> Operator for Filter<P> {
+    fn process(&mut self, _: &mut TopicManager, record: Record) -> Result<Vec<Record>> {
+        Ok(match (self.0)(&record) {
+            true => vec![record],
+            false => Vec::new(),
+        })
+    }
+}
+
+struct Map<F>(F);
+
+impl<F: Fn(Record) -> Record + Send> Operator for Map<F> {
+    fn process(&mut self, _: &mut TopicManager, record: Record) -> Result<Vec<Record>> {
+        Ok(vec![(self.0)(record)])
+    }
+}
+
+struct Join<F> {
+    table: Table,
+    joiner: F,
+}
+
+impl<F: Fn(Record, &[u8]) -> Record + Send> Operator for Join<F> {
+    fn process(&mut self, manager: &mut TopicManager, record: Record) -> Result<Vec<Record>> {
+        self.table.update(manager)?;
+        let Some(value) = record.key.as_deref().and_then(|k| self.table.get(k)) else {
+            return Ok(Vec::new());
+        };
+        let value = value.to_vec();
+        Ok(vec![(self.joiner)(record, &value)])
+    }
+}
+
+pub struct Stream {
+    source: String,
+    operators: Vec<Box<dyn Operator>>,
+}
+
+impl Stream {
+    /// A stream of the records of every partition of `topic`
+    pub fn from(topic: &str) -> Self {
+        Self {
+            source: topic.into(),
+            operators: Vec::new(),
+        }
+    }
+
+    /// Keep only the records matching `predicate`
+    pub fn filter(mut self, predicate: impl Fn(&Record) -> bool + Send + 'static) -> Self {
+        self.operators.push(Box::new(Filter(predicate)));
+        self
+    }
+
+    /// Rewrite every record with `f`
+    pub fn map(mut self, f: impl Fn(Record) -> Record + Send + 'static) -> Self {
+        self.operators.push(Box::new(Map(f)));
+        self
+    }
+
+    /// Join every record with the value of its key in the table of `topic`, the
+    /// records without a key or a value in the table are dropped
+    pub fn join(
+        mut self,
+        topic: &str,
+        joiner: impl Fn(Record, &[u8]) -> Record + Send + 'static,
+    ) -> Self {
+        self.operators.push(Box::new(Join {
+            table: Table::new(topic),
+            joiner,
+        }));
+        self
+    }
+
+    /// Aggregate the records of every key in `windows` with `aggregator`, from 0,
+    /// keeping the aggregates of the open windows in `store`
+    pub fn aggregate(
+        mut self,
+        windows: Windows,
+        store: impl StateStore + 'static,
+        aggregator: impl Fn(i64, &Record) -> i64 + Send + 'static,
+    ) -> Self {
+        let store = Box::new(store);
+        self.operators
+            .push(Box::new(Aggregate::new(windows, store, aggregator)));
+        self
+    }
+
+    /// Count the records of every key in `windows`
+    pub fn count(self, windows: Windows, store: impl StateStore + 'static) -> Self {
+        self.aggregate(windows, store, |count, _| count + 1)
+    }
+
+    /// Append the records of the stream to `topic`
+    pub fn to(self, topic: &str) -> Pipeline {
+        Pipeline {
+            stream: self,
+            sink: topic.into(),
+        }
+    }
+}
+
+/// A stream along with the topic its records are appended to
+pub struct Pipeline {
+    stream: Stream,
+    sink: String,
+}
+
+impl Pipeline {
+    fn process(&mut self, manager: &mut TopicManager, record: Record) -> Result<Vec<Record>> {
+        let mut records = vec![record];
+        for operator in &mut self.stream.operators {
+            let mut next = Vec::new();
+            for record in records {
+                next.extend(operator.process(manager, record)?);
+            }
+            records = next;
+        }
+        Ok(records)
+    }
+
+    fn send(&self, manager: &mut TopicManager, partition: u32, record: Record) -> Result<()> {
+        let topic = manager.topic(&self.sink).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Topic {} not found", self.sink),
+            )
+        })?;
+        let count = topic.partitions().len() as u32;
+        let n = match &record.key {
+            Some(key) => crc32fast::hash(key) % count,
+            None => partition % count,
+        };
+        let partition = topic
+            .partition(n)
+            .ok_or_else(|| Error::other("Partition out of range"))?;
+        partition.append_record_with_headers(
+            Some(record.timestamp),
+            record.key,
+            &record.value,
+            record.headers,
+        )?;
+        Ok(())
+    }
+}
+
+struct Task {
+    consumer: Consumer,
+    pipeline: Pipeline,
+}
+
+impl Task {
+    /// Run a pass, a failed one moves the consumer back to the committed offsets
+    /// and rolls the operators back for the next pass to process its records again
+    fn run(&mut self, manager: &mut TopicManager) -> Result<usize> {
+        self.pass(manager).or_else(|e| {
+            for operator in &mut self.pipeline.stream.operators {
+                operator.rollback();
+            }
+            self.consumer.seek_to_committed(manager)?;
+            Err(e)
+        })
+    }
+
+    fn pass(&mut self, manager: &mut TopicManager) -> Result<usize> {
+        let polled = self.consumer.poll(manager, POLL_BYTES)?;
+        if polled.is_empty() {
+            return Ok(0);
+        }
+        for consumed in &polled {
+            for record in self.pipeline.process(manager, consumed.record.clone())? {
+                self.pipeline.send(manager, consumed.partition, record)?;
+            }
+        }
+        for operator in &mut self.pipeline.stream.operators {
+            operator.flush(manager)?;
+        }
+        if let Some(topic) = manager.topic(&self.pipeline.sink) {
+            topic.flush()?;
+        }
+        self.consumer.commit(manager)?;
+        Ok(polled.len())
+    }
+}
+
+/// Runner of pipelines
+#[derive(Default)]
+pub struct Streams {
+    tasks: Vec<Task>,
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `pipeline` as `name`, from the input offsets committed by that name
+    pub fn add(&mut self, manager: &TopicManager, name: &str, pipeline: Pipeline) -> Result<()> {
+        let source = &pipeline.stream.source;
+        let partitions: Vec<_> = (0..manager.describe_topic(source)?.len() as u32)
+            .map(|n| TopicPartition::new(source, n))
+            .collect();
+        let mut consumer = Consumer::new(name, OffsetReset::Earliest);
+        consumer.assign(manager, &partitions)?;
+        self.tasks.push(Task { consumer, pipeline });
+        Ok(())
+    }
+
+    /// Run a pass over every pipeline, returning the number of records processed
+    pub fn run_once(&mut self, manager: &mut TopicManager) -> Result<usize> {
+        let mut processed = 0;
+        for task in &mut self.tasks {
+            processed += task.run(manager)?;
+        }
+        Ok(processed)
+    }
+
+    /// Run a pass every `interval` on the workers of `scheduler`, until it shuts
+    /// down
+    pub fn spawn(
+        self,
+        manager: Arc<Mutex<TopicManager>>,
+        scheduler: &Scheduler,
+        interval: Duration,
+    ) {
+        let streams = Mutex::new(self);
+        scheduler.every(interval, move |_| {
+            let mut manager = manager
+                .lock()
+                .map_err(|_| Error::other("Topic manager lock poisoned"))?;
+            let mut streams = streams
+                .lock()
+                .map_err(|_| Error::other("Streams lock poisoned"))?;
+            streams.run_once(&mut manager).map(drop)
+        });
+    }
+}
+
+#[cfg(test)]
+mod streams_tests {
+    use super::window::Windows;
+    use super::{Stream, Streams};
+    use crate::state::ChangelogStore;
+    use crate::topic::{TopicConfig, TopicManager, CLEANUP_POLICY};
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_pipeline() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let topic = manager.create_topic("words", TopicConfig::new(2)).unwrap();
+        for (n, word) in [(0, "a"), (1, "skip"), (1, "b"), (0, "c")] {
+            let partition = topic.partition(n).unwrap();
+            partition
+                .append_record_at(1000, None, word.as_bytes())
+                .unwrap();
+        }
+        manager.create_topic("upper", TopicConfig::new(1)).unwrap();
+        let pipeline = || {
+            Stream::from("words")
+                .filter(|r| r.value != b"skip")
+                .map(|mut r| {
+                    r.value.make_ascii_uppercase();
+                    r
+                })
+                .to("upper")
+        };
+
+        let mut streams = Streams::new();
+        streams.add(&manager, "uppercase", pipeline()).unwrap();
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 4);
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 0);
+
+        // A restarted pipeline resumes from its committed offsets
+        let partition = manager.topic("words").unwrap().partition(1).unwrap();
+        partition.append_record_at(2000, None, b"d").unwrap();
+        let mut streams = Streams::new();
+        streams.add(&manager, "uppercase", pipeline()).unwrap();
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 1);
+
+        let upper = manager.partition("upper", 0).unwrap().read_range(0, 10);
+        let upper = upper.unwrap();
+        let mut values: Vec<_> = upper.iter().map(|r| r.value.clone()).collect();
+        values.sort();
+        assert_eq!(values, [b"A", b"B", b"C", b"D"]);
+        assert_eq!(upper[0].timestamp, 1000);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_join() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let compacted = TopicConfig::new(2).with(CLEANUP_POLICY, "compact");
+        let users = manager.create_topic("users", compacted).unwrap();
+        users
+            .partition(1)
+            .unwrap()
+            .append_record(Some(b"u1".to_vec()), b"ada")
+            .unwrap();
+        let clicks = manager.create_topic("clicks", TopicConfig::new(1)).unwrap();
+        let clicks = clicks.partition(0).unwrap();
+        clicks.append_record(Some(b"u1".to_vec()), b"home").unwrap();
+        clicks.append_record(Some(b"u2".to_vec()), b"home").unwrap();
+        manager
+            .create_topic("enriched", TopicConfig::new(1))
+            .unwrap();
+
+        let pipeline = Stream::from("clicks")
+            .join("users", |mut r, name| {
+                r.value = [name, b" ", &r.value].concat();
+                r
+            })
+            .to("enriched");
+        let mut streams = Streams::new();
+        streams.add(&manager, "enrich", pipeline).unwrap();
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 2);
+
+        // Later updates of the table are seen by the records following them
+        let users = manager.topic("users").unwrap();
+        let ada = users.partition(1).unwrap();
+        ada.append_record(Some(b"u1".to_vec()), b"ada l.").unwrap();
+        let bob = users.partition(0).unwrap();
+        bob.append_record(Some(b"u2".to_vec()), b"bob").unwrap();
+        let clicks = manager.topic("clicks").unwrap().partition(0).unwrap();
+        clicks.append_record(Some(b"u1".to_vec()), b"cart").unwrap();
+        clicks.append_record(Some(b"u2".to_vec()), b"cart").unwrap();
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 2);
+
+        let enriched = manager.partition("enriched", 0).unwrap().read_range(0, 10);
+        let values: Vec<_> = enriched.unwrap().into_iter().map(|r| r.value).collect();
+        assert_eq!(values, [&b"ada home"[..], b"ada l. cart", b"bob cart"]);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_window_count() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let hopping = Windows::hopping(Duration::from_secs(10), Duration::from_secs(5));
+        assert_eq!(hopping.starts(12_000), [10_000, 5_000]);
+        let views = manager.create_topic("views", TopicConfig::new(1)).unwrap();
+        let views = views.partition(0).unwrap();
+        for (ts, page) in [(1000, "a"), (2000, "a"), (5000, "b")] {
+            views.append_record_at(ts, Some(page.into()), b"").unwrap();
+        }
+        manager.create_topic("counts", TopicConfig::new(1)).unwrap();
+        let state = tmp_dir.path().join("counts.state");
+        let pipeline = || {
+            let store = ChangelogStore::open(&state).unwrap();
+            Stream::from("views")
+                .count(Windows::tumbling(Duration::from_secs(10)), store)
+                .to("counts")
+        };
+        let mut streams = Streams::new();
+        streams.add(&manager, "count", pipeline()).unwrap();
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 3);
+        assert_eq!(manager.partition("counts", 0).unwrap().end_offset(), 0);
+
+        // The open windows are restored from the changelog after a restart
+        drop(streams);
+        let mut streams = Streams::new();
+        streams.add(&manager, "count", pipeline()).unwrap();
+        let views = manager.topic("views").unwrap().partition(0).unwrap();
+        views
+            .append_record_at(12_000, Some(b"a".to_vec()), b"")
+            .unwrap();
+        views
+            .append_record_at(3000, Some(b"a".to_vec()), b"")
+            .unwrap();
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 2);
+
+        let counts = manager.partition("counts", 0).unwrap().read_range(0, 10);
+        let counts: Vec<_> = counts
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.key.unwrap(), r.value, r.timestamp))
+            .collect();
+        let count = |n: i64| n.to_be_bytes().to_vec();
+        assert_eq!(
+            counts,
+            [
+                (b"a".to_vec(), count(2), 10_000),
+                (b"b".to_vec(), count(1), 10_000)
+            ]
+        );
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_window_stream_time_restored() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let views = manager.create_topic("views", TopicConfig::new(1)).unwrap();
+        let views = views.partition(0).unwrap();
+        for ts in [1000, 12_000] {
+            views
+                .append_record_at(ts, Some(b"a".to_vec()), b"")
+                .unwrap();
+        }
+        manager.create_topic("counts", TopicConfig::new(1)).unwrap();
+        let state = tmp_dir.path().join("counts.state");
+        let pipeline = || {
+            let store = ChangelogStore::open(&state).unwrap();
+            Stream::from("views")
+                .count(Windows::tumbling(Duration::from_secs(10)), store)
+                .to("counts")
+        };
+        let mut streams = Streams::new();
+        streams.add(&manager, "count", pipeline()).unwrap();
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 2);
+
+        // A late record after a restart doesn't reopen the window emitted before
+        drop(streams);
+        let mut streams = Streams::new();
+        streams.add(&manager, "count", pipeline()).unwrap();
+        let views = manager.topic("views").unwrap().partition(0).unwrap();
+        for ts in [3000, 25_000] {
+            views
+                .append_record_at(ts, Some(b"a".to_vec()), b"")
+                .unwrap();
+        }
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 2);
+
+        let counts = manager.partition("counts", 0).unwrap().read_range(0, 10);
+        let ends: Vec<_> = counts.unwrap().into_iter().map(|r| r.timestamp).collect();
+        assert_eq!(ends, [10_000, 20_000]);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_failed_pass_is_retried() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let topic = manager.create_topic("words", TopicConfig::new(1)).unwrap();
+        for word in ["a", "b"] {
+            let partition = topic.partition(0).unwrap();
+            partition.append_record(None, word.as_bytes()).unwrap();
+        }
+        let mut streams = Streams::new();
+        let pipeline = Stream::from("words").to("copy");
+        streams.add(&manager, "copy", pipeline).unwrap();
+
+        // The output topic is missing, the records are polled again once it's there
+        assert!(streams.run_once(&mut manager).is_err());
+        manager.create_topic("copy", TopicConfig::new(1)).unwrap();
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 2);
+        let copy = manager.partition("copy", 0).unwrap().read_range(0, 10);
+        assert_eq!(copy.unwrap().len(), 2);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_failed_pass_through_aggregate() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let views = manager.create_topic("views", TopicConfig::new(1)).unwrap();
+        let views = views.partition(0).unwrap();
+        for ts in [1000, 2000, 12_000] {
+            views
+                .append_record_at(ts, Some(b"a".to_vec()), b"")
+                .unwrap();
+        }
+        let store = ChangelogStore::open(tmp_dir.path().join("counts.state")).unwrap();
+        let pipeline = Stream::from("views")
+            .count(Windows::tumbling(Duration::from_secs(10)), store)
+            .to("counts");
+        let mut streams = Streams::new();
+        streams.add(&manager, "count", pipeline).unwrap();
+
+        // Closing the first window fails, the pass is aggregated again from scratch
+        assert!(streams.run_once(&mut manager).is_err());
+        manager.create_topic("counts", TopicConfig::new(1)).unwrap();
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 3);
+        let views = manager.topic("views").unwrap().partition(0).unwrap();
+        views
+            .append_record_at(25_000, Some(b"a".to_vec()), b"")
+            .unwrap();
+        assert_eq!(streams.run_once(&mut manager).unwrap(), 1);
+
+        let counts = manager.partition("counts", 0).unwrap().read_range(0, 10);
+        let counts: Vec<_> = counts
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.value, r.timestamp))
+            .collect();
+        let count = |n: i64| n.to_be_bytes().to_vec();
+        assert_eq!(counts, [(count(2), 10_000), (count(1), 20_000)]);
+        tmp_dir.close().unwrap();
+    }
+}
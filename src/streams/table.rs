@@ -0,0 +1,79 @@
+//! Tables materialized from topics
+//!
+//! A `Table` holds the latest value of every key of a topic, keyed records of all
+//! its partitions applied in order and tombstones removing their key. Records
+//! without a key are skipped. The topic is meant to be compacted, materializing it
+//! from scratch then reads about one record per key.
+//!
+//! The first update bootstraps the table from the latest values of the partitions,
+//! see `Partition::latest_values`, the following ones fetch the records appended
+//! since.
+use crate::topic::TopicManager;
+use std::collections::HashMap;
+use std::io::Result;
+
+/// Bytes read from a partition of the topic at once
+const FETCH_BYTES: usize = 1024 * 1024;
+
+pub struct Table {
+    topic: String,
+    positions: Vec<u64>,
+    entries: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Table {
+    /// An empty table of `topic`, filled by `update`
+    pub fn new(topic: &str) -> Self {
+        Self {
+            topic: topic.into(),
+            positions: Vec::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Apply the records appended to the topic since the previous update
+    pub fn update(&mut self, manager: &TopicManager) -> Result<()> {
+        let partitions = manager.describe_topic(&self.topic)?.len();
+        self.positions.resize(partitions, 0);
+        for (n, position) in self.positions.iter_mut().enumerate() {
+            let log = manager.partition(&self.topic, n as u32)?;
+            if *position == 0 {
+                *position = log.end_offset();
+                self.entries.extend(log.latest_values()?);
+            }
+            while *position < log.end_offset() {
+                let records = log.fetch(*position, FETCH_BYTES)?;
+                let Some(last) = records.last() else {
+                    break;
+                };
+                *position = last.offset + 1;
+                for record in records {
+                    let Some(key) = record.key else {
+                        continue;
+                    };
+                    match record.attributes.tombstone() {
+                        true => self.entries.remove(&key),
+                        false => self.entries.insert(key, record.value),
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The latest value of `key`, as of the latest update
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.entries.get(key).map(Vec::as_slice)
+    }
+
+    /// Every key along with its latest value, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.entries
+            .iter()
+            .map(|(k, v)| (k.as_slice(), v.as_slice()))
+    }
+}
@@ -0,0 +1,182 @@
+//! Windowed aggregation
+//!
+//! Records are aggregated per key into the time windows their timestamp falls in,
+//! tumbling windows one after the other or hopping ones overlapping each other.
+//! The stream time is the latest timestamp seen, a window closes once it reaches
+//! the end of the window: its aggregate is emitted, keyed as the records were, and
+//! the records still belonging to it afterwards are dropped as late.
+//!
+//! The aggregates of the open windows live in a `StateStore`, keyed by the start
+//! of the window followed by the key, and are deleted once the window closes. The
+//! changes of a pass are staged in memory and written to the store along with the
+//! stream time when flushed, a failed pass discards them so its records are
+//! aggregated again from the state of the previous one. With a `ChangelogStore`
+//! both survive restarts, and windows already closed stay closed.
+use crate::partition::record::Record;
+use crate::state::StateStore;
+use crate::streams::Operator;
+use crate::topic::TopicManager;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Bound;
+use std::time::Duration;
+
+/// Header holding the start of the window of an aggregate, in milliseconds since
+/// the unix epoch
+pub const WINDOW_START_HEADER: &str = "window.start";
+/// Key of the stream time in the store, sorting after every window start
+const STREAM_TIME_KEY: &[u8] = b"\xff\xff\xff\xff\xff\xff\xff\xffstream.time";
+
+/// Windows of `size` starting every `advance`, both in milliseconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Windows {
+    size: u64,
+    advance: u64,
+}
+
+impl Windows {
+    pub fn tumbling(size: Duration) -> Self {
+        Self::hopping(size, size)
+    }
+
+    pub fn hopping(size: Duration, advance: Duration) -> Self {
+        Self {
+            size: (size.as_millis() as u64).max(1),
+            advance: (advance.as_millis() as u64).max(1),
+        }
+    }
+
+    /// The starts of the windows `timestamp` falls in, latest first
+    pub fn starts(&self, timestamp: u64) -> Vec<u64> {
+        let latest = timestamp - timestamp % self.advance;
+        (0..=latest / self.advance)
+            .map(|n| latest - n * self.advance)
+            .take_while(|start| start + self.size > timestamp)
+            .collect()
+    }
+}
+
+pub(crate) struct Aggregate<F> {
+    windows: Windows,
+    store: Box<dyn StateStore>,
+    aggregator: F,
+    stream_time: u64,
+    /// Stream time as of the latest flush
+    flushed_stream_time: u64,
+    /// Aggregates updated since the latest flush, `None` for the deleted ones
+    pending: BTreeMap<Vec<u8>, Option<i64>>,
+}
+
+impl<F> Aggregate<F> {
+    pub(crate) fn new(windows: Windows, store: Box<dyn StateStore>, aggregator: F) -> Self {
+        let stream_time = store
+            .get(STREAM_TIME_KEY)
+            .and_then(|value| value.try_into().ok())
+            .map_or(0, u64::from_be_bytes);
+        Self {
+            windows,
+            store,
+            aggregator,
+            stream_time,
+            flushed_stream_time: stream_time,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// The aggregate of `entry`, staged or in the store
+    fn get(&self, entry: &[u8]) -> Result<Option<i64>> {
+        match self.pending.get(entry) {
+            Some(aggregate) => Ok(*aggregate),
+            None => match self.store.get(entry) {
+                Some(value) => decode(entry, value).map(|(_, _, aggregate)| Some(aggregate)),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Take the aggregates of the windows closed by the stream time out of the
+    /// store, as records
+    fn close(&mut self) -> Result<Vec<Record>> {
+        let Some(bound) = (self.stream_time + 1).checked_sub(self.windows.size) else {
+            return Ok(Vec::new());
+        };
+        let bound = bound.to_be_bytes();
+        let mut closed = BTreeMap::new();
+        for (entry, value) in self.store.range(Bound::Unbounded, Bound::Excluded(&bound)) {
+            closed.insert(entry.to_vec(), decode(entry, value)?.2);
+        }
+        for (entry, aggregate) in self
+            .pending
+            .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(&bound[..])))
+        {
+            match aggregate {
+                Some(aggregate) => closed.insert(entry.clone(), *aggregate),
+                None => closed.remove(entry),
+            };
+        }
+        let mut emitted = Vec::new();
+        for (entry, aggregate) in closed {
+            let (start, key, _) = decode(&entry, &aggregate.to_be_bytes())?;
+            let end = start + self.windows.size;
+            let key = (!key.is_empty()).then(|| key.to_vec());
+            let mut record = Record::with_timestamp(0, end, key, aggregate.to_be_bytes().to_vec());
+            record
+                .headers
+                .push((WINDOW_START_HEADER.into(), start.to_string().into_bytes()));
+            emitted.push(record);
+            self.pending.insert(entry, None);
+        }
+        Ok(emitted)
+    }
+}
+
+impl<F: Fn(i64, &Record) -> i64 + Send> Operator for Aggregate<F> {
+    fn process(&mut self, _: &mut TopicManager, record: Record) -> Result<Vec<Record>> {
+        self.stream_time = self.stream_time.max(record.timestamp);
+        let key = record.key.as_deref().unwrap_or_default();
+        for start in self.windows.starts(record.timestamp) {
+            if start + self.windows.size <= self.stream_time {
+                continue;
+            }
+            let entry = [&start.to_be_bytes(), key].concat();
+            let current = self.get(&entry)?.unwrap_or(0);
+            let aggregate = (self.aggregator)(current, &record);
+            self.pending.insert(entry, Some(aggregate));
+        }
+        self.close()
+    }
+
+    fn flush(&mut self, _: &mut TopicManager) -> Result<()> {
+        for (entry, aggregate) in std::mem::take(&mut self.pending) {
+            match aggregate {
+                Some(aggregate) => self.store.put(&entry, &aggregate.to_be_bytes())?,
+                None => self.store.delete(&entry)?,
+            }
+        }
+        let stream_time = self.stream_time.to_be_bytes();
+        if self.store.get(STREAM_TIME_KEY) != Some(&stream_time[..]) {
+            self.store.put(STREAM_TIME_KEY, &stream_time)?;
+        }
+        self.store.flush()?;
+        self.flushed_stream_time = self.stream_time;
+        Ok(())
+    }
+
+    fn rollback(&mut self) {
+        self.pending.clear();
+        self.stream_time = self.flushed_stream_time;
+    }
+}
+
+/// The window start, key and aggregate of an entry of the store
+fn decode<'a>(entry: &'a [u8], value: &[u8]) -> Result<(u64, &'a [u8], i64)> {
+    match (entry.split_first_chunk(), value.try_into()) {
+        (Some((start, key)), Ok(value)) => {
+            Ok((u64::from_be_bytes(*start), key, i64::from_be_bytes(value)))
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid window aggregate in the state store",
+        )),
+    }
+}
@@ -0,0 +1,825 @@
+//! Topics, named groups of partitions sharing the same configuration
+//!
+//! A `TopicManager` owns a root directory where every topic lives in its own
+//! sub-directory, holding its configuration and a directory per partition:
+//!
+//! ```text
+//! root/
+//!   events/
+//!     topic.json
+//!     0/
+//!     1/
+//!   __offsets/
+//! ```
+//!
+//! Names starting with a double underscore are reserved to internal topics, like
+//! the one storing consumer group offsets.
+//!
+//! A deleted topic has its directory renamed with the `deleted` extension before
+//! it's removed, the leftovers of an interrupted deletion are removed on open
+//! rather than loaded. A topic directory without its configuration is the leftover
+//! of an interrupted creation, it's ignored on open and wiped when the topic is
+//! created again.
+use crate::memory::MemoryManager;
+use crate::offsets::{OffsetReset, OffsetStore, OffsetTarget, PositionReset, OFFSETS_TOPIC};
+use crate::partition::manifest::PartitionConfig;
+use crate::partition::record::Record;
+use crate::partition::{Partition, DELETED_EXTENSION};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const CONFIG_FILE: &str = "topic.json";
+/// Setting bounding the encoded size of the records of a topic
+pub const MAX_RECORD_BYTES: &str = "max.record.bytes";
+/// Setting bounding the bytes stored by each partition of a topic
+pub const MAX_PARTITION_BYTES: &str = "max.partition.bytes";
+/// Setting choosing between `CreateTime` and `LogAppendTime` record timestamps
+pub const TIMESTAMP_TYPE: &str = "message.timestamp.type";
+/// Setting bounding the ratio of dirty bytes a partition is compacted past
+pub const MIN_CLEANABLE_DIRTY_RATIO: &str = "min.cleanable.dirty.ratio";
+/// Setting keeping the records more recent than this many milliseconds from being
+/// compacted
+pub const MIN_COMPACTION_LAG_MS: &str = "min.compaction.lag.ms";
+/// Setting keeping the latest this many records of every key when compacting, 1
+/// by default
+pub const COMPACTION_VERSIONS: &str = "compaction.versions";
+/// Setting choosing how old records are cleaned up, see `CleanupPolicy`
+pub const CLEANUP_POLICY: &str = "cleanup.policy";
+/// Setting bounding how long records are retained by a deleting cleanup policy,
+/// in milliseconds, records are kept forever if unset
+pub const RETENTION_MS: &str = "retention.ms";
+/// Setting indexing the records of a topic every this many bytes instead of every
+/// fixed number of records
+pub const INDEX_INTERVAL_BYTES: &str = "index.interval.bytes";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopicConfig {
+    pub partitions: u32,
+    /// Free form settings, e.g. retention or cleanup policies
+    #[serde(default)]
+    pub configs: BTreeMap<String, String>,
+}
+
+impl TopicConfig {
+    pub fn new(partitions: u32) -> Self {
+        Self {
+            partitions,
+            configs: BTreeMap::new(),
+        }
+    }
+
+    pub fn with(mut self, key: &str, value: &str) -> Self {
+        self.configs.insert(key.into(), value.into());
+        self
+    }
+
+    /// Parse the value of a setting, if present
+    fn parse<T: FromStr>(&self, key: &str) -> Result<Option<T>> {
+        self.configs
+            .get(key)
+            .map(|v| {
+                v.parse::<T>().map_err(|_| {
+                    Error::new(ErrorKind::InvalidInput, format!("Invalid {} {:?}", key, v))
+                })
+            })
+            .transpose()
+    }
+
+    /// The settings of the partitions of the topic
+    fn partition_config(&self) -> Result<PartitionConfig> {
+        Ok(PartitionConfig {
+            max_record_size: self.parse(MAX_RECORD_BYTES)?,
+            max_size: self.parse(MAX_PARTITION_BYTES)?,
+            timestamp_type: self.parse(TIMESTAMP_TYPE)?,
+            index_interval_bytes: self.parse(INDEX_INTERVAL_BYTES)?,
+            min_cleanable_dirty_ratio: self.parse(MIN_CLEANABLE_DIRTY_RATIO)?,
+            min_compaction_lag_ms: self.parse(MIN_COMPACTION_LAG_MS)?,
+            compaction_versions: self.parse(COMPACTION_VERSIONS)?,
+            ..Default::default()
+        })
+    }
+}
+
+/// How the partitions of a topic get rid of old records, parsed from a comma
+/// separated list like `compact,delete`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CleanupPolicy {
+    /// Keep only the latest record of every key
+    pub compact: bool,
+    /// Delete the segments older than the retention
+    pub delete: bool,
+}
+
+impl Default for CleanupPolicy {
+    fn default() -> Self {
+        Self {
+            compact: false,
+            delete: true,
+        }
+    }
+}
+
+impl FromStr for CleanupPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut policy = CleanupPolicy {
+            compact: false,
+            delete: false,
+        };
+        for name in s.split(',') {
+            match name.trim() {
+                "compact" => policy.compact = true,
+                "delete" => policy.delete = true,
+                other => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Unknown cleanup policy {:?}", other),
+                    ))
+                }
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// Start and end offsets of a partition of a topic
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct PartitionOffsets {
+    pub partition: u32,
+    pub start_offset: u64,
+    pub end_offset: u64,
+}
+
+/// The records fetched from a partition of a topic
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartitionFetch {
+    pub partition: u32,
+    pub records: Vec<Record>,
+}
+
+pub struct Topic {
+    dir: PathBuf,
+    name: String,
+    config: TopicConfig,
+    partitions: Vec<Partition>,
+    cleanup_policy: CleanupPolicy,
+    retention: Option<Duration>,
+}
+
+impl Topic {
+    fn open(dir: &Path, name: &str, config: TopicConfig) -> Result<Self> {
+        // Leftovers of partitions whose destruction was interrupted
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == DELETED_EXTENSION) {
+                fs::remove_dir_all(path)?;
+            }
+        }
+        let partition_config = config.partition_config()?;
+        let cleanup_policy: Option<CleanupPolicy> = config.parse(CLEANUP_POLICY)?;
+        let retention: Option<u64> = config.parse(RETENTION_MS)?;
+        let partitions = (0..config.partitions)
+            .map(|n| Partition::open_or_create(dir.join(n.to_string()), &partition_config))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            name: name.into(),
+            config,
+            partitions,
+            cleanup_policy: cleanup_policy.unwrap_or_default(),
+            retention: retention.map(Duration::from_millis),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &TopicConfig {
+        &self.config
+    }
+
+    pub fn partition(&mut self, partition: u32) -> Option<&mut Partition> {
+        self.partitions.get_mut(partition as usize)
+    }
+
+    pub fn partitions(&self) -> &[Partition] {
+        &self.partitions
+    }
+
+    /// Destroy the last partition of the topic, lowering its number of partitions.
+    ///
+    /// Only the last one can go, partitions are numbered contiguously and records
+    /// are routed to them by number.
+    pub fn delete_partition(&mut self, partition: u32) -> Result<()> {
+        if partition as usize + 1 != self.partitions.len() || partition == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Partition {} of topic {} is not the last of more than one",
+                    partition, self.name
+                ),
+            ));
+        }
+        // Renamed first, an interrupted deletion never leaves behind the directory of
+        // a partition the configuration no longer counts, only leftovers removed on
+        // open
+        let deleted = self
+            .dir
+            .join(format!("{}.{}", partition, DELETED_EXTENSION));
+        fs::rename(self.dir.join(partition.to_string()), &deleted)?;
+        self.partitions.pop();
+        let mut config = self.config.clone();
+        config.partitions -= 1;
+        write_config(&self.dir, &config)?;
+        self.config = config;
+        fs::remove_dir_all(deleted)
+    }
+
+    pub fn offsets(&self) -> Vec<PartitionOffsets> {
+        self.partitions
+            .iter()
+            .enumerate()
+            .map(|(n, p)| PartitionOffsets {
+                partition: n as u32,
+                start_offset: p.start_offset(),
+                end_offset: p.end_offset(),
+            })
+            .collect()
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.partitions.iter_mut().try_for_each(|p| p.flush())
+    }
+
+    /// Close every partition of the topic, see `Partition::close`
+    pub fn close(self) -> Result<()> {
+        self.partitions.into_iter().try_for_each(Partition::close)
+    }
+
+    /// Fetch the records of several partitions, each from its own offset, taking at
+    /// most `max_partition_bytes` from each and `max_bytes` overall.
+    ///
+    /// Partitions are fetched in the order given, the first one with records gets
+    /// at least one whatever the limits, see `Partition::fetch`. Partitions are
+    /// skipped once the overall budget is spent.
+    pub fn fetch(
+        &self,
+        positions: &[(u32, u64)],
+        max_bytes: usize,
+        max_partition_bytes: usize,
+    ) -> Result<Vec<PartitionFetch>> {
+        let mut fetches = Vec::new();
+        let mut remaining = max_bytes;
+        let mut fetched_any = false;
+        for &(n, from) in positions {
+            let partition = self.partitions.get(n as usize).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("Partition {} of topic {} not found", n, self.name),
+                )
+            })?;
+            let budget = max_partition_bytes.min(remaining);
+            let mut records = match (budget, fetched_any) {
+                (0, true) => Vec::new(),
+                _ => partition.fetch(from, budget)?,
+            };
+            let mut bytes: usize = records.iter().map(Record::binary_size).sum();
+            // Only the first partition with records may exceed its budget
+            if fetched_any && bytes > budget {
+                records.clear();
+                bytes = 0;
+            }
+            remaining = remaining.saturating_sub(bytes);
+            fetched_any |= !records.is_empty();
+            fetches.push(PartitionFetch {
+                partition: n,
+                records,
+            });
+        }
+        Ok(fetches)
+    }
+
+    pub fn cleanup_policy(&self) -> CleanupPolicy {
+        self.cleanup_policy
+    }
+
+    /// Clean up every partition according to the cleanup policy, expired segments
+    /// are deleted before compacting what's left
+    pub fn cleanup(&mut self) -> Result<()> {
+        for partition in &mut self.partitions {
+            if let (true, Some(retention)) = (self.cleanup_policy.delete, self.retention) {
+                partition.delete_expired(retention)?;
+            }
+            if self.cleanup_policy.compact {
+                partition.clean(None)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Admin entry point handling the lifecycle of the topics under a root directory
+pub struct TopicManager {
+    root: PathBuf,
+    topics: HashMap<String, Topic>,
+    offsets: OffsetStore,
+}
+
+impl TopicManager {
+    /// Open the root directory, creating it if missing, and load every topic in it
+    pub fn open(root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        let mut topics = HashMap::new();
+        for entry in fs::read_dir(&root)? {
+            let entry = entry?;
+            if entry
+                .path()
+                .extension()
+                .is_some_and(|e| e == DELETED_EXTENSION)
+            {
+                fs::remove_dir_all(entry.path())?;
+                continue;
+            }
+            let config_path = entry.path().join(CONFIG_FILE);
+            if !config_path.exists() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let config: TopicConfig = serde_json::from_slice(&fs::read(config_path)?)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let topic = Topic::open(&entry.path(), &name, config)?;
+            topics.insert(name, topic);
+        }
+        let offsets = OffsetStore::open(root.join(OFFSETS_TOPIC))?;
+        Ok(Self {
+            root,
+            topics,
+            offsets,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn create_topic(&mut self, name: &str, config: TopicConfig) -> Result<&mut Topic> {
+        let valid_name = !name.is_empty()
+            && !name.starts_with('.')
+            && !name.starts_with("__")
+            && !name.ends_with(&format!(".{}", DELETED_EXTENSION))
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+        if !valid_name {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid topic name {:?}", name),
+            ));
+        }
+        if config.partitions == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A topic needs at least one partition",
+            ));
+        }
+        if self.topics.contains_key(name) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Topic {} already exists", name),
+            ));
+        }
+        let dir = self.root.join(name);
+        // Left behind by an interrupted creation, its partitions aren't reused
+        if dir.exists() {
+            if dir.join(CONFIG_FILE).exists() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("Directory of topic {} already exists", name),
+                ));
+            }
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        let topic = Topic::open(&dir, name, config)?;
+        // The configuration is written last, a topic directory without it is ignored
+        write_config(&dir, &topic.config)?;
+        Ok(self.topics.entry(name.into()).or_insert(topic))
+    }
+
+    /// Delete the topic and its partitions, renaming its directory first so an
+    /// interrupted removal isn't loaded back
+    pub fn delete_topic(&mut self, name: &str) -> Result<()> {
+        match self.topics.remove(name) {
+            Some(topic) => {
+                drop(topic);
+                let deleted = self.root.join(format!("{}.{}", name, DELETED_EXTENSION));
+                fs::rename(self.root.join(name), &deleted)?;
+                fs::remove_dir_all(deleted)
+            }
+            None => Err(not_found(name)),
+        }
+    }
+
+    pub fn list_topics(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.topics.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    pub fn topic(&mut self, name: &str) -> Option<&mut Topic> {
+        self.topics.get_mut(name)
+    }
+
+    /// The partition `partition` of `topic`, for reading
+    pub fn partition(&self, topic: &str, partition: u32) -> Result<&Partition> {
+        self.topics
+            .get(topic)
+            .ok_or_else(|| not_found(topic))?
+            .partitions
+            .get(partition as usize)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("Partition {} of topic {} not found", partition, topic),
+                )
+            })
+    }
+
+    /// The offset `group` resumes consuming a partition from, see
+    /// `OffsetStore::position`
+    pub fn position(
+        &self,
+        group: &str,
+        topic: &str,
+        partition: u32,
+        reset: OffsetReset,
+        on_reset: impl FnOnce(&PositionReset),
+    ) -> Result<u64> {
+        let log = self.partition(topic, partition)?;
+        self.offsets
+            .position(group, topic, partition, log, reset, on_reset)
+    }
+
+    /// The store of the offsets committed by consumer groups
+    pub fn offsets(&mut self) -> &mut OffsetStore {
+        &mut self.offsets
+    }
+
+    /// Commit new offsets for `group` on the `partitions` of `topic`, all of them if
+    /// none given, moved to `target`. Meant for operators replaying or skipping
+    /// records, the consumers of the group should be stopped meanwhile. Returns the
+    /// offsets committed by partition.
+    pub fn reset_group_offsets(
+        &mut self,
+        group: &str,
+        topic: &str,
+        partitions: &[u32],
+        target: OffsetTarget,
+    ) -> Result<Vec<(u32, u64)>> {
+        let selected: Vec<u32> = match partitions {
+            [] => {
+                let topic = self.topics.get(topic).ok_or_else(|| not_found(topic))?;
+                (0..topic.partitions.len() as u32).collect()
+            }
+            _ => partitions.to_vec(),
+        };
+        let mut offsets = Vec::with_capacity(selected.len());
+        for n in selected {
+            offsets.push((n, target.resolve(self.partition(topic, n)?)?));
+        }
+        // Resolved first, nothing is committed if any partition is missing
+        for &(n, offset) in &offsets {
+            self.offsets.commit(group, topic, n, offset)?;
+        }
+        Ok(offsets)
+    }
+
+    /// Clean up the partitions of every topic, see `Topic::cleanup`
+    pub fn cleanup(&mut self) -> Result<()> {
+        self.topics.values_mut().try_for_each(Topic::cleanup)
+    }
+
+    /// Release the pages of the coldest segments of every topic, see
+    /// `MemoryManager::enforce`
+    pub fn enforce_memory(&self, memory: &MemoryManager) -> Result<usize> {
+        memory.enforce(self.topics.values().flat_map(Topic::partitions))
+    }
+
+    pub fn describe_topic(&self, name: &str) -> Result<Vec<PartitionOffsets>> {
+        self.topics
+            .get(name)
+            .map(Topic::offsets)
+            .ok_or_else(|| not_found(name))
+    }
+
+    /// Close every topic and the offsets partition, so that they open again
+    /// without scanning their logs
+    pub fn close(self) -> Result<()> {
+        self.topics.into_values().try_for_each(Topic::close)?;
+        self.offsets.close()
+    }
+}
+
+fn write_config(dir: &Path, config: &TopicConfig) -> Result<()> {
+    let content = serde_json::to_vec_pretty(config).map_err(Error::other)?;
+    let tmp_path = dir.join(format!("{}.tmp", CONFIG_FILE));
+    fs::write(&tmp_path, content)?;
+    fs::rename(tmp_path, dir.join(CONFIG_FILE))
+}
+
+fn not_found(name: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("Topic {} not found", name))
+}
+
+#[cfg(test)]
+mod topic_tests {
+    use super::{CleanupPolicy, PartitionOffsets, TopicConfig, TopicManager};
+    use crate::offsets::OffsetTarget;
+    use crate::partition::record::now_millis;
+    use std::fs;
+    use std::io::ErrorKind;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_create_topic() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let config = TopicConfig::new(3).with("retention.ms", "1000");
+
+        let topic = manager.create_topic("events", config.clone()).unwrap();
+
+        assert_eq!(topic.name(), "events");
+        assert_eq!(topic.partitions().len(), 3);
+        assert!(tmp_dir.path().join("events/topic.json").exists());
+        assert!(tmp_dir.path().join("events/2").is_dir());
+        let err = manager
+            .create_topic("events", config.clone())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        for name in [
+            "",
+            "../events",
+            ".hidden",
+            "with space",
+            "__offsets",
+            "events.deleted",
+        ] {
+            let err = manager.create_topic(name, config.clone()).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        assert!(manager.create_topic("empty", TopicConfig::new(0)).is_err());
+        let invalid = TopicConfig::new(1).with("max.record.bytes", "lots");
+        let err = manager.create_topic("bounded", invalid).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let bounded = TopicConfig::new(1).with("max.record.bytes", "64");
+        let partition = manager
+            .create_topic("bounded", bounded)
+            .unwrap()
+            .partition(0);
+        assert!(partition.unwrap().append_record(None, &[0; 64]).is_err());
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_list_describe_reopen() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager.create_topic("orders", TopicConfig::new(2)).unwrap();
+        let topic = manager.create_topic("events", TopicConfig::new(1)).unwrap();
+        let partition = topic.partition(0).unwrap();
+        for _ in 0..5 {
+            partition.append_record(None, b"value").unwrap();
+        }
+        topic.flush().unwrap();
+
+        assert_eq!(manager.list_topics(), vec!["events", "orders"]);
+        assert_eq!(
+            manager.describe_topic("events").unwrap(),
+            vec![PartitionOffsets {
+                partition: 0,
+                start_offset: 0,
+                end_offset: 5
+            }]
+        );
+        assert_eq!(
+            manager.describe_topic("missing").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        drop(manager);
+
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        assert_eq!(manager.list_topics(), vec!["events", "orders"]);
+        manager.offsets().commit("group", "events", 0, 3).unwrap();
+        assert_eq!(manager.offsets().committed("group", "events", 0), Some(3));
+        assert_eq!(manager.topic("orders").unwrap().config().partitions, 2);
+        assert_eq!(manager.describe_topic("events").unwrap()[0].end_offset, 5);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_delete_topic() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        manager.create_topic("events", TopicConfig::new(2)).unwrap();
+
+        manager.delete_topic("events").unwrap();
+
+        assert!(manager.list_topics().is_empty());
+        assert!(!tmp_dir.path().join("events").exists());
+        assert_eq!(
+            manager.delete_topic("events").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_delete_and_create() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        for name in ["events", "orders"] {
+            let topic = manager.create_topic(name, TopicConfig::new(1)).unwrap();
+            topic
+                .partition(0)
+                .unwrap()
+                .append_record(None, b"old")
+                .unwrap();
+            topic.flush().unwrap();
+        }
+        drop(manager);
+        // A deletion interrupted after the rename, a creation before the config
+        let root = tmp_dir.path();
+        fs::rename(root.join("events"), root.join("events.deleted")).unwrap();
+        fs::remove_file(root.join("orders/topic.json")).unwrap();
+
+        let mut manager = TopicManager::open(root).unwrap();
+        assert!(manager.list_topics().is_empty());
+        assert!(!root.join("events.deleted").exists());
+        let topic = manager.create_topic("orders", TopicConfig::new(1)).unwrap();
+        assert_eq!(topic.partitions()[0].end_offset(), 0);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_reset_group_offsets() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let topic = manager.create_topic("events", TopicConfig::new(2)).unwrap();
+        for n in 0..2 {
+            let partition = topic.partition(n).unwrap();
+            for i in 0..10u64 {
+                partition
+                    .append_record_at(i * 1000, None, b"value")
+                    .unwrap();
+            }
+        }
+        manager.offsets().commit("group", "events", 0, 4).unwrap();
+
+        let offsets = manager
+            .reset_group_offsets("group", "events", &[], OffsetTarget::Latest)
+            .unwrap();
+        assert_eq!(offsets, vec![(0, 10), (1, 10)]);
+        let target = "timestamp:2500".parse().unwrap();
+        let offsets = manager
+            .reset_group_offsets("group", "events", &[1], target)
+            .unwrap();
+        assert_eq!(offsets, vec![(1, 3)]);
+        assert_eq!(manager.offsets().committed("group", "events", 0), Some(10));
+        assert_eq!(manager.offsets().committed("group", "events", 1), Some(3));
+
+        let err = manager
+            .reset_group_offsets("group", "events", &[0, 2], OffsetTarget::Earliest)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(manager.offsets().committed("group", "events", 0), Some(10));
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_fetch() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let topic = manager.create_topic("events", TopicConfig::new(3)).unwrap();
+        for n in 0..3 {
+            let partition = topic.partition(n).unwrap();
+            for i in 0..10u64 {
+                partition.append_record(None, &[0; 100]).unwrap();
+                partition.append_record(None, &i.to_be_bytes()).unwrap();
+            }
+        }
+        let positions = [(0, 0), (1, 0), (2, 0)];
+
+        let fetches = topic.fetch(&positions, usize::MAX, 500).unwrap();
+        assert!(fetches.iter().all(|f| (2..10).contains(&f.records.len())));
+        let fetches = topic.fetch(&positions, 500, usize::MAX).unwrap();
+        assert!(!fetches[0].records.is_empty());
+        assert!(fetches[2].records.is_empty());
+        // The first records exceed the limits, one is returned anyway
+        let fetches = topic.fetch(&positions, 10, 10).unwrap();
+        assert_eq!(fetches[0].records.len(), 1);
+        assert!(fetches[1].records.is_empty());
+        assert!(topic.fetch(&[(3, 0)], 10, 10).is_err());
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_delete_partition() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let topic = manager.create_topic("events", TopicConfig::new(3)).unwrap();
+
+        for partition in [0, 1, 3] {
+            let err = topic.delete_partition(partition).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        topic.delete_partition(2).unwrap();
+        assert_eq!(topic.partitions().len(), 2);
+        assert!(!tmp_dir.path().join("events/2").exists());
+        assert!(!tmp_dir.path().join("events/2.deleted").exists());
+        // Leftover of an interrupted deletion
+        fs::create_dir(tmp_dir.path().join("events/1.deleted")).unwrap();
+        drop(manager);
+
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        assert_eq!(manager.topic("events").unwrap().partitions().len(), 2);
+        assert!(!tmp_dir.path().join("events/1.deleted").exists());
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_timestamp_type() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let append_time = TopicConfig::new(1).with("message.timestamp.type", "LogAppendTime");
+        for (name, config) in [("created", TopicConfig::new(1)), ("appended", append_time)] {
+            let partition = manager.create_topic(name, config).unwrap().partition(0);
+            partition
+                .unwrap()
+                .append_record_at(42, None, b"value")
+                .unwrap();
+        }
+        let timestamp = |manager: &mut TopicManager, name| {
+            manager.topic(name).unwrap().partitions()[0]
+                .read_range(0, 1)
+                .unwrap()[0]
+                .timestamp
+        };
+
+        assert_eq!(timestamp(&mut manager, "created"), 42);
+        assert!(timestamp(&mut manager, "appended") > 42);
+        let invalid = TopicConfig::new(1).with("message.timestamp.type", "Whenever");
+        assert!(manager.create_topic("invalid", invalid).is_err());
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_cleanup_policy() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut manager = TopicManager::open(tmp_dir.path()).unwrap();
+        let config = TopicConfig::new(1)
+            .with("cleanup.policy", "compact,delete")
+            .with("retention.ms", "3600000");
+        let topic = manager.create_topic("changelog", config).unwrap();
+        assert_eq!(
+            topic.cleanup_policy(),
+            CleanupPolicy {
+                compact: true,
+                delete: true
+            }
+        );
+        let partition = topic.partition(0).unwrap();
+        let expired = now_millis() - 2 * 3600 * 1000;
+        for i in 0..200u64 {
+            partition
+                .append_record_at(expired, Some("key".into()), &i.to_be_bytes())
+                .unwrap();
+        }
+        let retained = partition.end_offset();
+        for i in 0..200u64 {
+            partition
+                .append_record(Some((i % 2).to_string().into()), &i.to_be_bytes())
+                .unwrap();
+        }
+        let end_offset = partition.end_offset();
+
+        manager.cleanup().unwrap();
+
+        let partition = &manager.topic("changelog").unwrap().partitions()[0];
+        assert!(partition.start_offset() > 0);
+        let records = partition.read_range(0, end_offset).unwrap();
+        assert!(records.len() < (end_offset - retained) as usize);
+        assert!(records.iter().filter(|r| r.offset >= retained).count() >= 2);
+        let invalid = TopicConfig::new(1).with("cleanup.policy", "compact,archive");
+        assert!(manager.create_topic("invalid", invalid).is_err());
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+//! W3C trace context propagation
+//!
+//! The `traceparent` of a producer travels with its records as a header of the
+//! same name, so a consumer can continue the trace they belong to:
+//!
+//! ```text
+//! 00-<trace id, 32 hex>-<parent span id, 16 hex>-<flags, 2 hex>
+//! ```
+//!
+//! Only version `00` is understood, a header that doesn't parse is ignored rather
+//! than failing the record.
+use crate::partition::record::Record;
+use std::fmt;
+use std::str::FromStr;
+
+/// Name of the header carrying the trace context
+pub const TRACEPARENT: &str = "traceparent";
+
+const SAMPLED: u8 = 0x01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub parent_id: [u8; 8],
+    pub flags: u8,
+}
+
+impl TraceContext {
+    pub fn is_sampled(&self) -> bool {
+        self.flags & SAMPLED != 0
+    }
+
+    /// The trace context of a record, if it carries a valid one
+    pub fn extract(record: &Record) -> Option<Self> {
+        std::str::from_utf8(record.header(TRACEPARENT)?)
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// Set the trace context header of `headers`, replacing any previous one
+    pub fn inject(&self, headers: &mut Vec<(String, Vec<u8>)>) {
+        headers.retain(|(name, _)| name != TRACEPARENT);
+        headers.push((TRACEPARENT.into(), self.to_string().into_bytes()));
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "00-")?;
+        self.trace_id
+            .iter()
+            .try_for_each(|b| write!(f, "{:02x}", b))?;
+        write!(f, "-")?;
+        self.parent_id
+            .iter()
+            .try_for_each(|b| write!(f, "{:02x}", b))?;
+        write!(f, "-{:02x}", self.flags)
+    }
+}
+
+impl FromStr for TraceContext {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut fields = s.trim().split('-');
+        let (Some("00"), Some(trace_id), Some(parent_id), Some(flags), None) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            return Err(());
+        };
+        let trace_id: [u8; 16] = hex(trace_id)?;
+        let parent_id: [u8; 8] = hex(parent_id)?;
+        let [flags] = hex(flags)?;
+        // All zeros ids are invalid
+        if trace_id == [0; 16] || parent_id == [0; 8] {
+            return Err(());
+        }
+        Ok(Self {
+            trace_id,
+            parent_id,
+            flags,
+        })
+    }
+}
+
+fn hex<const N: usize>(s: &str) -> Result<[u8; N], ()> {
+    if s.len() != N * 2 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return Err(());
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| ())?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use super::{TraceContext, TRACEPARENT};
+    use crate::partition::record::Record;
+
+    #[test]
+    fn test_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context: TraceContext = header.parse().unwrap();
+        assert!(context.is_sampled());
+        assert_eq!(
+            context.parent_id,
+            [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]
+        );
+        assert_eq!(context.to_string(), header);
+
+        let mut record = Record::new(0, None, b"value".to_vec());
+        record.headers.push((TRACEPARENT.into(), b"stale".to_vec()));
+        assert_eq!(TraceContext::extract(&record), None);
+        context.inject(&mut record.headers);
+        assert_eq!(record.headers.len(), 1);
+        assert_eq!(TraceContext::extract(&record), Some(context));
+
+        for invalid in [
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert!(invalid.parse::<TraceContext>().is_err());
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Light reshaping of records on their way in or out
+//!
+//! A `Transform` rewrites the key, value or headers of a record, or drops it, a
+//! `Transforms` chain applies several in order and stops at the first dropping
+//! the record. The same transforms work on records produced and on records fetched
+//! through the `Fields` they share.
+//!
+//! The built-ins covering JSON values leave the records whose value isn't a JSON
+//! object untouched.
+use crate::partition::record::Record;
+use crate::producer::ProducerRecord;
+use serde_json::Value;
+
+/// The parts of a record a transform can read and rewrite
+pub trait Fields {
+    fn key(&self) -> Option<&[u8]>;
+    fn value(&self) -> &[u8];
+    fn header(&self, name: &str) -> Option<&[u8]>;
+    fn set_key(&mut self, key: Option<Vec<u8>>);
+    fn set_value(&mut self, value: Vec<u8>);
+    fn add_header(&mut self, name: &str, value: &[u8]);
+}
+
+impl Fields for ProducerRecord {
+    fn key(&self) -> Option<&[u8]> {
+        self.key.as_deref()
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn set_key(&mut self, key: Option<Vec<u8>>) {
+        self.key = key;
+    }
+
+    fn set_value(&mut self, value: Vec<u8>) {
+        self.value = value;
+    }
+
+    fn add_header(&mut self, name: &str, value: &[u8]) {
+        self.headers.push((name.into(), value.to_vec()));
+    }
+}
+
+impl Fields for Record {
+    fn key(&self) -> Option<&[u8]> {
+        self.key.as_deref()
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    fn header(&self, name: &str) -> Option<&[u8]> {
+        Record::header(self, name)
+    }
+
+    fn set_key(&mut self, key: Option<Vec<u8>>) {
+        self.key = key;
+    }
+
+    fn set_value(&mut self, value: Vec<u8>) {
+        self.value = value;
+    }
+
+    fn add_header(&mut self, name: &str, value: &[u8]) {
+        self.headers.push((name.into(), value.to_vec()));
+    }
+}
+
+pub trait Transform: Send {
+    /// Rewrite `record` in place, returning whether to keep it
+    fn apply(&self, record: &mut dyn Fields) -> bool;
+}
+
+impl<F: Fn(&mut dyn Fields) -> bool + Send> Transform for F {
+    fn apply(&self, record: &mut dyn Fields) -> bool {
+        self(record)
+    }
+}
+
+#[derive(Default)]
+pub struct Transforms {
+    chain: Vec<Box<dyn Transform>>,
+}
+
+impl Transforms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, transform: impl Transform + 'static) {
+        self.chain.push(Box::new(transform));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chain.is_empty()
+    }
+
+    /// Apply the chain to `record`, returning whether to keep it
+    pub fn apply(&self, record: &mut dyn Fields) -> bool {
+        self.chain.iter().all(|t| t.apply(record))
+    }
+}
+
+/// Replace a field of JSON values with `mask`, the path separated by dots
+pub struct MaskField {
+    pub path: String,
+    pub mask: String,
+}
+
+impl Transform for MaskField {
+    fn apply(&self, record: &mut dyn Fields) -> bool {
+        let Ok(mut json) = serde_json::from_slice::<Value>(record.value()) else {
+            return true;
+        };
+        if let Some(field) = lookup(&mut json, &self.path) {
+            *field = Value::String(self.mask.clone());
+            record.set_value(json.to_string().into_bytes());
+        }
+        true
+    }
+}
+
+/// Add a header to every record
+pub struct AddHeader {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Transform for AddHeader {
+    fn apply(&self, record: &mut dyn Fields) -> bool {
+        record.add_header(&self.name, &self.value);
+        true
+    }
+}
+
+/// Key records by a field of their JSON value, strings as their content and any
+/// other value as its JSON text
+pub struct ReKey {
+    pub path: String,
+}
+
+impl Transform for ReKey {
+    fn apply(&self, record: &mut dyn Fields) -> bool {
+        let Ok(mut json) = serde_json::from_slice::<Value>(record.value()) else {
+            return true;
+        };
+        match lookup(&mut json, &self.path) {
+            Some(Value::String(s)) => record.set_key(Some(s.as_bytes().to_vec())),
+            Some(field) => record.set_key(Some(field.to_string().into_bytes())),
+            None => {}
+        }
+        true
+    }
+}
+
+/// Keep only the records matching a predicate
+pub struct Filter<P>(pub P);
+
+impl<P: Fn(&dyn Fields) -> bool + Send> Transform for Filter<P> {
+    fn apply(&self, record: &mut dyn Fields) -> bool {
+        (self.0)(record)
+    }
+}
+
+fn lookup<'a>(json: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    path.split('.')
+        .try_fold(json, |value, name| value.as_object_mut()?.get_mut(name))
+}
+
+#[cfg(test)]
+mod transform_tests {
+    use super::{AddHeader, Fields, Filter, MaskField, ReKey, Transforms};
+    use crate::partition::record::Record;
+
+    #[test]
+    fn test_chain() {
+        let mut transforms = Transforms::new();
+        transforms.push(Filter(|r: &dyn Fields| r.header("debug").is_none()));
+        transforms.push(MaskField {
+            path: "user.email".into(),
+            mask: "***".into(),
+        });
+        transforms.push(ReKey {
+            path: "user.id".into(),
+        });
+        transforms.push(AddHeader {
+            name: "transformed".into(),
+            value: b"1".to_vec(),
+        });
+
+        let value = br#"{"user":{"id":42,"email":"a@b.c"},"kind":"login"}"#;
+        let mut record = Record::new(0, None, value.to_vec());
+        assert!(transforms.apply(&mut record));
+        let json: serde_json::Value = serde_json::from_slice(&record.value).unwrap();
+        assert_eq!(json["user"]["email"], "***");
+        assert_eq!(json["kind"], "login");
+        assert_eq!(record.key.as_deref(), Some(&b"42"[..]));
+        assert_eq!(record.header("transformed"), Some(&b"1"[..]));
+
+        let mut binary = Record::new(1, None, vec![0, 159]);
+        assert!(transforms.apply(&mut binary));
+        assert_eq!((binary.key, binary.value), (None, vec![0, 159]));
+        let mut debug = Record::new(2, None, value.to_vec());
+        debug.headers.push(("debug".into(), vec![]));
+        assert!(!transforms.apply(&mut debug));
+        assert!(debug.header("transformed").is_none());
+    }
+}
@@ -0,0 +1,204 @@
+//! Typed access to a partition
+//!
+//! A `TypedPartition` wraps a `Partition`, serializing keys and values on append
+//! and deserializing them on read through a pluggable `Codec`, so applications
+//! can work with their own types instead of raw bytes.
+#[cfg(feature = "prost")]
+pub mod protobuf;
+pub mod schema;
+
+use crate::partition::record::Record;
+use crate::partition::{AppendInfo, Partition};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+
+/// Conversion of values of type `T` from and to their binary representation
+pub trait Codec<T> {
+    fn encode(&self, value: &T) -> Result<Vec<u8>>;
+    fn decode(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// JSON encoding of any serde serializable type
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Json {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// Compact bincode encoding of any serde serializable type
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl<T: Serialize + DeserializeOwned> Codec<T> for Bincode {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// A record with its key and value decoded
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypedRecord<K, V> {
+    pub offset: u64,
+    pub timestamp: u64,
+    pub key: Option<K>,
+    pub value: V,
+}
+
+pub struct TypedPartition<K, V, KC = Json, VC = Json> {
+    partition: Partition,
+    key_codec: KC,
+    value_codec: VC,
+    _types: PhantomData<(K, V)>,
+}
+
+impl<K, V> TypedPartition<K, V, Json, Json>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn json(partition: Partition) -> Self {
+        Self::new(partition, Json, Json)
+    }
+}
+
+impl<K, V, KC, VC> TypedPartition<K, V, KC, VC>
+where
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    pub fn new(partition: Partition, key_codec: KC, value_codec: VC) -> Self {
+        Self {
+            partition,
+            key_codec,
+            value_codec,
+            _types: PhantomData,
+        }
+    }
+
+    pub fn partition(&mut self) -> &mut Partition {
+        &mut self.partition
+    }
+
+    pub fn into_inner(self) -> Partition {
+        self.partition
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.partition.flush()
+    }
+
+    pub fn append(&mut self, key: Option<&K>, value: &V) -> Result<AppendInfo> {
+        let key = key.map(|k| self.key_codec.encode(k)).transpose()?;
+        let value = self.value_codec.encode(value)?;
+        self.partition.append_record(key, &value)
+    }
+
+    pub fn find(&mut self, offset: u64) -> Result<TypedRecord<K, V>> {
+        let record = self.partition.find_record(offset)?;
+        self.decode(record)
+    }
+
+    pub fn read_range(&self, from: u64, to: u64) -> Result<Vec<TypedRecord<K, V>>> {
+        self.partition
+            .read_range(from, to)?
+            .into_iter()
+            .map(|r| self.decode(r))
+            .collect()
+    }
+
+    fn decode(&self, record: Record) -> Result<TypedRecord<K, V>> {
+        Ok(TypedRecord {
+            offset: record.offset,
+            timestamp: record.timestamp,
+            key: record.key.map(|k| self.key_codec.decode(&k)).transpose()?,
+            value: self.value_codec.decode(&record.value)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod typed_tests {
+    use super::TypedPartition;
+    use crate::partition::Partition;
+    use serde::{Deserialize, Serialize};
+    use tempdir::TempDir;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Event {
+        id: u32,
+        name: String,
+    }
+
+    #[test]
+    fn test_json_append_find() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let mut typed = TypedPartition::<String, Event>::json(partition);
+        let event = Event {
+            id: 1,
+            name: "created".into(),
+        };
+
+        typed.append(Some(&"user-1".into()), &event).unwrap();
+        typed.append(None, &event).unwrap();
+
+        let record = typed.find(0).unwrap();
+        assert_eq!(record.offset, 0);
+        assert_eq!(record.key, Some("user-1".into()));
+        assert_eq!(record.value, event);
+        let records = typed.read_range(0, 2).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].key, None);
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        partition.append_record(None, b"not json").unwrap();
+        let mut typed = TypedPartition::<String, Event>::json(partition);
+
+        assert!(typed.find(0).is_err());
+        tmp_dir.close().unwrap();
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode_append_find() {
+        use super::Bincode;
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let mut typed = TypedPartition::<u64, Event, _, _>::new(partition, Bincode, Bincode);
+        let event = Event {
+            id: 7,
+            name: "updated".into(),
+        };
+
+        typed.append(Some(&42), &event).unwrap();
+
+        let record = typed.find(0).unwrap();
+        assert_eq!(record.key, Some(42));
+        assert_eq!(record.value, event);
+        assert_eq!(
+            typed.partition().find_record(0).unwrap().key,
+            Some(vec![42, 0, 0, 0, 0, 0, 0, 0])
+        );
+        tmp_dir.close().unwrap();
+    }
+}
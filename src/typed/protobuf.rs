@@ -0,0 +1,197 @@
+//! Protobuf encoding of record values through prost
+//!
+//! `ProtobufSerde` can optionally frame each message with the layout used by the
+//! common schema registries, a schema id `Envelope` followed by the message
+//! indexes:
+//!
+//! ```text
+//! | magic (0) | schema id (u32, big endian) | message indexes | protobuf payload |
+//! ```
+//!
+//! where message indexes are the zig-zag varint encoded path of the message type
+//! inside its schema file, the common case of the first message being encoded as a
+//! single zero byte.
+use crate::partition::{AppendInfo, Partition};
+use crate::typed::schema::Envelope;
+use crate::typed::Codec;
+use byteorder::WriteBytesExt;
+use prost::encoding::{decode_varint, encode_varint};
+use prost::Message;
+use std::io::{Error, ErrorKind, Result};
+
+/// Schema registry framing metadata
+#[derive(Clone, Debug, PartialEq)]
+pub struct Framing {
+    pub schema_id: u32,
+    pub message_indexes: Vec<i32>,
+}
+
+impl Framing {
+    pub fn new(schema_id: u32) -> Self {
+        Self {
+            schema_id,
+            message_indexes: vec![0],
+        }
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>) -> Result<()> {
+        Envelope::new(self.schema_id).write(buf)?;
+        if self.message_indexes == [0] {
+            buf.write_u8(0)?;
+        } else {
+            encode_varint(zigzag(self.message_indexes.len() as i32), buf);
+            for index in &self.message_indexes {
+                encode_varint(zigzag(*index), buf);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the framing out of `buf`, advancing it to the start of the payload
+    pub fn from_binary(buf: &mut &[u8]) -> Result<Self> {
+        let schema_id = Envelope::from_binary(buf)?.schema_id;
+        let count = read_varint(buf)?;
+        let message_indexes = if count == 0 {
+            vec![0]
+        } else {
+            (0..count)
+                .map(|_| read_varint(buf))
+                .collect::<Result<_>>()?
+        };
+        Ok(Self {
+            schema_id,
+            message_indexes,
+        })
+    }
+}
+
+fn zigzag(n: i32) -> u64 {
+    ((n << 1) ^ (n >> 31)) as u32 as u64
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<i32> {
+    let n = decode_varint(buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))? as u32;
+    Ok(((n >> 1) as i32) ^ -((n & 1) as i32))
+}
+
+/// Protobuf codec for prost messages, with optional schema registry framing
+#[derive(Clone, Debug, Default)]
+pub struct ProtobufSerde {
+    framing: Option<Framing>,
+}
+
+impl ProtobufSerde {
+    pub fn new() -> Self {
+        Self { framing: None }
+    }
+
+    pub fn with_framing(framing: Framing) -> Self {
+        Self {
+            framing: Some(framing),
+        }
+    }
+}
+
+impl<M: Message + Default> Codec<M> for ProtobufSerde {
+    fn encode(&self, value: &M) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(value.encoded_len() + 6);
+        if let Some(framing) = &self.framing {
+            framing.write(&mut buf)?;
+        }
+        value
+            .encode(&mut buf)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        Ok(buf)
+    }
+
+    fn decode(&self, mut bytes: &[u8]) -> Result<M> {
+        if let Some(framing) = &self.framing {
+            let found = Framing::from_binary(&mut bytes)?;
+            if found.schema_id != framing.schema_id {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Schema id mismatch, expected {} found {}",
+                        framing.schema_id, found.schema_id
+                    ),
+                ));
+            }
+        }
+        M::decode(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// Append a prost message as the value of a new record
+pub fn append_message<M: Message + Default>(
+    partition: &mut Partition,
+    serde: &ProtobufSerde,
+    key: Option<Vec<u8>>,
+    message: &M,
+) -> Result<AppendInfo> {
+    partition.append_record(key, &serde.encode(message)?)
+}
+
+/// Fetch the record at `offset` decoding its value as a prost message
+pub fn find_message<M: Message + Default>(
+    partition: &mut Partition,
+    serde: &ProtobufSerde,
+    offset: u64,
+) -> Result<M> {
+    serde.decode(&partition.find_record(offset)?.value)
+}
+
+#[cfg(test)]
+mod protobuf_tests {
+    use super::{append_message, find_message, Framing, ProtobufSerde};
+    use crate::partition::Partition;
+    use crate::typed::Codec;
+    use tempdir::TempDir;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Event {
+        #[prost(uint32, tag = "1")]
+        id: u32,
+        #[prost(string, tag = "2")]
+        name: String,
+    }
+
+    fn event() -> Event {
+        Event {
+            id: 3,
+            name: "created".into(),
+        }
+    }
+
+    #[test]
+    fn test_framing() {
+        let serde = ProtobufSerde::with_framing(Framing::new(42));
+        let bytes = serde.encode(&event()).unwrap();
+        assert_eq!(&bytes[..6], &[0, 0, 0, 0, 42, 0]);
+        assert_eq!(Codec::<Event>::decode(&serde, &bytes).unwrap(), event());
+
+        let nested = Framing {
+            schema_id: 7,
+            message_indexes: vec![1, 2],
+        };
+        let mut buf = vec![];
+        nested.write(&mut buf).unwrap();
+        assert_eq!(buf, &[0, 0, 0, 0, 7, 4, 2, 4]);
+        assert_eq!(Framing::from_binary(&mut &buf[..]).unwrap(), nested);
+
+        let other = ProtobufSerde::with_framing(Framing::new(43));
+        assert!(Codec::<Event>::decode(&other, &bytes).is_err());
+    }
+
+    #[test]
+    fn test_append_find_message() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let mut partition = Partition::open(tmp_dir.path()).unwrap();
+        let serde = ProtobufSerde::new();
+
+        append_message(&mut partition, &serde, None, &event()).unwrap();
+
+        let found: Event = find_message(&mut partition, &serde, 0).unwrap();
+        assert_eq!(found, event());
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+//! Schema id envelope and schema registry hook
+//!
+//! Values can be wrapped in a small envelope carrying the id of the schema they
+//! were written with:
+//!
+//! ```text
+//! | magic (0) | schema id (u32, big endian) | payload |
+//! ```
+//!
+//! A `SchemaProvider` is asked for the schema id of every value produced and to
+//! validate every value consumed, letting a schema registry act at the edge of the
+//! partition.
+use crate::typed::Codec;
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Error, ErrorKind, Result};
+
+const ENVELOPE_MAGIC_BYTE: u8 = 0;
+pub const ENVELOPE_SIZE: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Envelope {
+    pub schema_id: u32,
+}
+
+impl Envelope {
+    pub fn new(schema_id: u32) -> Self {
+        Self { schema_id }
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.write_u8(ENVELOPE_MAGIC_BYTE)?;
+        buf.write_u32::<NetworkEndian>(self.schema_id)
+    }
+
+    /// Read the envelope out of `buf`, advancing it to the start of the payload
+    pub fn from_binary(buf: &mut &[u8]) -> Result<Self> {
+        if buf.read_u8()? != ENVELOPE_MAGIC_BYTE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Missing envelope magic byte",
+            ));
+        }
+        Ok(Self {
+            schema_id: buf.read_u32::<NetworkEndian>()?,
+        })
+    }
+
+    /// Prepend an envelope with `schema_id` to `payload`
+    pub fn wrap(schema_id: u32, payload: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(ENVELOPE_SIZE + payload.len());
+        Self::new(schema_id).write(&mut buf)?;
+        buf.extend_from_slice(payload);
+        Ok(buf)
+    }
+
+    /// Split an enveloped value into its envelope and payload
+    pub fn unwrap(mut bytes: &[u8]) -> Result<(Self, &[u8])> {
+        let envelope = Self::from_binary(&mut bytes)?;
+        Ok((envelope, bytes))
+    }
+}
+
+/// Hook into a schema registry, resolving schema ids on produce and validating
+/// them on consume
+pub trait SchemaProvider {
+    /// Return the schema id `payload` is written with, failing if it isn't valid
+    fn schema_id(&self, payload: &[u8]) -> Result<u32>;
+
+    /// Check that `payload` can be read with the schema `schema_id`
+    fn validate(&self, schema_id: u32, payload: &[u8]) -> Result<()>;
+}
+
+/// A provider with a single known schema
+#[derive(Clone, Copy, Debug)]
+pub struct StaticSchema(pub u32);
+
+impl SchemaProvider for StaticSchema {
+    fn schema_id(&self, _payload: &[u8]) -> Result<u32> {
+        Ok(self.0)
+    }
+
+    fn validate(&self, schema_id: u32, _payload: &[u8]) -> Result<()> {
+        if schema_id == self.0 {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown schema id {}", schema_id),
+            ))
+        }
+    }
+}
+
+/// Codec adapter wrapping the values encoded by `C` in a schema id envelope
+/// resolved and validated through `P`
+#[derive(Clone, Debug)]
+pub struct Enveloped<C, P> {
+    codec: C,
+    provider: P,
+}
+
+impl<C, P> Enveloped<C, P> {
+    pub fn new(codec: C, provider: P) -> Self {
+        Self { codec, provider }
+    }
+}
+
+impl<T, C: Codec<T>, P: SchemaProvider> Codec<T> for Enveloped<C, P> {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        let payload = self.codec.encode(value)?;
+        let schema_id = self.provider.schema_id(&payload)?;
+        Envelope::wrap(schema_id, &payload)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        let (envelope, payload) = Envelope::unwrap(bytes)?;
+        self.provider.validate(envelope.schema_id, payload)?;
+        self.codec.decode(payload)
+    }
+}
+
+#[cfg(test)]
+mod schema_tests {
+    use super::{Envelope, Enveloped, SchemaProvider, StaticSchema};
+    use crate::partition::Partition;
+    use crate::typed::{Codec, Json, TypedPartition};
+    use std::io::{Error, ErrorKind, Result};
+    use tempdir::TempDir;
+
+    struct NonEmpty;
+
+    impl SchemaProvider for NonEmpty {
+        fn schema_id(&self, payload: &[u8]) -> Result<u32> {
+            if payload == b"\"\"" {
+                Err(Error::new(ErrorKind::InvalidInput, "Empty payload"))
+            } else {
+                Ok(1)
+            }
+        }
+
+        fn validate(&self, _schema_id: u32, _payload: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_envelope() {
+        let bytes = Envelope::wrap(258, b"payload").unwrap();
+        assert_eq!(&bytes[..5], &[0, 0, 0, 1, 2]);
+        let (envelope, payload) = Envelope::unwrap(&bytes).unwrap();
+        assert_eq!(envelope, Envelope::new(258));
+        assert_eq!(payload, b"payload");
+        assert!(Envelope::unwrap(b"payload").is_err());
+    }
+
+    #[test]
+    fn test_enveloped_codec() {
+        let codec = Enveloped::new(Json, StaticSchema(9));
+        let bytes = codec.encode(&"value".to_string()).unwrap();
+        assert_eq!(&bytes[..5], &[0, 0, 0, 0, 9]);
+        assert_eq!(Codec::<String>::decode(&codec, &bytes).unwrap(), "value");
+
+        let other = Enveloped::new(Json, StaticSchema(10));
+        assert!(Codec::<String>::decode(&other, &bytes).is_err());
+    }
+
+    #[test]
+    fn test_typed_partition_validation() {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        let partition = Partition::open(tmp_dir.path()).unwrap();
+        let mut typed = TypedPartition::<String, String, _, _>::new(
+            partition,
+            Json,
+            Enveloped::new(Json, NonEmpty),
+        );
+
+        typed.append(None, &"value".to_string()).unwrap();
+        assert!(typed.append(None, &String::new()).is_err());
+
+        assert_eq!(typed.find(0).unwrap().value, "value");
+        assert_eq!(
+            &typed.partition().find_record(0).unwrap().value[..5],
+            &[0, 0, 0, 0, 1]
+        );
+        tmp_dir.close().unwrap();
+    }
+}
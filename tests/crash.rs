@@ -0,0 +1,72 @@
+//! Crash injection, run with `cargo test --features fail --test crash`
+//!
+//! Failpoints sit where a crash leaves the files of a partition half updated:
+//!
+//! - `segment::log_appended`, a record is in the log but not in the index yet
+//! - `partition::segment_roll`, the new active segment is created but the previous
+//!   one not sealed yet
+//! - `partition::swap_staged`, a rewritten log replaced the original one but its
+//!   index is still in the staging directory
+//!
+//! The harness fails the partition at each of them, drops it without a close as a
+//! killed process would, and checks what reopening it recovers.
+//!
+//! A test binary of its own, failpoints are global to the process and would fail the
+//! other tests running in parallel.
+#![cfg(feature = "fail")]
+use fail::FailScenario;
+use shoju::partition::Partition;
+use std::io::Result;
+use std::path::Path;
+use tempdir::TempDir;
+
+/// Append records until the failpoint fires, merging the sealed segments every
+/// now and then, returning the offsets and values of the acknowledged records
+fn run_until_crash(dir: &Path) -> Vec<(u64, Vec<u8>)> {
+    let mut partition = Partition::open(dir).unwrap();
+    let mut acknowledged = Vec::new();
+    let crashed = (0..2000u64).try_for_each(|i| -> Result<()> {
+        let value = i.to_be_bytes().to_vec();
+        let info = partition.append_record(None, &value)?;
+        acknowledged.push((info.offset, value));
+        if i % 200 == 199 {
+            partition.merge_segments(64 * 1024)?;
+        }
+        Ok(())
+    });
+    assert!(crashed.is_err());
+    acknowledged
+}
+
+fn assert_recovered(dir: &Path, acknowledged: &[(u64, Vec<u8>)]) {
+    let mut partition = Partition::open(dir).unwrap();
+    for (offset, value) in acknowledged {
+        assert_eq!(&partition.find_record(*offset).unwrap().value, value);
+    }
+    let records = partition.read_range(0, u64::MAX).unwrap();
+    assert!(records.windows(2).all(|w| w[0].offset < w[1].offset));
+    let end_offset = partition.end_offset();
+    assert!(acknowledged.last().is_none_or(|(o, _)| *o < end_offset));
+    let info = partition.append_record(None, b"after").unwrap();
+    assert_eq!(info.offset, end_offset);
+    assert_eq!(partition.find_record(end_offset).unwrap().value, b"after");
+}
+
+#[test]
+fn test_crash_recovery() {
+    let scenario = FailScenario::setup();
+    for (failpoint, actions) in [
+        ("segment::log_appended", "1500*off->return"),
+        ("partition::segment_roll", "5*off->return"),
+        ("partition::swap_staged", "3*off->return"),
+    ] {
+        let tmp_dir = TempDir::new("test_tempdir").unwrap();
+        fail::cfg(failpoint, actions).unwrap();
+        let acknowledged = run_until_crash(tmp_dir.path());
+        fail::remove(failpoint);
+        assert!(!acknowledged.is_empty(), "{}", failpoint);
+        assert_recovered(tmp_dir.path(), &acknowledged);
+        tmp_dir.close().unwrap();
+    }
+    scenario.teardown();
+}